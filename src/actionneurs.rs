@@ -0,0 +1,484 @@
+use log::{debug, info, warn};
+
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
+use millegrilles_common_rust::chrono;
+use millegrilles_common_rust::chrono::{DateTime, Utc};
+use millegrilles_common_rust::constantes::*;
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
+use millegrilles_common_rust::millegrilles_cryptographie::deser_message_buffer;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::{epochseconds, optionepochseconds, MessageMilleGrillesBufferDefault};
+use millegrilles_common_rust::mongo_dao::{opt_chrono_datetime_as_bson_datetime, convertir_to_bson, MongoDao};
+use millegrilles_common_rust::bson::serde_helpers::chrono_datetime_as_bson_datetime;
+use millegrilles_common_rust::mongodb::options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument};
+use millegrilles_common_rust::recepteur_messages::MessageValide;
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+use millegrilles_common_rust::serde_json;
+use millegrilles_common_rust::serde_json::Value;
+use millegrilles_common_rust::tokio_stream::StreamExt;
+
+use crate::boite_envoi::{instance_appareil_connecte, mettre_en_file, RowBoiteEnvoi, CONST_EXPIRATION_COMMANDE_SECS};
+use crate::common::*;
+use crate::domain_manager::SenseursPassifsDomainManager;
+
+/// Delai de livraison et confirmation d'une commande par defaut.
+pub const CONST_COMMANDE_TIMEOUT_DEFAUT_SECS: u32 = 30;
+const CONST_COMMANDE_TIMEOUT_MAX_SECS: u32 = 300;
+const CONST_COMMANDES_HISTORIQUE_LIMITE: i64 = 100;
+
+const CONST_ETAT_EN_ATTENTE: &str = "en_attente";
+const CONST_ETAT_LIVREE: &str = "livree";
+const CONST_ETAT_CONFIRMEE: &str = "confirmee";
+const CONST_ETAT_ECHEC: &str = "echec";
+
+/// Commande usager vers un actionneur (switch, sortie) d'un appareil.
+#[derive(Deserialize)]
+struct CommandeActionnerAppareil {
+    uuid_appareil: String,
+    senseur_id: String,
+    action: String,
+    valeur: Option<Value>,
+    /// Delai (secondes) pour la confirmation de la commande par l'appareil.
+    timeout: Option<u32>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RowCommandeAppareil {
+    pub commande_id: String,
+    pub user_id: String,
+    pub uuid_appareil: String,
+    pub senseur_id: String,
+    pub action: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub valeur: Option<Value>,
+    /// Instance du relai qui a recu la commande. Absente tant qu'elle est dans la boite d'envoi.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance_id: Option<String>,
+    pub etat: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub erreur: Option<String>,
+    #[serde(with="chrono_datetime_as_bson_datetime")]
+    pub date_creation: DateTime<Utc>,
+    /// Delai de confirmation pour un appareil connecte. Pour une commande dans la boite d'envoi,
+    /// expiration de la boite d'envoi puis delai de confirmation a partir de la livraison.
+    #[serde(with="chrono_datetime_as_bson_datetime")]
+    pub expiration: DateTime<Utc>,
+    /// Delai (secondes) de confirmation, applique a la livraison depuis la boite d'envoi.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u32>,
+    /// Conservee via $currentDate.
+    #[serde(default, skip_serializing, deserialize_with="opt_chrono_datetime_as_bson_datetime::deserialize")]
+    pub date_livraison: Option<DateTime<Utc>>,
+    /// Conservee via $currentDate.
+    #[serde(default, skip_serializing, deserialize_with="opt_chrono_datetime_as_bson_datetime::deserialize")]
+    pub date_confirmation: Option<DateTime<Utc>>,
+    /// Conservee via $currentDate.
+    #[serde(default, skip_serializing, deserialize_with="opt_chrono_datetime_as_bson_datetime::deserialize")]
+    pub date_echec: Option<DateTime<Utc>>,
+}
+
+/// Commande transmise au relai de l'appareil.
+#[derive(Serialize)]
+struct CommandeRelaiAppareil<'a> {
+    commande_id: &'a str,
+    uuid_appareil: &'a str,
+    senseur_id: &'a str,
+    action: &'a str,
+    valeur: Option<&'a Value>,
+    #[serde(with="epochseconds")]
+    expiration: DateTime<Utc>,
+}
+
+/// Evenement d'etat emis par le relai (livree, confirmee ou echec).
+#[derive(Deserialize)]
+struct EvenementEtatCommandeRelai {
+    commande_id: String,
+    etat: String,
+    erreur: Option<String>,
+}
+
+/// Evenement d'etat re-emis pour l'usager.
+#[derive(Serialize)]
+struct EvenementEtatCommandeAppareil {
+    commande_id: String,
+    uuid_appareil: String,
+    senseur_id: String,
+    action: String,
+    etat: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    erreur: Option<String>,
+}
+
+impl From<RowCommandeAppareil> for EvenementEtatCommandeAppareil {
+    fn from(value: RowCommandeAppareil) -> Self {
+        Self {
+            commande_id: value.commande_id,
+            uuid_appareil: value.uuid_appareil,
+            senseur_id: value.senseur_id,
+            action: value.action,
+            etat: value.etat,
+            erreur: value.erreur,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ReponseActionnerAppareil {
+    ok: bool,
+    commande_id: String,
+    #[serde(with="epochseconds")]
+    expiration: DateTime<Utc>,
+    /// Appareil hors ligne, la commande sera livree a la reconnexion.
+    en_file: bool,
+}
+
+/// Action sur un actionneur d'appareil. Utilise par actionnerAppareil et par les scenes.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ActionActionneur {
+    pub uuid_appareil: String,
+    pub senseur_id: String,
+    pub action: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub valeur: Option<Value>,
+}
+
+/// Commande conservee et transmise au relai (ou a la boite d'envoi).
+pub struct CommandeActionneurTransmise {
+    pub commande_id: String,
+    pub expiration: DateTime<Utc>,
+    pub en_file: bool,
+}
+
+/// La commande est conservee hors session : le relai peut repondre avant la fin du traitement.
+pub async fn commande_actionner_appareil<M>(middleware: &M, m: MessageValide, _gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("commande_actionner_appareil Consommer commande : {:?}", m.type_message);
+    let commande: CommandeActionnerAppareil = deser_message_buffer!(m.message);
+    let commande_id = m.message.parse()?.id.to_string();
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    };
+
+    let timeout = commande.timeout.unwrap_or(CONST_COMMANDE_TIMEOUT_DEFAUT_SECS);
+    if timeout == 0 || timeout > CONST_COMMANDE_TIMEOUT_MAX_SECS {
+        let message = format!("timeout doit etre entre 1 et {} secondes", CONST_COMMANDE_TIMEOUT_MAX_SECS);
+        return Ok(Some(middleware.reponse_err(None, None, Some(message.as_str()))?))
+    }
+
+    let action = ActionActionneur {
+        uuid_appareil: commande.uuid_appareil,
+        senseur_id: commande.senseur_id,
+        action: commande.action,
+        valeur: commande.valeur,
+    };
+    match actionner_appareil(middleware, user_id.as_str(), commande_id, action, timeout).await? {
+        Some(transmise) => {
+            let reponse = ReponseActionnerAppareil {
+                ok: true, commande_id: transmise.commande_id, expiration: transmise.expiration, en_file: transmise.en_file };
+            Ok(Some(middleware.build_reponse(&reponse)?.0))
+        },
+        None => Ok(Some(middleware.reponse_err(None, None, Some("Appareil inconnu"))?))
+    }
+}
+
+/// Conserve la commande et la transmet au relai courant de l'appareil. Retourne None si l'appareil est inconnu.
+pub async fn actionner_appareil<M>(middleware: &M, user_id: &str, commande_id: String, action: ActionActionneur, timeout: u32)
+    -> Result<Option<CommandeActionneurTransmise>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    let filtre = doc! { CHAMP_UUID_APPAREIL: &action.uuid_appareil, CHAMP_USER_ID: user_id };
+    let collection_appareils = middleware.get_collection_typed::<DocAppareil>(COLLECTIONS_APPAREILS)?;
+    let doc_appareil = match collection_appareils.find_one(filtre, None).await? {
+        Some(inner) => inner,
+        None => return Ok(None)
+    };
+
+    // Route vers la partition du relai courant de l'appareil, comme commande_challenge_appareil.
+    // La commande est conservee dans la boite d'envoi si l'appareil est hors ligne.
+    let instance_id = instance_appareil_connecte(&doc_appareil).map(|i| i.to_string());
+
+    // Une commande en file expire avec la boite d'envoi, le delai de confirmation debute a la livraison
+    let maintenant = Utc::now();
+    let expiration = match instance_id.as_ref() {
+        Some(_) => maintenant + chrono::Duration::seconds(timeout as i64),
+        None => maintenant + chrono::Duration::seconds(CONST_EXPIRATION_COMMANDE_SECS),
+    };
+    let row = RowCommandeAppareil {
+        commande_id,
+        user_id: user_id.to_string(),
+        uuid_appareil: action.uuid_appareil,
+        senseur_id: action.senseur_id,
+        action: action.action,
+        valeur: action.valeur,
+        instance_id,
+        etat: CONST_ETAT_EN_ATTENTE.to_string(),
+        erreur: None,
+        date_creation: maintenant,
+        expiration,
+        timeout: Some(timeout),
+        date_livraison: None,
+        date_confirmation: None,
+        date_echec: None,
+    };
+    let mut doc_row = convertir_to_bson(&row)?;
+    doc_row.insert(CHAMP_CREATION, maintenant);
+    doc_row.insert(CHAMP_MODIFICATION, maintenant);
+    let collection = middleware.get_collection(COLLECTIONS_COMMANDES_APPAREILS)?;
+    collection.insert_one(doc_row, None).await?;
+
+    let commande_relai = CommandeRelaiAppareil {
+        commande_id: row.commande_id.as_str(),
+        uuid_appareil: row.uuid_appareil.as_str(),
+        senseur_id: row.senseur_id.as_str(),
+        action: row.action.as_str(),
+        valeur: row.valeur.as_ref(),
+        expiration,
+    };
+    let en_file = match row.instance_id.as_ref() {
+        Some(instance_id) => {
+            let routage = RoutageMessageAction::builder(ROLE_RELAI_NOM, COMMANDE_APPAREIL_RELAI, vec![Securite::L2Prive])
+                .partition(instance_id.as_str())
+                .blocking(false)
+                .build();
+            middleware.transmettre_commande(routage, &commande_relai).await?;
+            false
+        },
+        None => {
+            let mut message = RowBoiteEnvoi::commande(
+                &row.user_id, &row.uuid_appareil, COMMANDE_APPAREIL_RELAI, serde_json::to_value(&commande_relai)?, Some(expiration));
+            message.commande_id = Some(row.commande_id.clone());
+            let mut session = middleware.get_session().await?;
+            mettre_en_file(middleware, message, &mut session).await?;
+            true
+        }
+    };
+
+    Ok(Some(CommandeActionneurTransmise { commande_id: row.commande_id, expiration, en_file }))
+}
+
+/// Etat de livraison (livree) et de confirmation (confirmee, echec) d'une commande, emis par le relai.
+pub async fn evenement_etat_commande_appareil<M>(middleware: &M, m: &MessageValide) -> Result<(), Error>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    debug!("evenement_etat_commande_appareil Recu evenement {:?}", &m.type_message);
+    let certificat = m.certificat.as_ref();
+    if ! certificat.verifier_exchanges(vec![Securite::L2Prive])? {
+        warn!("evenement_etat_commande_appareil Evenement recu sans securite 2.prive, SKIP");
+        return Ok(())
+    }
+    if ! certificat.verifier_roles_string(vec![ROLE_RELAI_NOM.to_string()])? {
+        warn!("evenement_etat_commande_appareil Evenement recu sans role {}, SKIP", ROLE_RELAI_NOM);
+        return Ok(())
+    }
+
+    let evenement: EvenementEtatCommandeRelai = deser_message_buffer!(m.message);
+    let instance_id = certificat.get_common_name()?;
+
+    // Les transitions vont uniquement vers l'avant
+    let (etats_precedents, champ_date) = match evenement.etat.as_str() {
+        CONST_ETAT_LIVREE => (vec![CONST_ETAT_EN_ATTENTE], "date_livraison"),
+        CONST_ETAT_CONFIRMEE => (vec![CONST_ETAT_EN_ATTENTE, CONST_ETAT_LIVREE], "date_confirmation"),
+        CONST_ETAT_ECHEC => (vec![CONST_ETAT_EN_ATTENTE, CONST_ETAT_LIVREE], "date_echec"),
+        _ => {
+            warn!("evenement_etat_commande_appareil Etat {} non supporte pour commande {}, SKIP", evenement.etat, evenement.commande_id);
+            return Ok(())
+        }
+    };
+
+    let filtre = doc! {
+        "commande_id": &evenement.commande_id,
+        CHAMP_INSTANCE_ID: &instance_id,
+        "etat": {"$in": etats_precedents},
+    };
+    let ops = doc! {
+        "$set": { "etat": &evenement.etat, "erreur": evenement.erreur.clone() },
+        "$currentDate": { CHAMP_MODIFICATION: true, champ_date: true },
+    };
+    let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build();
+    let collection = middleware.get_collection_typed::<RowCommandeAppareil>(COLLECTIONS_COMMANDES_APPAREILS)?;
+    match collection.find_one_and_update(filtre, ops, options).await? {
+        Some(row) => emettre_evenement_etat_commande(middleware, row).await,
+        None => {
+            warn!("evenement_etat_commande_appareil Commande {} inconnue, deja terminee ou d'un autre relai ({})", evenement.commande_id, instance_id);
+            Ok(())
+        }
+    }
+}
+
+/// Assigne l'instance du relai a une commande livree depuis la boite d'envoi et demarre son delai
+/// de confirmation. Retourne la nouvelle expiration, None si la commande n'est plus en attente.
+pub async fn assigner_instance_commande<M>(middleware: &M, commande_id: &str, instance_id: &str)
+    -> Result<Option<DateTime<Utc>>, Error>
+    where M: MongoDao
+{
+    let collection = middleware.get_collection_typed::<RowCommandeAppareil>(COLLECTIONS_COMMANDES_APPAREILS)?;
+    let filtre = doc! {
+        "commande_id": commande_id,
+        "etat": CONST_ETAT_EN_ATTENTE,
+        CHAMP_INSTANCE_ID: {"$exists": false},
+    };
+    let row = match collection.find_one(filtre.clone(), None).await? {
+        Some(inner) => inner,
+        None => return Ok(None)
+    };
+
+    let timeout = row.timeout.unwrap_or(CONST_COMMANDE_TIMEOUT_DEFAUT_SECS);
+    let expiration = Utc::now() + chrono::Duration::seconds(timeout as i64);
+    let ops = doc! {
+        "$set": { CHAMP_INSTANCE_ID: instance_id, "expiration": expiration },
+        "$currentDate": { CHAMP_MODIFICATION: true },
+    };
+    // Le filtre sur l'etat evite de livrer une commande expiree entre-temps
+    let resultat = collection.update_one(filtre, ops, None).await?;
+    match resultat.modified_count {
+        0 => Ok(None),
+        _ => Ok(Some(expiration))
+    }
+}
+
+async fn emettre_evenement_etat_commande<M>(middleware: &M, row: RowCommandeAppareil) -> Result<(), Error>
+    where M: GenerateurMessages
+{
+    let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_ETAT_COMMANDE_APPAREIL, vec![Securite::L2Prive])
+        .partition(row.user_id.as_str())
+        .build();
+    let evenement: EvenementEtatCommandeAppareil = row.into();
+    middleware.emettre_evenement(routage, &evenement).await?;
+    Ok(())
+}
+
+/// Passe en echec les commandes non confirmees a leur expiration. Une commande encore dans la boite
+/// d'envoi (sans instance) expire avec celle-ci, son delai de confirmation debute a la livraison.
+pub async fn expirer_commandes_appareils<M>(middleware: &M) -> Result<(), Error>
+    where M: GenerateurMessages + MongoDao
+{
+    let filtre = doc! {
+        "etat": {"$in": [CONST_ETAT_EN_ATTENTE, CONST_ETAT_LIVREE]},
+        "expiration": {"$lte": Utc::now()},
+    };
+    let collection = middleware.get_collection_typed::<RowCommandeAppareil>(COLLECTIONS_COMMANDES_APPAREILS)?;
+    let mut curseur = collection.find(filtre, None).await?;
+    let mut commandes = Vec::new();
+    while let Some(row) = curseur.next().await {
+        commandes.push(row?);
+    }
+
+    for commande in commandes {
+        // Le filtre sur l'etat evite d'ecraser une confirmation recue entre-temps
+        let filtre = doc! {
+            "commande_id": &commande.commande_id,
+            "etat": {"$in": [CONST_ETAT_EN_ATTENTE, CONST_ETAT_LIVREE]},
+        };
+        let erreur = match commande.instance_id.as_ref() {
+            Some(_) => "timeout",
+            None => "appareil hors ligne",
+        };
+        let ops = doc! {
+            "$set": { "etat": CONST_ETAT_ECHEC, "erreur": erreur },
+            "$currentDate": { CHAMP_MODIFICATION: true, "date_echec": true },
+        };
+        let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build();
+        if let Some(row) = collection.find_one_and_update(filtre, ops, options).await? {
+            info!("expirer_commandes_appareils Commande {} expiree (appareil {})", row.commande_id, row.uuid_appareil);
+            emettre_evenement_etat_commande(middleware, row).await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct RequeteGetCommandesAppareil {
+    uuid_appareil: String,
+    senseur_id: Option<String>,
+    limite: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct CommandeAppareilReponse {
+    commande_id: String,
+    senseur_id: String,
+    action: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    valeur: Option<Value>,
+    etat: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    erreur: Option<String>,
+    #[serde(with="epochseconds")]
+    date_creation: DateTime<Utc>,
+    #[serde(with="epochseconds")]
+    expiration: DateTime<Utc>,
+    #[serde(with="optionepochseconds")]
+    date_livraison: Option<DateTime<Utc>>,
+    #[serde(with="optionepochseconds")]
+    date_confirmation: Option<DateTime<Utc>>,
+    #[serde(with="optionepochseconds")]
+    date_echec: Option<DateTime<Utc>>,
+}
+
+impl From<RowCommandeAppareil> for CommandeAppareilReponse {
+    fn from(value: RowCommandeAppareil) -> Self {
+        Self {
+            commande_id: value.commande_id,
+            senseur_id: value.senseur_id,
+            action: value.action,
+            valeur: value.valeur,
+            etat: value.etat,
+            erreur: value.erreur,
+            date_creation: value.date_creation,
+            expiration: value.expiration,
+            date_livraison: value.date_livraison,
+            date_confirmation: value.date_confirmation,
+            date_echec: value.date_echec,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ReponseGetCommandesAppareil {
+    ok: bool,
+    uuid_appareil: String,
+    commandes: Vec<CommandeAppareilReponse>,
+}
+
+/// Historique des commandes d'un appareil, plus recentes en premier.
+pub async fn requete_get_commandes_appareil<M>(middleware: &M, m: MessageValide, _gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_get_commandes_appareil Consommer requete : {:?}", & m.type_message);
+    let requete: RequeteGetCommandesAppareil = deser_message_buffer!(m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    };
+
+    let mut filtre = doc! { CHAMP_USER_ID: &user_id, CHAMP_UUID_APPAREIL: &requete.uuid_appareil };
+    if let Some(senseur_id) = requete.senseur_id.as_ref() {
+        filtre.insert("senseur_id", senseur_id);
+    }
+    let limite = requete.limite.unwrap_or(CONST_COMMANDES_HISTORIQUE_LIMITE).clamp(1, CONST_COMMANDES_HISTORIQUE_LIMITE);
+    let options = FindOptions::builder()
+        .sort(doc! {"date_creation": -1})
+        .limit(limite)
+        .build();
+
+    let collection = middleware.get_collection_typed::<RowCommandeAppareil>(COLLECTIONS_COMMANDES_APPAREILS)?;
+    let mut curseur = collection.find(filtre, options).await?;
+    let mut commandes = Vec::new();
+    while let Some(row) = curseur.next().await {
+        match row {
+            Ok(row) => commandes.push(row.into()),
+            Err(e) => warn!("requete_get_commandes_appareil Erreur mapping commande : {:?}", e)
+        }
+    }
+
+    let reponse = ReponseGetCommandesAppareil { ok: true, uuid_appareil: requete.uuid_appareil, commandes };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
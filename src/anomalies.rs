@@ -0,0 +1,542 @@
+use std::collections::HashMap;
+use log::{debug, error, info};
+use chrono_tz::Tz;
+
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::bson::serde_helpers::chrono_datetime_as_bson_datetime;
+use millegrilles_common_rust::certificats::VerificateurPermissions;
+use millegrilles_common_rust::chrono::{DateTime, Duration, Timelike, Utc};
+use millegrilles_common_rust::constantes::*;
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
+use millegrilles_common_rust::millegrilles_cryptographie::deser_message_buffer;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::{epochseconds, MessageMilleGrillesBufferDefault};
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::optionepochseconds;
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, convertir_to_bson, opt_chrono_datetime_as_bson_datetime, MongoDao};
+use millegrilles_common_rust::mongodb::ClientSession;
+use millegrilles_common_rust::mongodb::options::{AggregateOptions, FindOptions, UpdateOptions};
+use millegrilles_common_rust::recepteur_messages::MessageValide;
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+use millegrilles_common_rust::tokio_stream::StreamExt;
+
+use crate::common::*;
+use crate::domain_manager::SenseursPassifsDomainManager;
+use crate::requetes::parser_timezone;
+use crate::transactions::SenseurHoraireRow;
+
+/// Limite de z-score par defaut lorsque l'usager n'a pas configure anomalie_z_limite.
+const CONST_ANOMALIE_Z_LIMITE_DEFAUT: f64 = 3.0;
+
+/// Nombre d'echantillons requis pour une heure du jour avant de signaler des anomalies.
+const CONST_BASELINE_ECHANTILLONS_MIN: u32 = 7;
+
+/// Fenetre glissante de la baseline (en jours). Au dela, les anciennes valeurs perdent du poids.
+const CONST_BASELINE_FENETRE: u32 = 30;
+
+/// Ecart-type minimal pour eviter des z-scores extremes sur un signal tres stable.
+const CONST_ECART_TYPE_MIN: f64 = 0.05;
+
+/// Nombre d'anomalies retournees par defaut, aussi le maximum permis par requete.
+const CONST_ANOMALIES_LIMITE_DEFAUT: i64 = 100;
+
+const METRIQUE_VALEUR: &str = "valeur";
+const METRIQUE_VARIATION: &str = "variation";
+
+#[derive(Deserialize)]
+struct ConfigurationAnomaliesUsager {
+    user_id: String,
+    timezone: Option<String>,
+    anomalie_z_limite: Option<f64>,
+}
+
+/// Moyenne et variance par heure du jour (algorithme de Welford).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct BaselineSenseur {
+    user_id: String,
+    uuid_appareil: String,
+    senseur_id: String,
+    metrique: String,
+    heure_jour: u32,
+    n: u32,
+    moyenne: f64,
+    m2: f64,
+    /// Heure de la derniere rangee ajoutee. Une rangee deja vue n'est pas ajoutee de nouveau.
+    #[serde(default,
+        serialize_with = "optionepochseconds::serialize",
+        deserialize_with = "opt_chrono_datetime_as_bson_datetime::deserialize")]
+    derniere_heure: Option<DateTime<Utc>>,
+}
+
+impl BaselineSenseur {
+    fn new(row: &SenseurHoraireRow, metrique: &str, heure_jour: u32) -> Self {
+        Self {
+            user_id: row.user_id.clone(),
+            uuid_appareil: row.uuid_appareil.clone(),
+            senseur_id: row.senseur_id.clone(),
+            metrique: metrique.to_string(),
+            heure_jour,
+            n: 0,
+            moyenne: 0.0,
+            m2: 0.0,
+            derniere_heure: None,
+        }
+    }
+
+    fn ecart_type(&self) -> f64 {
+        if self.n < 2 {
+            return 0.0
+        }
+        f64::max((self.m2 / (self.n - 1) as f64).sqrt(), CONST_ECART_TYPE_MIN)
+    }
+
+    fn ajouter(&mut self, valeur: f64) {
+        // Le nombre d'echantillons est plafonne a la fenetre pour que la baseline suive les saisons.
+        let n = u32::min(self.n + 1, CONST_BASELINE_FENETRE);
+        if n == self.n {
+            self.m2 *= (n - 1) as f64 / n as f64;
+        }
+        self.n = n;
+        let delta = valeur - self.moyenne;
+        self.moyenne += delta / n as f64;
+        self.m2 += delta * (valeur - self.moyenne);
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AnomalieSenseur {
+    pub user_id: String,
+    pub uuid_appareil: String,
+    pub senseur_id: String,
+    #[serde(with="chrono_datetime_as_bson_datetime")]
+    pub heure: DateTime<Utc>,
+    /// valeur (moyenne horaire) ou variation (ecart avec l'heure precedente)
+    pub metrique: String,
+    pub valeur: f64,
+    pub moyenne: f64,
+    pub ecart_type: f64,
+    pub z_score: f64,
+}
+
+#[derive(Serialize)]
+struct EvenementAnomalieSenseur<'a> {
+    uuid_appareil: &'a str,
+    senseur_id: &'a str,
+    #[serde(with="epochseconds")]
+    heure: DateTime<Utc>,
+    metrique: &'a str,
+    valeur: f64,
+    moyenne: f64,
+    ecart_type: f64,
+    z_score: f64,
+}
+
+impl<'a> From<&'a AnomalieSenseur> for EvenementAnomalieSenseur<'a> {
+    fn from(value: &'a AnomalieSenseur) -> Self {
+        Self {
+            uuid_appareil: value.uuid_appareil.as_str(),
+            senseur_id: value.senseur_id.as_str(),
+            heure: value.heure,
+            metrique: value.metrique.as_str(),
+            valeur: value.valeur,
+            moyenne: value.moyenne,
+            ecart_type: value.ecart_type,
+            z_score: value.z_score,
+        }
+    }
+}
+
+/// Detecte les anomalies des rangees horaires apres la confirmation de leurs transactions senseurHoraire.
+/// Chaque rangee est traitee dans sa propre session. Les evenements sont emis apres le commit.
+pub async fn traiter_anomalies_rangees<M>(middleware: &M, rows: Vec<SenseurHoraireRow>)
+    where M: GenerateurMessages + MongoDao
+{
+    for row in rows {
+        let anomalies = match detecter_anomalies_session(middleware, &row).await {
+            Ok(inner) => inner,
+            Err(e) => {
+                error!("traiter_anomalies_rangees Erreur detection anomalies {}/{} : {:?}", row.uuid_appareil, row.senseur_id, e);
+                continue
+            }
+        };
+        for anomalie in anomalies {
+            let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_ANOMALIE_SENSEUR, vec![Securite::L2Prive])
+                .partition(&anomalie.user_id)
+                .build();
+            if let Err(e) = middleware.emettre_evenement(routage, &EvenementAnomalieSenseur::from(&anomalie)).await {
+                error!("traiter_anomalies_rangees Erreur emission evenement anomalie : {:?}", e);
+            }
+        }
+    }
+}
+
+async fn detecter_anomalies_session<M>(middleware: &M, row: &SenseurHoraireRow) -> Result<Vec<AnomalieSenseur>, Error>
+    where M: MongoDao
+{
+    let mut session = middleware.get_session().await?;
+    session.start_transaction(None).await?;
+    match detecter_anomalies_horaire(middleware, row, &mut session).await {
+        Ok(anomalies) => {
+            session.commit_transaction().await?;
+            Ok(anomalies)
+        },
+        Err(e) => {
+            session.abort_transaction().await?;
+            Err(e)
+        }
+    }
+}
+
+/// Compare la rangee horaire a la baseline du senseur pour la meme heure du jour, puis met la baseline a jour.
+/// Les anomalies sont conservees dans COLLECTIONS_ANOMALIES_SENSEURS et retournees pour etre emises a l'usager.
+async fn detecter_anomalies_horaire<M>(middleware: &M, row: &SenseurHoraireRow, session: &mut ClientSession)
+    -> Result<Vec<AnomalieSenseur>, Error>
+    where M: MongoDao
+{
+    let valeur = match row.avg {
+        Some(inner) => inner,
+        None => return Ok(Vec::new())  // Pas de valeur numerique
+    };
+
+    let collection_usager = middleware.get_collection_typed::<ConfigurationAnomaliesUsager>(COLLECTIONS_USAGER)?;
+    let configuration = collection_usager.find_one_with_session(doc!{CHAMP_USER_ID: &row.user_id}, None, session).await?;
+    let (timezone, z_limite) = match configuration {
+        Some(inner) => (inner.timezone, inner.anomalie_z_limite.unwrap_or(CONST_ANOMALIE_Z_LIMITE_DEFAUT)),
+        None => (None, CONST_ANOMALIE_Z_LIMITE_DEFAUT)
+    };
+    let tz: Tz = parser_timezone(timezone.as_ref());
+    let heure_jour = row.heure.with_timezone(&tz).hour();
+
+    let mut anomalies = Vec::new();
+    if let Some(anomalie) = evaluer_metrique(middleware, row, METRIQUE_VALEUR, heure_jour, valeur, z_limite, session).await? {
+        anomalies.push(anomalie);
+    }
+
+    // Variation avec l'heure precedente (e.g. congelateur qui se rechauffe plus vite qu'a l'habitude)
+    let collection_horaire = middleware.get_collection_typed::<SenseurHoraireRow>(COLLECTIONS_SENSEURS_HORAIRE)?;
+    let filtre_precedent = doc! {
+        CHAMP_USER_ID: &row.user_id,
+        CHAMP_UUID_APPAREIL: &row.uuid_appareil,
+        "senseur_id": &row.senseur_id,
+        "heure": row.heure - Duration::hours(1),
+    };
+    if let Some(precedent) = collection_horaire.find_one_with_session(filtre_precedent, None, session).await? {
+        if let Some(valeur_precedente) = precedent.avg {
+            let variation = valeur - valeur_precedente;
+            if let Some(anomalie) = evaluer_metrique(middleware, row, METRIQUE_VARIATION, heure_jour, variation, z_limite, session).await? {
+                anomalies.push(anomalie);
+            }
+        }
+    }
+
+    let collection_anomalies = middleware.get_collection(COLLECTIONS_ANOMALIES_SENSEURS)?;
+    for anomalie in &anomalies {
+        info!("detecter_anomalies_horaire Anomalie {} senseur {}/{} a {:?} (z={:.2})",
+            anomalie.metrique, anomalie.uuid_appareil, anomalie.senseur_id, anomalie.heure, anomalie.z_score);
+
+        let filtre = doc! {
+            CHAMP_USER_ID: &anomalie.user_id,
+            CHAMP_UUID_APPAREIL: &anomalie.uuid_appareil,
+            "senseur_id": &anomalie.senseur_id,
+            "heure": anomalie.heure,
+            "metrique": &anomalie.metrique,
+        };
+        let ops = doc! {
+            "$set": convertir_to_bson(&anomalie)?,
+            "$setOnInsert": { CHAMP_CREATION: Utc::now() },
+            "$currentDate": { CHAMP_MODIFICATION: true },
+        };
+        let options = UpdateOptions::builder().upsert(true).build();
+        collection_anomalies.update_one_with_session(filtre, ops, options, session).await?;
+    }
+
+    Ok(anomalies)
+}
+
+async fn evaluer_metrique<M>(
+    middleware: &M, row: &SenseurHoraireRow, metrique: &str, heure_jour: u32, valeur: f64, z_limite: f64,
+    session: &mut ClientSession
+)
+    -> Result<Option<AnomalieSenseur>, Error>
+    where M: MongoDao
+{
+    let filtre = doc! {
+        CHAMP_USER_ID: &row.user_id,
+        CHAMP_UUID_APPAREIL: &row.uuid_appareil,
+        "senseur_id": &row.senseur_id,
+        "metrique": metrique,
+        "heure_jour": heure_jour,
+    };
+    let collection = middleware.get_collection_typed::<BaselineSenseur>(COLLECTIONS_BASELINES_SENSEURS)?;
+    let mut baseline = match collection.find_one_with_session(filtre.clone(), None, session).await? {
+        Some(inner) => inner,
+        None => BaselineSenseur::new(row, metrique, heure_jour)
+    };
+
+    if let Some(derniere_heure) = baseline.derniere_heure {
+        if row.heure <= derniere_heure {
+            debug!("evaluer_metrique Rangee {:?} deja ajoutee a la baseline {} {}/{}", row.heure, metrique, row.uuid_appareil, row.senseur_id);
+            return Ok(None)
+        }
+    }
+
+    let mut anomalie = None;
+    if baseline.n >= CONST_BASELINE_ECHANTILLONS_MIN {
+        let ecart_type = baseline.ecart_type();
+        let z_score = (valeur - baseline.moyenne) / ecart_type;
+        debug!("evaluer_metrique {} {}/{} heure_jour {} z={:.2}", metrique, row.uuid_appareil, row.senseur_id, heure_jour, z_score);
+        if z_score.abs() > z_limite {
+            anomalie = Some(AnomalieSenseur {
+                user_id: row.user_id.clone(),
+                uuid_appareil: row.uuid_appareil.clone(),
+                senseur_id: row.senseur_id.clone(),
+                heure: row.heure,
+                metrique: metrique.to_string(),
+                valeur,
+                moyenne: baseline.moyenne,
+                ecart_type,
+                z_score,
+            });
+        }
+    }
+
+    baseline.ajouter(valeur);
+    let ops = doc! {
+        "$set": {"n": baseline.n, "moyenne": baseline.moyenne, "m2": baseline.m2, "derniere_heure": row.heure},
+        "$setOnInsert": { CHAMP_CREATION: Utc::now() },
+        "$currentDate": { CHAMP_MODIFICATION: true },
+    };
+    let options = UpdateOptions::builder().upsert(true).build();
+    let collection = middleware.get_collection(COLLECTIONS_BASELINES_SENSEURS)?;
+    collection.update_one_with_session(filtre, ops, options, session).await?;
+
+    Ok(anomalie)
+}
+
+/// Reconstruit les baselines a partir des rangees horaires, en ordre chronologique pour chaque senseur.
+/// Appele a la fin d'une regeneration : la detection est desactivee pendant la regeneration et les
+/// anciennes baselines ne correspondent plus aux rangees regenerees. Aucune anomalie n'est produite.
+pub async fn reconstruire_baselines<M>(middleware: &M) -> Result<(), Error>
+    where M: MongoDao
+{
+    let mut timezones: HashMap<String, Tz> = HashMap::new();
+    let collection_usager = middleware.get_collection_typed::<ConfigurationAnomaliesUsager>(COLLECTIONS_USAGER)?;
+    let mut curseur_usagers = collection_usager.find(doc!{}, None).await?;
+    while let Some(usager) = curseur_usagers.next().await {
+        let document = usager?;
+        let tz = parser_timezone(document.timezone.as_ref());
+        timezones.insert(document.user_id, tz);
+    }
+    let tz_defaut = parser_timezone(None);
+
+    let pipeline = vec![
+        doc! { "$match": {"avg": {"$ne": null}} },
+        doc! { "$sort": {CHAMP_USER_ID: 1, CHAMP_UUID_APPAREIL: 1, "senseur_id": 1, "heure": 1} },
+    ];
+    let options = AggregateOptions::builder().allow_disk_use(true).build();
+    let collection_horaire = middleware.get_collection(COLLECTIONS_SENSEURS_HORAIRE)?;
+    let mut curseur = collection_horaire.aggregate(pipeline, options).await?;
+
+    let mut baselines: HashMap<(String, String, String, &'static str, u32), BaselineSenseur> = HashMap::new();
+    let mut precedent: Option<SenseurHoraireRow> = None;
+    while let Some(d) = curseur.next().await {
+        let row: SenseurHoraireRow = convertir_bson_deserializable(d?)?;
+        let valeur = match row.avg {
+            Some(inner) => inner,
+            None => continue
+        };
+        let tz = timezones.get(&row.user_id).unwrap_or(&tz_defaut);
+        let heure_jour = row.heure.with_timezone(tz).hour();
+
+        let mut metriques = vec![(METRIQUE_VALEUR, valeur)];
+        if let Some(precedent) = precedent.as_ref() {
+            let meme_senseur = precedent.user_id == row.user_id && precedent.uuid_appareil == row.uuid_appareil
+                && precedent.senseur_id == row.senseur_id;
+            if meme_senseur && precedent.heure == row.heure - Duration::hours(1) {
+                if let Some(valeur_precedente) = precedent.avg {
+                    metriques.push((METRIQUE_VARIATION, valeur - valeur_precedente));
+                }
+            }
+        }
+
+        for (metrique, valeur) in metriques {
+            let cle = (row.user_id.clone(), row.uuid_appareil.clone(), row.senseur_id.clone(), metrique, heure_jour);
+            let baseline = baselines.entry(cle).or_insert_with(|| BaselineSenseur::new(&row, metrique, heure_jour));
+            baseline.ajouter(valeur);
+            baseline.derniere_heure = Some(row.heure);
+        }
+        precedent = Some(row);
+    }
+
+    let collection_baselines = middleware.get_collection(COLLECTIONS_BASELINES_SENSEURS)?;
+    collection_baselines.delete_many(doc!{}, None).await?;
+    let maintenant = Utc::now();
+    let documents: Vec<_> = baselines.into_values().map(|baseline| doc! {
+        CHAMP_USER_ID: baseline.user_id,
+        CHAMP_UUID_APPAREIL: baseline.uuid_appareil,
+        "senseur_id": baseline.senseur_id,
+        "metrique": baseline.metrique,
+        "heure_jour": baseline.heure_jour,
+        "n": baseline.n,
+        "moyenne": baseline.moyenne,
+        "m2": baseline.m2,
+        "derniere_heure": baseline.derniere_heure,
+        CHAMP_CREATION: maintenant,
+        CHAMP_MODIFICATION: maintenant,
+    }).collect();
+    info!("reconstruire_baselines {} baselines reconstruites", documents.len());
+    if !documents.is_empty() {
+        collection_baselines.insert_many(documents, None).await?;
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct RequeteGetAnomaliesSenseurs {
+    uuid_appareil: Option<String>,
+    senseur_id: Option<String>,
+    intervalle_min: Option<usize>,
+    intervalle_max: Option<usize>,
+    limite: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct AnomalieReponse {
+    uuid_appareil: String,
+    senseur_id: String,
+    #[serde(with="epochseconds")]
+    heure: DateTime<Utc>,
+    metrique: String,
+    valeur: f64,
+    moyenne: f64,
+    ecart_type: f64,
+    z_score: f64,
+}
+
+impl From<AnomalieSenseur> for AnomalieReponse {
+    fn from(value: AnomalieSenseur) -> Self {
+        Self {
+            uuid_appareil: value.uuid_appareil,
+            senseur_id: value.senseur_id,
+            heure: value.heure,
+            metrique: value.metrique,
+            valeur: value.valeur,
+            moyenne: value.moyenne,
+            ecart_type: value.ecart_type,
+            z_score: value.z_score,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ReponseGetAnomaliesSenseurs {
+    ok: bool,
+    anomalies: Vec<AnomalieReponse>,
+}
+
+pub async fn requete_get_anomalies_senseurs<M>(middleware: &M, m: MessageValide, _gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_get_anomalies_senseurs Consommer requete : {:?}", & m.message);
+    let requete: RequeteGetAnomaliesSenseurs = deser_message_buffer!(m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    };
+
+    let mut filtre = doc! { CHAMP_USER_ID: &user_id };
+    if let Some(uuid_appareil) = requete.uuid_appareil.as_ref() {
+        filtre.insert(CHAMP_UUID_APPAREIL, uuid_appareil);
+    }
+    if let Some(senseur_id) = requete.senseur_id.as_ref() {
+        filtre.insert("senseur_id", senseur_id);
+    }
+    let mut intervalle = doc! {};
+    if let Some(inner) = requete.intervalle_min.and_then(|v| DateTime::from_timestamp(v as i64, 0)) {
+        intervalle.insert("$gte", inner);
+    }
+    if let Some(inner) = requete.intervalle_max.and_then(|v| DateTime::from_timestamp(v as i64, 0)) {
+        intervalle.insert("$lt", inner);
+    }
+    if !intervalle.is_empty() {
+        filtre.insert("heure", intervalle);
+    }
+
+    let limite = requete.limite.unwrap_or(CONST_ANOMALIES_LIMITE_DEFAUT).clamp(1, CONST_ANOMALIES_LIMITE_DEFAUT);
+    let options = FindOptions::builder()
+        .sort(doc!{"heure": -1})
+        .limit(limite)
+        .build();
+    let collection = middleware.get_collection(COLLECTIONS_ANOMALIES_SENSEURS)?;
+    let mut curseur = collection.find(filtre, options).await?;
+    let mut anomalies = Vec::new();
+    while let Some(d) = curseur.next().await {
+        let anomalie: AnomalieSenseur = convertir_bson_deserializable(d?)?;
+        anomalies.push(anomalie.into());
+    }
+
+    let reponse = ReponseGetAnomaliesSenseurs { ok: true, anomalies };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_setup::setup;
+
+    fn baseline() -> BaselineSenseur {
+        BaselineSenseur {
+            user_id: "usager".to_string(),
+            uuid_appareil: "appareil".to_string(),
+            senseur_id: "temp".to_string(),
+            metrique: METRIQUE_VALEUR.to_string(),
+            heure_jour: 0,
+            n: 0,
+            moyenne: 0.0,
+            m2: 0.0,
+            derniere_heure: None,
+        }
+    }
+
+    #[test]
+    fn test_baseline_moyenne_variance() {
+        setup("test_baseline_moyenne_variance");
+        let mut baseline = baseline();
+        assert_eq!(0.0, baseline.ecart_type());
+        for valeur in [1.0, 2.0, 3.0, 4.0, 5.0] {
+            baseline.ajouter(valeur);
+        }
+        assert_eq!(5, baseline.n);
+        assert!((baseline.moyenne - 3.0).abs() < 1e-9);
+        assert!((baseline.ecart_type() - 2.5f64.sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_baseline_ecart_type_minimal() {
+        setup("test_baseline_ecart_type_minimal");
+        let mut baseline = baseline();
+        for _ in 0..10 {
+            baseline.ajouter(-18.0);
+        }
+        assert_eq!(CONST_ECART_TYPE_MIN, baseline.ecart_type());
+    }
+
+    #[test]
+    fn test_baseline_fenetre_glissante() {
+        setup("test_baseline_fenetre_glissante");
+        let mut baseline = baseline();
+        for _ in 0..100 {
+            baseline.ajouter(10.0);
+        }
+        assert_eq!(CONST_BASELINE_FENETRE, baseline.n);
+        for _ in 0..CONST_BASELINE_FENETRE {
+            baseline.ajouter(20.0);
+        }
+        // Les anciennes valeurs perdent du poids sans etre oubliees completement
+        assert_eq!(CONST_BASELINE_FENETRE, baseline.n);
+        assert!(baseline.moyenne > 15.0 && baseline.moyenne < 20.0, "moyenne {}", baseline.moyenne);
+        assert!(baseline.m2 >= 0.0);
+    }
+}
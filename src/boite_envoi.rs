@@ -0,0 +1,217 @@
+use log::{debug, info, warn};
+
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::bson::serde_helpers::chrono_datetime_as_bson_datetime;
+use millegrilles_common_rust::chrono;
+use millegrilles_common_rust::chrono::{DateTime, Utc};
+use millegrilles_common_rust::constantes::*;
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
+use millegrilles_common_rust::mongo_dao::{convertir_to_bson, MongoDao};
+use millegrilles_common_rust::mongodb::ClientSession;
+use millegrilles_common_rust::mongodb::options::{FindOptions, UpdateOptions};
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+use millegrilles_common_rust::serde_json::Value;
+use millegrilles_common_rust::tokio_stream::StreamExt;
+
+use crate::actionneurs::assigner_instance_commande;
+use crate::common::*;
+
+/// Conservation d'une mise a jour de configuration pour un appareil hors ligne.
+const CONST_EXPIRATION_CONFIGURATION_SECS: i64 = 7 * 86_400;
+/// Conservation d'une commande (challenge, actionneur) pour un appareil hors ligne.
+pub const CONST_EXPIRATION_COMMANDE_SECS: i64 = 600;
+
+const CONST_TYPE_EVENEMENT: &str = "evenement";
+const CONST_TYPE_COMMANDE: &str = "commande";
+
+/// Message en attente de livraison a un appareil hors ligne.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RowBoiteEnvoi {
+    pub user_id: String,
+    pub uuid_appareil: String,
+    /// evenement (partition user_id) ou commande (relai, partition instance_id)
+    pub type_message: String,
+    pub action: String,
+    /// Un message avec la meme cle remplace le precedent (configuration remplacee).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cle_deduplication: Option<String>,
+    /// Commande d'actionneur associee, l'instance du relai est assignee a la livraison.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commande_id: Option<String>,
+    pub contenu: Value,
+    #[serde(with="chrono_datetime_as_bson_datetime")]
+    pub date_creation: DateTime<Utc>,
+    #[serde(with="chrono_datetime_as_bson_datetime")]
+    pub expiration: DateTime<Utc>,
+}
+
+impl RowBoiteEnvoi {
+    /// Evenement de configuration. Remplace une mise a jour precedente avec la meme action.
+    pub fn configuration<U,A,S>(user_id: U, uuid_appareil: A, action: S, contenu: Value) -> Self
+        where U: ToString, A: ToString, S: ToString
+    {
+        let maintenant = Utc::now();
+        let action = action.to_string();
+        Self {
+            user_id: user_id.to_string(),
+            uuid_appareil: uuid_appareil.to_string(),
+            type_message: CONST_TYPE_EVENEMENT.to_string(),
+            cle_deduplication: Some(action.clone()),
+            action,
+            commande_id: None,
+            contenu,
+            date_creation: maintenant,
+            expiration: maintenant + chrono::Duration::seconds(CONST_EXPIRATION_CONFIGURATION_SECS),
+        }
+    }
+
+    /// Commande vers le relai de l'appareil.
+    pub fn commande<U,A,S>(user_id: U, uuid_appareil: A, action: S, contenu: Value, expiration: Option<DateTime<Utc>>) -> Self
+        where U: ToString, A: ToString, S: ToString
+    {
+        let maintenant = Utc::now();
+        Self {
+            user_id: user_id.to_string(),
+            uuid_appareil: uuid_appareil.to_string(),
+            type_message: CONST_TYPE_COMMANDE.to_string(),
+            action: action.to_string(),
+            cle_deduplication: None,
+            commande_id: None,
+            contenu,
+            date_creation: maintenant,
+            expiration: expiration.unwrap_or_else(|| maintenant + chrono::Duration::seconds(CONST_EXPIRATION_COMMANDE_SECS)),
+        }
+    }
+}
+
+/// Retourne l'instance du relai si l'appareil peut recevoir un message immediatement. Regle unique
+/// pour les commandes (challenge, actionneurs) et les evenements de configuration : un appareil sans
+/// instance de relai connue est hors ligne.
+pub fn instance_appareil_connecte(doc_appareil: &DocAppareil) -> Option<&str> {
+    match doc_appareil.connecte {
+        Some(true) => doc_appareil.instance_id.as_deref(),
+        _ => None
+    }
+}
+
+/// Conserve un message pour un appareil hors ligne. Un message avec une cle de deduplication
+/// remplace le message precedent et est replace a la fin de la file.
+pub async fn mettre_en_file<M>(middleware: &M, message: RowBoiteEnvoi, session: &mut ClientSession)
+    -> Result<(), Error>
+    where M: MongoDao
+{
+    debug!("mettre_en_file Message {} pour appareil hors ligne {}", message.action, message.uuid_appareil);
+    let collection = middleware.get_collection(COLLECTIONS_BOITE_ENVOI)?;
+    match message.cle_deduplication.as_ref() {
+        Some(cle) => {
+            let filtre = doc! {
+                CHAMP_USER_ID: &message.user_id,
+                CHAMP_UUID_APPAREIL: &message.uuid_appareil,
+                "cle_deduplication": cle,
+            };
+            let ops = doc! { "$set": convertir_to_bson(&message)? };
+            let options = UpdateOptions::builder().upsert(true).build();
+            collection.update_one_with_session(filtre, ops, options, session).await?;
+        },
+        None => {
+            collection.insert_one_with_session(convertir_to_bson(&message)?, None, session).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Emet un evenement de configuration a l'appareil ou le conserve s'il est hors ligne.
+pub async fn emettre_configuration_appareil<M>(
+    middleware: &M, doc_appareil: &DocAppareil, user_id: &str, action: &str, contenu: Value, session: &mut ClientSession
+)
+    -> Result<(), Error>
+    where M: GenerateurMessages + MongoDao
+{
+    if instance_appareil_connecte(doc_appareil).is_some() {
+        let routage = RoutageMessageAction::builder(DOMAINE_NOM, action, vec![Securite::L2Prive])
+            .partition(user_id)
+            .build();
+        middleware.emettre_evenement(routage, &contenu).await?;
+    } else if ! middleware.get_mode_regeneration() {
+        let message = RowBoiteEnvoi::configuration(user_id, &doc_appareil.uuid_appareil, action, contenu);
+        mettre_en_file(middleware, message, session).await?;
+    }
+    Ok(())
+}
+
+/// Livre dans l'ordre les messages en attente d'un appareil qui vient de se connecter.
+/// Les messages expires sont retires sans etre livres.
+pub async fn livrer_boite_envoi<M>(middleware: &M, user_id: &str, uuid_appareil: &str, instance_id: &str)
+    -> Result<(), Error>
+    where M: GenerateurMessages + MongoDao
+{
+    let filtre = doc! { CHAMP_USER_ID: user_id, CHAMP_UUID_APPAREIL: uuid_appareil };
+    let options = FindOptions::builder().sort(doc! {"date_creation": 1, "_id": 1}).build();
+    let collection = middleware.get_collection_typed::<RowBoiteEnvoi>(COLLECTIONS_BOITE_ENVOI)?;
+    let mut curseur = collection.find(filtre.clone(), options).await?;
+    let mut messages = Vec::new();
+    while let Some(row) = curseur.next().await {
+        match row {
+            Ok(inner) => messages.push(inner),
+            Err(e) => warn!("livrer_boite_envoi Erreur mapping message : {:?}", e)
+        }
+    }
+
+    let maintenant = Utc::now();
+    let mut livres = 0;
+    for message in messages.into_iter().filter(|m| m.expiration > maintenant) {
+        match message.type_message.as_str() {
+            CONST_TYPE_COMMANDE => {
+                let mut contenu = message.contenu;
+                if let Some(commande_id) = message.commande_id.as_ref() {
+                    // Le delai de confirmation de la commande d'actionneur debute a la livraison
+                    match assigner_instance_commande(middleware, commande_id, instance_id).await? {
+                        Some(expiration) => {
+                            if let Some(inner) = contenu.as_object_mut() {
+                                inner.insert("expiration".to_string(), Value::from(expiration.timestamp()));
+                            }
+                        },
+                        None => {
+                            debug!("livrer_boite_envoi Commande {} deja terminee, SKIP", commande_id);
+                            continue
+                        }
+                    }
+                }
+                let routage = RoutageMessageAction::builder(ROLE_RELAI_NOM, message.action.as_str(), vec![Securite::L2Prive])
+                    .partition(instance_id)
+                    .blocking(false)
+                    .build();
+                middleware.transmettre_commande(routage, &contenu).await?;
+            },
+            _ => {
+                let routage = RoutageMessageAction::builder(DOMAINE_NOM, message.action.as_str(), vec![Securite::L2Prive])
+                    .partition(user_id)
+                    .build();
+                middleware.emettre_evenement(routage, &message.contenu).await?;
+            }
+        }
+        livres += 1;
+    }
+
+    // Retirer les messages livres et expires. Un message ajoute pendant la livraison est conserve.
+    let mut filtre_retirer = filtre;
+    filtre_retirer.insert("date_creation", doc! {"$lte": maintenant});
+    collection.delete_many(filtre_retirer, None).await?;
+
+    if livres > 0 {
+        info!("livrer_boite_envoi {} messages livres a l'appareil {}", livres, uuid_appareil);
+    }
+
+    Ok(())
+}
+
+/// Retire les messages expires des appareils qui ne se sont pas reconnectes.
+pub async fn purger_boite_envoi<M>(middleware: &M) -> Result<(), Error>
+    where M: MongoDao
+{
+    let collection = middleware.get_collection(COLLECTIONS_BOITE_ENVOI)?;
+    let resultat = collection.delete_many(doc! {"expiration": {"$lte": Utc::now()}}, None).await?;
+    debug!("purger_boite_envoi {} messages expires retires", resultat.deleted_count);
+    Ok(())
+}
@@ -197,6 +197,240 @@ where M: MongoDao + ConfigMessages
         Some(options_relais)
     ).await?;
 
+    // Rapports de completude
+    let options_rapports_completude = IndexOptions {
+        nom_index: Some(String::from(INDEX_USER_APPAREIL_RAPPORTS_COMPLETUDE)),
+        unique: true
+    };
+    let champs_index_rapports_completude = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_USER_ID), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_UUID_APPAREIL), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        COLLECTIONS_RAPPORTS_COMPLETUDE,
+        champs_index_rapports_completude,
+        Some(options_rapports_completude)
+    ).await?;
+
+    // Baselines pour detection d'anomalies
+    let options_baselines = IndexOptions {
+        nom_index: Some(String::from(INDEX_BASELINES_SENSEURS)),
+        unique: true
+    };
+    let champs_index_baselines = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_USER_ID), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_UUID_APPAREIL), direction: 1},
+        ChampIndex {nom_champ: String::from("senseur_id"), direction: 1},
+        ChampIndex {nom_champ: String::from("metrique"), direction: 1},
+        ChampIndex {nom_champ: String::from("heure_jour"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        COLLECTIONS_BASELINES_SENSEURS,
+        champs_index_baselines,
+        Some(options_baselines)
+    ).await?;
+
+    // Anomalies
+    let options_anomalies = IndexOptions {
+        nom_index: Some(String::from(INDEX_ANOMALIES_SENSEURS)),
+        unique: true
+    };
+    let champs_index_anomalies = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_USER_ID), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_UUID_APPAREIL), direction: 1},
+        ChampIndex {nom_champ: String::from("senseur_id"), direction: 1},
+        ChampIndex {nom_champ: String::from("heure"), direction: 1},
+        ChampIndex {nom_champ: String::from("metrique"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        COLLECTIONS_ANOMALIES_SENSEURS,
+        champs_index_anomalies,
+        Some(options_anomalies)
+    ).await?;
+
+    // Configuration de retention
+    let options_retention = IndexOptions {
+        nom_index: Some(String::from(INDEX_RETENTION)),
+        unique: true
+    };
+    let champs_index_retention = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_USER_ID), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_UUID_APPAREIL), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        COLLECTIONS_RETENTION,
+        champs_index_retention,
+        Some(options_retention)
+    ).await?;
+
+    // Sommaires quotidiens (apres elagage)
+    let options_senseurs_quotidien = IndexOptions {
+        nom_index: Some(String::from(INDEX_SENSEURS_QUOTIDIEN)),
+        unique: true
+    };
+    let champs_index_senseurs_quotidien = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_USER_ID), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_UUID_APPAREIL), direction: 1},
+        ChampIndex {nom_champ: String::from("senseur_id"), direction: 1},
+        ChampIndex {nom_champ: String::from("jour"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        COLLECTIONS_SENSEURS_QUOTIDIEN,
+        champs_index_senseurs_quotidien,
+        Some(options_senseurs_quotidien)
+    ).await?;
+
+    // Correspondance des senseurs legacy (uuid_senseur) vers les appareils
+    let options_migration_senseurs = IndexOptions {
+        nom_index: Some(String::from(INDEX_MIGRATION_SENSEURS)),
+        unique: true
+    };
+    let champs_index_migration_senseurs = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_USER_ID), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_UUID_SENSEUR), direction: 1},
+        ChampIndex {nom_champ: String::from("senseur"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        COLLECTIONS_MIGRATION_SENSEURS,
+        champs_index_migration_senseurs,
+        Some(options_migration_senseurs)
+    ).await?;
+
+    // Sante des relais (heartbeat)
+    let options_sante_relais = IndexOptions {
+        nom_index: Some(String::from(INDEX_SANTE_RELAIS)),
+        unique: true
+    };
+    let champs_index_sante_relais = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_INSTANCE_ID), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        COLLECTIONS_SANTE_RELAIS,
+        champs_index_sante_relais,
+        Some(options_sante_relais)
+    ).await?;
+
+    // Commandes d'actionneurs (historique)
+    let options_commandes_appareils_id = IndexOptions {
+        nom_index: Some(String::from(INDEX_COMMANDES_APPAREILS_ID)),
+        unique: true
+    };
+    let champs_index_commandes_appareils_id = vec!(
+        ChampIndex {nom_champ: String::from("commande_id"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        COLLECTIONS_COMMANDES_APPAREILS,
+        champs_index_commandes_appareils_id,
+        Some(options_commandes_appareils_id)
+    ).await?;
+
+    let options_commandes_appareils_user = IndexOptions {
+        nom_index: Some(String::from(INDEX_COMMANDES_APPAREILS_USER)),
+        unique: false
+    };
+    let champs_index_commandes_appareils_user = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_USER_ID), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_UUID_APPAREIL), direction: 1},
+        ChampIndex {nom_champ: String::from("date_creation"), direction: -1},
+    );
+    middleware.create_index(
+        middleware,
+        COLLECTIONS_COMMANDES_APPAREILS,
+        champs_index_commandes_appareils_user,
+        Some(options_commandes_appareils_user)
+    ).await?;
+
+    // Boite d'envoi des appareils hors ligne
+    let options_boite_envoi = IndexOptions {
+        nom_index: Some(String::from(INDEX_BOITE_ENVOI)),
+        unique: false
+    };
+    let champs_index_boite_envoi = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_USER_ID), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_UUID_APPAREIL), direction: 1},
+        ChampIndex {nom_champ: String::from("date_creation"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        COLLECTIONS_BOITE_ENVOI,
+        champs_index_boite_envoi,
+        Some(options_boite_envoi)
+    ).await?;
+
+    // Scenes usager
+    let options_scenes = IndexOptions {
+        nom_index: Some(String::from(INDEX_SCENES)),
+        unique: true
+    };
+    let champs_index_scenes = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_USER_ID), direction: 1},
+        ChampIndex {nom_champ: String::from("scene_id"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        COLLECTIONS_SCENES,
+        champs_index_scenes,
+        Some(options_scenes)
+    ).await?;
+
+    // Horaires usager
+    let options_horaires = IndexOptions {
+        nom_index: Some(String::from(INDEX_HORAIRES)),
+        unique: true
+    };
+    let champs_index_horaires = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_USER_ID), direction: 1},
+        ChampIndex {nom_champ: String::from("horaire_id"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        COLLECTIONS_HORAIRES,
+        champs_index_horaires,
+        Some(options_horaires)
+    ).await?;
+
+    // Versions des programmes
+    let options_programmes_versions = IndexOptions {
+        nom_index: Some(String::from(INDEX_PROGRAMMES_VERSIONS)),
+        unique: true
+    };
+    let champs_index_programmes_versions = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_USER_ID), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_UUID_APPAREIL), direction: 1},
+        ChampIndex {nom_champ: String::from("programme_id"), direction: 1},
+        ChampIndex {nom_champ: String::from("version"), direction: -1},
+    );
+    middleware.create_index(
+        middleware,
+        COLLECTIONS_PROGRAMMES_VERSIONS,
+        champs_index_programmes_versions,
+        Some(options_programmes_versions)
+    ).await?;
+
+    // Gabarits de configuration usager
+    let options_gabarits = IndexOptions {
+        nom_index: Some(String::from(INDEX_GABARITS)),
+        unique: true
+    };
+    let champs_index_gabarits = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_USER_ID), direction: 1},
+        ChampIndex {nom_champ: String::from("gabarit_id"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        COLLECTIONS_GABARITS,
+        champs_index_gabarits,
+        Some(options_gabarits)
+    ).await?;
+
     Ok(())
 }
 
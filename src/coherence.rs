@@ -0,0 +1,576 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use log::{debug, info, warn};
+
+use millegrilles_common_rust::bson::{doc, Document};
+use millegrilles_common_rust::bson::serde_helpers::chrono_datetime_as_bson_datetime;
+use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
+use millegrilles_common_rust::chrono::{DateTime, Duration, Utc};
+use millegrilles_common_rust::constantes::*;
+use millegrilles_common_rust::db_structs::TransactionValide;
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::GenerateurMessages;
+use millegrilles_common_rust::middleware::sauvegarder_traiter_transaction_serializable_v2;
+use millegrilles_common_rust::millegrilles_cryptographie::deser_message_buffer;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::MessageMilleGrillesBufferDefault;
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::mongodb::options::{AggregateOptions, FindOptions};
+use millegrilles_common_rust::mongodb::ClientSession;
+use millegrilles_common_rust::recepteur_messages::MessageValide;
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+use millegrilles_common_rust::serde_json;
+use millegrilles_common_rust::tokio_stream::StreamExt;
+
+use crate::anomalies::traiter_anomalies_rangees;
+use crate::common::*;
+use crate::domain_manager::SenseursPassifsDomainManager;
+use crate::lectures::{generer_transactions, pipeline_derniere_rangee_senseur, LecturesCumulees};
+use crate::retention::charger_jours_elagues;
+use crate::transactions::{maj_appareil_senseur_horaire, SenseurHoraireRow};
+
+/// Entree senseurs d'un appareil sans historique (rangees horaires, sommaires quotidiens ou lectures courantes).
+const CATEGORIE_SENSEURS_SANS_HISTORIQUE: &str = "senseurs_sans_historique";
+/// Rangees horaires ou sommaires quotidiens d'un appareil qui n'existe plus.
+const CATEGORIE_HORAIRE_APPAREIL_INEXISTANT: &str = "horaire_appareil_inexistant";
+/// Lectures cumulees (COLLECTIONS_LECTURES) jamais agregees en transaction senseurHoraire.
+const CATEGORIE_LECTURES_ORPHELINES: &str = "lectures_orphelines";
+/// lectures_disponibles et types_donnees de l'appareil differents de l'historique.
+const CATEGORIE_TYPES_DONNEES: &str = "types_donnees";
+
+const CATEGORIES: [&str; 4] = [
+    CATEGORIE_SENSEURS_SANS_HISTORIQUE,
+    CATEGORIE_HORAIRE_APPAREIL_INEXISTANT,
+    CATEGORIE_LECTURES_ORPHELINES,
+    CATEGORIE_TYPES_DONNEES,
+];
+
+/// Nombre maximal d'exemples retournes par categorie.
+const CONST_COHERENCE_EXEMPLES_MAX: usize = 100;
+
+/// Age minimal d'une lecture cumulee non agregee. L'aggregation est faite 65 minutes apres l'heure.
+const CONST_LECTURES_ORPHELINES_HEURES: i64 = 2;
+
+#[derive(Deserialize)]
+struct CommandeVerifierCoherence {
+    /// Appliquer les corrections. Rapport seulement si absent.
+    reparer: Option<bool>,
+    /// Categories a verifier. Toutes si absent.
+    categories: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+struct IncoherenceSenseur {
+    user_id: String,
+    uuid_appareil: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    senseur_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+#[derive(Serialize)]
+struct RapportCategorie {
+    categorie: String,
+    nombre: usize,
+    reparees: usize,
+    exemples: Vec<IncoherenceSenseur>,
+}
+
+impl RapportCategorie {
+    fn new(categorie: &str) -> Self {
+        Self { categorie: categorie.to_string(), nombre: 0, reparees: 0, exemples: Vec::new() }
+    }
+
+    fn ajouter(&mut self, user_id: &str, uuid_appareil: &str, senseur_id: Option<&String>, detail: Option<String>) {
+        self.nombre += 1;
+        if self.exemples.len() < CONST_COHERENCE_EXEMPLES_MAX {
+            self.exemples.push(IncoherenceSenseur {
+                user_id: user_id.to_string(),
+                uuid_appareil: uuid_appareil.to_string(),
+                senseur_id: senseur_id.cloned(),
+                detail,
+            });
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ReponseVerifierCoherence {
+    ok: bool,
+    reparer: bool,
+    categories: Vec<RapportCategorie>,
+}
+
+#[derive(Deserialize)]
+struct RowAppareilCoherence {
+    user_id: Option<String>,
+    uuid_appareil: String,
+    senseurs: Option<Document>,
+    lectures_disponibles: Option<Vec<String>>,
+    types_donnees: Option<HashMap<String, String>>,
+}
+
+#[derive(Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone)]
+struct CleSenseur {
+    user_id: String,
+    uuid_appareil: String,
+    senseur_id: String,
+}
+
+#[derive(Deserialize)]
+struct TypeDonneesHistorique {
+    #[serde(rename="type")]
+    type_: String,
+}
+
+#[derive(Deserialize)]
+struct RowSenseurHistorique {
+    _id: CleSenseur,
+    type_donnees: Option<TypeDonneesHistorique>,
+}
+
+#[derive(Deserialize)]
+struct RowLecturesCle {
+    user_id: String,
+    uuid_appareil: String,
+    senseur_id: String,
+    #[serde(with="chrono_datetime_as_bson_datetime")]
+    heure: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TransactionSupprimerSenseursHoraire {
+    pub user_id: String,
+    pub uuid_appareil: String,
+}
+
+/// Senseurs avec un historique (rangees horaires ou sommaires quotidiens) et le type de la rangee la plus recente.
+async fn charger_historique_senseurs<M>(middleware: &M) -> Result<BTreeMap<CleSenseur, Option<String>>, Error>
+    where M: MongoDao
+{
+    let mut historique = BTreeMap::new();
+    // Les sommaires quotidiens sont plus vieux que les rangees horaires, le type horaire a priorite.
+    for nom_collection in [COLLECTIONS_SENSEURS_QUOTIDIEN, COLLECTIONS_SENSEURS_HORAIRE] {
+        let collection = middleware.get_collection(nom_collection)?;
+        let options = AggregateOptions::builder().allow_disk_use(true).build();
+        let mut curseur = collection.aggregate(pipeline_derniere_rangee_senseur(false), options).await?;
+        while let Some(d) = curseur.next().await {
+            let row: RowSenseurHistorique = convertir_bson_deserializable(d?)?;
+            let type_donnees = row.type_donnees.map(|t| t.type_);
+            let entree = historique.entry(row._id).or_insert(None);
+            if type_donnees.is_some() {
+                *entree = type_donnees;
+            }
+        }
+    }
+    Ok(historique)
+}
+
+pub async fn commande_verifier_coherence<M>(middleware: &M, m: MessageValide, gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    debug!("commande_verifier_coherence Consommer commande : {:?}", & m.type_message);
+    if ! m.certificat.verifier_exchanges(vec![Securite::L3Protege, Securite::L4Secure])? &&
+        ! m.certificat.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE)? {
+        return Ok(Some(middleware.reponse_err(None, None, Some("Acces refuse"))?))
+    }
+    let commande: CommandeVerifierCoherence = deser_message_buffer!(m.message);
+    let reparer = commande.reparer.unwrap_or(false);
+
+    let categories: Vec<&str> = match commande.categories.as_ref() {
+        Some(categories) => {
+            if let Some(inconnue) = categories.iter().find(|c| !CATEGORIES.contains(&c.as_str())) {
+                let message = format!("Categorie inconnue : {}", inconnue);
+                return Ok(Some(middleware.reponse_err(None, None, Some(message.as_str()))?))
+            }
+            CATEGORIES.iter().filter(|c| categories.iter().any(|d| d.as_str() == **c)).copied().collect()
+        },
+        None => CATEGORIES.to_vec()
+    };
+
+    info!("commande_verifier_coherence Verification {:?} (reparer : {})", categories, reparer);
+
+    let historique = charger_historique_senseurs(middleware).await?;
+
+    let mut appareils = Vec::new();
+    {
+        let options = FindOptions::builder()
+            .projection(doc!{CHAMP_USER_ID: 1, CHAMP_UUID_APPAREIL: 1, "senseurs": 1, CHAMP_LECTURES_DISPONIBLES: 1, "types_donnees": 1})
+            .build();
+        let collection = middleware.get_collection(COLLECTIONS_APPAREILS)?;
+        let mut curseur = collection.find(doc!{}, options).await?;
+        while let Some(d) = curseur.next().await {
+            let row: RowAppareilCoherence = convertir_bson_deserializable(d?)?;
+            if row.user_id.is_some() {
+                appareils.push(row);
+            }
+        }
+    }
+
+    let mut rapports = Vec::new();
+    for categorie in categories {
+        let rapport = match categorie {
+            CATEGORIE_SENSEURS_SANS_HISTORIQUE =>
+                verifier_senseurs_sans_historique(middleware, &appareils, &historique, reparer).await?,
+            CATEGORIE_HORAIRE_APPAREIL_INEXISTANT =>
+                verifier_horaire_appareil_inexistant(middleware, gestionnaire, &appareils, &historique, reparer).await?,
+            CATEGORIE_LECTURES_ORPHELINES =>
+                verifier_lectures_orphelines(middleware, gestionnaire, reparer).await?,
+            CATEGORIE_TYPES_DONNEES =>
+                verifier_types_donnees(middleware, &appareils, &historique, reparer).await?,
+            _ => continue
+        };
+        info!("commande_verifier_coherence {} : {} incoherences, {} reparees", rapport.categorie, rapport.nombre, rapport.reparees);
+        rapports.push(rapport);
+    }
+
+    let reponse = ReponseVerifierCoherence { ok: true, reparer, categories: rapports };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+/// Entrees senseurs des appareils sans historique ni lectures courantes. L'entree est retiree directement,
+/// elle provient des lectures (aucune transaction) et n'est pas reproduite par une regeneration.
+async fn verifier_senseurs_sans_historique<M>(
+    middleware: &M, appareils: &Vec<RowAppareilCoherence>, historique: &BTreeMap<CleSenseur, Option<String>>, reparer: bool
+)
+    -> Result<RapportCategorie, Error>
+    where M: MongoDao
+{
+    let mut rapport = RapportCategorie::new(CATEGORIE_SENSEURS_SANS_HISTORIQUE);
+
+    // Senseurs avec des lectures de l'heure courante (pas encore agregees)
+    let mut lectures_courantes = BTreeSet::new();
+    {
+        let options = FindOptions::builder()
+            .projection(doc!{CHAMP_USER_ID: 1, CHAMP_UUID_APPAREIL: 1, "senseur_id": 1})
+            .build();
+        let collection = middleware.get_collection(COLLECTIONS_LECTURES)?;
+        let mut curseur = collection.find(doc!{}, options).await?;
+        while let Some(d) = curseur.next().await {
+            if let Ok(cle) = convertir_bson_deserializable::<CleSenseur>(d?) {
+                lectures_courantes.insert(cle);
+            }
+        }
+    }
+
+    let collection = middleware.get_collection(COLLECTIONS_APPAREILS)?;
+    for appareil in appareils {
+        let user_id = match appareil.user_id.as_ref() { Some(inner) => inner, None => continue };
+        let senseurs = match appareil.senseurs.as_ref() { Some(inner) => inner, None => continue };
+
+        let mut retirer = Document::new();
+        for senseur_id in senseurs.keys() {
+            let cle = CleSenseur {
+                user_id: user_id.clone(),
+                uuid_appareil: appareil.uuid_appareil.clone(),
+                senseur_id: senseur_id.clone(),
+            };
+            if historique.contains_key(&cle) || lectures_courantes.contains(&cle) {
+                continue
+            }
+            rapport.ajouter(user_id, &appareil.uuid_appareil, Some(senseur_id), None);
+            retirer.insert(format!("senseurs.{}", senseur_id), true);
+        }
+
+        if reparer && !retirer.is_empty() {
+            let nombre = retirer.len();
+            let filtre = doc! { CHAMP_USER_ID: user_id, CHAMP_UUID_APPAREIL: &appareil.uuid_appareil };
+            let ops = doc! { "$unset": retirer, "$currentDate": { CHAMP_MODIFICATION: true } };
+            let mut session = middleware.get_session().await?;
+            session.start_transaction(None).await?;
+            match collection.update_one_with_session(filtre, ops, None, &mut session).await {
+                Ok(_) => {
+                    session.commit_transaction().await?;
+                    rapport.reparees += nombre;
+                },
+                Err(e) => {
+                    warn!("verifier_senseurs_sans_historique Erreur reparation appareil {} : {:?}", appareil.uuid_appareil, e);
+                    session.abort_transaction().await?;
+                }
+            }
+        }
+    }
+
+    Ok(rapport)
+}
+
+/// Historique d'appareils inexistants. La suppression passe par une transaction (rangees conservees par regeneration).
+async fn verifier_horaire_appareil_inexistant<M>(
+    middleware: &M, gestionnaire: &SenseursPassifsDomainManager, appareils: &Vec<RowAppareilCoherence>,
+    historique: &BTreeMap<CleSenseur, Option<String>>, reparer: bool
+)
+    -> Result<RapportCategorie, Error>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    let mut rapport = RapportCategorie::new(CATEGORIE_HORAIRE_APPAREIL_INEXISTANT);
+
+    let appareils_existants: BTreeSet<(&str, &str)> = appareils.iter()
+        .filter_map(|a| a.user_id.as_ref().map(|u| (u.as_str(), a.uuid_appareil.as_str())))
+        .collect();
+
+    let mut appareils_inexistants: BTreeMap<(&str, &str), Vec<&String>> = BTreeMap::new();
+    for cle in historique.keys() {
+        let appareil = (cle.user_id.as_str(), cle.uuid_appareil.as_str());
+        if !appareils_existants.contains(&appareil) {
+            appareils_inexistants.entry(appareil).or_default().push(&cle.senseur_id);
+        }
+    }
+
+    for ((user_id, uuid_appareil), senseurs) in appareils_inexistants {
+        rapport.ajouter(user_id, uuid_appareil, None, Some(format!("senseurs : {:?}", senseurs)));
+
+        if reparer {
+            let transaction = TransactionSupprimerSenseursHoraire {
+                user_id: user_id.to_string(),
+                uuid_appareil: uuid_appareil.to_string(),
+            };
+            let mut session = middleware.get_session().await?;
+            session.start_transaction(None).await?;
+            match sauvegarder_traiter_transaction_serializable_v2(
+                middleware, &transaction, gestionnaire, &mut session, DOMAINE_NOM, TRANSACTION_SUPPRIMER_SENSEURS_HORAIRE).await
+            {
+                Ok(_) => {
+                    session.commit_transaction().await?;
+                    rapport.reparees += 1;
+                },
+                Err(e) => {
+                    warn!("verifier_horaire_appareil_inexistant Erreur suppression historique appareil {} : {:?}", uuid_appareil, e);
+                    session.abort_transaction().await?;
+                }
+            }
+        }
+    }
+
+    Ok(rapport)
+}
+
+/// Lectures cumulees non agregees. Les lectures valides sont soumises en transaction senseurHoraire,
+/// les lectures deja agregees (rangee existante) ou invalides sont retirees. Chaque document est traite
+/// dans sa propre session : il n'est retire qu'avec la sauvegarde de sa transaction horaire.
+async fn verifier_lectures_orphelines<M>(middleware: &M, gestionnaire: &SenseursPassifsDomainManager, reparer: bool)
+    -> Result<RapportCategorie, Error>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    let mut rapport = RapportCategorie::new(CATEGORIE_LECTURES_ORPHELINES);
+
+    let limite = Utc::now() - Duration::hours(CONST_LECTURES_ORPHELINES_HEURES);
+    let collection_lectures = middleware.get_collection(COLLECTIONS_LECTURES)?;
+
+    let mut documents = Vec::new();
+    let mut curseur = collection_lectures.find(doc!{"heure": {"$lt": limite}}, None).await?;
+    while let Some(d) = curseur.next().await {
+        documents.push(d?);
+    }
+
+    let mut rangees = Vec::new();
+    for document in documents {
+        let id_document = document.get("_id").cloned();
+        let mut session = middleware.get_session().await?;
+        session.start_transaction(None).await?;
+        match verifier_lecture_orpheline(middleware, gestionnaire, document, reparer, &mut rapport, &mut session).await {
+            Ok(rangee) => {
+                session.commit_transaction().await?;
+                rangees.extend(rangee);
+            },
+            Err(e) => {
+                warn!("verifier_lectures_orphelines Erreur traitement lectures {:?} : {:?}", id_document, e);
+                session.abort_transaction().await?;
+            }
+        }
+    }
+
+    // Detection d'anomalies apres le commit des transactions horaires
+    traiter_anomalies_rangees(middleware, rangees).await;
+
+    Ok(rapport)
+}
+
+/// Verifie (et repare au besoin) un document de lectures cumulees dans la session recue.
+async fn verifier_lecture_orpheline<M>(
+    middleware: &M, gestionnaire: &SenseursPassifsDomainManager, document: Document, reparer: bool,
+    rapport: &mut RapportCategorie, session: &mut ClientSession
+)
+    -> Result<Option<SenseurHoraireRow>, Error>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    let collection_lectures = middleware.get_collection(COLLECTIONS_LECTURES)?;
+    let id_document = document.get("_id").cloned();
+    let cle = match convertir_bson_deserializable::<RowLecturesCle>(document.clone()) {
+        Ok(inner) => inner,
+        Err(e) => {
+            rapport.ajouter("", "", None, Some(format!("lecture invalide {:?} : {:?}", id_document, e)));
+            if reparer {
+                if let Some(id_document) = id_document {
+                    collection_lectures.delete_one_with_session(doc!{"_id": id_document}, None, session).await?;
+                    rapport.reparees += 1;
+                }
+            }
+            return Ok(None)
+        }
+    };
+
+    let filtre_horaire = doc! {
+        CHAMP_USER_ID: &cle.user_id,
+        CHAMP_UUID_APPAREIL: &cle.uuid_appareil,
+        "senseur_id": &cle.senseur_id,
+        "heure": cle.heure,
+    };
+    // Une heure d'un jour deja elague (retention) ne doit pas etre reintroduite
+    let fin = cle.heure + Duration::hours(1);
+    let collection_horaire = middleware.get_collection(COLLECTIONS_SENSEURS_HORAIRE)?;
+    let deja_agregee = collection_horaire.count_documents_with_session(filtre_horaire, None, session).await? > 0 ||
+        charger_jours_elagues(middleware, &cle.user_id, &cle.uuid_appareil, &cle.senseur_id, &cle.heure, &fin, session).await?
+            .contient(&cle.heure);
+    let detail = if deja_agregee { "deja agregee" } else { "non agregee" };
+    rapport.ajouter(&cle.user_id, &cle.uuid_appareil, Some(&cle.senseur_id),
+                    Some(format!("{} {}", detail, cle.heure.to_rfc3339())));
+
+    if !reparer {
+        return Ok(None)
+    }
+
+    if deja_agregee {
+        if let Some(id_document) = id_document {
+            collection_lectures.delete_one_with_session(doc!{"_id": id_document}, None, session).await?;
+            rapport.reparees += 1;
+        }
+        return Ok(None)
+    }
+
+    match convertir_bson_deserializable::<LecturesCumulees>(document) {
+        Ok(lectures) => {
+            // Le document de lectures est retire dans la session, apres la sauvegarde de la transaction horaire
+            let rangee = generer_transactions(middleware, gestionnaire, lectures, session).await?;
+            if rangee.is_some() {
+                rapport.reparees += 1;
+            }
+            Ok(rangee)
+        },
+        Err(e) => {
+            warn!("verifier_lectures_orphelines Erreur mapping LecturesCumulees {:?} : {:?}", id_document, e);
+            Ok(None)
+        }
+    }
+}
+
+/// lectures_disponibles et types_donnees d'apres l'historique. Les corrections sont appliquees directement,
+/// l'appareil est reconstruit de la meme facon par rebuild_sensor_list lors d'une regeneration.
+async fn verifier_types_donnees<M>(
+    middleware: &M, appareils: &Vec<RowAppareilCoherence>, historique: &BTreeMap<CleSenseur, Option<String>>, reparer: bool
+)
+    -> Result<RapportCategorie, Error>
+    where M: MongoDao
+{
+    let mut rapport = RapportCategorie::new(CATEGORIE_TYPES_DONNEES);
+
+    let mut historique_appareils: BTreeMap<(&str, &str), Vec<(&String, Option<&String>)>> = BTreeMap::new();
+    for (cle, type_donnees) in historique {
+        historique_appareils.entry((cle.user_id.as_str(), cle.uuid_appareil.as_str()))
+            .or_default()
+            .push((&cle.senseur_id, type_donnees.as_ref()));
+    }
+
+    for appareil in appareils {
+        let user_id = match appareil.user_id.as_ref() { Some(inner) => inner, None => continue };
+        let senseurs_historique = historique_appareils.remove(&(user_id.as_str(), appareil.uuid_appareil.as_str()))
+            .unwrap_or_default();
+
+        // Une session par appareil, une erreur n'annule pas les reparations des autres appareils
+        let mut session = middleware.get_session().await?;
+        session.start_transaction(None).await?;
+        match verifier_types_donnees_appareil(
+            middleware, user_id, appareil, &senseurs_historique, reparer, &mut rapport, &mut session).await
+        {
+            Ok(reparees) => {
+                session.commit_transaction().await?;
+                rapport.reparees += reparees;
+            },
+            Err(e) => {
+                warn!("verifier_types_donnees Erreur appareil {} : {:?}", appareil.uuid_appareil, e);
+                session.abort_transaction().await?;
+            }
+        }
+    }
+
+    Ok(rapport)
+}
+
+/// Verifie (et repare) lectures_disponibles et types_donnees d'un appareil. Retourne le nombre de senseurs repares.
+async fn verifier_types_donnees_appareil<M>(
+    middleware: &M, user_id: &String, appareil: &RowAppareilCoherence, senseurs_historique: &Vec<(&String, Option<&String>)>,
+    reparer: bool, rapport: &mut RapportCategorie, session: &mut ClientSession
+)
+    -> Result<usize, Error>
+    where M: MongoDao
+{
+    let mut reparees = 0;
+    let lectures_disponibles: BTreeSet<&String> = appareil.lectures_disponibles.iter().flatten().collect();
+
+    // Senseurs avec historique absents de lectures_disponibles ou avec un type different
+    for (senseur_id, type_donnees) in senseurs_historique {
+        let type_appareil = appareil.types_donnees.as_ref().and_then(|t| t.get(*senseur_id));
+        let disponible = lectures_disponibles.contains(senseur_id);
+        let type_different = type_donnees.is_some() && type_appareil != *type_donnees;
+        if disponible && !type_different {
+            continue
+        }
+        let detail = match disponible {
+            false => "absent de lectures_disponibles".to_string(),
+            true => format!("types_donnees {:?}, historique {:?}", type_appareil, type_donnees),
+        };
+        rapport.ajouter(user_id, &appareil.uuid_appareil, Some(*senseur_id), Some(detail));
+        if reparer {
+            maj_appareil_senseur_horaire(
+                middleware, user_id, &appareil.uuid_appareil, senseur_id, *type_donnees, session).await?;
+            reparees += 1;
+        }
+    }
+
+    // Senseurs dans lectures_disponibles sans historique
+    let mut retirer = Vec::new();
+    let mut retirer_types = Document::new();
+    for senseur_id in lectures_disponibles {
+        if senseurs_historique.iter().any(|(s, _)| *s == senseur_id) {
+            continue
+        }
+        rapport.ajouter(user_id, &appareil.uuid_appareil, Some(senseur_id), Some("aucun historique".to_string()));
+        retirer.push(senseur_id.as_str());
+        retirer_types.insert(format!("types_donnees.{}", senseur_id), true);
+    }
+    if reparer && !retirer.is_empty() {
+        let nombre = retirer.len();
+        let filtre = doc! { CHAMP_USER_ID: user_id, CHAMP_UUID_APPAREIL: &appareil.uuid_appareil };
+        let ops = doc! {
+            "$pull": { CHAMP_LECTURES_DISPONIBLES: { "$in": retirer } },
+            "$unset": retirer_types,
+            "$currentDate": { CHAMP_MODIFICATION: true },
+        };
+        let collection = middleware.get_collection(COLLECTIONS_APPAREILS)?;
+        collection.update_one_with_session(filtre, ops, None, session).await?;
+        reparees += nombre;
+    }
+
+    Ok(reparees)
+}
+
+/// Supprime l'historique (rangees horaires et sommaires quotidiens) d'un appareil.
+pub async fn transaction_supprimer_senseurs_horaire<M>(middleware: &M, transaction: TransactionValide, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("transaction_supprimer_senseurs_horaire Consommer transaction : {:?}", transaction.transaction.id);
+    let contenu: TransactionSupprimerSenseursHoraire = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+
+    let filtre = doc! {
+        CHAMP_USER_ID: &contenu.user_id,
+        CHAMP_UUID_APPAREIL: &contenu.uuid_appareil,
+    };
+    let resultat_horaire = middleware.get_collection(COLLECTIONS_SENSEURS_HORAIRE)?
+        .delete_many_with_session(filtre.clone(), None, session).await?;
+    let resultat_quotidien = middleware.get_collection(COLLECTIONS_SENSEURS_QUOTIDIEN)?
+        .delete_many_with_session(filtre, None, session).await?;
+    info!("transaction_supprimer_senseurs_horaire Appareil {} : {} rangees horaires, {} sommaires quotidiens supprimes",
+        contenu.uuid_appareil, resultat_horaire.deleted_count, resultat_quotidien.deleted_count);
+
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
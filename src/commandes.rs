@@ -1,22 +1,33 @@
 use log::debug;
 use millegrilles_common_rust::bson::{doc, Document};
 
+use crate::actionneurs::commande_actionner_appareil;
+use crate::boite_envoi::{instance_appareil_connecte, mettre_en_file, RowBoiteEnvoi};
 use crate::common::*;
+use crate::coherence::commande_verifier_coherence;
+use crate::compaction::commande_compacter_senseurs_horaire;
 use crate::domain_manager::SenseursPassifsDomainManager;
-use crate::evenements::EvenementPresenceAppareilUser;
+use crate::gabarits::{commande_appliquer_gabarit, commande_sauvegarder_gabarit};
+use crate::horaires::commande_sauvegarder_horaire;
+use crate::maintenance::deconnecter_appareils_instance;
+use crate::migration::commande_migrer_transactions_legacy;
+use crate::programmes::{commande_maj_appareil, commande_restaurer_version_programme, commande_sauvegarder_programme};
+use crate::relais::{commande_confirmer_relai, commande_revoquer_relai};
+use crate::sante_relais::marquer_relai_deconnecte;
+use crate::retention::commande_maj_retention;
+use crate::scenes::{commande_executer_scene, commande_sauvegarder_scene};
 use crate::transactions::{TransactionInitialiserAppareil, TransactionMajConfigurationUsager, TransactionShowHideSensor};
 use millegrilles_common_rust::certificats::{calculer_fingerprint, charger_certificat, ValidateurX509, VerificateurPermissions};
-use millegrilles_common_rust::chrono::{DateTime, Utc};
+use millegrilles_common_rust::chrono::Utc;
 use millegrilles_common_rust::constantes::*;
 use millegrilles_common_rust::error::Error;
 use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
 use millegrilles_common_rust::get_domaine_action;
 use millegrilles_common_rust::middleware::{sauvegarder_traiter_transaction_serializable_v2, sauvegarder_traiter_transaction_v2};
 use millegrilles_common_rust::millegrilles_cryptographie::deser_message_buffer;
-use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::optionepochseconds;
 use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::MessageMilleGrillesBufferDefault;
 use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
-use millegrilles_common_rust::mongodb::options::{FindOneAndUpdateOptions, ReturnDocument, UpdateOptions};
+use millegrilles_common_rust::mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
 use millegrilles_common_rust::mongodb::ClientSession;
 use millegrilles_common_rust::rabbitmq_dao::TypeMessageOut;
 use millegrilles_common_rust::recepteur_messages::{MessageValide, TypeMessage};
@@ -48,19 +59,34 @@ pub async fn consommer_commande<M>(middleware: &M, m: MessageValide, gestionnair
         COMMANDE_CHALLENGE_APPAREIL => commande_challenge_appareil(middleware, m, gestionnaire, &mut session).await,
         COMMANDE_SIGNER_APPAREIL => commande_signer_appareil(middleware, m, gestionnaire, &mut session).await,
         COMMANDE_CONFIRMER_RELAI => commande_confirmer_relai(middleware, m,  &mut session).await,
+        COMMANDE_REVOQUER_RELAI => commande_revoquer_relai(middleware, m, &mut session).await,
         COMMANDE_RESET_CERTIFICATS => commande_reset_certificats(middleware, m, &mut session).await,
         COMMAND_DISCONNECT_RELAY => command_disconnect_relay(middleware, m, &mut session).await,
+        // La compaction, la verification de coherence et la migration gerent leurs propres sessions
+        COMMANDE_COMPACTER_SENSEURS_HORAIRE => commande_compacter_senseurs_horaire(middleware, m, gestionnaire).await,
+        COMMANDE_VERIFIER_COHERENCE => commande_verifier_coherence(middleware, m, gestionnaire).await,
+        COMMANDE_MIGRER_TRANSACTIONS_LEGACY => commande_migrer_transactions_legacy(middleware, m, gestionnaire).await,
+        // Conservees hors session, le relai peut confirmer avant la fin du traitement
+        COMMANDE_ACTIONNER_APPAREIL => commande_actionner_appareil(middleware, m, gestionnaire).await,
+        COMMANDE_EXECUTER_SCENE => commande_executer_scene(middleware, m, gestionnaire).await,
+        // Une transaction (et une session) par appareil
+        COMMANDE_APPLIQUER_GABARIT => commande_appliquer_gabarit(middleware, m, gestionnaire).await,
+        TRANSACTION_SAUVEGARDER_SCENE => commande_sauvegarder_scene(middleware, m, gestionnaire, &mut session).await,
+        TRANSACTION_SAUVEGARDER_HORAIRE => commande_sauvegarder_horaire(middleware, m, gestionnaire, &mut session).await,
         TRANSACTION_MAJ_CONFIGURATION_USAGER => commande_maj_configuration_usager(middleware, m, gestionnaire, &mut session).await,
         TRANSACTION_MAJ_SENSEUR |
         TRANSACTION_MAJ_NOEUD |
-        TRANSACTION_SUPPRESSION_SENSEUR |
-        TRANSACTION_MAJ_APPAREIL => {
+        TRANSACTION_SUPPRESSION_SENSEUR => {
             // Pour l'instant, aucune autre validation. On traite comme une transaction
             Ok(sauvegarder_traiter_transaction_v2(middleware, m, gestionnaire, &mut session).await?)
         }
+        // Les programmes sont valides selon le schema de leur classe
+        TRANSACTION_MAJ_APPAREIL => commande_maj_appareil(middleware, m, gestionnaire, &mut session).await,
+        TRANSACTION_SAUVEGARDER_PROGRAMME => commande_sauvegarder_programme(middleware, m, gestionnaire, &mut session).await,
+        TRANSACTION_RESTAURER_VERSION_PROGRAMME => commande_restaurer_version_programme(middleware, m, gestionnaire, &mut session).await,
+        TRANSACTION_SAUVEGARDER_GABARIT => commande_sauvegarder_gabarit(middleware, m, gestionnaire, &mut session).await,
         TRANSACTION_APPAREIL_SUPPRIMER |
-        TRANSACTION_APPAREIL_RESTAURER |
-        TRANSACTION_SAUVEGARDER_PROGRAMME => {
+        TRANSACTION_APPAREIL_RESTAURER => {
             if user_id.is_none() {
                 Err(format!("senseurspassifs.consommer_commande: Commande autorisation invalide (user_id requis) pour message {:?}", m.type_message))?
             }
@@ -68,6 +94,7 @@ pub async fn consommer_commande<M>(middleware: &M, m: MessageValide, gestionnair
             Ok(sauvegarder_traiter_transaction_v2(middleware, m, gestionnaire, &mut session).await?)
         }
         TRANSACTION_SHOW_HIDE_SENSOR => command_show_hide_sensor(middleware, m, gestionnaire, &mut session).await,
+        TRANSACTION_MAJ_RETENTION => commande_maj_retention(middleware, m, gestionnaire, &mut session).await,
         _ => Err(format!("senseurspassifs.consommer_commande: Commande {} inconnue : {}, message dropped", DOMAINE_NOM, action))?,
     };
 
@@ -332,7 +359,7 @@ async fn commande_challenge_appareil<M>(middleware: &M, m: MessageValide, _gesti
     let collection = middleware.get_collection(COLLECTIONS_APPAREILS)?;
 
     let doc_appareil_option = {
-        let filtre = doc! {"uuid_appareil": &commande.uuid_appareil, "user_id": user_id};
+        let filtre = doc! {"uuid_appareil": &commande.uuid_appareil, "user_id": &user_id};
         collection.find_one_with_session(filtre, None, session).await?
     };
 
@@ -345,15 +372,6 @@ async fn commande_challenge_appareil<M>(middleware: &M, m: MessageValide, _gesti
         }
     };
 
-    let instance_id = match doc_appareil.instance_id {
-        Some(inner) => inner,
-        None => {
-            // let reponse = json!({"ok": false, "err": "Pas d'instance_id pour cet appareil"});
-            // return Ok(Some(middleware.formatter_reponse(reponse, None)?))
-            return Ok(Some(middleware.reponse_err(None, None, Some("Pas d'instance_id pour cet appareil"))?))
-        }
-    };
-
     // Emettre la commande de challenge
     let message_challenge = json!({
         "ok": true,
@@ -362,6 +380,17 @@ async fn commande_challenge_appareil<M>(middleware: &M, m: MessageValide, _gesti
         "cle_publique": doc_appareil.cle_publique,
         "fingerprint": doc_appareil.fingerprint,
     });
+
+    let instance_id = match instance_appareil_connecte(&doc_appareil) {
+        Some(inner) => inner,
+        None => {
+            // Appareil hors ligne (meme regle que les actionneurs), le challenge est livre a la reconnexion
+            let message = RowBoiteEnvoi::commande(&user_id, &commande.uuid_appareil, "challengeAppareil", message_challenge, None);
+            mettre_en_file(middleware, message, session).await?;
+            let reponse = json!({"ok": true, "en_file": true});
+            return Ok(Some(middleware.build_reponse(&reponse)?.0))
+        }
+    };
     let routage = RoutageMessageAction::builder("senseurspassifs_relai", "challengeAppareil", vec![Securite::L2Prive])
         .partition(instance_id)
         .blocking(false)
@@ -377,52 +406,6 @@ struct CommandeChallengeAppareil {
     challenge: Vec<u8>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-struct CommandeConfirmerRelai {
-    fingerprint: String,
-    #[serde(default, with="optionepochseconds")]
-    expiration: Option<DateTime<Utc>>,
-}
-
-#[derive(Deserialize)]
-pub struct RowRelais {
-    // pub fingerprint: String,
-    // pub user_id: String,
-    // #[serde(default, with="optionepochseconds")]
-    // pub expiration: Option<DateTime<Utc>>,
-}
-
-async fn commande_confirmer_relai<M>(middleware: &M, m: MessageValide, session: &mut ClientSession)
-    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
-    where M: GenerateurMessages + MongoDao
-{
-    debug ! ("commande_confirmer_relai Consommer requete : {:?}", m.type_message);
-    let commande: CommandeConfirmerRelai = deser_message_buffer!(m.message);
-
-    let certificat = m.certificat.as_ref();
-    let common_name = certificat.get_common_name()?;
-    let user_id = match certificat.get_user_id()? {
-        Some(inner) => inner,
-        None => Err(Error::Str("commande_confirmer_relai Certificat sans user_id"))?
-    };
-
-    let filtre = doc! { "uuid_appareil": &common_name, "user_id": &user_id };
-    let ops = doc! {
-        "$set": { "fingerprint": &commande.fingerprint },
-        "$setOnInsert": {
-            "uuid_appareil": common_name,
-            "user_id": user_id,
-            CHAMP_CREATION: Utc::now()
-        },
-        "$currentDate": { CHAMP_MODIFICATION: true }
-    };
-    let collection = middleware.get_collection(COLLECTIONS_RELAIS)?;
-    let options = UpdateOptions::builder().upsert(true).build();
-    collection.update_one_with_session(filtre, ops, options, session).await?;
-
-    Ok(Some(middleware.reponse_ok(None, None)?))
-}
-
 async fn signer_certificat<M>(middleware: &M, user_id: &str, filtre_appareil: Document, doc_appareil: DocAppareil, csr_inclus: Option<&String>, session: &mut ClientSession)
     -> Result<Vec<String>, Error>
     where M: GenerateurMessages + MongoDao
@@ -531,37 +514,8 @@ pub async fn command_disconnect_relay<M>(middleware: &M, m: MessageValide, sessi
     }
 
     let instance_id = m.certificat.get_common_name()?;
-
-    let collection = middleware.get_collection_typed::<DocAppareil>(COLLECTIONS_APPAREILS)?;
-    let filtre = doc!{ "instance_id": &instance_id, "connecte": true };
-    let mut cursor = collection.find_with_session(filtre, None, session).await?;
-    while cursor.advance(session).await? {
-        let device = cursor.deserialize_current()?;
-
-        // Emit event for device
-        {
-            if let Some(user_id) = device.user_id {
-                let evenement_reemis = EvenementPresenceAppareilUser {
-                    uuid_appareil: device.uuid_appareil,
-                    user_id,
-                    version: device.version,
-                    connecte: false
-                };
-                let routage = RoutageMessageAction::builder(DOMAINE_NOM, "presenceAppareil", vec![Securite::L2Prive])
-                    .partition(&evenement_reemis.user_id)
-                    .build();
-                middleware.emettre_evenement(routage, &evenement_reemis).await?;
-            }
-        }
-    }
-
-    let ops = doc! {
-        "$unset": {"instance_id": true},
-        "$set": {"connecte": false},
-        "$currentDate": {CHAMP_MODIFICATION: true},
-    };
-    let filtre = doc!{ "instance_id": instance_id, "connecte": true };
-    collection.update_many_with_session(filtre, ops, None, session).await?;
+    deconnecter_appareils_instance(middleware, instance_id.as_str(), session).await?;
+    marquer_relai_deconnecte(middleware, instance_id.as_str(), session).await?;
 
     Ok(Some(middleware.reponse_ok(None, None)?))
 }
@@ -21,6 +21,29 @@ pub const REQUETE_GET_APPAREIL_PROGRAMMES_CONFIGURATION: &str = "getAppareilProg
 pub const REQUETE_GET_STATISTIQUES_SENSEUR: &str = "getStatistiquesSenseur";
 pub const REQUETE_GET_CONFIGURATION_USAGER: &str = "getConfigurationUsager";
 pub const REQUETE_GET_TIMEZONE_APPAREIL: &str = "getTimezoneAppareil";
+pub const REQUETE_EXPORT_STATISTIQUES: &str = "exportStatistiques";
+pub const REQUETE_COMPARAISON_SENSEURS: &str = "getComparaisonSenseurs";
+pub const REQUETE_GET_LECTURES_SENSEUR: &str = "getLecturesSenseur";
+pub const REQUETE_GET_COMPLETUDE_SENSEUR: &str = "getCompletudeSenseur";
+pub const REQUETE_GET_RAPPORTS_COMPLETUDE: &str = "getRapportsCompletude";
+pub const REQUETE_GET_ANOMALIES_SENSEURS: &str = "getAnomaliesSenseurs";
+pub const REQUETE_COMPARAISON_PERIODES: &str = "getComparaisonPeriodes";
+pub const REQUETE_GET_DEGRES_JOURS: &str = "getDegresJours";
+pub const REQUETE_HISTOGRAMME_SENSEUR: &str = "getHistogrammeSenseur";
+pub const REQUETE_MATRICE_HORAIRE_SENSEUR: &str = "getMatriceHoraireSenseur";
+pub const REQUETE_GET_RETENTION: &str = "getRetention";
+pub const REQUETE_GET_RELAIS_APPAREILS: &str = "getRelaisAppareils";
+pub const REQUETE_GET_SANTE_RELAIS: &str = "getSanteRelais";
+pub const REQUETE_GET_COMMANDES_APPAREIL: &str = "getCommandesAppareil";
+pub const REQUETE_GET_SCENES: &str = "getScenes";
+pub const REQUETE_GET_CLASSES_PROGRAMMES: &str = "getClassesProgrammes";
+pub const REQUETE_GET_VERSIONS_PROGRAMME: &str = "getVersionsProgramme";
+pub const REQUETE_DIFF_VERSIONS_PROGRAMME: &str = "getDiffVersionsProgramme";
+pub const REQUETE_SIMULER_PROGRAMME: &str = "simulerProgramme";
+pub const REQUETE_GET_GABARITS: &str = "getGabarits";
+pub const REQUETE_GET_DERIVE_GABARIT: &str = "getDeriveGabarit";
+pub const REQUETE_GET_HORAIRES: &str = "getHoraires";
+pub const REQUETE_PREVISUALISER_HORAIRE: &str = "getProchainsDeclenchementsHoraire";
 
 pub const EVENEMENT_LECTURE: &str = "lecture";
 pub const EVENEMENT_LECTURE_CONFIRMEE: &str = "lectureConfirmee";
@@ -28,13 +51,28 @@ pub const EVENEMENT_MAJ_CONFIGURATION_APPAREIL: &str = "majConfigurationAppareil
 pub const EVENEMENT_MAJ_DISPLAYS: &str = "evenementMajDisplays";
 pub const EVENEMENT_MAJ_PROGRAMMES: &str = "evenementMajProgrammes";
 pub const EVENEMENT_PRESENCE_APPAREIL: &str = "presenceAppareil";
+pub const EVENEMENT_RAPPORT_COMPLETUDE: &str = "rapportCompletude";
+pub const EVENEMENT_ANOMALIE_SENSEUR: &str = "anomalieSenseur";
+pub const EVENEMENT_REGENERATION_SENSEURS_HORAIRE: &str = "regenerationSenseursHoraire";
+pub const EVENEMENT_HEARTBEAT_RELAI: &str = "heartbeatRelai";
+pub const EVENEMENT_RELAI_PERDU: &str = "relaiPerdu";
+pub const EVENEMENT_ETAT_COMMANDE_APPAREIL: &str = "etatCommandeAppareil";
 
 pub const COMMANDE_INSCRIRE_APPAREIL: &str = "inscrireAppareil";
 pub const COMMANDE_CHALLENGE_APPAREIL: &str = "challengeAppareil";
 pub const COMMANDE_SIGNER_APPAREIL: &str = "signerAppareil";
 pub const COMMANDE_CONFIRMER_RELAI: &str = "confirmerRelai";
+pub const COMMANDE_REVOQUER_RELAI: &str = "revoquerRelai";
 pub const COMMANDE_RESET_CERTIFICATS: &str = "resetCertificatsAppareils";
 pub const COMMAND_DISCONNECT_RELAY: &str = "disconnectRelay";
+pub const COMMANDE_COMPACTER_SENSEURS_HORAIRE: &str = "compacterSenseursHoraire";
+pub const COMMANDE_VERIFIER_COHERENCE: &str = "verifierCoherence";
+pub const COMMANDE_MIGRER_TRANSACTIONS_LEGACY: &str = "migrerTransactionsLegacy";
+pub const COMMANDE_ACTIONNER_APPAREIL: &str = "actionnerAppareil";
+/// Commande transmise au relai (partition instance_id) pour un actionneur d'appareil.
+pub const COMMANDE_APPAREIL_RELAI: &str = "commandeAppareil";
+pub const COMMANDE_EXECUTER_SCENE: &str = "executerScene";
+pub const COMMANDE_APPLIQUER_GABARIT: &str = "appliquerGabarit";
 
 pub const TRANSACTION_LECTURE: &str = "lecture";
 pub const TRANSACTION_MAJ_SENSEUR: &str = "majSenseur";
@@ -45,15 +83,26 @@ pub const TRANSACTION_MAJ_APPAREIL: &str = "majAppareil";
 pub const TRANSACTION_SHOW_HIDE_SENSOR: &str = "showHideSensor";
 pub const TRANSACTION_SAUVEGARDER_PROGRAMME: &str = "sauvegarderProgramme";
 pub const TRANSACTION_SENSEUR_HORAIRE: &str = "senseurHoraire";
+pub const TRANSACTION_SENSEUR_HORAIRE_MENSUEL: &str = "senseurHoraireMensuel";
 pub const TRANSACTION_APPAREIL_SUPPRIMER: &str = "supprimerAppareil";
 pub const TRANSACTION_APPAREIL_RESTAURER: &str = "restaurerAppareil";
 pub const TRANSACTION_MAJ_CONFIGURATION_USAGER: &str = "majConfigurationUsager";
+pub const TRANSACTION_MAJ_RETENTION: &str = "majRetention";
+pub const TRANSACTION_ELAGUER_SENSEURS_HORAIRE: &str = "elaguerSenseursHoraire";
+pub const TRANSACTION_SUPPRIMER_SENSEURS_HORAIRE: &str = "supprimerSenseursHoraire";
+pub const TRANSACTION_MIGRATION_SENSEUR_LEGACY: &str = "migrationSenseurLegacy";
+pub const TRANSACTION_SAUVEGARDER_SCENE: &str = "sauvegarderScene";
+pub const TRANSACTION_SAUVEGARDER_HORAIRE: &str = "sauvegarderHoraire";
+pub const TRANSACTION_RESTAURER_VERSION_PROGRAMME: &str = "restaurerVersionProgramme";
+pub const TRANSACTION_SAUVEGARDER_GABARIT: &str = "sauvegarderGabarit";
 
 //const CHAMP_INSTANCE_ID: &str = "instance_id";
 pub const CHAMP_INSTANCE_ID: &str = "instance_id";
 pub const CHAMP_UUID_SENSEUR: &str = "uuid_senseur";
 pub const CHAMP_UUID_APPAREIL: &str = "uuid_appareil";
 pub const CHAMP_SENSEURS: &str = "senseurs";
+/// Marque d'une transaction legacy remplacee par migrerTransactionsLegacy (uuid_appareil, senseur_id, date)
+pub const CHAMP_MIGRATION_LEGACY: &str = "migration_legacy";
 pub const CHAMP_USER_ID: &str = "user_id";
 pub const CHAMP_DERNIERE_LECTURE: &str = "derniere_lecture_dt";
 pub const CHAMP_PRESENT: &str = "present";
@@ -76,6 +125,22 @@ pub const COLLECTIONS_SENSEURS_HORAIRE: &str = "SenseursPassifs/senseurs_horaire
 pub const COLLECTIONS_NOTIFICATIONS_USAGERS: &str = "SenseursPassifs/notifications_usagers";
 pub const COLLECTIONS_RELAIS: &str = "SenseursPassifs/relais";
 pub const COLLECTIONS_USAGER: &str = "SenseursPassifs/usager";
+pub const COLLECTIONS_RAPPORTS_COMPLETUDE: &str = "SenseursPassifs/rapports_completude";
+pub const COLLECTIONS_BASELINES_SENSEURS: &str = "SenseursPassifs/baselines_senseurs";
+pub const COLLECTIONS_ANOMALIES_SENSEURS: &str = "SenseursPassifs/anomalies_senseurs";
+pub const COLLECTIONS_RETENTION: &str = "SenseursPassifs/retention";
+pub const COLLECTIONS_SENSEURS_QUOTIDIEN: &str = "SenseursPassifs/senseurs_quotidien";
+pub const COLLECTIONS_REGENERATION: &str = "SenseursPassifs/regeneration";
+pub const COLLECTIONS_MIGRATION_SENSEURS: &str = "SenseursPassifs/migration_senseurs";
+pub const COLLECTIONS_SANTE_RELAIS: &str = "SenseursPassifs/sante_relais";
+pub const COLLECTIONS_COMMANDES_APPAREILS: &str = "SenseursPassifs/commandes_appareils";
+pub const COLLECTIONS_BOITE_ENVOI: &str = "SenseursPassifs/boite_envoi";
+pub const COLLECTIONS_SCENES: &str = "SenseursPassifs/scenes";
+pub const COLLECTIONS_HORAIRES: &str = "SenseursPassifs/horaires";
+pub const COLLECTIONS_PROGRAMMES_VERSIONS: &str = "SenseursPassifs/programmes_versions";
+pub const COLLECTIONS_GABARITS: &str = "SenseursPassifs/gabarits";
+/// Transactions remplacees (e.g. senseurHoraire compactees). Conservees telles quelles, jamais regenerees.
+pub const COLLECTIONS_TRANSACTIONS_ARCHIVES: &str = "SenseursPassifs/transactions_archives";
 
 pub const INDEX_LECTURES_NOEUD: &str = "lectures_noeud";
 pub const INDEX_LECTURES_SENSEURS: &str = "lectures_senseur";
@@ -85,8 +150,24 @@ pub const INDEX_USER_APPAREILS: &str = "user_appareils";
 pub const INDEX_APPAREILS_DERNIERE_LECTURE: &str = "appareils_derniere_lecture";
 pub const INDEX_USER_NOTIFICATIONS: &str = "user_notifications_usager";
 pub const INDEX_USER_APPAREIL_RELAIS: &str = "user_appareil_relais";
+pub const INDEX_USER_APPAREIL_RAPPORTS_COMPLETUDE: &str = "user_appareil_rapports_completude";
+pub const INDEX_BASELINES_SENSEURS: &str = "baselines_senseurs";
+pub const INDEX_ANOMALIES_SENSEURS: &str = "anomalies_senseurs";
+pub const INDEX_RETENTION: &str = "user_appareil_retention";
+pub const INDEX_SENSEURS_QUOTIDIEN: &str = "senseurs_quotidien";
+pub const INDEX_MIGRATION_SENSEURS: &str = "user_senseur_migration";
+pub const INDEX_SANTE_RELAIS: &str = "instance_sante_relais";
+pub const INDEX_COMMANDES_APPAREILS_ID: &str = "commandes_appareils_id";
+pub const INDEX_COMMANDES_APPAREILS_USER: &str = "commandes_appareils_user";
+pub const INDEX_BOITE_ENVOI: &str = "user_appareil_boite_envoi";
+pub const INDEX_SCENES: &str = "user_scenes";
+pub const INDEX_HORAIRES: &str = "user_horaires";
+pub const INDEX_PROGRAMMES_VERSIONS: &str = "programmes_versions";
+pub const INDEX_GABARITS: &str = "user_gabarits";
 
 pub const CONST_APAREIL_LECTURE_TIMEOUT_SECS: i64 = 900;
+/// Delai sans heartbeat apres lequel un relai est considere perdu.
+pub const CONST_RELAI_HEARTBEAT_TIMEOUT_SECS: i64 = 180;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TransactionMajNoeud {
@@ -235,9 +316,9 @@ pub struct LectureSenseur {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GeopositionAppareil {
-    latitude: Option<f32>,
-    longitude: Option<f32>,
-    accuracy: Option<f32>,
+    pub latitude: Option<f32>,
+    pub longitude: Option<f32>,
+    pub accuracy: Option<f32>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -278,10 +359,10 @@ pub struct ParametresDisplayLigne {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ProgrammeAppareil {
     pub programme_id: String,
-    class: String,
-    descriptif: Option<String>,
-    actif: Option<bool>,
-    args: HashMap<String, Value>
+    pub class: String,
+    pub descriptif: Option<String>,
+    pub actif: Option<bool>,
+    pub args: HashMap<String, Value>
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
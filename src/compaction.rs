@@ -0,0 +1,427 @@
+use std::collections::HashMap;
+use log::{debug, info, warn};
+
+use millegrilles_common_rust::bson::{doc, Document};
+use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
+use millegrilles_common_rust::chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use millegrilles_common_rust::constantes::*;
+use millegrilles_common_rust::db_structs::TransactionValide;
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::GenerateurMessages;
+use millegrilles_common_rust::middleware::sauvegarder_traiter_transaction_serializable_v2;
+use millegrilles_common_rust::millegrilles_cryptographie::deser_message_buffer;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::{epochseconds, MessageMilleGrillesBufferDefault};
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::mongodb::ClientSession;
+use millegrilles_common_rust::mongodb::options::FindOptions;
+use millegrilles_common_rust::recepteur_messages::MessageValide;
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+use millegrilles_common_rust::serde_json;
+use millegrilles_common_rust::tokio_stream::StreamExt;
+
+use crate::common::*;
+use crate::constants::COLLECTION_NAME_TRANSACTIONS;
+use crate::domain_manager::SenseursPassifsDomainManager;
+use crate::regeneration::ajouter_rangees_regeneration;
+use crate::retention::{charger_jours_elagues, charger_sommaires_elagues, conserver_sommaires_quotidiens, JoursElagues, SommaireQuotidien};
+use crate::transactions::{inserer_senseur_horaire, maj_appareil_senseur_horaire, SenseurHoraireRow};
+
+/// Rangee horaire conservee dans une transaction compactee. Les lectures brutes ne sont pas conservees.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RangeeSenseurHoraire {
+    #[serde(with="epochseconds")]
+    pub heure: DateTime<Utc>,
+    #[serde(rename="type", skip_serializing_if = "Option::is_none")]
+    pub type_: Option<String>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub avg: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nombre_lectures: Option<u32>,
+}
+
+impl From<&TransactionLectureHoraire> for RangeeSenseurHoraire {
+    fn from(value: &TransactionLectureHoraire) -> Self {
+        Self {
+            heure: value.heure,
+            type_: value.lectures.first().map(|l| l.type_.clone()),
+            min: value.min,
+            max: value.max,
+            avg: value.avg,
+            nombre_lectures: Some(value.lectures.len() as u32),
+        }
+    }
+}
+
+/// Un mois de rangees horaires pour un senseur. Remplace les transactions senseurHoraire individuelles.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionSenseurHoraireMensuel {
+    pub user_id: String,
+    pub uuid_appareil: String,
+    pub senseur_id: String,
+    /// Mois UTC (YYYY-MM)
+    pub mois: String,
+    pub rangees: Vec<RangeeSenseurHoraire>,
+    /// Sommaires quotidiens des jours deja elagues lors de la compaction. Leurs rangees horaires ne sont
+    /// pas dans rangees, les transactions elaguerSenseursHoraire anterieures ne les retrouvent pas en regeneration.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sommaires_quotidiens: Option<Vec<SommaireQuotidien>>,
+}
+
+impl TransactionSenseurHoraireMensuel {
+    /// Prepare la transaction d'un mois. Les rangees en double (meme heure) sont retirees et les rangees
+    /// des jours elagues sont remplacees par leur sommaire quotidien.
+    fn new(user_id: String, uuid_appareil: String, senseur_id: String, mois: String,
+           mut rangees: Vec<RangeeSenseurHoraire>, sommaires: Vec<SommaireQuotidien>) -> Self
+    {
+        rangees.sort_by_key(|r| r.heure);
+        rangees.dedup_by_key(|r| r.heure);
+        let jours_elagues = JoursElagues::from(&sommaires);
+        rangees.retain(|r| !jours_elagues.contient(&r.heure));
+        let sommaires_quotidiens = match sommaires.is_empty() {
+            true => None,
+            false => Some(sommaires)
+        };
+        Self { user_id, uuid_appareil, senseur_id, mois, rangees, sommaires_quotidiens }
+    }
+
+    /// Rangees horaires a inserer, sans les heures des jours elagues.
+    fn rows(&self, jours_elagues: &JoursElagues) -> Vec<SenseurHoraireRow> {
+        self.rangees.iter()
+            .filter(|rangee| !jours_elagues.contient(&rangee.heure))
+            .map(|rangee| SenseurHoraireRow {
+                creation: Utc::now(),
+                user_id: self.user_id.clone(),
+                uuid_appareil: self.uuid_appareil.clone(),
+                senseur_id: self.senseur_id.clone(),
+                heure: rangee.heure,
+                type_: rangee.type_.clone(),
+                min: rangee.min,
+                max: rangee.max,
+                avg: rangee.avg,
+                nombre_lectures: rangee.nombre_lectures,
+            })
+            .collect()
+    }
+}
+
+pub async fn transaction_senseur_horaire_mensuel<M>(
+    middleware: &M, transaction: TransactionValide, gestionnaire: &SenseursPassifsDomainManager, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("transaction_senseur_horaire_mensuel Consommer transaction : {:?}", transaction.transaction.id);
+    let contenu: TransactionSenseurHoraireMensuel = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+
+    // Les sommaires sont conserves avant les rangees, ils excluent les heures des jours elagues
+    if let Some(sommaires) = contenu.sommaires_quotidiens.as_ref() {
+        conserver_sommaires_quotidiens(
+            middleware, &contenu.user_id, &contenu.uuid_appareil, &contenu.senseur_id, sommaires, session).await?;
+    }
+
+    let (debut, fin) = match (contenu.rangees.iter().map(|r| r.heure).min(), contenu.rangees.iter().map(|r| r.heure).max()) {
+        (Some(debut), Some(fin)) => (debut, fin + Duration::hours(1)),
+        _ => return Ok(Some(middleware.reponse_ok(None, None)?))  // Aucune rangee
+    };
+
+    // Ne pas reintroduire des heures deja remplacees par un sommaire quotidien (retention)
+    let jours_elagues = charger_jours_elagues(
+        middleware, &contenu.user_id, &contenu.uuid_appareil, &contenu.senseur_id, &debut, &fin, session).await?;
+
+    let rows = contenu.rows(&jours_elagues);
+
+    if middleware.get_mode_regeneration() {
+        // Insertion en lot. L'appareil est mis a jour par rebuild_sensor_list a la fin de la regeneration.
+        ajouter_rangees_regeneration(middleware, gestionnaire, rows, session).await?;
+        return Ok(Some(middleware.reponse_ok(None, None)?))
+    }
+
+    let mut inserees = 0;
+    for row in &rows {
+        if inserer_senseur_horaire(middleware, row, session).await? {
+            inserees += 1;
+        }
+    }
+    debug!("transaction_senseur_horaire_mensuel {} rangees inserees sur {}", inserees, contenu.rangees.len());
+
+    let type_donnees = contenu.rangees.iter().find_map(|r| r.type_.as_ref());
+    maj_appareil_senseur_horaire(
+        middleware, &contenu.user_id, &contenu.uuid_appareil, &contenu.senseur_id, type_donnees, session).await?;
+
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
+
+#[derive(Deserialize)]
+struct CommandeCompacterSenseursHoraire {
+    /// Mois UTC a compacter (YYYY-MM). Le plus vieux mois avec des transactions individuelles si absent.
+    mois: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ReponseCompacterSenseursHoraire {
+    ok: bool,
+    mois: Option<String>,
+    senseurs: usize,
+    transactions_compactees: usize,
+}
+
+#[derive(Deserialize)]
+struct RowTransactionHoraire {
+    id: String,
+    contenu: String,
+}
+
+fn debut_mois(date: NaiveDate) -> Option<NaiveDate> {
+    date.with_day(1)
+}
+
+fn mois_suivant(date: NaiveDate) -> Option<NaiveDate> {
+    match date.month() {
+        12 => NaiveDate::from_ymd_opt(date.year() + 1, 1, 1),
+        m => NaiveDate::from_ymd_opt(date.year(), m + 1, 1),
+    }
+}
+
+/// Debut et fin (UTC) d'un mois. None si le mois suivant n'est pas representable.
+fn bornes_mois(mois: NaiveDate) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let debut = debut_mois(mois)?.and_hms_opt(0, 0, 0)?.and_utc();
+    let fin = mois_suivant(mois)?.and_hms_opt(0, 0, 0)?.and_utc();
+    Some((debut, fin))
+}
+
+fn filtre_transactions_horaires() -> Document {
+    doc! {
+        "routage.domaine": DOMAINE_NOM,
+        "routage.action": TRANSACTION_SENSEUR_HORAIRE,
+    }
+}
+
+/// Trouve le mois de la plus vieille transaction senseurHoraire individuelle. Les transactions
+/// illisibles sont ignorees (elles ne sont pas compactees).
+async fn trouver_plus_vieux_mois<M>(middleware: &M) -> Result<Option<NaiveDate>, Error>
+    where M: MongoDao
+{
+    let options = FindOptions::builder()
+        .sort(doc!{"estampille": 1})
+        .projection(doc!{"id": 1, "contenu": 1})
+        .build();
+    let collection = middleware.get_collection(COLLECTION_NAME_TRANSACTIONS)?;
+    let mut curseur = collection.find(filtre_transactions_horaires(), options).await?;
+    while let Some(d) = curseur.next().await {
+        let row: RowTransactionHoraire = match convertir_bson_deserializable(d?) {
+            Ok(inner) => inner,
+            Err(e) => {
+                warn!("trouver_plus_vieux_mois Transaction senseurHoraire illisible, ignoree : {:?}", e);
+                continue
+            }
+        };
+        match serde_json::from_str::<TransactionLectureHoraire>(row.contenu.as_str()) {
+            Ok(contenu) => return Ok(debut_mois(contenu.heure.date_naive())),
+            Err(e) => warn!("trouver_plus_vieux_mois Transaction {} invalide, ignoree : {:?}", row.id, e)
+        }
+    }
+    Ok(None)
+}
+
+/// Convertit les transactions senseurHoraire d'un mois complete en une transaction senseurHoraireMensuel
+/// par senseur, puis deplace les transactions individuelles dans COLLECTIONS_TRANSACTIONS_ARCHIVES.
+/// Un mois est traite par commande.
+pub async fn commande_compacter_senseurs_horaire<M>(middleware: &M, m: MessageValide, gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    debug!("commande_compacter_senseurs_horaire Consommer commande : {:?}", & m.type_message);
+    if ! m.certificat.verifier_exchanges(vec![Securite::L3Protege, Securite::L4Secure])? &&
+        ! m.certificat.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE)? {
+        return Ok(Some(middleware.reponse_err(None, None, Some("Acces refuse"))?))
+    }
+    let commande: CommandeCompacterSenseursHoraire = deser_message_buffer!(m.message);
+
+    let mois = match commande.mois.as_ref() {
+        Some(mois) => match NaiveDate::parse_from_str(format!("{}-01", mois).as_str(), "%Y-%m-%d") {
+            Ok(inner) => inner,
+            Err(_) => return Ok(Some(middleware.reponse_err(None, None, Some("mois invalide (YYYY-MM)"))?))
+        },
+        None => match trouver_plus_vieux_mois(middleware).await? {
+            Some(inner) => inner,
+            None => {
+                let reponse = ReponseCompacterSenseursHoraire { ok: true, mois: None, senseurs: 0, transactions_compactees: 0 };
+                return Ok(Some(middleware.build_reponse(&reponse)?.0))
+            }
+        }
+    };
+
+    // Seuls les mois termines sont compactes (rangees en retard possibles pour le mois courant)
+    let mois_courant = match debut_mois(Utc::now().date_naive()) {
+        Some(inner) => inner,
+        None => Err(Error::Str("commande_compacter_senseurs_horaire Erreur calcul du mois courant"))?
+    };
+    if mois >= mois_courant {
+        return Ok(Some(middleware.reponse_err(None, None, Some("Le mois n'est pas termine"))?))
+    }
+
+    let (debut, fin) = match bornes_mois(mois) {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("mois invalide (YYYY-MM)"))?))
+    };
+    let nom_mois = mois.format("%Y-%m").to_string();
+    info!("commande_compacter_senseurs_horaire Compaction du mois {}", nom_mois);
+
+    // L'estampille suit l'heure de la lecture d'environ 65 minutes. Elargir la recherche et filtrer sur l'heure.
+    // L'estampille est conservee en date BSON, la comparaison avec un entier ne trouverait aucune transaction.
+    let mut filtre = filtre_transactions_horaires();
+    filtre.insert("estampille", doc!{
+        "$gte": debut - Duration::days(1),
+        "$lt": fin + Duration::days(2),
+    });
+    let options = FindOptions::builder().projection(doc!{"id": 1, "contenu": 1}).build();
+    let collection_transactions = middleware.get_collection(COLLECTION_NAME_TRANSACTIONS)?;
+    let mut curseur = collection_transactions.find(filtre, options).await?;
+
+    let mut groupes: HashMap<(String, String, String), (Vec<String>, Vec<RangeeSenseurHoraire>)> = HashMap::new();
+    while let Some(d) = curseur.next().await {
+        let row: RowTransactionHoraire = convertir_bson_deserializable(d?)?;
+        let contenu: TransactionLectureHoraire = match serde_json::from_str(row.contenu.as_str()) {
+            Ok(inner) => inner,
+            Err(e) => {
+                warn!("commande_compacter_senseurs_horaire Transaction {} invalide, ignoree : {:?}", row.id, e);
+                continue
+            }
+        };
+        if contenu.heure < debut || contenu.heure >= fin {
+            continue
+        }
+        let groupe = groupes.entry((contenu.user_id.clone(), contenu.uuid_appareil.clone(), contenu.senseur_id.clone()))
+            .or_insert_with(|| (Vec::new(), Vec::new()));
+        groupe.0.push(row.id);
+        groupe.1.push(RangeeSenseurHoraire::from(&contenu));
+    }
+
+    let mut senseurs = 0;
+    let mut transactions_compactees = 0;
+    for ((user_id, uuid_appareil, senseur_id), (ids, rangees)) in groupes {
+        let mut session = middleware.get_session().await?;
+        session.start_transaction(None).await?;
+        let resultat = compacter_senseur(
+            middleware, gestionnaire, &user_id, &uuid_appareil, &senseur_id, &nom_mois, rangees, &debut, &fin,
+            &ids, &mut session).await;
+        match resultat {
+            Ok(()) => {
+                session.commit_transaction().await?;
+                senseurs += 1;
+                transactions_compactees += ids.len();
+            },
+            Err(e) => {
+                session.abort_transaction().await?;
+                warn!("commande_compacter_senseurs_horaire Erreur compaction {}/{} : {:?}", uuid_appareil, senseur_id, e);
+            }
+        }
+    }
+
+    info!("commande_compacter_senseurs_horaire Mois {} : {} transactions compactees pour {} senseurs",
+        nom_mois, transactions_compactees, senseurs);
+
+    let reponse = ReponseCompacterSenseursHoraire { ok: true, mois: Some(nom_mois), senseurs, transactions_compactees };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+async fn compacter_senseur<M>(
+    middleware: &M, gestionnaire: &SenseursPassifsDomainManager, user_id: &String, uuid_appareil: &String,
+    senseur_id: &String, nom_mois: &String, rangees: Vec<RangeeSenseurHoraire>, debut: &DateTime<Utc>, fin: &DateTime<Utc>,
+    ids: &Vec<String>, session: &mut ClientSession
+)
+    -> Result<(), Error>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    // La transaction compactee a une estampille posterieure aux transactions elaguerSenseursHoraire du mois.
+    // Les sommaires des jours deja elagues sont conserves dans la transaction pour la regeneration.
+    let sommaires = charger_sommaires_elagues(middleware, user_id, uuid_appareil, senseur_id, debut, fin, session).await?;
+    let transaction = TransactionSenseurHoraireMensuel::new(
+        user_id.clone(), uuid_appareil.clone(), senseur_id.clone(), nom_mois.clone(), rangees, sommaires);
+
+    sauvegarder_traiter_transaction_serializable_v2(
+        middleware, &transaction, gestionnaire, session, DOMAINE_NOM, TRANSACTION_SENSEUR_HORAIRE_MENSUEL).await?;
+
+    // Les transactions individuelles sont archivees telles quelles (signature conservee). Elles ne sont
+    // plus dans la collection de transactions du domaine et ne sont pas rejouees par la regeneration.
+    let collection_transactions = middleware.get_collection(COLLECTION_NAME_TRANSACTIONS)?;
+    let filtre = doc! { "id": {"$in": ids.clone()} };
+    let mut archives = Vec::with_capacity(ids.len());
+    {
+        let mut curseur = collection_transactions.find_with_session(filtre.clone(), None, session).await?;
+        while let Some(d) = curseur.next(session).await {
+            archives.push(d?);
+        }
+    }
+    if !archives.is_empty() {
+        middleware.get_collection(COLLECTIONS_TRANSACTIONS_ARCHIVES)?
+            .insert_many_with_session(archives, None, session).await?;
+    }
+    collection_transactions.delete_many_with_session(filtre, None, session).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_setup::setup;
+
+    fn date(timestamp: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(timestamp, 0).expect("timestamp")
+    }
+
+    fn rangee(heure: DateTime<Utc>, avg: f64) -> RangeeSenseurHoraire {
+        RangeeSenseurHoraire { heure, type_: Some("temperature".to_string()), min: Some(avg), max: Some(avg), avg: Some(avg), nombre_lectures: Some(1) }
+    }
+
+    #[test]
+    fn test_bornes_mois() {
+        setup("test_bornes_mois");
+        let mois = NaiveDate::from_ymd_opt(2024, 12, 1).expect("date");
+        assert_eq!(Some((date(1733011200), date(1735689600))), bornes_mois(mois));
+        assert_eq!(None, bornes_mois(NaiveDate::MAX));
+    }
+
+    #[test]
+    fn test_compaction_apres_elagage_regeneration() {
+        setup("test_compaction_apres_elagage_regeneration");
+        // 2024-03-09 00:00 a 2024-03-12 00:00 EST/EDT, une rangee par heure (71 heures, 10 mars de 23 heures)
+        let debut = date(1709960400);
+        let rangees: Vec<RangeeSenseurHoraire> = (0..71).map(|i| rangee(debut + Duration::hours(i), i as f64)).collect();
+
+        // Le 10 mars a ete elague avant la compaction (sommaire quotidien, rangees horaires supprimees)
+        let sommaire = SommaireQuotidien {
+            jour: "2024-03-10".to_string(),
+            heure: date(1710046800),
+            min: Some(24.0),
+            max: Some(46.0),
+            somme: 805.0,
+            nombre_heures: 23,
+            type_: Some("temperature".to_string()),
+            timezone: Some("America/Toronto".to_string()),
+        };
+        let transaction = TransactionSenseurHoraireMensuel::new(
+            "usager".to_string(), "appareil".to_string(), "temp".to_string(), "2024-03".to_string(),
+            rangees.clone(), vec![sommaire]);
+        assert_eq!(48, transaction.rangees.len());
+        assert!(transaction.rangees.iter().all(|r| r.heure < date(1710046800) || r.heure >= date(1710129600)));
+
+        // Regeneration : la transaction est relue, le sommaire est conserve et exclut les heures du jour elague
+        let contenu = serde_json::to_string(&transaction).expect("serialiser");
+        let relue: TransactionSenseurHoraireMensuel = serde_json::from_str(contenu.as_str()).expect("deserialiser");
+        let sommaires = relue.sommaires_quotidiens.as_ref().expect("sommaires");
+        assert_eq!(1, sommaires.len());
+        assert_eq!(805.0, sommaires[0].somme);
+        let jours_elagues = JoursElagues::from(sommaires);
+        assert_eq!(48, relue.rows(&jours_elagues).len());
+
+        // Sans jour elague, toutes les rangees (dedoublonnees) sont conservees
+        let mut doublons = rangees.clone();
+        doublons.push(rangee(debut, 0.0));
+        let transaction = TransactionSenseurHoraireMensuel::new(
+            "usager".to_string(), "appareil".to_string(), "temp".to_string(), "2024-03".to_string(), doublons, vec![]);
+        assert_eq!(71, transaction.rangees.len());
+        assert!(transaction.sommaires_quotidiens.is_none());
+    }
+}
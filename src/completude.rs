@@ -0,0 +1,385 @@
+use std::collections::{BTreeMap, HashMap};
+use log::{debug, info, warn};
+use chrono_tz::Tz;
+
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::bson::serde_helpers::chrono_datetime_as_bson_datetime;
+use millegrilles_common_rust::certificats::VerificateurPermissions;
+use millegrilles_common_rust::chrono::{DateTime, Duration, Utc};
+use millegrilles_common_rust::constantes::*;
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
+use millegrilles_common_rust::millegrilles_cryptographie::deser_message_buffer;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::{epochseconds, MessageMilleGrillesBufferDefault};
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, convertir_to_bson, MongoDao};
+use millegrilles_common_rust::mongodb::options::{FindOptions, UpdateOptions};
+use millegrilles_common_rust::recepteur_messages::MessageValide;
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+use millegrilles_common_rust::tokio_stream::StreamExt;
+
+use crate::common::*;
+use crate::domain_manager::SenseursPassifsDomainManager;
+use crate::lectures::heure_juste;
+use crate::requetes::parser_timezone;
+use crate::statistiques::formatter_intervalle;
+
+/// Un senseur est considere muet lorsque sa derniere lecture est plus vieille que ce delai
+/// alors que l'appareil est toujours connecte.
+const CONST_SENSEUR_MUET_SECS: i64 = 3_600;
+
+/// Periode couverte par le rapport periodique de completude.
+const CONST_RAPPORT_COMPLETUDE_HEURES: i64 = 24;
+
+#[derive(Deserialize)]
+struct RowCompletude {
+    #[serde(with="chrono_datetime_as_bson_datetime")]
+    heure: DateTime<Utc>,
+    nombre_lectures: Option<u32>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CompletudeHeure {
+    #[serde(with="epochseconds")]
+    pub heure: DateTime<Utc>,
+    /// Nombre de lectures recues, absent pour les anciennes rangees.
+    pub nombre_lectures: Option<u32>,
+    /// Ratio de lectures recues sur lectures attendues (max 1.0).
+    pub ratio: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CompletudeJour {
+    pub jour: String,
+    pub heures_attendues: u32,
+    pub heures_presentes: u32,
+    /// Moyenne des ratios horaires de la journee, 0.0 a 1.0.
+    pub score: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AnalyseCompletude {
+    /// Nombre de lectures attendues par heure. Fourni par la requete ou deduit du maximum observe.
+    pub lectures_par_heure: Option<f64>,
+    pub heures: Vec<CompletudeHeure>,
+    pub heures_manquantes: Vec<i64>,
+    pub jours: Vec<CompletudeJour>,
+    pub score: f64,
+}
+
+fn ratio_heure(nombre_lectures: Option<u32>, lectures_par_heure: Option<f64>) -> f64 {
+    match (nombre_lectures, lectures_par_heure) {
+        (Some(nombre), Some(attendu)) if attendu > 0.0 => f64::min(nombre as f64 / attendu, 1.0),
+        _ => 1.0  // Compte inconnu, la presence de la rangee suffit
+    }
+}
+
+/// Analyse la completude des rangees horaires d'un senseur entre min_date et max_date.
+pub(crate) async fn analyser_completude<M>(
+    middleware: &M, user_id: &str, uuid_appareil: &str, senseur_id: &str,
+    min_date: &DateTime<Utc>, max_date: &DateTime<Utc>, tz: &Tz, lectures_par_heure: Option<f64>
+)
+    -> Result<AnalyseCompletude, Error>
+    where M: MongoDao
+{
+    let min_date = heure_juste(min_date);
+    let filtre = doc! {
+        CHAMP_USER_ID: user_id,
+        CHAMP_UUID_APPAREIL: uuid_appareil,
+        "senseur_id": senseur_id,
+        "heure": {"$gte": &min_date, "$lt": max_date},
+    };
+    let options = FindOptions::builder()
+        .projection(doc!{"heure": 1, "nombre_lectures": 1})
+        .sort(doc!{"heure": 1})
+        .build();
+    let collection = middleware.get_collection(COLLECTIONS_SENSEURS_HORAIRE)?;
+    let mut curseur = collection.find(filtre, options).await?;
+
+    let mut rows: HashMap<DateTime<Utc>, Option<u32>> = HashMap::new();
+    while let Some(d) = curseur.next().await {
+        let row: RowCompletude = convertir_bson_deserializable(d?)?;
+        rows.insert(row.heure, row.nombre_lectures);
+    }
+
+    let lectures_par_heure = match lectures_par_heure {
+        Some(inner) => Some(inner),
+        None => rows.values().filter_map(|n| *n).max().map(|n| n as f64)
+    };
+
+    let mut heures = Vec::new();
+    let mut heures_manquantes = Vec::new();
+    let mut jours: BTreeMap<String, (u32, u32, f64)> = BTreeMap::new();
+    let mut courant = min_date;
+    while &courant < max_date {
+        let jour = jours.entry(formatter_intervalle(&courant, "jours", tz)).or_insert((0, 0, 0.0));
+        jour.0 += 1;
+        match rows.get(&courant) {
+            Some(nombre_lectures) => {
+                let ratio = ratio_heure(*nombre_lectures, lectures_par_heure);
+                jour.1 += 1;
+                jour.2 += ratio;
+                heures.push(CompletudeHeure { heure: courant, nombre_lectures: *nombre_lectures, ratio });
+            },
+            None => heures_manquantes.push(courant.timestamp())
+        }
+        courant += Duration::hours(1);
+    }
+
+    let mut total_attendues = 0;
+    let mut total_ratios = 0.0;
+    let jours = jours.into_iter().map(|(jour, (heures_attendues, heures_presentes, somme_ratios))| {
+        total_attendues += heures_attendues;
+        total_ratios += somme_ratios;
+        CompletudeJour { jour, heures_attendues, heures_presentes, score: somme_ratios / heures_attendues as f64 }
+    }).collect();
+
+    let score = if total_attendues > 0 { total_ratios / total_attendues as f64 } else { 0.0 };
+
+    Ok(AnalyseCompletude { lectures_par_heure, heures, heures_manquantes, jours, score })
+}
+
+#[derive(Deserialize)]
+struct RequeteCompletudeSenseur {
+    uuid_appareil: String,
+    senseur_id: String,
+    timezone: Option<String>,
+    intervalle_min: usize,
+    intervalle_max: Option<usize>,
+    /// Nombre de lectures attendues par heure. Deduit du maximum observe lorsqu'absent.
+    lectures_par_heure: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct ReponseCompletudeSenseur {
+    ok: bool,
+    #[serde(flatten)]
+    analyse: AnalyseCompletude,
+}
+
+pub async fn requete_completude_senseur<M>(middleware: &M, m: MessageValide, _gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_completude_senseur Consommer requete : {:?}", & m.message);
+    let requete: RequeteCompletudeSenseur = deser_message_buffer!(m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    };
+
+    let tz = parser_timezone(requete.timezone.as_ref());
+    let min_date = match DateTime::from_timestamp(requete.intervalle_min as i64, 0) {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("intervalle_min invalide"))?))
+    };
+    let max_date = match requete.intervalle_max {
+        Some(inner) => match DateTime::from_timestamp(inner as i64, 0) {
+            Some(inner) => inner,
+            None => return Ok(Some(middleware.reponse_err(None, None, Some("intervalle_max invalide"))?))
+        },
+        None => heure_juste(&Utc::now())  // Exclure l'heure courante, pas encore aggregee
+    };
+
+    if (max_date - min_date).num_days() > 366 {
+        return Ok(Some(middleware.reponse_err(None, None, Some("Intervalle trop grand (max 366 jours)"))?))
+    }
+
+    let analyse = analyser_completude(
+        middleware, user_id.as_str(), &requete.uuid_appareil, &requete.senseur_id,
+        &min_date, &max_date, &tz, requete.lectures_par_heure).await?;
+
+    let reponse = ReponseCompletudeSenseur { ok: true, analyse };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+#[derive(Deserialize)]
+struct RapportAggregateRow {
+    _id: String,
+    /// nombre_lectures de chaque rangee horaire, null si inconnu
+    lectures: Vec<Option<u32>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RapportCompletudeSenseur {
+    pub senseur_id: String,
+    pub heures_presentes: u32,
+    pub nombre_lectures: u32,
+    /// Nombre de lectures attendues par heure, deduit du maximum observe sur la periode.
+    #[serde(default)]
+    pub lectures_par_heure: Option<f64>,
+    /// Moyenne des ratios horaires (voir ratio_heure) sur les heures de la periode, 0.0 a 1.0.
+    pub score: f64,
+    /// true si l'appareil est connecte mais que le senseur ne produit plus de lectures.
+    pub muet: bool,
+    #[serde(with="epochseconds")]
+    pub derniere_lecture: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RapportCompletudeAppareil {
+    pub user_id: String,
+    pub uuid_appareil: String,
+    #[serde(with="epochseconds")]
+    pub debut: DateTime<Utc>,
+    #[serde(with="epochseconds")]
+    pub fin: DateTime<Utc>,
+    pub connecte: bool,
+    pub senseurs: Vec<RapportCompletudeSenseur>,
+    pub senseurs_muets: Vec<String>,
+}
+
+/// Produit le rapport de completude des dernieres 24 heures pour chaque appareil.
+/// Le rapport est conserve dans COLLECTIONS_RAPPORTS_COMPLETUDE et emis a l'usager.
+pub async fn generer_rapports_completude<M>(middleware: &M) -> Result<(), Error>
+    where M: GenerateurMessages + MongoDao
+{
+    let fin = heure_juste(&Utc::now());
+    let debut = fin - Duration::hours(CONST_RAPPORT_COMPLETUDE_HEURES);
+    let limite_muet = Utc::now() - Duration::seconds(CONST_SENSEUR_MUET_SECS);
+
+    let filtre = doc! { CHAMP_USER_ID: {"$exists": true}, CHAMP_SUPPRIME: {"$ne": true} };
+    let collection_appareils = middleware.get_collection_typed::<DocAppareil>(COLLECTIONS_APPAREILS)?;
+    let mut curseur = collection_appareils.find(filtre, None).await?;
+    while let Some(appareil) = curseur.next().await {
+        let appareil = match appareil {
+            Ok(inner) => inner,
+            Err(e) => {
+                warn!("generer_rapports_completude Erreur mapping DocAppareil : {:?}", e);
+                continue
+            }
+        };
+        if let Err(e) = generer_rapport_appareil(middleware, appareil, &debut, &fin, &limite_muet).await {
+            warn!("generer_rapports_completude Erreur rapport appareil : {:?}", e);
+        }
+    }
+
+    Ok(())
+}
+
+async fn generer_rapport_appareil<M>(
+    middleware: &M, appareil: DocAppareil, debut: &DateTime<Utc>, fin: &DateTime<Utc>, limite_muet: &DateTime<Utc>)
+    -> Result<(), Error>
+    where M: GenerateurMessages + MongoDao
+{
+    let user_id = match appareil.user_id {
+        Some(inner) => inner,
+        None => return Ok(())
+    };
+    let connecte = appareil.connecte == Some(true);
+
+    let pipeline = vec![
+        doc! { "$match": {
+            CHAMP_USER_ID: &user_id,
+            CHAMP_UUID_APPAREIL: &appareil.uuid_appareil,
+            "heure": {"$gte": debut, "$lt": fin},
+        } },
+        doc! { "$group": {
+            "_id": "$senseur_id",
+            "lectures": {"$push": {"$ifNull": ["$nombre_lectures", null]}},
+        } },
+    ];
+    let collection = middleware.get_collection(COLLECTIONS_SENSEURS_HORAIRE)?;
+    let mut resultat = collection.aggregate(pipeline, None).await?;
+    let mut compteurs = HashMap::new();
+    while let Some(d) = resultat.next().await {
+        let row: RapportAggregateRow = convertir_bson_deserializable(d?)?;
+        compteurs.insert(row._id, row.lectures);
+    }
+
+    let heures_periode = (*fin - *debut).num_hours() as f64;
+    let mut senseurs = Vec::new();
+    let mut senseurs_muets = Vec::new();
+    if let Some(lectures) = appareil.senseurs {
+        for (senseur_id, lecture) in lectures {
+            let lectures_heures = compteurs.remove(&senseur_id).unwrap_or_default();
+            // Meme taux attendu et meme ratio par heure que analyser_completude
+            let lectures_par_heure = lectures_heures.iter().filter_map(|n| *n).max().map(|n| n as f64);
+            let somme_ratios: f64 = lectures_heures.iter().map(|n| ratio_heure(*n, lectures_par_heure)).sum();
+            let heures_presentes = lectures_heures.len() as u32;
+            let nombre_lectures = lectures_heures.iter().filter_map(|n| *n).sum();
+            let muet = connecte && &lecture.timestamp < limite_muet;
+            if muet {
+                senseurs_muets.push(senseur_id.clone());
+            }
+            senseurs.push(RapportCompletudeSenseur {
+                senseur_id,
+                heures_presentes,
+                nombre_lectures,
+                lectures_par_heure,
+                score: f64::min(somme_ratios / heures_periode, 1.0),
+                muet,
+                derniere_lecture: lecture.timestamp,
+            });
+        }
+    }
+
+    if senseurs.is_empty() {
+        return Ok(())
+    }
+
+    let rapport = RapportCompletudeAppareil {
+        user_id, uuid_appareil: appareil.uuid_appareil, debut: debut.to_owned(), fin: fin.to_owned(),
+        connecte, senseurs, senseurs_muets,
+    };
+
+    if !rapport.senseurs_muets.is_empty() {
+        info!("generer_rapport_appareil Appareil {} connecte avec senseurs muets : {:?}",
+            rapport.uuid_appareil, rapport.senseurs_muets);
+    }
+
+    let filtre = doc! { CHAMP_USER_ID: &rapport.user_id, CHAMP_UUID_APPAREIL: &rapport.uuid_appareil };
+    let ops = doc! {
+        "$set": convertir_to_bson(&rapport)?,
+        "$setOnInsert": { CHAMP_CREATION: Utc::now() },
+        "$currentDate": { CHAMP_MODIFICATION: true },
+    };
+    let options = UpdateOptions::builder().upsert(true).build();
+    let collection_rapports = middleware.get_collection(COLLECTIONS_RAPPORTS_COMPLETUDE)?;
+    collection_rapports.update_one(filtre, ops, options).await?;
+
+    let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_RAPPORT_COMPLETUDE, vec![Securite::L2Prive])
+        .partition(&rapport.user_id)
+        .build();
+    middleware.emettre_evenement(routage, &rapport).await?;
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct RequeteRapportsCompletude {
+    uuid_appareil: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ReponseRapportsCompletude {
+    ok: bool,
+    rapports: Vec<RapportCompletudeAppareil>,
+}
+
+pub async fn requete_rapports_completude<M>(middleware: &M, m: MessageValide, _gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_rapports_completude Consommer requete : {:?}", & m.message);
+    let requete: RequeteRapportsCompletude = deser_message_buffer!(m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    };
+
+    let mut filtre = doc! { CHAMP_USER_ID: &user_id };
+    if let Some(uuid_appareil) = requete.uuid_appareil.as_ref() {
+        filtre.insert(CHAMP_UUID_APPAREIL, uuid_appareil);
+    }
+    let collection = middleware.get_collection_typed::<RapportCompletudeAppareil>(COLLECTIONS_RAPPORTS_COMPLETUDE)?;
+    let mut curseur = collection.find(filtre, None).await?;
+    let mut rapports = Vec::new();
+    while let Some(rapport) = curseur.next().await {
+        rapports.push(rapport?);
+    }
+
+    let reponse = ReponseRapportsCompletude { ok: true, rapports };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
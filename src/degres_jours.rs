@@ -0,0 +1,161 @@
+use std::collections::BTreeMap;
+use log::debug;
+
+use millegrilles_common_rust::certificats::VerificateurPermissions;
+use millegrilles_common_rust::chrono::{DateTime, Duration, Utc};
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::GenerateurMessages;
+use millegrilles_common_rust::millegrilles_cryptographie::deser_message_buffer;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::MessageMilleGrillesBufferDefault;
+use millegrilles_common_rust::mongo_dao::MongoDao;
+use millegrilles_common_rust::recepteur_messages::MessageValide;
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+
+use crate::domain_manager::SenseursPassifsDomainManager;
+use crate::lectures::heure_juste;
+use crate::requetes::{charger_configuration_usager, parser_timezone, query_aggregate};
+use crate::statistiques::{degres_jour, moyennes_journalieres, SenseurRef, CONST_DEGRES_JOURS_BASE_DEFAUT};
+
+/// Premier mois de la saison de chauffage (juillet a juin).
+const CONST_SAISON_MOIS_DEBUT: u32 = 7;
+
+#[derive(Deserialize)]
+struct RequeteGetDegresJours {
+    /// Senseur de temperature exterieure. Utilise senseur_exterieur de la configuration usager si absent.
+    senseur: Option<SenseurRef>,
+    /// Temperature de base (°C), 18°C par defaut.
+    base: Option<f64>,
+    intervalle_min: usize,
+    intervalle_max: Option<usize>,
+}
+
+#[derive(Default, Serialize)]
+struct TotalDegresJours {
+    periode: String,
+    chauffage: f64,
+    climatisation: f64,
+    jours: u32,
+}
+
+impl TotalDegresJours {
+    fn ajouter(&mut self, chauffage: f64, climatisation: f64) {
+        self.chauffage += chauffage;
+        self.climatisation += climatisation;
+        self.jours += 1;
+    }
+}
+
+#[derive(Serialize)]
+struct DegresJoursQuotidien {
+    jour: String,
+    moyenne: f64,
+    chauffage: f64,
+    climatisation: f64,
+}
+
+#[derive(Serialize)]
+struct ReponseGetDegresJours {
+    ok: bool,
+    senseur: SenseurRef,
+    base: f64,
+    timezone: String,
+    quotidien: Vec<DegresJoursQuotidien>,
+    mensuel: Vec<TotalDegresJours>,
+    saisonnier: Vec<TotalDegresJours>,
+}
+
+/// Saison de chauffage d'un jour local YYYY-MM-DD (e.g. 2024-03-15 => 2023-2024).
+fn saison_jour(jour: &str) -> String {
+    let annee: i32 = jour.get(0..4).and_then(|a| a.parse().ok()).unwrap_or(0);
+    let mois: u32 = jour.get(5..7).and_then(|m| m.parse().ok()).unwrap_or(1);
+    if mois >= CONST_SAISON_MOIS_DEBUT {
+        format!("{}-{}", annee, annee + 1)
+    } else {
+        format!("{}-{}", annee - 1, annee)
+    }
+}
+
+pub async fn requete_get_degres_jours<M>(middleware: &M, m: MessageValide, _gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_get_degres_jours Consommer requete : {:?}", & m.message);
+    let requete: RequeteGetDegresJours = deser_message_buffer!(m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    };
+
+    let configuration = charger_configuration_usager(middleware, user_id.as_str()).await?;
+    let senseur = match requete.senseur.or(configuration.senseur_exterieur) {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("Aucun senseur exterieur configure"))?))
+    };
+    let tz = parser_timezone(configuration.timezone.as_ref());
+    let base = requete.base.unwrap_or(CONST_DEGRES_JOURS_BASE_DEFAUT);
+
+    let min_date = match DateTime::from_timestamp(requete.intervalle_min as i64, 0) {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("intervalle_min invalide"))?))
+    };
+    let max_date = match requete.intervalle_max {
+        Some(inner) => match DateTime::from_timestamp(inner as i64, 0) {
+            Some(inner) => inner,
+            None => return Ok(Some(middleware.reponse_err(None, None, Some("intervalle_max invalide"))?))
+        },
+        None => heure_juste(&Utc::now())
+    };
+    if max_date <= min_date || max_date - min_date > Duration::days(3 * 366) {
+        return Ok(Some(middleware.reponse_err(None, None, Some("Intervalle invalide (max 3 ans)"))?))
+    }
+
+    // Moyenne journaliere calculee a partir des moyennes horaires, jour local de l'usager.
+    let rows = query_aggregate(middleware, user_id.as_str(), &senseur.uuid_appareil, &senseur.senseur_id,
+                               "heures", &tz, min_date, Some(max_date)).await?;
+
+    let mut quotidien = Vec::new();
+    let mut mensuel: BTreeMap<String, TotalDegresJours> = BTreeMap::new();
+    let mut saisonnier: BTreeMap<String, TotalDegresJours> = BTreeMap::new();
+    for (jour, moyenne) in moyennes_journalieres(&rows, &tz) {
+        let (chauffage, climatisation) = degres_jour(moyenne, base);
+
+        let mois = jour.get(0..7).unwrap_or(jour.as_str()).to_string();
+        mensuel.entry(mois.clone())
+            .or_insert_with(|| TotalDegresJours { periode: mois, ..Default::default() })
+            .ajouter(chauffage, climatisation);
+
+        let saison = saison_jour(jour.as_str());
+        saisonnier.entry(saison.clone())
+            .or_insert_with(|| TotalDegresJours { periode: saison, ..Default::default() })
+            .ajouter(chauffage, climatisation);
+
+        quotidien.push(DegresJoursQuotidien { jour, moyenne, chauffage, climatisation });
+    }
+
+    let reponse = ReponseGetDegresJours {
+        ok: true,
+        senseur,
+        base,
+        timezone: tz.to_string(),
+        quotidien,
+        mensuel: mensuel.into_values().collect(),
+        saisonnier: saisonnier.into_values().collect(),
+    };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_setup::setup;
+
+    #[test]
+    fn test_saison_jour() {
+        setup("test_saison_jour");
+        assert_eq!("2023-2024", saison_jour("2024-03-15"));
+        assert_eq!("2023-2024", saison_jour("2024-06-30"));
+        assert_eq!("2024-2025", saison_jour("2024-07-01"));
+        assert_eq!("2024-2025", saison_jour("2024-12-31"));
+    }
+}
@@ -1,13 +1,22 @@
+use crate::actionneurs::expirer_commandes_appareils;
+use crate::anomalies::reconstruire_baselines;
+use crate::boite_envoi::purger_boite_envoi;
 use crate::builder::preparer_index_mongodb;
 use crate::commandes::consommer_commande;
+use crate::completude::generer_rapports_completude;
 use crate::common::*;
 use crate::constants::*;
 use crate::evenements::consommer_evenement;
+use crate::horaires::executer_horaires;
 use crate::lectures::{generer_transactions_lectures_horaires, rebuild_sensor_list};
 use crate::maintenance::{maintain_device_certificates, mark_devices_offline};
+use crate::regeneration::{terminer_regeneration, EtatRegeneration};
 use crate::requetes::consommer_requete;
+use crate::retention::appliquer_retention;
+use crate::sante_relais::detecter_relais_perdus;
 use crate::transactions::aiguillage_transaction;
 use log::error;
+use std::sync::Arc;
 use millegrilles_common_rust::async_trait::async_trait;
 use millegrilles_common_rust::backup::BackupStarter;
 use millegrilles_common_rust::certificats::ValidateurX509;
@@ -26,15 +35,18 @@ use millegrilles_common_rust::mongo_dao::{start_transaction_regular, MongoDao};
 use millegrilles_common_rust::mongodb::ClientSession;
 use millegrilles_common_rust::rabbitmq_dao::{ConfigQueue, ConfigRoutingExchange, QueueType};
 use millegrilles_common_rust::recepteur_messages::MessageValide;
+use millegrilles_common_rust::tokio::sync::Mutex;
 
 #[derive(Clone)]
 pub struct SenseursPassifsDomainManager {
     pub instance_id: String,
+    /// Lot de rangees horaires et point de reprise pendant la regeneration
+    pub regeneration: Arc<Mutex<EtatRegeneration>>,
 }
 
 impl SenseursPassifsDomainManager {
     pub fn new(instance_id: String) -> SenseursPassifsDomainManager {
-        SenseursPassifsDomainManager { instance_id }
+        SenseursPassifsDomainManager { instance_id, regeneration: Arc::new(Mutex::new(EtatRegeneration::default())) }
     }
 }
 
@@ -47,8 +59,18 @@ impl GestionnaireDomaineV2 for SenseursPassifsDomainManager {
         Ok(vec![
             COLLECTIONS_INSTANCES.to_string(),
             COLLECTIONS_APPAREILS.to_string(),
-            COLLECTIONS_SENSEURS_HORAIRE.to_string(),
             COLLECTIONS_USAGER.to_string(),
+            COLLECTIONS_RETENTION.to_string(),
+            COLLECTIONS_MIGRATION_SENSEURS.to_string(),
+            COLLECTIONS_SCENES.to_string(),
+            COLLECTIONS_HORAIRES.to_string(),
+            COLLECTIONS_PROGRAMMES_VERSIONS.to_string(),
+            COLLECTIONS_GABARITS.to_string(),
+
+            // Les rangees horaires et sommaires quotidiens sont videes par le domaine au debut
+            // de la regeneration (point de reprise, voir regeneration.rs)
+            // COLLECTIONS_SENSEURS_HORAIRE.to_string(),
+            // COLLECTIONS_SENSEURS_QUOTIDIEN.to_string(),
 
             // Ignorer les collections lectures et relais pour regeneration
             // Elles ne sont pas conservees dans des transactions (purement volatiles)
@@ -136,12 +158,42 @@ impl GestionnaireDomaineSimple for SenseursPassifsDomainManager {
             }
         }
 
+        if let Err(e) = detecter_relais_perdus(middleware).await {
+            error!("traiter_cedule Error detecter_relais_perdus : {:?}", e);
+        }
+
+        if let Err(e) = expirer_commandes_appareils(middleware).await {
+            error!("traiter_cedule Error expirer_commandes_appareils : {:?}", e);
+        }
+
+        if let Err(e) = executer_horaires(middleware).await {
+            error!("traiter_cedule Error executer_horaires : {:?}", e);
+        }
+
         if minute == 28 && heure % 12 == 4 {
             if let Err(e) = maintain_device_certificates(middleware).await {
                 error!("traiter_cedule Error maintain_device_certificates : {:?}", e);
             }
         }
 
+        if minute == 33 && heure == 5 {
+            if let Err(e) = generer_rapports_completude(middleware).await {
+                error!("traiter_cedule Error generer_rapports_completude : {:?}", e);
+            }
+        }
+
+        if minute == 41 {
+            if let Err(e) = purger_boite_envoi(middleware).await {
+                error!("traiter_cedule Error purger_boite_envoi : {:?}", e);
+            }
+        }
+
+        if minute == 47 && heure == 3 {
+            if let Err(e) = appliquer_retention(middleware, self).await {
+                error!("traiter_cedule Error appliquer_retention : {:?}", e);
+            }
+        }
+
         Ok(())
     }
 
@@ -163,6 +215,11 @@ impl GestionnaireDomaineSimple for SenseursPassifsDomainManager {
     {
         let mut session = middleware.get_session().await?;
         start_transaction_regular(&mut session).await?;
+        if let Err(e) = terminer_regeneration(middleware, self, &mut session).await {
+            error!("traitement_post_regeneration Error completing hourly rows: {:?}", e);
+            session.abort_transaction().await?;
+            Err(e)?
+        }
         match rebuild_sensor_list(middleware, &mut session).await {
             Ok(()) => session.commit_transaction().await?,
             Err(e) => {
@@ -172,6 +229,12 @@ impl GestionnaireDomaineSimple for SenseursPassifsDomainManager {
             },
         }
 
+        // Anomaly detection is disabled during regeneration, rebuild the baselines from the hourly rows
+        if let Err(e) = reconstruire_baselines(middleware).await {
+            error!("traitement_post_regeneration Error rebuilding anomaly baselines: {:?}", e);
+            Err(e)?
+        }
+
         Ok(())
     }
 }
@@ -192,6 +255,29 @@ pub fn preparer_queues(manager: &SenseursPassifsDomainManager) -> Vec<QueueType>
         REQUETE_GET_STATISTIQUES_SENSEUR,
         REQUETE_GET_CONFIGURATION_USAGER,
         REQUETE_GET_TIMEZONE_APPAREIL,
+        REQUETE_EXPORT_STATISTIQUES,
+        REQUETE_COMPARAISON_SENSEURS,
+        REQUETE_GET_LECTURES_SENSEUR,
+        REQUETE_GET_COMPLETUDE_SENSEUR,
+        REQUETE_GET_RAPPORTS_COMPLETUDE,
+        REQUETE_GET_ANOMALIES_SENSEURS,
+        REQUETE_COMPARAISON_PERIODES,
+        REQUETE_GET_DEGRES_JOURS,
+        REQUETE_HISTOGRAMME_SENSEUR,
+        REQUETE_MATRICE_HORAIRE_SENSEUR,
+        REQUETE_GET_RETENTION,
+        REQUETE_GET_RELAIS_APPAREILS,
+        REQUETE_GET_SANTE_RELAIS,
+        REQUETE_GET_COMMANDES_APPAREIL,
+        REQUETE_GET_SCENES,
+        REQUETE_GET_CLASSES_PROGRAMMES,
+        REQUETE_GET_VERSIONS_PROGRAMME,
+        REQUETE_DIFF_VERSIONS_PROGRAMME,
+        REQUETE_SIMULER_PROGRAMME,
+        REQUETE_GET_GABARITS,
+        REQUETE_GET_DERIVE_GABARIT,
+        REQUETE_GET_HORAIRES,
+        REQUETE_PREVISUALISER_HORAIRE,
     ];
     for req in requetes_privees {
         rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", DOMAINE_NOM, req), exchange: Securite::L2Prive});
@@ -209,6 +295,8 @@ pub fn preparer_queues(manager: &SenseursPassifsDomainManager) -> Vec<QueueType>
         rk_volatils.push(ConfigRoutingExchange { routing_key: format!("evenement.{}.{}", ROLE_RELAI_NOM, evnt), exchange: Securite::L2Prive });
     }
     rk_volatils.push(ConfigRoutingExchange { routing_key: format!("evenement.{}.{}", ROLE_RELAI_NOM, EVENEMENT_PRESENCE_APPAREIL), exchange: Securite::L2Prive });
+    rk_volatils.push(ConfigRoutingExchange { routing_key: format!("evenement.{}.{}", ROLE_RELAI_NOM, EVENEMENT_HEARTBEAT_RELAI), exchange: Securite::L2Prive });
+    rk_volatils.push(ConfigRoutingExchange { routing_key: format!("evenement.{}.{}", ROLE_RELAI_NOM, EVENEMENT_ETAT_COMMANDE_APPAREIL), exchange: Securite::L2Prive });
 
     let commandes_transactions: Vec<&str> = vec![
         // Transactions usager, verifier via commande
@@ -222,17 +310,31 @@ pub fn preparer_queues(manager: &SenseursPassifsDomainManager) -> Vec<QueueType>
         TRANSACTION_APPAREIL_RESTAURER,
         TRANSACTION_MAJ_CONFIGURATION_USAGER,
         TRANSACTION_SHOW_HIDE_SENSOR,
+        TRANSACTION_MAJ_RETENTION,
         COMMANDE_INSCRIRE_APPAREIL,
         COMMANDE_CHALLENGE_APPAREIL,
         COMMANDE_SIGNER_APPAREIL,
         COMMANDE_CONFIRMER_RELAI,
+        COMMANDE_REVOQUER_RELAI,
         COMMANDE_RESET_CERTIFICATS,
         COMMAND_DISCONNECT_RELAY,
+        COMMANDE_ACTIONNER_APPAREIL,
+        COMMANDE_EXECUTER_SCENE,
+        TRANSACTION_SAUVEGARDER_SCENE,
+        TRANSACTION_SAUVEGARDER_HORAIRE,
+        TRANSACTION_RESTAURER_VERSION_PROGRAMME,
+        COMMANDE_APPLIQUER_GABARIT,
+        TRANSACTION_SAUVEGARDER_GABARIT,
     ];
     for cmd in commandes_transactions {
         rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", DOMAINE_NOM, cmd), exchange: Securite::L2Prive});
     }
 
+    // Commandes d'administration
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", DOMAINE_NOM, COMMANDE_COMPACTER_SENSEURS_HORAIRE), exchange: Securite::L3Protege});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", DOMAINE_NOM, COMMANDE_VERIFIER_COHERENCE), exchange: Securite::L3Protege});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", DOMAINE_NOM, COMMANDE_MIGRER_TRANSACTIONS_LEGACY), exchange: Securite::L3Protege});
+
     rk_volatils.push(ConfigRoutingExchange {
         routing_key: format!("commande.{}.{}.{}", DOMAINE_NOM, manager.instance_id.as_str(), TRANSACTION_LECTURE).into(),
         exchange: Securite::L2Prive
@@ -260,10 +362,19 @@ pub fn preparer_queues(manager: &SenseursPassifsDomainManager) -> Vec<QueueType>
         TRANSACTION_SUPPRESSION_SENSEUR,
         TRANSACTION_MAJ_APPAREIL,
         TRANSACTION_SENSEUR_HORAIRE,
+        TRANSACTION_SENSEUR_HORAIRE_MENSUEL,
         TRANSACTION_INIT_APPAREIL,
         TRANSACTION_APPAREIL_SUPPRIMER,
         TRANSACTION_APPAREIL_RESTAURER,
         TRANSACTION_SHOW_HIDE_SENSOR,
+        TRANSACTION_MAJ_RETENTION,
+        TRANSACTION_ELAGUER_SENSEURS_HORAIRE,
+        TRANSACTION_SUPPRIMER_SENSEURS_HORAIRE,
+        TRANSACTION_MIGRATION_SENSEUR_LEGACY,
+        TRANSACTION_SAUVEGARDER_SCENE,
+        TRANSACTION_SAUVEGARDER_HORAIRE,
+        TRANSACTION_RESTAURER_VERSION_PROGRAMME,
+        TRANSACTION_SAUVEGARDER_GABARIT,
     ];
     for trans in &transactions_sec {
         rk_transactions.push(ConfigRoutingExchange {
@@ -7,10 +7,13 @@ use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageM
 use millegrilles_common_rust::mongo_dao::MongoDao;
 use millegrilles_common_rust::serde::{Deserialize, Serialize};
 
+use crate::actionneurs::evenement_etat_commande_appareil;
+use crate::boite_envoi::livrer_boite_envoi;
 use crate::common::*;
 
 use crate::domain_manager::SenseursPassifsDomainManager;
 use crate::lectures::evenement_domaine_lecture;
+use crate::sante_relais::evenement_heartbeat_relai;
 use millegrilles_common_rust::constantes::*;
 use millegrilles_common_rust::error::Error as CommonError;
 use millegrilles_common_rust::get_domaine_action;
@@ -36,6 +39,8 @@ where M: ValidateurX509 + GenerateurMessages + MongoDao
     match action.as_str() {
         EVENEMENT_LECTURE => { evenement_domaine_lecture(middleware, &m, gestionnaire).await?; Ok(None) },
         EVENEMENT_PRESENCE_APPAREIL => { evenement_appareil_presence(middleware, &m).await?; Ok(None) },
+        EVENEMENT_HEARTBEAT_RELAI => { evenement_heartbeat_relai(middleware, &m).await?; Ok(None) },
+        EVENEMENT_ETAT_COMMANDE_APPAREIL => { evenement_etat_commande_appareil(middleware, &m).await?; Ok(None) },
         EVENEMENT_CEDULE => Ok(None),  // Obsolete, utiliser evenement ping
         _ => Err(format!("senseurspassifs.consommer_evenement: Mauvais type d'action pour une transaction : {}", action))?,
     }
@@ -111,6 +116,12 @@ pub async fn evenement_appareil_presence<M>(middleware: &M, m: &MessageValide) -
     let collection = middleware.get_collection(COLLECTIONS_APPAREILS)?;
     collection.update_one(filtre, ops, None).await?;
 
+    // Livrer les messages conserves pendant que l'appareil etait hors ligne (partition du relai)
+    if ! deconnecte {
+        let instance_id = certificat.get_common_name()?;
+        livrer_boite_envoi(middleware, &evenement.user_id, &evenement.uuid_appareil, &instance_id).await?;
+    }
+
     // Re-emettre l'evenement pour le userId
     {
         let evenement_reemis = EvenementPresenceAppareilUser {
@@ -0,0 +1,424 @@
+use std::collections::HashMap;
+use log::{debug, info, warn};
+
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
+use millegrilles_common_rust::chrono::Utc;
+use millegrilles_common_rust::db_structs::TransactionValide;
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::GenerateurMessages;
+use millegrilles_common_rust::middleware::{sauvegarder_traiter_transaction_serializable_v2, sauvegarder_traiter_transaction_v2};
+use millegrilles_common_rust::millegrilles_cryptographie::deser_message_buffer;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::MessageMilleGrillesBufferDefault;
+use millegrilles_common_rust::mongo_dao::{convertir_to_bson, MongoDao};
+use millegrilles_common_rust::mongodb::ClientSession;
+use millegrilles_common_rust::mongodb::options::{FindOptions, UpdateOptions};
+use millegrilles_common_rust::recepteur_messages::MessageValide;
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+use millegrilles_common_rust::serde_json;
+use millegrilles_common_rust::tokio_stream::StreamExt;
+
+use crate::common::*;
+use crate::domain_manager::SenseursPassifsDomainManager;
+use crate::programmes::{reponse_erreurs_validation, valider_programmes, ErreurValidationProgramme};
+use crate::transactions::TransactionMajAppareil;
+
+/// Nombre maximal d'appareils par commande appliquerGabarit.
+const CONST_GABARIT_APPAREILS_MAX: usize = 100;
+
+/// Configuration partagee par plusieurs appareils. Le descriptif et la geoposition sont propres a
+/// chaque appareil et ne font pas partie du gabarit.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConfigurationGabarit {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cacher_senseurs: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub descriptif_senseurs: Option<HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub displays: Option<HashMap<String, ParametresDisplay>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub programmes: Option<HashMap<String, ProgrammeAppareil>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filtres_senseurs: Option<HashMap<String, Vec<String>>>,
+}
+
+impl From<ConfigurationGabarit> for ConfigurationAppareil {
+    fn from(value: ConfigurationGabarit) -> Self {
+        Self {
+            descriptif: None,
+            cacher_senseurs: value.cacher_senseurs,
+            descriptif_senseurs: value.descriptif_senseurs,
+            displays: value.displays,
+            programmes: value.programmes,
+            timezone: value.timezone,
+            geoposition: None,
+            filtres_senseurs: value.filtres_senseurs,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionSauvegarderGabarit {
+    pub gabarit_id: String,
+    pub nom: Option<String>,
+    pub configuration: Option<ConfigurationGabarit>,
+    pub supprimer: Option<bool>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RowGabarit {
+    user_id: String,
+    gabarit_id: String,
+    nom: String,
+    configuration: ConfigurationGabarit,
+    /// Groupe : appareils auxquels le gabarit a ete applique
+    #[serde(default)]
+    appareils: Vec<String>,
+}
+
+async fn charger_gabarit<M>(middleware: &M, user_id: &str, gabarit_id: &str) -> Result<Option<RowGabarit>, Error>
+    where M: MongoDao
+{
+    let filtre = doc! { CHAMP_USER_ID: user_id, "gabarit_id": gabarit_id };
+    let collection = middleware.get_collection_typed::<RowGabarit>(COLLECTIONS_GABARITS)?;
+    Ok(collection.find_one(filtre, None).await?)
+}
+
+pub async fn commande_sauvegarder_gabarit<M>(middleware: &M, m: MessageValide, gestionnaire: &SenseursPassifsDomainManager, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    debug!("commande_sauvegarder_gabarit Consommer commande : {:?}", & m.type_message);
+    let commande: TransactionSauvegarderGabarit = deser_message_buffer!(m.message);
+
+    if m.certificat.get_user_id()?.is_none() {
+        return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    }
+
+    if commande.supprimer != Some(true) {
+        match commande.nom.as_ref() {
+            Some(nom) if !nom.trim().is_empty() => (),
+            _ => return Ok(Some(middleware.reponse_err(None, None, Some("nom de gabarit requis"))?))
+        }
+        let configuration = match commande.configuration.as_ref() {
+            Some(inner) => inner,
+            None => return Ok(Some(middleware.reponse_err(None, None, Some("configuration requise"))?))
+        };
+        // Les references de senseurs sont verifiees pour chaque appareil a l'application du gabarit
+        if let Some(programmes) = configuration.programmes.as_ref() {
+            let erreurs = valider_programmes(middleware, None, "", programmes.values()).await?;
+            if !erreurs.is_empty() {
+                return reponse_erreurs_validation(middleware, erreurs)
+            }
+        }
+    }
+
+    Ok(sauvegarder_traiter_transaction_v2(middleware, m, gestionnaire, session).await?)
+}
+
+pub async fn transaction_sauvegarder_gabarit<M>(middleware: &M, transaction: TransactionValide, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("transaction_sauvegarder_gabarit Consommer transaction : {:?}", transaction.transaction.id);
+    let contenu_transaction: TransactionSauvegarderGabarit = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+
+    let user_id = match transaction.certificat.get_user_id()? {
+        Some(user) => user.to_owned(),
+        None => Err(Error::Str("senseurspassifs.transaction_sauvegarder_gabarit Erreur user_id absent du certificat"))?
+    };
+
+    let filtre = doc! { CHAMP_USER_ID: &user_id, "gabarit_id": &contenu_transaction.gabarit_id };
+    let collection = middleware.get_collection(COLLECTIONS_GABARITS)?;
+    match (contenu_transaction.supprimer, contenu_transaction.configuration) {
+        (Some(true), _) | (_, None) => {
+            collection.delete_one_with_session(filtre, None, session).await?;
+        },
+        (_, Some(configuration)) => {
+            let ops = doc! {
+                "$set": {
+                    "nom": contenu_transaction.nom,
+                    "configuration": convertir_to_bson(configuration)?,
+                },
+                "$setOnInsert": {
+                    CHAMP_USER_ID: &user_id,
+                    "gabarit_id": &contenu_transaction.gabarit_id,
+                    "appareils": [],
+                    CHAMP_CREATION: Utc::now(),
+                },
+                "$currentDate": { CHAMP_MODIFICATION: true },
+            };
+            let options = UpdateOptions::builder().upsert(true).build();
+            collection.update_one_with_session(filtre, ops, options, session).await?;
+        }
+    }
+
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
+
+/// Ajoute l'appareil au groupe du gabarit. Appele par la transaction majAppareil emise par appliquerGabarit.
+pub async fn associer_appareil_gabarit<M>(middleware: &M, user_id: &str, gabarit_id: &str, uuid_appareil: &str, session: &mut ClientSession)
+    -> Result<(), Error>
+    where M: MongoDao
+{
+    let filtre = doc! { CHAMP_USER_ID: user_id, "gabarit_id": gabarit_id };
+    let ops = doc! {
+        "$addToSet": { "appareils": uuid_appareil },
+        "$currentDate": { CHAMP_MODIFICATION: true },
+    };
+    let collection = middleware.get_collection(COLLECTIONS_GABARITS)?;
+    collection.update_one_with_session(filtre, ops, None, session).await?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ReponseGetGabarits {
+    ok: bool,
+    gabarits: Vec<RowGabarit>,
+}
+
+pub async fn requete_get_gabarits<M>(middleware: &M, m: MessageValide, _gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_get_gabarits Consommer requete : {:?}", & m.type_message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    };
+
+    let options = FindOptions::builder().sort(doc! {"nom": 1}).build();
+    let collection = middleware.get_collection_typed::<RowGabarit>(COLLECTIONS_GABARITS)?;
+    let mut curseur = collection.find(doc!{CHAMP_USER_ID: &user_id}, options).await?;
+    let mut gabarits = Vec::new();
+    while let Some(row) = curseur.next().await {
+        match row {
+            Ok(row) => gabarits.push(row),
+            Err(e) => warn!("requete_get_gabarits Erreur mapping gabarit : {:?}", e)
+        }
+    }
+
+    let reponse = ReponseGetGabarits { ok: true, gabarits };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+#[derive(Deserialize)]
+struct CommandeAppliquerGabarit {
+    gabarit_id: String,
+    /// Appareils cibles. Par defaut, le groupe du gabarit (appareils deja associes).
+    uuid_appareils: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+struct ResultatAppliquerGabarit {
+    uuid_appareil: String,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    err: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    erreurs_validation: Vec<ErreurValidationProgramme>,
+}
+
+#[derive(Serialize)]
+struct ReponseAppliquerGabarit {
+    /// true si le gabarit a ete applique a tous les appareils
+    ok: bool,
+    gabarit_id: String,
+    resultats: Vec<ResultatAppliquerGabarit>,
+}
+
+/// Applique un gabarit en emettant une transaction majAppareil par appareil. Chaque appareil est
+/// traite dans sa propre session, un appareil en erreur n'interrompt pas les suivants.
+pub async fn commande_appliquer_gabarit<M>(middleware: &M, m: MessageValide, gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    debug!("commande_appliquer_gabarit Consommer commande : {:?}", & m.type_message);
+    let commande: CommandeAppliquerGabarit = deser_message_buffer!(m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    };
+
+    let gabarit = match charger_gabarit(middleware, user_id.as_str(), commande.gabarit_id.as_str()).await? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("Gabarit inconnu"))?))
+    };
+
+    let mut uuid_appareils = commande.uuid_appareils.unwrap_or_else(|| gabarit.appareils.clone());
+    uuid_appareils.sort();
+    uuid_appareils.dedup();
+    if uuid_appareils.is_empty() || uuid_appareils.len() > CONST_GABARIT_APPAREILS_MAX {
+        let message = format!("Le gabarit doit etre applique a 1 a {} appareils", CONST_GABARIT_APPAREILS_MAX);
+        return Ok(Some(middleware.reponse_err(None, None, Some(message.as_str()))?))
+    }
+
+    let collection_appareils = middleware.get_collection_typed::<DocAppareil>(COLLECTIONS_APPAREILS)?;
+    let mut resultats = Vec::with_capacity(uuid_appareils.len());
+    for uuid_appareil in uuid_appareils {
+        let mut resultat = ResultatAppliquerGabarit { uuid_appareil: uuid_appareil.clone(), ok: false, err: None, erreurs_validation: Vec::new() };
+
+        let filtre = doc! { CHAMP_USER_ID: &user_id, CHAMP_UUID_APPAREIL: &uuid_appareil };
+        if collection_appareils.find_one(filtre, None).await?.is_none() {
+            resultat.err = Some("Appareil inconnu".to_string());
+            resultats.push(resultat);
+            continue
+        }
+
+        if let Some(programmes) = gabarit.configuration.programmes.as_ref() {
+            resultat.erreurs_validation = valider_programmes(middleware, Some(user_id.as_str()), uuid_appareil.as_str(), programmes.values()).await?;
+            if !resultat.erreurs_validation.is_empty() {
+                resultat.err = Some("Programme invalide".to_string());
+                resultats.push(resultat);
+                continue
+            }
+        }
+
+        let transaction = TransactionMajAppareil {
+            uuid_appareil: uuid_appareil.clone(),
+            configuration: gabarit.configuration.clone().into(),
+            user_id: Some(user_id.clone()),
+            gabarit_id: Some(gabarit.gabarit_id.clone()),
+        };
+        let mut session = middleware.get_session().await?;
+        session.start_transaction(None).await?;
+        match sauvegarder_traiter_transaction_serializable_v2(
+            middleware, &transaction, gestionnaire, &mut session, DOMAINE_NOM, TRANSACTION_MAJ_APPAREIL).await
+        {
+            Ok(_) => {
+                session.commit_transaction().await?;
+                resultat.ok = true;
+            },
+            Err(e) => {
+                warn!("commande_appliquer_gabarit Erreur application gabarit {} a l'appareil {} : {:?}", gabarit.gabarit_id, uuid_appareil, e);
+                session.abort_transaction().await?;
+                resultat.err = Some(format!("{:?}", e));
+            }
+        }
+        resultats.push(resultat);
+    }
+
+    let ok = resultats.iter().all(|r| r.ok);
+    info!("commande_appliquer_gabarit Gabarit {} applique, {}/{} appareils",
+        gabarit.gabarit_id, resultats.iter().filter(|r| r.ok).count(), resultats.len());
+    let reponse = ReponseAppliquerGabarit { ok, gabarit_id: gabarit.gabarit_id, resultats };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+/// Champs de la configuration de l'appareil qui different du gabarit. Les dictionnaires fusionnes par
+/// majAppareil (descriptif_senseurs, filtres_senseurs) sont compares par cle.
+fn champs_derives(gabarit: &ConfigurationGabarit, configuration: Option<&ConfigurationAppareil>) -> Result<Vec<String>, Error> {
+    fn different<T: Serialize>(gabarit: &T, appareil: Option<&T>) -> Result<bool, Error> {
+        Ok(Some(serde_json::to_value(gabarit)?) != appareil.map(serde_json::to_value).transpose()?)
+    }
+
+    let mut champs = Vec::new();
+    if let Some(inner) = gabarit.cacher_senseurs.as_ref() {
+        if different(inner, configuration.and_then(|c| c.cacher_senseurs.as_ref()))? {
+            champs.push("cacher_senseurs".to_string());
+        }
+    }
+    if let Some(inner) = gabarit.displays.as_ref() {
+        if different(inner, configuration.and_then(|c| c.displays.as_ref()))? {
+            champs.push("displays".to_string());
+        }
+    }
+    if let Some(inner) = gabarit.programmes.as_ref() {
+        if different(inner, configuration.and_then(|c| c.programmes.as_ref()))? {
+            champs.push("programmes".to_string());
+        }
+    }
+    if let Some(inner) = gabarit.timezone.as_ref() {
+        if configuration.and_then(|c| c.timezone.as_ref()) != Some(inner) {
+            champs.push("timezone".to_string());
+        }
+    }
+    if let Some(inner) = gabarit.descriptif_senseurs.as_ref() {
+        let appareil = configuration.and_then(|c| c.descriptif_senseurs.as_ref());
+        for (cle, valeur) in inner {
+            if appareil.and_then(|a| a.get(cle)) != Some(valeur) {
+                champs.push(format!("descriptif_senseurs.{}", cle));
+            }
+        }
+    }
+    if let Some(inner) = gabarit.filtres_senseurs.as_ref() {
+        let appareil = configuration.and_then(|c| c.filtres_senseurs.as_ref());
+        for (cle, valeur) in inner {
+            if appareil.and_then(|a| a.get(cle)) != Some(valeur) {
+                champs.push(format!("filtres_senseurs.{}", cle));
+            }
+        }
+    }
+    champs.sort();
+    Ok(champs)
+}
+
+#[derive(Deserialize)]
+struct RequeteDeriveGabarit {
+    gabarit_id: String,
+}
+
+#[derive(Serialize)]
+struct DeriveAppareil {
+    uuid_appareil: String,
+    conforme: bool,
+    /// true si l'appareil du groupe n'existe plus
+    absent: bool,
+    champs_derives: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ReponseDeriveGabarit {
+    ok: bool,
+    gabarit_id: String,
+    appareils: Vec<DeriveAppareil>,
+}
+
+/// Compare la configuration courante des appareils du groupe avec le gabarit.
+pub async fn requete_get_derive_gabarit<M>(middleware: &M, m: MessageValide, _gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_get_derive_gabarit Consommer requete : {:?}", & m.type_message);
+    let requete: RequeteDeriveGabarit = deser_message_buffer!(m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    };
+
+    let gabarit = match charger_gabarit(middleware, user_id.as_str(), requete.gabarit_id.as_str()).await? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("Gabarit inconnu"))?))
+    };
+
+    let filtre = doc! { CHAMP_USER_ID: &user_id, CHAMP_UUID_APPAREIL: {"$in": gabarit.appareils.clone()} };
+    let collection = middleware.get_collection_typed::<DocAppareil>(COLLECTIONS_APPAREILS)?;
+    let mut curseur = collection.find(filtre, None).await?;
+    let mut configurations = HashMap::new();
+    while let Some(row) = curseur.next().await {
+        match row {
+            Ok(appareil) => { configurations.insert(appareil.uuid_appareil, appareil.configuration); },
+            Err(e) => warn!("requete_get_derive_gabarit Erreur mapping appareil : {:?}", e)
+        }
+    }
+
+    let mut appareils = Vec::with_capacity(gabarit.appareils.len());
+    for uuid_appareil in gabarit.appareils.iter() {
+        let derive = match configurations.get(uuid_appareil) {
+            Some(configuration) => {
+                let champs_derives = champs_derives(&gabarit.configuration, configuration.as_ref())?;
+                DeriveAppareil { uuid_appareil: uuid_appareil.clone(), conforme: champs_derives.is_empty(), absent: false, champs_derives }
+            },
+            None => DeriveAppareil { uuid_appareil: uuid_appareil.clone(), conforme: false, absent: true, champs_derives: Vec::new() }
+        };
+        appareils.push(derive);
+    }
+
+    let reponse = ReponseDeriveGabarit { ok: true, gabarit_id: gabarit.gabarit_id, appareils };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
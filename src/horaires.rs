@@ -0,0 +1,760 @@
+use std::collections::HashMap;
+use log::{debug, info, warn};
+use chrono_tz::Tz;
+
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
+use millegrilles_common_rust::chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Timelike, Utc};
+use millegrilles_common_rust::db_structs::TransactionValide;
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::GenerateurMessages;
+use millegrilles_common_rust::middleware::sauvegarder_traiter_transaction_v2;
+use millegrilles_common_rust::millegrilles_cryptographie::deser_message_buffer;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::{optionepochseconds, MessageMilleGrillesBufferDefault};
+use millegrilles_common_rust::mongo_dao::{convertir_to_bson, opt_chrono_datetime_as_bson_datetime, MongoDao};
+use millegrilles_common_rust::mongodb::ClientSession;
+use millegrilles_common_rust::mongodb::options::{FindOptions, UpdateOptions};
+use millegrilles_common_rust::recepteur_messages::MessageValide;
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+use millegrilles_common_rust::serde_json;
+use millegrilles_common_rust::tokio_stream::StreamExt;
+
+use crate::actionneurs::{actionner_appareil, ActionActionneur, CONST_COMMANDE_TIMEOUT_DEFAUT_SECS};
+use crate::common::*;
+use crate::domain_manager::SenseursPassifsDomainManager;
+use crate::requetes::{charger_configuration_usager, parser_timezone};
+use crate::scenes::executer_scene;
+
+/// Horizon de calcul des prochains declenchements.
+const CONST_HORIZON_PREVISUALISATION_JOURS: i64 = 366;
+const CONST_PREVISUALISATION_DEFAUT: usize = 10;
+const CONST_PREVISUALISATION_MAX: usize = 100;
+/// Decalage maximal (minutes) par rapport au lever ou coucher du soleil.
+const CONST_DECALAGE_SOLEIL_MAX_MINUTES: i32 = 720;
+/// Fenetre maximale (minutes) de rattrapage d'un declenchement manque (cedule en retard, redemarrage).
+const CONST_RATTRAPAGE_MAX_MINUTES: i64 = 15;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EvenementSoleil {
+    Lever,
+    Coucher,
+}
+
+/// Moment de declenchement d'un horaire, evalue dans la timezone de l'usager.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DeclencheurHoraire {
+    /// Expression cron a 5 champs : minute heure jour_mois mois jour_semaine (0-7, 0 et 7 = dimanche).
+    Cron { expression: String },
+    /// Lever ou coucher du soleil a la geoposition d'un appareil, avec un decalage en minutes.
+    Soleil {
+        evenement: EvenementSoleil,
+        #[serde(default)]
+        decalage_minutes: i32,
+        /// Appareil qui fournit la geoposition. Par defaut, l'appareil de la commande.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        uuid_appareil: Option<String>,
+        /// Jours de la semaine (0 = dimanche). Tous les jours si absent.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        jours_semaine: Option<Vec<u32>>,
+    },
+}
+
+/// Action executee au declenchement.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CibleHoraire {
+    Scene { scene_id: String },
+    Commande(ActionActionneur),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionSauvegarderHoraire {
+    pub horaire_id: String,
+    pub nom: Option<String>,
+    pub actif: Option<bool>,
+    pub declencheur: Option<DeclencheurHoraire>,
+    pub cible: Option<CibleHoraire>,
+    pub supprimer: Option<bool>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct ResultatHoraire {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    err: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RowHoraire {
+    user_id: String,
+    horaire_id: String,
+    nom: Option<String>,
+    #[serde(default = "horaire_actif_defaut")]
+    actif: bool,
+    declencheur: DeclencheurHoraire,
+    cible: CibleHoraire,
+    #[serde(default,
+        serialize_with = "optionepochseconds::serialize",
+        deserialize_with = "opt_chrono_datetime_as_bson_datetime::deserialize")]
+    derniere_execution: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dernier_resultat: Option<ResultatHoraire>,
+}
+
+fn horaire_actif_defaut() -> bool { true }
+
+/// Expression cron avec les valeurs permises de chaque champ.
+struct ExpressionCron {
+    minutes: Vec<bool>,
+    heures: Vec<bool>,
+    jours_mois: Vec<bool>,
+    mois: Vec<bool>,
+    jours_semaine: Vec<bool>,
+    jours_mois_restreint: bool,
+    jours_semaine_restreint: bool,
+}
+
+impl ExpressionCron {
+    fn parse(expression: &str) -> Result<Self, String> {
+        let champs: Vec<&str> = expression.split_whitespace().collect();
+        if champs.len() != 5 {
+            Err(format!("Expression cron '{}' invalide, 5 champs requis", expression))?
+        }
+        let mut jours_semaine = parser_champ_cron(champs[4], 0, 7)?;
+        if jours_semaine[7] {
+            jours_semaine[0] = true;  // 7 = dimanche
+        }
+        jours_semaine.truncate(7);
+        Ok(Self {
+            minutes: parser_champ_cron(champs[0], 0, 59)?,
+            heures: parser_champ_cron(champs[1], 0, 23)?,
+            jours_mois: parser_champ_cron(champs[2], 1, 31)?,
+            mois: parser_champ_cron(champs[3], 1, 12)?,
+            jours_semaine,
+            jours_mois_restreint: !champs[2].starts_with('*'),
+            jours_semaine_restreint: !champs[4].starts_with('*'),
+        })
+    }
+
+    fn correspond_jour(&self, date: &NaiveDate) -> bool {
+        if !self.mois[date.month() as usize] {
+            return false
+        }
+        let jour_mois = self.jours_mois[date.day() as usize];
+        let jour_semaine = self.jours_semaine[date.weekday().num_days_from_sunday() as usize];
+        // Comme cron : si les deux champs sont restreints, l'un ou l'autre suffit
+        match (self.jours_mois_restreint, self.jours_semaine_restreint) {
+            (true, true) => jour_mois || jour_semaine,
+            _ => jour_mois && jour_semaine,
+        }
+    }
+}
+
+/// Parse un champ cron (*, */n, a, a-b, a-b/n, listes separees par des virgules).
+fn parser_champ_cron(champ: &str, min: u32, max: u32) -> Result<Vec<bool>, String> {
+    let mut valeurs = vec![false; max as usize + 1];
+    for partie in champ.split(',') {
+        let (plage, pas) = match partie.split_once('/') {
+            Some((plage, pas)) => (plage, pas.parse::<u32>().map_err(|_| format!("Pas cron '{}' invalide", partie))?),
+            None => (partie, 1)
+        };
+        if pas == 0 {
+            Err(format!("Pas cron '{}' invalide", partie))?
+        }
+        let (debut, fin) = if plage == "*" {
+            (min, max)
+        } else if let Some((debut, fin)) = plage.split_once('-') {
+            let debut = debut.parse::<u32>().map_err(|_| format!("Valeur cron '{}' invalide", partie))?;
+            let fin = fin.parse::<u32>().map_err(|_| format!("Valeur cron '{}' invalide", partie))?;
+            (debut, fin)
+        } else {
+            let valeur = plage.parse::<u32>().map_err(|_| format!("Valeur cron '{}' invalide", partie))?;
+            match partie.contains('/') {
+                true => (valeur, max),
+                false => (valeur, valeur)
+            }
+        };
+        if debut < min || fin > max || debut > fin {
+            Err(format!("Valeur cron '{}' hors limites ({}-{})", partie, min, max))?
+        }
+        for valeur in (debut..=fin).step_by(pas as usize) {
+            valeurs[valeur as usize] = true;
+        }
+    }
+    Ok(valeurs)
+}
+
+/// Declencheur pret a etre evalue (expression parsee, geoposition chargee).
+enum DeclencheurPrepare {
+    Cron(ExpressionCron),
+    Soleil {
+        evenement: EvenementSoleil,
+        decalage_minutes: i32,
+        latitude: f64,
+        longitude: f64,
+        jours_semaine: Option<Vec<u32>>,
+    },
+}
+
+impl DeclencheurPrepare {
+    /// Declenchements (a la minute) pour une date locale, en ordre chronologique.
+    fn declenchements_jour(&self, date: NaiveDate, tz: &Tz) -> Vec<DateTime<Utc>> {
+        match self {
+            DeclencheurPrepare::Cron(cron) => {
+                let mut declenchements = Vec::new();
+                if !cron.correspond_jour(&date) {
+                    return declenchements
+                }
+                for heure in (0..24u32).filter(|h| cron.heures[*h as usize]) {
+                    for minute in (0..60u32).filter(|m| cron.minutes[*m as usize]) {
+                        let local = match date.and_hms_opt(heure, minute, 0) {
+                            Some(inner) => inner,
+                            None => continue
+                        };
+                        // Heure inexistante (passage a l'heure d'ete) ignoree, heure ambigue declenchee une fois
+                        if let Some(moment) = tz.from_local_datetime(&local).earliest() {
+                            declenchements.push(moment.with_timezone(&Utc));
+                        }
+                    }
+                }
+                declenchements
+            },
+            DeclencheurPrepare::Soleil { evenement, decalage_minutes, latitude, longitude, jours_semaine } => {
+                if let Some(jours) = jours_semaine.as_ref() {
+                    if !jours.contains(&date.weekday().num_days_from_sunday()) {
+                        return Vec::new()
+                    }
+                }
+                match calculer_soleil(date, *latitude, *longitude, *evenement, tz) {
+                    Some(moment) => {
+                        let moment = moment + Duration::minutes(*decalage_minutes as i64);
+                        vec![tronquer_minute(moment)]
+                    },
+                    None => Vec::new()  // Pas de lever/coucher (latitude polaire)
+                }
+            }
+        }
+    }
+
+    /// Dernier declenchement dans l'intervalle ]debut, fin]. Les jours adjacents sont verifies pour
+    /// les decalages qui traversent minuit.
+    fn dernier_declenchement(&self, debut: &DateTime<Utc>, fin: &DateTime<Utc>, tz: &Tz) -> Option<DateTime<Utc>> {
+        let date_debut = debut.with_timezone(tz).date_naive() - Duration::days(1);
+        let date_fin = fin.with_timezone(tz).date_naive() + Duration::days(1);
+        date_debut.iter_days().take_while(|d| d <= &date_fin)
+            .flat_map(|d| self.declenchements_jour(d, tz))
+            .filter(|moment| moment > debut && moment <= fin)
+            .max()
+    }
+
+    /// Prochains declenchements strictement apres le moment donne.
+    fn prochains(&self, apres: &DateTime<Utc>, tz: &Tz, nombre: usize) -> Vec<DateTime<Utc>> {
+        let date = apres.with_timezone(tz).date_naive();
+        let mut resultat = Vec::new();
+        for i in -1..CONST_HORIZON_PREVISUALISATION_JOURS {
+            for moment in self.declenchements_jour(date + Duration::days(i), tz) {
+                if &moment > apres && !resultat.contains(&moment) {
+                    resultat.push(moment);
+                }
+            }
+            if resultat.len() >= nombre {
+                break
+            }
+        }
+        resultat.sort();
+        resultat.truncate(nombre);
+        resultat
+    }
+}
+
+fn tronquer_minute(moment: DateTime<Utc>) -> DateTime<Utc> {
+    moment.with_second(0).and_then(|m| m.with_nanosecond(0)).unwrap_or(moment)
+}
+
+/// Heure du lever ou coucher du soleil pour une date locale. Algorithme de l'Almanac for Computers
+/// (US Naval Observatory), precision d'environ une minute. None si le soleil ne se leve ou ne se couche pas.
+fn calculer_soleil(date: NaiveDate, latitude: f64, longitude: f64, evenement: EvenementSoleil, tz: &Tz) -> Option<DateTime<Utc>> {
+    const ZENITH: f64 = 90.833;  // Refraction et rayon du soleil
+    let sin_deg = |x: f64| x.to_radians().sin();
+    let cos_deg = |x: f64| x.to_radians().cos();
+    let normaliser = |x: f64, max: f64| x.rem_euclid(max);
+
+    let jour = date.ordinal() as f64;
+    let longitude_heures = longitude / 15.0;
+    let t = match evenement {
+        EvenementSoleil::Lever => jour + (6.0 - longitude_heures) / 24.0,
+        EvenementSoleil::Coucher => jour + (18.0 - longitude_heures) / 24.0,
+    };
+
+    // Anomalie moyenne et longitude vraie du soleil
+    let anomalie = 0.9856 * t - 3.289;
+    let longitude_soleil = normaliser(anomalie + 1.916 * sin_deg(anomalie) + 0.020 * sin_deg(2.0 * anomalie) + 282.634, 360.0);
+
+    // Ascension droite, dans le meme quadrant que la longitude
+    let mut ascension = normaliser((0.91764 * longitude_soleil.to_radians().tan()).atan().to_degrees(), 360.0);
+    ascension += (longitude_soleil / 90.0).floor() * 90.0 - (ascension / 90.0).floor() * 90.0;
+    let ascension = ascension / 15.0;
+
+    // Declinaison et angle horaire
+    let sin_declinaison = 0.39782 * sin_deg(longitude_soleil);
+    let cos_declinaison = sin_declinaison.asin().cos();
+    let cos_angle = (cos_deg(ZENITH) - sin_declinaison * sin_deg(latitude)) / (cos_declinaison * cos_deg(latitude));
+    if !(-1.0..=1.0).contains(&cos_angle) {
+        return None
+    }
+    let angle = match evenement {
+        EvenementSoleil::Lever => 360.0 - cos_angle.acos().to_degrees(),
+        EvenementSoleil::Coucher => cos_angle.acos().to_degrees(),
+    } / 15.0;
+
+    let heure_locale_moyenne = angle + ascension - 0.06571 * t - 6.622;
+    let heure_utc = normaliser(heure_locale_moyenne - longitude_heures, 24.0);
+
+    let minuit = date.and_hms_opt(0, 0, 0)?.and_utc();
+    let mut moment = minuit + Duration::seconds((heure_utc * 3600.0).round() as i64);
+
+    // L'heure UTC est relative au jour UTC, ramener sur la date locale demandee
+    let date_locale = moment.with_timezone(tz).date_naive();
+    if date_locale < date {
+        moment += Duration::days(1);
+    } else if date_locale > date {
+        moment -= Duration::days(1);
+    }
+    Some(moment)
+}
+
+/// Charge la geoposition (latitude, longitude) de la configuration d'un appareil.
+async fn charger_geoposition<M>(middleware: &M, user_id: &str, uuid_appareil: &str) -> Result<Option<(f64, f64)>, Error>
+    where M: MongoDao
+{
+    let filtre = doc! { CHAMP_USER_ID: user_id, CHAMP_UUID_APPAREIL: uuid_appareil };
+    let collection = middleware.get_collection_typed::<DocAppareil>(COLLECTIONS_APPAREILS)?;
+    let geoposition = match collection.find_one(filtre, None).await? {
+        Some(appareil) => appareil.configuration.and_then(|c| c.geoposition),
+        None => None
+    };
+    Ok(match geoposition {
+        Some(GeopositionAppareil { latitude: Some(latitude), longitude: Some(longitude), .. }) => Some((latitude as f64, longitude as f64)),
+        _ => None
+    })
+}
+
+async fn preparer_declencheur<M>(middleware: &M, user_id: &str, declencheur: &DeclencheurHoraire, cible: Option<&CibleHoraire>)
+    -> Result<DeclencheurPrepare, String>
+    where M: MongoDao
+{
+    match declencheur {
+        DeclencheurHoraire::Cron { expression } => Ok(DeclencheurPrepare::Cron(ExpressionCron::parse(expression)?)),
+        DeclencheurHoraire::Soleil { evenement, decalage_minutes, uuid_appareil, jours_semaine } => {
+            if decalage_minutes.abs() > CONST_DECALAGE_SOLEIL_MAX_MINUTES {
+                Err(format!("decalage_minutes doit etre entre -{0} et {0}", CONST_DECALAGE_SOLEIL_MAX_MINUTES))?
+            }
+            if let Some(jours) = jours_semaine.as_ref() {
+                if jours.iter().any(|j| *j > 6) {
+                    Err("jours_semaine doit contenir des valeurs de 0 (dimanche) a 6".to_string())?
+                }
+            }
+            let uuid_appareil = match (uuid_appareil, cible) {
+                (Some(inner), _) => inner,
+                (None, Some(CibleHoraire::Commande(action))) => &action.uuid_appareil,
+                _ => Err("uuid_appareil requis pour la geoposition".to_string())?
+            };
+            let (latitude, longitude) = match charger_geoposition(middleware, user_id, uuid_appareil).await {
+                Ok(Some(inner)) => inner,
+                Ok(None) => Err(format!("Geoposition absente pour l'appareil {}", uuid_appareil))?,
+                Err(e) => Err(format!("Erreur chargement geoposition : {:?}", e))?
+            };
+            Ok(DeclencheurPrepare::Soleil {
+                evenement: *evenement,
+                decalage_minutes: *decalage_minutes,
+                latitude,
+                longitude,
+                jours_semaine: jours_semaine.clone(),
+            })
+        }
+    }
+}
+
+pub async fn commande_sauvegarder_horaire<M>(middleware: &M, m: MessageValide, gestionnaire: &SenseursPassifsDomainManager, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    debug!("commande_sauvegarder_horaire Consommer commande : {:?}", & m.type_message);
+    let commande: TransactionSauvegarderHoraire = deser_message_buffer!(m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    };
+
+    if commande.supprimer != Some(true) {
+        let (declencheur, cible) = match (commande.declencheur.as_ref(), commande.cible.as_ref()) {
+            (Some(declencheur), Some(cible)) => (declencheur, cible),
+            _ => return Ok(Some(middleware.reponse_err(None, None, Some("declencheur et cible requis"))?))
+        };
+        if let Err(e) = preparer_declencheur(middleware, user_id.as_str(), declencheur, Some(cible)).await {
+            return Ok(Some(middleware.reponse_err(None, None, Some(e.as_str()))?))
+        }
+    }
+
+    Ok(sauvegarder_traiter_transaction_v2(middleware, m, gestionnaire, session).await?)
+}
+
+pub async fn transaction_sauvegarder_horaire<M>(middleware: &M, transaction: TransactionValide, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("transaction_sauvegarder_horaire Consommer transaction : {:?}", transaction.transaction.id);
+    let contenu_transaction: TransactionSauvegarderHoraire = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+
+    let user_id = match transaction.certificat.get_user_id()? {
+        Some(user) => user.to_owned(),
+        None => Err(Error::Str("senseurspassifs.transaction_sauvegarder_horaire Erreur user_id absent du certificat"))?
+    };
+
+    let filtre = doc! { CHAMP_USER_ID: &user_id, "horaire_id": &contenu_transaction.horaire_id };
+    let collection = middleware.get_collection(COLLECTIONS_HORAIRES)?;
+    if contenu_transaction.supprimer == Some(true) {
+        collection.delete_one_with_session(filtre, None, session).await?;
+        return Ok(Some(middleware.reponse_ok(None, None)?))
+    }
+    let (declencheur, cible) = match (contenu_transaction.declencheur, contenu_transaction.cible) {
+        (Some(declencheur), Some(cible)) => (declencheur, cible),
+        _ => Err(Error::String(format!(
+            "senseurspassifs.transaction_sauvegarder_horaire Horaire {} sans declencheur ou cible", contenu_transaction.horaire_id)))?
+    };
+
+    let ops = doc! {
+        "$set": {
+            "nom": contenu_transaction.nom,
+            "actif": contenu_transaction.actif.unwrap_or(true),
+            "declencheur": convertir_to_bson(declencheur)?,
+            "cible": convertir_to_bson(cible)?,
+        },
+        "$setOnInsert": {
+            CHAMP_USER_ID: &user_id,
+            "horaire_id": &contenu_transaction.horaire_id,
+            CHAMP_CREATION: Utc::now(),
+        },
+        "$currentDate": { CHAMP_MODIFICATION: true },
+    };
+    let options = UpdateOptions::builder().upsert(true).build();
+    collection.update_one_with_session(filtre, ops, options, session).await?;
+
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
+
+/// Evalue les horaires actifs depuis leur derniere execution (fenetre limitee a CONST_RATTRAPAGE_MAX_MINUTES)
+/// jusqu'a la minute courante. Un declenchement manque (cedule en retard) est execute une seule fois.
+pub async fn executer_horaires<M>(middleware: &M) -> Result<(), Error>
+    where M: GenerateurMessages + MongoDao
+{
+    let maintenant = tronquer_minute(Utc::now());
+    let debut_rattrapage = maintenant - Duration::minutes(CONST_RATTRAPAGE_MAX_MINUTES);
+
+    let collection = middleware.get_collection_typed::<RowHoraire>(COLLECTIONS_HORAIRES)?;
+    let mut curseur = collection.find(doc! { "actif": {"$ne": false} }, None).await?;
+    let mut horaires = Vec::new();
+    while let Some(row) = curseur.next().await {
+        match row {
+            Ok(inner) => horaires.push(inner),
+            Err(e) => warn!("executer_horaires Erreur mapping horaire : {:?}", e)
+        }
+    }
+
+    let mut timezones: HashMap<String, Tz> = HashMap::new();
+    for horaire in horaires {
+        let tz = match timezones.get(&horaire.user_id) {
+            Some(inner) => inner.to_owned(),
+            None => {
+                let tz = parser_timezone(charger_configuration_usager(middleware, horaire.user_id.as_str()).await?.timezone.as_ref());
+                timezones.insert(horaire.user_id.clone(), tz);
+                tz
+            }
+        };
+
+        let declencheur = match preparer_declencheur(middleware, horaire.user_id.as_str(), &horaire.declencheur, Some(&horaire.cible)).await {
+            Ok(inner) => inner,
+            Err(e) => {
+                warn!("executer_horaires Horaire {} invalide : {}", horaire.horaire_id, e);
+                continue
+            }
+        };
+        // Un nouvel horaire est evalue uniquement a la minute courante
+        let debut = match horaire.derniere_execution {
+            Some(inner) => inner.max(debut_rattrapage),
+            None => maintenant - Duration::minutes(1)
+        };
+        let minute = match declencheur.dernier_declenchement(&debut, &maintenant, &tz) {
+            Some(inner) => inner,
+            None => continue
+        };
+
+        // Reserver la minute, evite une double execution
+        let filtre = doc! {
+            CHAMP_USER_ID: &horaire.user_id,
+            "horaire_id": &horaire.horaire_id,
+            "derniere_execution": {"$not": {"$gte": minute}},
+        };
+        let ops = doc! { "$set": { "derniere_execution": minute } };
+        let collection_horaires = middleware.get_collection(COLLECTIONS_HORAIRES)?;
+        if collection_horaires.update_one(filtre, ops, None).await?.modified_count == 0 {
+            continue
+        }
+
+        info!("executer_horaires Declenchement horaire {} (usager {})", horaire.horaire_id, horaire.user_id);
+        let resultat = executer_cible(middleware, &horaire, &minute).await;
+        if let Some(err) = resultat.err.as_ref() {
+            warn!("executer_horaires Horaire {} en erreur : {}", horaire.horaire_id, err);
+        }
+
+        let filtre = doc! { CHAMP_USER_ID: &horaire.user_id, "horaire_id": &horaire.horaire_id };
+        let ops = doc! { "$set": { "dernier_resultat": convertir_to_bson(resultat)? } };
+        collection_horaires.update_one(filtre, ops, None).await?;
+    }
+
+    Ok(())
+}
+
+async fn executer_cible<M>(middleware: &M, horaire: &RowHoraire, minute: &DateTime<Utc>) -> ResultatHoraire
+    where M: GenerateurMessages + MongoDao
+{
+    // horaire_id est unique par usager seulement, l'identificateur de commande doit etre unique globalement.
+    let prefixe_commande = format!("horaire/{}/{}/{}", horaire.user_id, horaire.horaire_id, minute.timestamp());
+    let resultat = match &horaire.cible {
+        CibleHoraire::Scene { scene_id } => {
+            match executer_scene(middleware, horaire.user_id.as_str(), scene_id.as_str(), prefixe_commande.as_str()).await {
+                Ok(Some(resultats)) => match resultats.iter().filter(|r| !r.ok).count() {
+                    0 => Ok(()),
+                    echecs => Err(format!("{} actions de la scene en echec", echecs))
+                },
+                Ok(None) => Err(format!("Scene {} inconnue", scene_id)),
+                Err(e) => Err(format!("{:?}", e))
+            }
+        },
+        CibleHoraire::Commande(action) => {
+            match actionner_appareil(middleware, horaire.user_id.as_str(), prefixe_commande, action.clone(), CONST_COMMANDE_TIMEOUT_DEFAUT_SECS).await {
+                Ok(Some(_)) => Ok(()),
+                Ok(None) => Err(format!("Appareil {} inconnu", action.uuid_appareil)),
+                Err(e) => Err(format!("{:?}", e))
+            }
+        }
+    };
+    match resultat {
+        Ok(()) => ResultatHoraire { ok: true, err: None },
+        Err(e) => ResultatHoraire { ok: false, err: Some(e) }
+    }
+}
+
+#[derive(Serialize)]
+struct ReponseGetHoraires {
+    ok: bool,
+    horaires: Vec<RowHoraire>,
+}
+
+pub async fn requete_get_horaires<M>(middleware: &M, m: MessageValide, _gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_get_horaires Consommer requete : {:?}", & m.type_message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    };
+
+    let options = FindOptions::builder().sort(doc! {"nom": 1}).build();
+    let collection = middleware.get_collection_typed::<RowHoraire>(COLLECTIONS_HORAIRES)?;
+    let mut curseur = collection.find(doc!{CHAMP_USER_ID: &user_id}, options).await?;
+    let mut horaires = Vec::new();
+    while let Some(row) = curseur.next().await {
+        match row {
+            Ok(row) => horaires.push(row),
+            Err(e) => warn!("requete_get_horaires Erreur mapping horaire : {:?}", e)
+        }
+    }
+
+    let reponse = ReponseGetHoraires { ok: true, horaires };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+#[derive(Deserialize)]
+struct RequetePrevisualiserHoraire {
+    /// Horaire sauvegarde.
+    horaire_id: Option<String>,
+    /// Declencheur non sauvegarde (previsualisation avant la sauvegarde).
+    declencheur: Option<DeclencheurHoraire>,
+    cible: Option<CibleHoraire>,
+    nombre: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct ReponsePrevisualiserHoraire {
+    ok: bool,
+    timezone: String,
+    /// Declenchements en epoch secondes.
+    declenchements: Vec<i64>,
+}
+
+/// Prochains declenchements d'un horaire, dans la timezone de l'usager.
+pub async fn requete_previsualiser_horaire<M>(middleware: &M, m: MessageValide, _gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_previsualiser_horaire Consommer requete : {:?}", & m.type_message);
+    let requete: RequetePrevisualiserHoraire = deser_message_buffer!(m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    };
+
+    let (declencheur, cible) = match (requete.declencheur, requete.horaire_id.as_ref()) {
+        (Some(declencheur), _) => (declencheur, requete.cible),
+        (None, Some(horaire_id)) => {
+            let filtre = doc! { CHAMP_USER_ID: &user_id, "horaire_id": horaire_id };
+            let collection = middleware.get_collection_typed::<RowHoraire>(COLLECTIONS_HORAIRES)?;
+            match collection.find_one(filtre, None).await? {
+                Some(horaire) => (horaire.declencheur, Some(horaire.cible)),
+                None => return Ok(Some(middleware.reponse_err(None, None, Some("Horaire inconnu"))?))
+            }
+        },
+        (None, None) => return Ok(Some(middleware.reponse_err(None, None, Some("horaire_id ou declencheur requis"))?))
+    };
+
+    let declencheur = match preparer_declencheur(middleware, user_id.as_str(), &declencheur, cible.as_ref()).await {
+        Ok(inner) => inner,
+        Err(e) => return Ok(Some(middleware.reponse_err(None, None, Some(e.as_str()))?))
+    };
+
+    let configuration_usager = charger_configuration_usager(middleware, user_id.as_str()).await?;
+    let tz = parser_timezone(configuration_usager.timezone.as_ref());
+    let nombre = requete.nombre.unwrap_or(CONST_PREVISUALISATION_DEFAUT).clamp(1, CONST_PREVISUALISATION_MAX);
+    let declenchements = declencheur.prochains(&Utc::now(), &tz, nombre).iter().map(|d| d.timestamp()).collect();
+
+    let reponse = ReponsePrevisualiserHoraire { ok: true, timezone: tz.name().to_string(), declenchements };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_setup::setup;
+
+    fn toronto() -> Tz {
+        "America/Toronto".parse::<Tz>().expect("tz")
+    }
+
+    fn utc(date: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(date).expect("date").with_timezone(&Utc)
+    }
+
+    fn valeurs(champ: &[bool]) -> Vec<usize> {
+        champ.iter().enumerate().filter(|(_, v)| **v).map(|(i, _)| i).collect()
+    }
+
+    #[test]
+    fn test_champ_cron_plages_et_pas() {
+        setup("test_champ_cron_plages_et_pas");
+        assert_eq!(vec![0, 15, 30, 45], valeurs(&parser_champ_cron("*/15", 0, 59).unwrap()));
+        assert_eq!(vec![8, 9, 10, 11, 12], valeurs(&parser_champ_cron("8-12", 0, 23).unwrap()));
+        assert_eq!(vec![8, 10, 12], valeurs(&parser_champ_cron("8-12/2", 0, 23).unwrap()));
+        assert_eq!(vec![5, 25, 45], valeurs(&parser_champ_cron("5/20", 0, 59).unwrap()));
+        assert_eq!(vec![1, 3, 20, 21, 22], valeurs(&parser_champ_cron("1,3,20-22", 1, 31).unwrap()));
+    }
+
+    #[test]
+    fn test_champ_cron_invalide() {
+        setup("test_champ_cron_invalide");
+        assert!(parser_champ_cron("60", 0, 59).is_err());
+        assert!(parser_champ_cron("0", 1, 31).is_err());
+        assert!(parser_champ_cron("12-8", 0, 23).is_err());
+        assert!(parser_champ_cron("*/0", 0, 59).is_err());
+        assert!(parser_champ_cron("a", 0, 59).is_err());
+        assert!(ExpressionCron::parse("0 8 * *").is_err());
+    }
+
+    #[test]
+    fn test_jour_semaine_7_dimanche() {
+        setup("test_jour_semaine_7_dimanche");
+        let cron = ExpressionCron::parse("0 8 * * 7").unwrap();
+        let dimanche = NaiveDate::from_ymd_opt(2024, 6, 23).unwrap();
+        assert!(cron.correspond_jour(&dimanche));
+        assert!(!cron.correspond_jour(&(dimanche + Duration::days(1))));
+
+        let semaine = ExpressionCron::parse("0 8 * * 1-5").unwrap();
+        assert!(!semaine.correspond_jour(&dimanche));
+        assert!(semaine.correspond_jour(&(dimanche + Duration::days(1))));
+    }
+
+    #[test]
+    fn test_jour_mois_ou_jour_semaine() {
+        setup("test_jour_mois_ou_jour_semaine");
+        // Les deux champs restreints : le 1er du mois ou un lundi
+        let cron = ExpressionCron::parse("0 8 1 * 1").unwrap();
+        assert!(cron.correspond_jour(&NaiveDate::from_ymd_opt(2024, 6, 1).unwrap()));  // samedi
+        assert!(cron.correspond_jour(&NaiveDate::from_ymd_opt(2024, 6, 3).unwrap()));  // lundi
+        assert!(!cron.correspond_jour(&NaiveDate::from_ymd_opt(2024, 6, 4).unwrap()));
+        // Mois restreint
+        let juin = ExpressionCron::parse("0 8 * 6 *").unwrap();
+        assert!(!juin.correspond_jour(&NaiveDate::from_ymd_opt(2024, 7, 1).unwrap()));
+    }
+
+    #[test]
+    fn test_heure_ete_heure_inexistante() {
+        setup("test_heure_ete_heure_inexistante");
+        let tz = toronto();
+        let declencheur = DeclencheurPrepare::Cron(ExpressionCron::parse("30 2 * * *").unwrap());
+        // 2024-03-10 02:30 n'existe pas a Toronto
+        let date = NaiveDate::from_ymd_opt(2024, 3, 10).unwrap();
+        assert!(declencheur.declenchements_jour(date, &tz).is_empty());
+        assert_eq!(vec![utc("2024-03-11T06:30:00Z")], declencheur.declenchements_jour(date + Duration::days(1), &tz));
+    }
+
+    #[test]
+    fn test_heure_ete_heure_ambigue() {
+        setup("test_heure_ete_heure_ambigue");
+        let tz = toronto();
+        let declencheur = DeclencheurPrepare::Cron(ExpressionCron::parse("30 1 * * *").unwrap());
+        // 2024-11-03 01:30 existe deux fois a Toronto, declenche une seule fois (EDT)
+        let date = NaiveDate::from_ymd_opt(2024, 11, 3).unwrap();
+        assert_eq!(vec![utc("2024-11-03T05:30:00Z")], declencheur.declenchements_jour(date, &tz));
+    }
+
+    #[test]
+    fn test_dernier_declenchement_rattrapage() {
+        setup("test_dernier_declenchement_rattrapage");
+        let tz = toronto();
+        let declencheur = DeclencheurPrepare::Cron(ExpressionCron::parse("*/5 * * * *").unwrap());
+        let fin = utc("2024-06-21T12:07:00Z");
+        // Declenchement de 12:05 manque, rattrape une seule fois
+        assert_eq!(Some(utc("2024-06-21T12:05:00Z")),
+                   declencheur.dernier_declenchement(&utc("2024-06-21T12:00:00Z"), &fin, &tz));
+        // Deja execute a 12:05
+        assert_eq!(None, declencheur.dernier_declenchement(&utc("2024-06-21T12:05:00Z"), &fin, &tz));
+    }
+
+    #[test]
+    fn test_soleil_montreal() {
+        setup("test_soleil_montreal");
+        let tz = toronto();
+        let date = NaiveDate::from_ymd_opt(2024, 6, 21).unwrap();
+        // Montreal, solstice d'ete : lever 05:05 EDT, coucher 20:47 EDT
+        let lever = calculer_soleil(date, 45.5017, -73.5673, EvenementSoleil::Lever, &tz).unwrap();
+        let coucher = calculer_soleil(date, 45.5017, -73.5673, EvenementSoleil::Coucher, &tz).unwrap();
+        assert!((lever - utc("2024-06-21T09:05:00Z")).num_minutes().abs() <= 2, "lever {:?}", lever);
+        assert!((coucher - utc("2024-06-22T00:47:00Z")).num_minutes().abs() <= 2, "coucher {:?}", coucher);
+        assert_eq!(date, coucher.with_timezone(&tz).date_naive());
+    }
+
+    #[test]
+    fn test_soleil_polaire() {
+        setup("test_soleil_polaire");
+        let tz = "Europe/Oslo".parse::<Tz>().unwrap();
+        // Tromso au solstice d'ete, le soleil ne se couche pas
+        let date = NaiveDate::from_ymd_opt(2024, 6, 21).unwrap();
+        assert_eq!(None, calculer_soleil(date, 69.6492, 18.9553, EvenementSoleil::Coucher, &tz));
+    }
+}
@@ -1,14 +1,14 @@
 use std::cmp::max;
-use std::collections::HashMap;
-use log::{debug, error, info, warn};
-use millegrilles_common_rust::bson::doc;
+use std::collections::{BTreeMap, HashMap};
+use log::{debug, error, warn};
+use millegrilles_common_rust::bson::{doc, Document};
 use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
 use millegrilles_common_rust::chrono;
 use millegrilles_common_rust::chrono::{DateTime, Timelike, Utc};
 use millegrilles_common_rust::constantes::Securite;
 use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
 use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, convertir_to_bson, convertir_to_bson_array, MongoDao};
-use millegrilles_common_rust::mongodb::options::{FindOneOptions, UpdateOptions};
+use millegrilles_common_rust::mongodb::options::{AggregateOptions, FindOneOptions, FindOptions, UpdateOptions};
 use millegrilles_common_rust::serde::{Deserialize, Serialize};
 use millegrilles_common_rust::constantes::*;
 use millegrilles_common_rust::math::{arrondir, compter_fract_digits};
@@ -20,11 +20,13 @@ use millegrilles_common_rust::recepteur_messages::MessageValide;
 use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::epochseconds;
 use millegrilles_common_rust::bson::serde_helpers::chrono_datetime_as_bson_datetime;
 use millegrilles_common_rust::mongodb::ClientSession;
+use millegrilles_common_rust::tokio_stream::StreamExt;
 
+use crate::anomalies::traiter_anomalies_rangees;
 use crate::common::*;
-use crate::commandes::RowRelais;
 use crate::domain_manager::SenseursPassifsDomainManager;
-use crate::transactions::SenseurHoraireRow;
+use crate::relais::verifier_relai_autorise;
+use crate::transactions::{maj_appareil_senseur_horaire, SenseurHoraireRow};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct LectureAppareilInfo {
@@ -140,15 +142,9 @@ impl EvenementLecture {
         let user_id = lecture.user_id;
         let uuid_appareil = lecture.uuid_appareil;
 
-        // Verifier que le relai est autorise a signer pour cet appareil
-        let filtre = doc! {
-            CHAMP_USER_ID: &user_id,
-            CHAMP_UUID_APPAREIL: &uuid_appareil,
-            "fingerprint": fingerprint_relai
-        };
-        let collection = middleware.get_collection_typed::<RowRelais>(COLLECTIONS_RELAIS)?;
-        match collection.find_one(filtre, None).await? {
-            Some(_inner) => {
+        // Verifier que le relai est autorise a signer pour cet appareil (non expire, non revoque)
+        match verifier_relai_autorise(middleware, &user_id, &uuid_appareil, fingerprint_relai, &self.instance_id).await? {
+            true => {
                 // Ok, autorise
                 Ok(LectureAppareilInfo {
                     uuid_appareil,
@@ -158,8 +154,8 @@ impl EvenementLecture {
                     notifications: lecture.notifications,
                 })
             },
-            None => {
-                // Il n'y a pas d'autorisation
+            false => {
+                // Il n'y a pas d'autorisation (absente, expiree ou revoquee)
                 Err(format!("charger_lecture_relayee Relai {} non autorise pour appareil {}", fingerprint_relai, uuid_appareil))?
             }
         }
@@ -327,7 +323,7 @@ async fn ajouter_lecture_db<M>(middleware: &M, lecture: &LectureAppareilInfo) ->
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-struct LecturesCumulees {
+pub(crate) struct LecturesCumulees {
     user_id: String,
     #[serde(
         serialize_with = "epochseconds::serialize",
@@ -354,9 +350,12 @@ pub async fn generer_transactions_lectures_horaires<M>(middleware: &M, gestionna
 
     let collection = middleware.get_collection(COLLECTIONS_LECTURES)?;
     let mut curseur = collection.find_with_session(filtre, None, &mut session).await?;
+    let mut rangees = Vec::new();
     while let Some(row) = curseur.next(&mut session).await {
         match convertir_bson_deserializable::<LecturesCumulees>(row?) {
-            Ok(lecture) => generer_transactions(middleware, gestionnaire, lecture, &mut session).await?,
+            Ok(lecture) => if let Some(rangee) = generer_transactions(middleware, gestionnaire, lecture, &mut session).await? {
+                rangees.push(rangee);
+            },
             Err(e) => {
                 error!("lectures.generer_transactions_lectures_horaires Erreur mapping LecturesCumulees : {:?}", e);
             }
@@ -364,12 +363,17 @@ pub async fn generer_transactions_lectures_horaires<M>(middleware: &M, gestionna
     }
     session.commit_transaction().await?;
 
+    // Detection apres le commit, les evenements d'anomalies ne doivent pas preceder les rangees.
+    traiter_anomalies_rangees(middleware, rangees).await;
+
     Ok(())
 }
 
-async fn generer_transactions<M>(
+/// Soumet la transaction senseurHoraire d'une heure de lectures. Retourne la rangee horaire lorsque
+/// la transaction est sauvegardee, pour la detection d'anomalies apres le commit de la session.
+pub(crate) async fn generer_transactions<M>(
     middleware: &M, gestionnaire: &SenseursPassifsDomainManager, lectures: LecturesCumulees, session: &mut ClientSession)
-    -> Result<(), Error>
+    -> Result<Option<SenseurHoraireRow>, Error>
     where M: ValidateurX509 + GenerateurMessages + MongoDao
 {
     debug!("generer_transactions heure avant {:?} pour user_id {}, appareil : {}, senseur_id : {}",
@@ -378,6 +382,54 @@ async fn generer_transactions<M>(
     let heure = lectures.heure;
     debug!("generer_transactions Heure : {:?}", heure);
 
+    let (val_min, val_max, moyenne) = calculer_statistiques_lectures(&lectures.lectures);
+
+    let transaction = TransactionLectureHoraire {
+        heure,
+        user_id: lectures.user_id,
+        uuid_appareil: lectures.uuid_appareil,
+        senseur_id: lectures.senseur_id,
+        lectures: lectures.lectures,
+        min: val_min,
+        max: val_max,
+        avg: moyenne
+    };
+
+    debug!("Soumettre transaction : {:?}", transaction);
+    match sauvegarder_traiter_transaction_serializable_v2(
+        middleware, &transaction, gestionnaire, session, DOMAINE_NOM, TRANSACTION_SENSEUR_HORAIRE).await
+    {
+        Ok(_) => {
+            // Cleanup table lectures
+            // let heure_max = transaction_convertie.heure.get_datetime().to_owned() + chrono::Duration::hours(1);
+            let filtre = doc! {
+                CHAMP_USER_ID: &transaction.user_id,
+                CHAMP_UUID_APPAREIL: &transaction.uuid_appareil,
+                "senseur_id": &transaction.senseur_id,
+                "heure": &transaction.heure,
+            };
+
+            // debug!("transaction_senseur_horaire nettoyage lectures filtre {:?}, ops {:?}", filtre, ops);
+            debug!("transaction_senseur_horaire nettoyage lectures filtre {:?}", filtre);
+            let collection = middleware.get_collection(COLLECTIONS_LECTURES)?;
+            match collection.delete_one_with_session(filtre, None, session).await {
+                Ok(r) => {
+                    debug!("transactions.transaction_senseur_horaire Resultat suppression lectures archivess : {:?}", r);
+                }
+                Err(e) => warn!("transactions.transaction_senseur_horaire Erreur suppression lectures {:?}", e)
+            }
+            Ok(Some(SenseurHoraireRow::from(&transaction)))
+        },
+        Err(e) => {
+            error!("generer_transactions Erreur traitemnet transaction {:?}", e);
+            Ok(None)
+        }
+    }
+}
+
+/// Calcule (min, max, moyenne) des valeurs numeriques d'une liste de lectures.
+/// La moyenne est arrondie au nombre de decimales le plus eleve des lectures.
+pub(crate) fn calculer_statistiques_lectures(lectures: &Vec<LectureSenseur>) -> (Option<f64>, Option<f64>, Option<f64>) {
     let mut val_max: Option<f64> = None;
     let mut val_min: Option<f64> = None;
 
@@ -386,7 +438,7 @@ async fn generer_transactions<M>(
     let mut compte_valeurs: u32 = 0;
     let mut fract_max: u8 = 0 ;  // Nombre de digits dans partie fractionnaire (pour round avg)
 
-    for lecture in &lectures.lectures {
+    for lecture in lectures {
         if let Some(valeur) = lecture.valeur {
 
             fract_max = max(fract_max, compter_fract_digits(valeur));
@@ -424,123 +476,167 @@ async fn generer_transactions<M>(
         None
     };
 
-    let transaction = TransactionLectureHoraire {
-        heure,
-        user_id: lectures.user_id,
-        uuid_appareil: lectures.uuid_appareil,
-        senseur_id: lectures.senseur_id,
-        lectures: lectures.lectures,
-        min: val_min,
-        max: val_max,
-        avg: moyenne
-    };
+    (val_min, val_max, moyenne)
+}
 
-    debug!("Soumettre transaction : {:?}", transaction);
-    match sauvegarder_traiter_transaction_serializable_v2(
-        middleware, &transaction, gestionnaire, session, DOMAINE_NOM, TRANSACTION_SENSEUR_HORAIRE).await
-    {
-        Ok(_) => {
-            // Cleanup table lectures
-            // let heure_max = transaction_convertie.heure.get_datetime().to_owned() + chrono::Duration::hours(1);
-            let filtre = doc! {
-                CHAMP_USER_ID: &transaction.user_id,
-                CHAMP_UUID_APPAREIL: &transaction.uuid_appareil,
-                "senseur_id": &transaction.senseur_id,
-                "heure": &transaction.heure,
-            };
+/// Lectures brutes d'un senseur pour une heure qui n'a pas encore ete aggregee.
+#[derive(Clone, Debug)]
+pub(crate) struct LecturesHeureOuverte {
+    pub(crate) heure: DateTime<Utc>,
+    pub(crate) lectures: Vec<LectureSenseur>,
+}
 
-            // debug!("transaction_senseur_horaire nettoyage lectures filtre {:?}, ops {:?}", filtre, ops);
-            debug!("transaction_senseur_horaire nettoyage lectures filtre {:?}", filtre);
-            let collection = middleware.get_collection(COLLECTIONS_LECTURES)?;
-            match collection.delete_one(filtre, None).await {
-                Ok(r) => {
-                    debug!("transactions.transaction_senseur_horaire Resultat suppression lectures archivess : {:?}", r);
-                }
-                Err(e) => warn!("transactions.transaction_senseur_horaire Erreur suppression lectures {:?}", e)
+/// Charge les lectures brutes (COLLECTIONS_LECTURES) d'un senseur, triees par heure.
+/// Ces lectures n'ont pas encore ete converties en transaction senseurHoraire.
+pub(crate) async fn charger_lectures_heures_ouvertes<M>(middleware: &M, user_id: &str, uuid_appareil: &str, senseur_id: &str)
+    -> Result<Vec<LecturesHeureOuverte>, Error>
+    where M: MongoDao
+{
+    let filtre = doc! {
+        CHAMP_USER_ID: user_id,
+        CHAMP_UUID_APPAREIL: uuid_appareil,
+        "senseur_id": senseur_id,
+    };
+    let options = FindOptions::builder().sort(doc!{"heure": 1}).build();
+    let collection = middleware.get_collection(COLLECTIONS_LECTURES)?;
+    let mut curseur = collection.find(filtre, options).await?;
+
+    let mut heures = Vec::new();
+    while let Some(row) = curseur.next().await {
+        match convertir_bson_deserializable::<LecturesCumulees>(row?) {
+            Ok(mut inner) => {
+                inner.lectures.sort_by_key(|l| l.timestamp);
+                heures.push(LecturesHeureOuverte { heure: inner.heure, lectures: inner.lectures });
+            },
+            Err(e) => {
+                warn!("lectures.charger_lectures_heures_ouvertes Erreur mapping LecturesCumulees : {:?}", e);
             }
-        },
-        Err(e) => {
-            error!("generer_transactions Erreur traitemnet transaction {:?}", e)
         }
     }
 
-    Ok(())
+    Ok(heures)
 }
 
-fn heure_juste(date: &DateTime<Utc>) -> DateTime<Utc> {
+pub(crate) fn heure_juste(date: &DateTime<Utc>) -> DateTime<Utc> {
     date.with_minute(0).expect("with_minutes")
         .with_second(0).expect("with_seconds")
         .with_nanosecond(0).expect("with_nanosecond")
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 struct DeviceHourAggregateId {
     user_id: String,
     uuid_appareil: String,
     senseur_id: String,
 }
 
+#[derive(Debug, Deserialize)]
+struct DeviceHourTypeDonnees {
+    #[serde(rename="type")]
+    type_: String,
+}
+
 #[derive(Debug, Deserialize)]
 struct DeviceHourAggregateRow {
     _id: DeviceHourAggregateId,
     #[serde(with="chrono_datetime_as_bson_datetime")]
     heure: DateTime<Utc>,
+    #[serde(rename="type")]
+    type_: Option<String>,
+    avg: Option<f64>,
+    /// Type de la rangee la plus recente qui en a un (rangee sans lectures : aucun type). Le type d'une
+    /// rangee est celui de la premiere lecture de l'heure, comme pour transaction_senseur_horaire.
+    type_donnees: Option<DeviceHourTypeDonnees>,
 }
 
-/// Call after rebuilding the database to reset the sensors per device
+#[derive(Debug, Deserialize)]
+struct DeviceDayAggregateRow {
+    _id: DeviceHourAggregateId,
+    type_donnees: Option<DeviceHourTypeDonnees>,
+}
+
+/// Pipeline de la derniere rangee par senseur. Les rangees sont triees par l'index (user_id, uuid_appareil, senseur_id, heure).
+pub(crate) fn pipeline_derniere_rangee_senseur(champs_derniere: bool) -> Vec<Document> {
+    let mut groupe = doc! {
+        "_id": { CHAMP_USER_ID: "$user_id", CHAMP_UUID_APPAREIL: "$uuid_appareil", "senseur_id": "$senseur_id" },
+        "type_donnees": {"$max": {"$cond": [
+            {"$eq": [{"$type": "$type"}, "string"]},
+            {"heure": "$heure", "type": "$type"},
+            null
+        ]}},
+    };
+    if champs_derniere {
+        groupe.insert("heure", doc!{"$last": "$heure"});
+        groupe.insert("type", doc!{"$last": "$type"});
+        groupe.insert("avg", doc!{"$last": "$avg"});
+    }
+    vec![
+        doc! { "$sort": {CHAMP_USER_ID: 1, CHAMP_UUID_APPAREIL: 1, "senseur_id": 1, "heure": 1} },
+        doc! { "$group": groupe },
+    ]
+}
+
+/// Call after rebuilding the database to reset the sensors per device.
+/// Restores senseurs, lectures_disponibles and types_donnees as maintained by transaction_senseur_horaire.
 pub async fn rebuild_sensor_list<M>(middleware: &M, session: &mut ClientSession)
     -> Result<(), Error>
     where M: MongoDao
 {
     let collection_appareils = middleware.get_collection_typed::<DocAppareil>(COLLECTIONS_APPAREILS)?;
-    let collection_senseurs_horaire = middleware.get_collection_typed::<SenseurHoraireRow>(COLLECTIONS_SENSEURS_HORAIRE)?;
-
-    let pipeline = vec![
-        doc! { "$project": {CHAMP_USER_ID: 1, CHAMP_UUID_APPAREIL: 1, "senseur_id": 1, "heure": 1} },
-        doc! { "$group": {
-            "_id": { CHAMP_USER_ID: "$user_id", CHAMP_UUID_APPAREIL: "$uuid_appareil", "senseur_id": "$senseur_id" },
-            "heure": {"$max": "$heure"},
-        } }
-    ];
+    let collection_senseurs_horaire = middleware.get_collection(COLLECTIONS_SENSEURS_HORAIRE)?;
+    let collection_senseurs_quotidien = middleware.get_collection(COLLECTIONS_SENSEURS_QUOTIDIEN)?;
+    let options = AggregateOptions::builder().allow_disk_use(true).build();
+
+    // Senseurs dont les rangees horaires ont ete remplacees par des sommaires quotidiens (retention)
+    let mut senseurs_quotidien = BTreeMap::new();
+    let mut cursor = collection_senseurs_quotidien.aggregate_with_session(
+        pipeline_derniere_rangee_senseur(false), options.clone(), session).await?;
+    while cursor.advance(session).await? {
+        let row: DeviceDayAggregateRow = convertir_bson_deserializable(cursor.deserialize_current()?)?;
+        senseurs_quotidien.insert(row._id, row.type_donnees.map(|t| t.type_));
+    }
+
+    let pipeline = pipeline_derniere_rangee_senseur(true);
     debug!("rebuild_sensor_list Pipeline {:?}", pipeline);
-    let mut cursor = collection_senseurs_horaire.aggregate_with_session(pipeline, None, session).await?;
+    let mut cursor = collection_senseurs_horaire.aggregate_with_session(pipeline, options, session).await?;
 
     while cursor.advance(session).await? {
         let row: DeviceHourAggregateRow = convertir_bson_deserializable(cursor.deserialize_current()?)?;
         debug!("rebuild_sensor_list Loading values for {:?}", row);
-        let filtre_row = doc!{
-            CHAMP_USER_ID: &row._id.user_id,
-            CHAMP_UUID_APPAREIL: &row._id.uuid_appareil,
-            "senseur_id": &row._id.senseur_id,
-            "heure": &row.heure,
+
+        // Les sommaires quotidiens sont plus vieux que les rangees horaires
+        let type_quotidien = senseurs_quotidien.remove(&row._id).flatten();
+        let type_donnees = row.type_donnees.map(|t| t.type_).or(type_quotidien);
+        maj_appareil_senseur_horaire(
+            middleware, &row._id.user_id, &row._id.uuid_appareil, &row._id.senseur_id, type_donnees.as_ref(), session).await?;
+
+        let heure = row.heure;
+        let value = match row.avg {
+            Some(value) => doc!{
+                "timestamp": heure.timestamp(),
+                "type": row.type_,
+                "valeur": value,
+            },
+            None => doc!{
+                "timestamp": heure.timestamp(),
+                "type": row.type_,
+            }
         };
-        let lecture = collection_senseurs_horaire.find_one_with_session(filtre_row, None, session).await?;
-        if let Some(lecture) = lecture {
-            debug!("rebuild_sensor_list Lecture loaded: {:?}", lecture);
-            let value = match lecture.avg {
-                Some(value) => doc!{
-                    "timestamp": lecture.heure.timestamp(),
-                    "type": lecture.type_,
-                    "valeur": value,
-                },
-                None => doc!{
-                    "timestamp": lecture.heure.timestamp(),
-                    "type": lecture.type_,
-                }
-            };
 
-            let filtre = doc!{CHAMP_USER_ID: lecture.user_id, CHAMP_UUID_APPAREIL: lecture.uuid_appareil};
-            let ops = doc!{
-                "$set": { format!{"senseurs.{}", lecture.senseur_id}: value },
-                "$currentDate": { CHAMP_MODIFICATION: true }
-            };
+        let filtre = doc!{CHAMP_USER_ID: &row._id.user_id, CHAMP_UUID_APPAREIL: &row._id.uuid_appareil};
+        let ops = doc!{
+            "$set": { format!{"senseurs.{}", row._id.senseur_id}: value },
+            "$currentDate": { CHAMP_MODIFICATION: true }
+        };
 
-            debug!("rebuild_sensor_list Row filtre: {:?} ops {:?}", filtre, ops);
+        debug!("rebuild_sensor_list Row filtre: {:?} ops {:?}", filtre, ops);
 
-            collection_appareils.update_one_with_session(filtre, ops, None, session).await?;
-        } else {
-            info!("rebuild_sensor_list No match for {:?}", row);
-        }
+        collection_appareils.update_one_with_session(filtre, ops, None, session).await?;
+    }
+
+    for (senseur, type_donnees) in senseurs_quotidien {
+        maj_appareil_senseur_horaire(
+            middleware, &senseur.user_id, &senseur.uuid_appareil, &senseur.senseur_id, type_donnees.as_ref(), session).await?;
     }
 
     Ok(())
@@ -8,6 +8,25 @@ mod builder;
 mod domain_manager;
 mod constants;
 mod maintenance;
+mod statistiques;
+mod reduction;
+mod completude;
+mod anomalies;
+mod degres_jours;
+mod retention;
+mod compaction;
+mod regeneration;
+mod coherence;
+mod migration;
+mod relais;
+mod sante_relais;
+mod actionneurs;
+mod boite_envoi;
+mod scenes;
+mod horaires;
+mod programmes;
+mod simulateur;
+mod gabarits;
 
 use log::{info};
 use millegrilles_common_rust::tokio as tokio;
@@ -6,6 +6,7 @@ use millegrilles_common_rust::constantes::*;
 use millegrilles_common_rust::error::Error;
 use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
 use millegrilles_common_rust::mongo_dao::MongoDao;
+use millegrilles_common_rust::mongodb::ClientSession;
 
 use crate::common::{DocAppareil, COLLECTIONS_APPAREILS, DOMAINE_NOM};
 use crate::evenements::EvenementPresenceAppareilUser;
@@ -77,3 +78,42 @@ where M: GenerateurMessages + MongoDao
 
     Ok(())
 }
+
+/// Marks offline the devices connected through a relay instance (relay disconnect or lost heartbeat).
+/// Returns the number of devices disconnected.
+pub async fn deconnecter_appareils_instance<M>(middleware: &M, instance_id: &str, session: &mut ClientSession)
+    -> Result<u64, Error>
+where M: GenerateurMessages + MongoDao
+{
+    let collection = middleware.get_collection_typed::<DocAppareil>(COLLECTIONS_APPAREILS)?;
+    let filtre = doc!{ "instance_id": instance_id, "connecte": true };
+    let mut cursor = collection.find_with_session(filtre.clone(), None, session).await?;
+    while cursor.advance(session).await? {
+        let device = cursor.deserialize_current()?;
+
+        // Emit event for device
+        {
+            if let Some(user_id) = device.user_id {
+                let evenement_reemis = EvenementPresenceAppareilUser {
+                    uuid_appareil: device.uuid_appareil,
+                    user_id,
+                    version: device.version,
+                    connecte: false
+                };
+                let routage = RoutageMessageAction::builder(DOMAINE_NOM, "presenceAppareil", vec![Securite::L2Prive])
+                    .partition(&evenement_reemis.user_id)
+                    .build();
+                middleware.emettre_evenement(routage, &evenement_reemis).await?;
+            }
+        }
+    }
+
+    let ops = doc! {
+        "$unset": {"instance_id": true},
+        "$set": {"connecte": false},
+        "$currentDate": {CHAMP_MODIFICATION: true},
+    };
+    let resultat = collection.update_many_with_session(filtre, ops, None, session).await?;
+
+    Ok(resultat.modified_count)
+}
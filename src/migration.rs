@@ -0,0 +1,427 @@
+use std::collections::{BTreeMap, HashMap};
+use log::{debug, info, warn};
+
+use millegrilles_common_rust::bson::{doc, Document};
+use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
+use millegrilles_common_rust::chrono::{DateTime, Utc};
+use millegrilles_common_rust::constantes::*;
+use millegrilles_common_rust::db_structs::TransactionValide;
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::GenerateurMessages;
+use millegrilles_common_rust::middleware::sauvegarder_traiter_transaction_serializable_v2;
+use millegrilles_common_rust::millegrilles_cryptographie::deser_message_buffer;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::MessageMilleGrillesBufferDefault;
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, convertir_to_bson, MongoDao};
+use millegrilles_common_rust::mongodb::options::{FindOptions, UpdateOptions};
+use millegrilles_common_rust::mongodb::ClientSession;
+use millegrilles_common_rust::recepteur_messages::MessageValide;
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+use millegrilles_common_rust::serde_json;
+use millegrilles_common_rust::tokio_stream::StreamExt;
+
+use crate::common::*;
+use crate::compaction::{RangeeSenseurHoraire, TransactionSenseurHoraireMensuel};
+use crate::constants::COLLECTION_NAME_TRANSACTIONS;
+use crate::domain_manager::SenseursPassifsDomainManager;
+use crate::lectures::{calculer_statistiques_lectures, heure_juste};
+
+/// Nombre d'identificateurs de transactions par requete $in.
+const CONST_MIGRATION_TAILLE_LOT: usize = 1000;
+
+/// Transaction lecture du modele legacy. L'appareil est identifie par uuid_senseur et le senseur par senseur.
+#[derive(Deserialize)]
+struct TransactionLecturesLegacy {
+    uuid_senseur: String,
+    senseur: String,
+    instance_id: String,
+    user_id: String,
+    #[serde(rename="type")]
+    type_: String,
+    lectures: Vec<LectureTransaction>,
+}
+
+#[derive(Deserialize)]
+struct TransactionMajSenseurLegacy {
+    uuid_senseur: String,
+    descriptif: Option<String>,
+    displays: Option<HashMap<String, ParametresDisplay>>,
+}
+
+/// Cle d'un senseur legacy dans une transaction lecture, majSenseur ou suppressionSenseur.
+#[derive(Deserialize)]
+struct CleTransactionLegacy {
+    uuid_senseur: String,
+    user_id: Option<String>,
+    senseur: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RowTransactionLegacy {
+    id: String,
+    contenu: String,
+}
+
+/// Correspondance d'un senseur legacy (uuid_senseur, senseur) vers un senseur d'appareil (uuid_appareil, senseur_id).
+#[derive(Serialize, Deserialize)]
+pub struct TransactionMigrationSenseurLegacy {
+    pub user_id: String,
+    pub uuid_senseur: String,
+    pub senseur: String,
+    pub instance_id: String,
+    pub uuid_appareil: String,
+    pub senseur_id: String,
+    #[serde(rename="type", skip_serializing_if = "Option::is_none")]
+    pub type_: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub descriptif: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub displays: Option<HashMap<String, ParametresDisplay>>,
+    /// Nombre de transactions lecture converties
+    pub transactions: usize,
+    /// Nombre de rangees horaires produites
+    pub heures: usize,
+}
+
+#[derive(Deserialize)]
+struct CommandeMigrerTransactionsLegacy {
+    /// Correspondance uuid_senseur -> uuid_appareil. L'appareil garde l'identificateur uuid_senseur si absent.
+    appareils: Option<HashMap<String, String>>,
+}
+
+#[derive(Serialize)]
+struct ReponseMigrerTransactionsLegacy {
+    ok: bool,
+    senseurs: usize,
+    transactions_migrees: usize,
+    heures: usize,
+    transactions_invalides: usize,
+    erreurs: usize,
+}
+
+fn filtre_transactions_legacy(action: &str) -> Document {
+    doc! {
+        "routage.domaine": DOMAINE_NOM,
+        "routage.action": action,
+        CHAMP_MIGRATION_LEGACY: {"$exists": false},
+    }
+}
+
+/// Indique si une transaction legacy (lecture, majSenseur, suppressionSenseur) vise un senseur deja migre.
+/// Les transactions legacy restent appliquees par leur handler tant que la migration n'est pas conservee.
+/// Une transaction lecture marquee par la migration est toujours ignoree : lors d'une regeneration, la
+/// correspondance des senseurs n'est pas encore reconstruite et les rangees viennent de senseurHoraireMensuel.
+pub async fn transaction_legacy_migree<M>(middleware: &M, action: &str, transaction: &TransactionValide, session: &mut ClientSession)
+    -> Result<bool, Error>
+    where M: MongoDao
+{
+    match action {
+        TRANSACTION_LECTURE => {
+            let filtre = doc! {
+                "id": &transaction.transaction.id,
+                CHAMP_MIGRATION_LEGACY: {"$exists": true},
+            };
+            let collection = middleware.get_collection(COLLECTION_NAME_TRANSACTIONS)?;
+            if collection.count_documents_with_session(filtre, None, session).await? > 0 {
+                return Ok(true)
+            }
+        },
+        TRANSACTION_MAJ_SENSEUR | TRANSACTION_SUPPRESSION_SENSEUR => (),
+        _ => return Ok(false)
+    }
+
+    let cle: CleTransactionLegacy = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+    let user_id = match cle.user_id {
+        Some(inner) => Some(inner),
+        None => transaction.certificat.get_user_id()?
+    };
+
+    let mut filtre = doc! { CHAMP_UUID_SENSEUR: &cle.uuid_senseur };
+    if let Some(user_id) = user_id.as_ref() {
+        filtre.insert(CHAMP_USER_ID, user_id);
+    }
+    if let Some(senseur) = cle.senseur.as_ref() {
+        filtre.insert("senseur", senseur);
+    }
+    let collection = middleware.get_collection(COLLECTIONS_MIGRATION_SENSEURS)?;
+    Ok(collection.count_documents_with_session(filtre, None, session).await? > 0)
+}
+
+/// Convertit les transactions lecture legacy (uuid_senseur) en transactions senseurHoraireMensuel
+/// (rangees horaires par uuid_appareil/senseur_id) et conserve la correspondance des senseurs.
+/// Les transactions lecture converties sont conservees et marquees comme remplacees, un senseur
+/// est traite par session. Les transactions deja marquees ne sont pas reconverties.
+pub async fn commande_migrer_transactions_legacy<M>(middleware: &M, m: MessageValide, gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    debug!("commande_migrer_transactions_legacy Consommer commande : {:?}", & m.type_message);
+    if ! m.certificat.verifier_exchanges(vec![Securite::L3Protege, Securite::L4Secure])? &&
+        ! m.certificat.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE)? {
+        return Ok(Some(middleware.reponse_err(None, None, Some("Acces refuse"))?))
+    }
+    let commande: CommandeMigrerTransactionsLegacy = deser_message_buffer!(m.message);
+    let appareils = commande.appareils.unwrap_or_default();
+
+    let collection_transactions = middleware.get_collection(COLLECTION_NAME_TRANSACTIONS)?;
+
+    // Identificateurs des transactions lecture par senseur legacy. Le contenu est recharge par senseur.
+    let mut senseurs: BTreeMap<(String, String, String), (String, Vec<String>)> = BTreeMap::new();
+    let mut transactions_invalides = 0;
+    {
+        let options = FindOptions::builder().projection(doc!{"id": 1, "contenu": 1}).build();
+        let mut curseur = collection_transactions.find(filtre_transactions_legacy(TRANSACTION_LECTURE), options).await?;
+        while let Some(d) = curseur.next().await {
+            let row: RowTransactionLegacy = convertir_bson_deserializable(d?)?;
+            let contenu: TransactionLecturesLegacy = match serde_json::from_str(row.contenu.as_str()) {
+                Ok(inner) => inner,
+                Err(e) => {
+                    warn!("commande_migrer_transactions_legacy Transaction {} invalide, conservee : {:?}", row.id, e);
+                    transactions_invalides += 1;
+                    continue
+                }
+            };
+            senseurs.entry((contenu.user_id, contenu.uuid_senseur, contenu.senseur))
+                .or_insert_with(|| (contenu.instance_id, Vec::new()))
+                .1.push(row.id);
+        }
+    }
+
+    // Dernier descriptif et derniers displays de chaque senseur legacy
+    let mut descriptifs: HashMap<String, String> = HashMap::new();
+    let mut displays: HashMap<String, HashMap<String, ParametresDisplay>> = HashMap::new();
+    {
+        let options = FindOptions::builder()
+            .projection(doc!{"contenu": 1})
+            .sort(doc!{"estampille": 1})
+            .build();
+        let filtre = doc! { "routage.domaine": DOMAINE_NOM, "routage.action": TRANSACTION_MAJ_SENSEUR };
+        let mut curseur = collection_transactions.find(filtre, options).await?;
+        while let Some(d) = curseur.next().await {
+            let contenu = match d?.get_str("contenu") {
+                Ok(inner) => inner.to_string(),
+                Err(_) => continue
+            };
+            if let Ok(contenu) = serde_json::from_str::<TransactionMajSenseurLegacy>(contenu.as_str()) {
+                if let Some(descriptif) = contenu.descriptif {
+                    descriptifs.insert(contenu.uuid_senseur.clone(), descriptif);
+                }
+                if let Some(inner) = contenu.displays {
+                    displays.insert(contenu.uuid_senseur, inner);
+                }
+            }
+        }
+    }
+
+    info!("commande_migrer_transactions_legacy Migration de {} senseurs legacy", senseurs.len());
+
+    let mut reponse = ReponseMigrerTransactionsLegacy {
+        ok: true, senseurs: 0, transactions_migrees: 0, heures: 0, transactions_invalides, erreurs: 0,
+    };
+    for ((user_id, uuid_senseur, senseur), (instance_id, ids)) in senseurs {
+        let uuid_appareil = appareils.get(&uuid_senseur).cloned().unwrap_or_else(|| uuid_senseur.clone());
+        let mut migration = TransactionMigrationSenseurLegacy {
+            descriptif: descriptifs.get(&uuid_senseur).cloned(),
+            displays: displays.get(&uuid_senseur).cloned(),
+            user_id, uuid_senseur, senseur_id: senseur.clone(), senseur, instance_id, uuid_appareil,
+            type_: None, transactions: ids.len(), heures: 0,
+        };
+
+        let mut session = middleware.get_session().await?;
+        session.start_transaction(None).await?;
+        match migrer_senseur(middleware, gestionnaire, &mut migration, &ids, &mut session).await {
+            Ok(()) => {
+                session.commit_transaction().await?;
+                reponse.senseurs += 1;
+                reponse.transactions_migrees += ids.len();
+                reponse.heures += migration.heures;
+            },
+            Err(e) => {
+                session.abort_transaction().await?;
+                warn!("commande_migrer_transactions_legacy Erreur migration senseur {}/{} : {:?}",
+                    migration.uuid_senseur, migration.senseur, e);
+                reponse.erreurs += 1;
+            }
+        }
+    }
+
+    info!("commande_migrer_transactions_legacy {} transactions migrees pour {} senseurs ({} heures), {} erreurs",
+        reponse.transactions_migrees, reponse.senseurs, reponse.heures, reponse.erreurs);
+
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+async fn migrer_senseur<M>(
+    middleware: &M, gestionnaire: &SenseursPassifsDomainManager, migration: &mut TransactionMigrationSenseurLegacy,
+    ids: &Vec<String>, session: &mut ClientSession
+)
+    -> Result<(), Error>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    let collection_transactions = middleware.get_collection(COLLECTION_NAME_TRANSACTIONS)?;
+
+    // Lectures regroupees par heure
+    let mut heures: BTreeMap<DateTime<Utc>, Vec<LectureSenseur>> = BTreeMap::new();
+    for lot in ids.chunks(CONST_MIGRATION_TAILLE_LOT) {
+        let filtre = doc! { "id": {"$in": lot.to_vec()} };
+        let options = FindOptions::builder().projection(doc!{"id": 1, "contenu": 1}).build();
+        let mut curseur = collection_transactions.find_with_session(filtre, options, session).await?;
+        while let Some(d) = curseur.next(session).await {
+            let row: RowTransactionLegacy = convertir_bson_deserializable(d?)?;
+            let contenu: TransactionLecturesLegacy = serde_json::from_str(row.contenu.as_str())?;
+            for lecture in contenu.lectures {
+                heures.entry(heure_juste(&lecture.timestamp)).or_default().push(LectureSenseur {
+                    timestamp: lecture.timestamp,
+                    type_: contenu.type_.clone(),
+                    valeur: Some(lecture.valeur),
+                    valeur_str: None,
+                });
+            }
+            migration.type_ = Some(contenu.type_);
+        }
+    }
+
+    // Une transaction senseurHoraireMensuel par mois
+    let mut mois: BTreeMap<String, Vec<RangeeSenseurHoraire>> = BTreeMap::new();
+    for (heure, mut lectures) in heures {
+        lectures.sort_by_key(|l| l.timestamp);
+        lectures.dedup_by_key(|l| l.timestamp);
+        let (min, max, avg) = calculer_statistiques_lectures(&lectures);
+        let transaction_heure = TransactionLectureHoraire {
+            heure,
+            user_id: migration.user_id.clone(),
+            uuid_appareil: migration.uuid_appareil.clone(),
+            senseur_id: migration.senseur_id.clone(),
+            lectures, min, max, avg,
+        };
+        mois.entry(heure.format("%Y-%m").to_string()).or_default()
+            .push(RangeeSenseurHoraire::from(&transaction_heure));
+        migration.heures += 1;
+    }
+
+    for (nom_mois, rangees) in mois {
+        let transaction = TransactionSenseurHoraireMensuel {
+            user_id: migration.user_id.clone(),
+            uuid_appareil: migration.uuid_appareil.clone(),
+            senseur_id: migration.senseur_id.clone(),
+            mois: nom_mois,
+            rangees,
+            sommaires_quotidiens: None,
+        };
+        sauvegarder_traiter_transaction_serializable_v2(
+            middleware, &transaction, gestionnaire, session, DOMAINE_NOM, TRANSACTION_SENSEUR_HORAIRE_MENSUEL).await?;
+    }
+
+    sauvegarder_traiter_transaction_serializable_v2(
+        middleware, &*migration, gestionnaire, session, DOMAINE_NOM, TRANSACTION_MIGRATION_SENSEUR_LEGACY).await?;
+
+    // Les transactions lecture sont conservees, marquees comme remplacees par la migration
+    let ops = doc! {
+        "$set": {
+            CHAMP_MIGRATION_LEGACY: {
+                CHAMP_UUID_APPAREIL: &migration.uuid_appareil,
+                "senseur_id": &migration.senseur_id,
+                "date": Utc::now(),
+            }
+        }
+    };
+    for lot in ids.chunks(CONST_MIGRATION_TAILLE_LOT) {
+        let filtre = doc! { "id": {"$in": lot.to_vec()} };
+        collection_transactions.update_many_with_session(filtre, ops.clone(), None, session).await?;
+    }
+
+    Ok(())
+}
+
+/// Conserve la correspondance d'un senseur legacy. L'instance legacy est conservee pour listeNoeuds.
+/// Le descriptif et les displays legacy sont reportes dans la configuration de l'appareil lorsqu'absents,
+/// la lecture courante legacy du senseur est retiree.
+pub async fn transaction_migration_senseur_legacy<M>(middleware: &M, transaction: TransactionValide, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("transaction_migration_senseur_legacy Consommer transaction : {:?}", transaction.transaction.id);
+    let contenu: TransactionMigrationSenseurLegacy = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+
+    let filtre = doc! {
+        CHAMP_USER_ID: &contenu.user_id,
+        CHAMP_UUID_SENSEUR: &contenu.uuid_senseur,
+        "senseur": &contenu.senseur,
+    };
+    let ops = doc! {
+        "$set": convertir_to_bson(&contenu)?,
+        "$setOnInsert": { CHAMP_CREATION: Utc::now() },
+        "$currentDate": { CHAMP_MODIFICATION: true },
+    };
+    let options = UpdateOptions::builder().upsert(true).build();
+    middleware.get_collection(COLLECTIONS_MIGRATION_SENSEURS)?
+        .update_one_with_session(filtre, ops, options, session).await?;
+
+    let filtre_instance = doc! { CHAMP_INSTANCE_ID: &contenu.instance_id };
+    let ops_instance = doc! {
+        "$setOnInsert": {
+            CHAMP_CREATION: Utc::now(),
+            CHAMP_INSTANCE_ID: &contenu.instance_id,
+        },
+        "$currentDate": { CHAMP_MODIFICATION: true },
+    };
+    let options = UpdateOptions::builder().upsert(true).build();
+    middleware.get_collection(COLLECTIONS_INSTANCES)?
+        .update_one_with_session(filtre_instance, ops_instance, options, session).await?;
+
+    appliquer_configuration_legacy(middleware, &contenu, session).await?;
+
+    // Lecture courante du modele legacy, remplacee par les lectures de l'appareil
+    let filtre_lectures = doc! {
+        CHAMP_USER_ID: &contenu.user_id,
+        CHAMP_UUID_SENSEUR: &contenu.uuid_senseur,
+    };
+    let ops_lectures = doc! { "$unset": { format!("{}.{}", CHAMP_SENSEURS, &contenu.senseur): true } };
+    middleware.get_collection(COLLECTIONS_LECTURES)?
+        .update_one_with_session(filtre_lectures, ops_lectures, None, session).await?;
+
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
+
+/// Reporte le descriptif et les displays legacy dans la configuration de l'appareil. Une valeur deja
+/// configuree sur l'appareil a preseance.
+async fn appliquer_configuration_legacy<M>(middleware: &M, migration: &TransactionMigrationSenseurLegacy, session: &mut ClientSession)
+    -> Result<(), Error>
+    where M: MongoDao
+{
+    let filtre = doc! { CHAMP_USER_ID: &migration.user_id, CHAMP_UUID_APPAREIL: &migration.uuid_appareil };
+    let collection = middleware.get_collection(COLLECTIONS_APPAREILS)?;
+    let configuration = match collection.find_one_with_session(filtre.clone(), None, session).await? {
+        Some(inner) => inner.get_document("configuration").ok().cloned(),
+        None => None
+    };
+    let present = |champ: &str| configuration.as_ref().map(|c| c.contains_key(champ)).unwrap_or(false);
+
+    let mut set_ops = doc! {};
+    if let Some(inner) = migration.descriptif.as_ref() {
+        if ! present("descriptif") {
+            set_ops.insert("configuration.descriptif", inner);
+        }
+    }
+    if let Some(inner) = migration.displays.as_ref() {
+        if ! present("displays") {
+            set_ops.insert("configuration.displays", convertir_to_bson(inner)?);
+        }
+    }
+    if set_ops.is_empty() {
+        return Ok(())
+    }
+
+    let ops = doc! {
+        "$set": set_ops,
+        "$setOnInsert": {
+            CHAMP_CREATION: Utc::now(),
+            CHAMP_UUID_APPAREIL: &migration.uuid_appareil,
+            CHAMP_USER_ID: &migration.user_id,
+        },
+        "$currentDate": { CHAMP_MODIFICATION: true },
+    };
+    let options = UpdateOptions::builder().upsert(true).build();
+    collection.update_one_with_session(filtre, ops, options, session).await?;
+
+    Ok(())
+}
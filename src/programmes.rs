@@ -0,0 +1,702 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use log::{debug, warn};
+
+use millegrilles_common_rust::bson::{doc, Document};
+use millegrilles_common_rust::bson::serde_helpers::chrono_datetime_as_bson_datetime;
+use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
+use millegrilles_common_rust::chrono::{DateTime, Utc};
+use millegrilles_common_rust::db_structs::TransactionValide;
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::GenerateurMessages;
+use millegrilles_common_rust::middleware::sauvegarder_traiter_transaction_v2;
+use millegrilles_common_rust::millegrilles_cryptographie::deser_message_buffer;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::{epochseconds, MessageMilleGrillesBufferDefault};
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, convertir_to_bson, MongoDao};
+use millegrilles_common_rust::mongodb::ClientSession;
+use millegrilles_common_rust::mongodb::options::{FindOneOptions, FindOptions};
+use millegrilles_common_rust::recepteur_messages::MessageValide;
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+use millegrilles_common_rust::serde_json;
+use millegrilles_common_rust::serde_json::Value;
+use millegrilles_common_rust::tokio_stream::StreamExt;
+
+use crate::common::*;
+use crate::domain_manager::SenseursPassifsDomainManager;
+use crate::transactions::{sauvegarder_programme_appareil, TransactionMajAppareil, TransactionSauvegarderProgramme};
+
+/// Nombre maximal de versions retournees par getVersionsProgramme.
+const CONST_VERSIONS_PROGRAMME_LIMITE: i64 = 100;
+
+/// Type d'un argument de programme.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TypeArgument {
+    Nombre { min: Option<f64>, max: Option<f64> },
+    Entier { min: Option<i64>, max: Option<i64> },
+    /// Liste de senseur_id. Un senseur d'un autre appareil est reference avec uuid_appareil:senseur_id.
+    ListeSenseurs,
+    /// Liste libre (e.g. plages horaires), contenu non valide.
+    Liste,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SchemaArgument {
+    pub nom: &'static str,
+    #[serde(flatten)]
+    pub type_argument: TypeArgument,
+    pub requis: bool,
+    pub description: &'static str,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ClasseProgramme {
+    pub class: &'static str,
+    pub description: &'static str,
+    pub args: &'static [SchemaArgument],
+}
+
+const ARG_PRECISION: SchemaArgument = SchemaArgument {
+    nom: "precision", type_argument: TypeArgument::Nombre { min: Some(0.0), max: Some(20.0) }, requis: false,
+    description: "Ecart tolere autour de la cible avant de changer l'etat des switches",
+};
+const ARG_DUREE_ON_MIN: SchemaArgument = SchemaArgument {
+    nom: "duree_on_min", type_argument: TypeArgument::Entier { min: Some(0), max: Some(86_400) }, requis: false,
+    description: "Duree minimale (secondes) en marche",
+};
+const ARG_DUREE_OFF_MIN: SchemaArgument = SchemaArgument {
+    nom: "duree_off_min", type_argument: TypeArgument::Entier { min: Some(0), max: Some(86_400) }, requis: false,
+    description: "Duree minimale (secondes) a l'arret",
+};
+
+const ARGS_TEMPERATURE: &[SchemaArgument] = &[
+    SchemaArgument { nom: "senseurs", type_argument: TypeArgument::ListeSenseurs, requis: true, description: "Senseurs de temperature" },
+    SchemaArgument { nom: "switches", type_argument: TypeArgument::ListeSenseurs, requis: true, description: "Switches controlees" },
+    SchemaArgument {
+        nom: "temperature", type_argument: TypeArgument::Nombre { min: Some(-50.0), max: Some(100.0) }, requis: true,
+        description: "Temperature cible (C)",
+    },
+    ARG_PRECISION,
+    ARG_DUREE_ON_MIN,
+    ARG_DUREE_OFF_MIN,
+];
+
+/// Schemas des classes de programmes documentees. Ce registre sert uniquement a valider les arguments
+/// connus : une classe absente (ou un argument absent du schema) est transmise telle quelle au relai.
+/// La liste des classes offertes a l'usager est completee par les classes configurees sur ses appareils
+/// (voir requete_get_classes_programmes).
+pub const CLASSES_PROGRAMMES: &[ClasseProgramme] = &[
+    ClasseProgramme {
+        class: "programmes.environnement.Humidificateur",
+        description: "Maintient l'humidite relative au-dessus de la cible",
+        args: &[
+            SchemaArgument { nom: "senseurs_humidite", type_argument: TypeArgument::ListeSenseurs, requis: true, description: "Senseurs d'humidite" },
+            SchemaArgument { nom: "switches_humidificateurs", type_argument: TypeArgument::ListeSenseurs, requis: true, description: "Switches des humidificateurs" },
+            SchemaArgument {
+                nom: "humidite", type_argument: TypeArgument::Nombre { min: Some(0.0), max: Some(100.0) }, requis: true,
+                description: "Humidite relative cible (%)",
+            },
+            ARG_PRECISION,
+            ARG_DUREE_ON_MIN,
+            ARG_DUREE_OFF_MIN,
+        ],
+    },
+    ClasseProgramme {
+        class: "programmes.environnement.Chauffage",
+        description: "Maintient la temperature au-dessus de la cible",
+        args: ARGS_TEMPERATURE,
+    },
+    ClasseProgramme {
+        class: "programmes.environnement.Climatisation",
+        description: "Maintient la temperature sous la cible",
+        args: ARGS_TEMPERATURE,
+    },
+    ClasseProgramme {
+        class: "programmes.horaire.HoraireHebdomadaire",
+        description: "Active les switches selon des plages horaires hebdomadaires",
+        args: &[
+            SchemaArgument { nom: "switches", type_argument: TypeArgument::ListeSenseurs, requis: true, description: "Switches controlees" },
+            SchemaArgument { nom: "horaire", type_argument: TypeArgument::Liste, requis: true, description: "Plages horaires (jour, heure, minute, etat)" },
+        ],
+    },
+];
+
+pub fn trouver_classe_programme(class: &str) -> Option<&'static ClasseProgramme> {
+    CLASSES_PROGRAMMES.iter().find(|c| c.class == class)
+}
+
+/// Erreur de validation d'un programme, retournee a l'usager.
+#[derive(Clone, Debug, Serialize)]
+pub struct ErreurValidationProgramme {
+    pub programme_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub argument: Option<String>,
+    /// argument_requis, type_invalide, hors_limites, senseur_inconnu
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl ErreurValidationProgramme {
+    fn new<S: ToString>(programme: &ProgrammeAppareil, argument: Option<&str>, code: &'static str, message: S) -> Self {
+        Self {
+            programme_id: programme.programme_id.clone(),
+            argument: argument.map(|a| a.to_string()),
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RowSenseursAppareil {
+    uuid_appareil: String,
+    senseurs: Option<Document>,
+}
+
+/// Charge les senseurs connus des appareils de l'usager (uuid_appareil:senseur_id).
+async fn charger_senseurs_usager<M>(middleware: &M, user_id: &str) -> Result<HashSet<String>, Error>
+    where M: MongoDao
+{
+    let options = FindOptions::builder().projection(doc! {CHAMP_UUID_APPAREIL: 1, "senseurs": 1}).build();
+    let collection = middleware.get_collection_typed::<RowSenseursAppareil>(COLLECTIONS_APPAREILS)?;
+    let mut curseur = collection.find(doc! {CHAMP_USER_ID: user_id}, options).await?;
+    let mut senseurs = HashSet::new();
+    while let Some(row) = curseur.next().await {
+        match row {
+            Ok(appareil) => if let Some(inner) = appareil.senseurs {
+                for senseur_id in inner.keys() {
+                    senseurs.insert(format!("{}:{}", appareil.uuid_appareil, senseur_id));
+                }
+            },
+            Err(e) => warn!("charger_senseurs_usager Erreur mapping appareil : {:?}", e)
+        }
+    }
+    Ok(senseurs)
+}
+
+fn valider_argument(programme: &ProgrammeAppareil, schema: &SchemaArgument, valeur: &Value, uuid_appareil: &str,
+                    senseurs: Option<&HashSet<String>>, erreurs: &mut Vec<ErreurValidationProgramme>)
+{
+    let nom = Some(schema.nom);
+    match &schema.type_argument {
+        TypeArgument::Nombre { min, max } => match valeur.as_f64() {
+            Some(v) => {
+                if min.map(|m| v < m).unwrap_or(false) || max.map(|m| v > m).unwrap_or(false) {
+                    erreurs.push(ErreurValidationProgramme::new(programme, nom, "hors_limites",
+                        format!("{} doit etre entre {:?} et {:?}", schema.nom, min, max)));
+                }
+            },
+            None => erreurs.push(ErreurValidationProgramme::new(programme, nom, "type_invalide", format!("{} doit etre un nombre", schema.nom)))
+        },
+        TypeArgument::Entier { min, max } => match valeur.as_i64() {
+            Some(v) => {
+                if min.map(|m| v < m).unwrap_or(false) || max.map(|m| v > m).unwrap_or(false) {
+                    erreurs.push(ErreurValidationProgramme::new(programme, nom, "hors_limites",
+                        format!("{} doit etre entre {:?} et {:?}", schema.nom, min, max)));
+                }
+            },
+            None => erreurs.push(ErreurValidationProgramme::new(programme, nom, "type_invalide", format!("{} doit etre un entier", schema.nom)))
+        },
+        TypeArgument::ListeSenseurs => {
+            let liste = match valeur.as_array() {
+                Some(inner) if !inner.is_empty() => inner,
+                _ => {
+                    erreurs.push(ErreurValidationProgramme::new(programme, nom, "type_invalide",
+                        format!("{} doit etre une liste non vide de senseur_id", schema.nom)));
+                    return
+                }
+            };
+            for item in liste {
+                let senseur_id = match item.as_str() {
+                    Some(inner) => inner,
+                    None => {
+                        erreurs.push(ErreurValidationProgramme::new(programme, nom, "type_invalide",
+                            format!("{} doit contenir des senseur_id (str)", schema.nom)));
+                        continue
+                    }
+                };
+                if let Some(senseurs) = senseurs {
+                    let reference = match senseur_id.contains(':') {
+                        true => senseur_id.to_string(),
+                        false => format!("{}:{}", uuid_appareil, senseur_id)
+                    };
+                    if !senseurs.contains(&reference) {
+                        erreurs.push(ErreurValidationProgramme::new(programme, nom, "senseur_inconnu",
+                            format!("Senseur {} inconnu", senseur_id)));
+                    }
+                }
+            }
+        },
+        TypeArgument::Liste => if !valeur.is_array() {
+            erreurs.push(ErreurValidationProgramme::new(programme, nom, "type_invalide", format!("{} doit etre une liste", schema.nom)));
+        },
+    }
+}
+
+fn valider_programme(programme: &ProgrammeAppareil, uuid_appareil: &str, senseurs: Option<&HashSet<String>>,
+                     erreurs: &mut Vec<ErreurValidationProgramme>)
+{
+    // Classe sans schema (e.g. ajoutee a un relai plus recent) : transmise sans validation
+    let classe = match trouver_classe_programme(programme.class.as_str()) {
+        Some(inner) => inner,
+        None => {
+            warn!("valider_programme Classe {} sans schema, programme {} non valide", programme.class, programme.programme_id);
+            return
+        }
+    };
+
+    for schema in classe.args {
+        match programme.args.get(schema.nom) {
+            Some(valeur) => valider_argument(programme, schema, valeur, uuid_appareil, senseurs, erreurs),
+            None => if schema.requis {
+                erreurs.push(ErreurValidationProgramme::new(programme, Some(schema.nom), "argument_requis",
+                    format!("Argument {} requis", schema.nom)));
+            }
+        }
+    }
+
+    for nom in programme.args.keys() {
+        if !classe.args.iter().any(|s| s.nom == nom.as_str()) {
+            warn!("valider_programme Argument {} absent du schema de la classe {}, transmis tel quel", nom, programme.class);
+        }
+    }
+}
+
+/// Valide les programmes d'un appareil. Les references de senseurs sont verifiees si l'usager est connu.
+pub async fn valider_programmes<'a, M, I>(middleware: &M, user_id: Option<&str>, uuid_appareil: &str, programmes: I)
+    -> Result<Vec<ErreurValidationProgramme>, Error>
+    where M: MongoDao, I: IntoIterator<Item = &'a ProgrammeAppareil>
+{
+    let senseurs = match user_id {
+        Some(inner) => Some(charger_senseurs_usager(middleware, inner).await?),
+        None => None
+    };
+    let mut erreurs = Vec::new();
+    for programme in programmes {
+        valider_programme(programme, uuid_appareil, senseurs.as_ref(), &mut erreurs);
+    }
+    Ok(erreurs)
+}
+
+#[derive(Serialize)]
+struct ReponseValidationProgrammes {
+    ok: bool,
+    err: &'static str,
+    erreurs_validation: Vec<ErreurValidationProgramme>,
+}
+
+pub(crate) fn reponse_erreurs_validation<M>(middleware: &M, erreurs: Vec<ErreurValidationProgramme>)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages
+{
+    debug!("reponse_erreurs_validation Programmes invalides : {:?}", erreurs);
+    let reponse = ReponseValidationProgrammes { ok: false, err: "Programme invalide", erreurs_validation: erreurs };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+pub async fn commande_sauvegarder_programme<M>(middleware: &M, m: MessageValide, gestionnaire: &SenseursPassifsDomainManager, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    debug!("commande_sauvegarder_programme Consommer commande : {:?}", & m.type_message);
+    let commande: TransactionSauvegarderProgramme = deser_message_buffer!(m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => Err(format!("senseurspassifs.commande_sauvegarder_programme: Commande autorisation invalide (user_id requis) pour message {:?}", m.type_message))?
+    };
+
+    if commande.supprimer != Some(true) {
+        let erreurs = valider_programmes(middleware, Some(user_id.as_str()), commande.uuid_appareil.as_str(), [&commande.programme]).await?;
+        if !erreurs.is_empty() {
+            return reponse_erreurs_validation(middleware, erreurs)
+        }
+    }
+
+    Ok(sauvegarder_traiter_transaction_v2(middleware, m, gestionnaire, session).await?)
+}
+
+pub async fn commande_maj_appareil<M>(middleware: &M, m: MessageValide, gestionnaire: &SenseursPassifsDomainManager, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    debug!("commande_maj_appareil Consommer commande : {:?}", & m.type_message);
+    let commande: TransactionMajAppareil = deser_message_buffer!(m.message);
+    let user_id = m.certificat.get_user_id()?;
+
+    // user_id et gabarit_id sont reserves aux transactions emises par le domaine (appliquerGabarit)
+    if commande.user_id.is_some() || commande.gabarit_id.is_some() {
+        return Ok(Some(middleware.reponse_err(None, None, Some("user_id et gabarit_id non permis"))?))
+    }
+
+    if let Some(programmes) = commande.configuration.programmes.as_ref() {
+        let erreurs = valider_programmes(middleware, user_id.as_deref(), commande.uuid_appareil.as_str(), programmes.values()).await?;
+        if !erreurs.is_empty() {
+            return reponse_erreurs_validation(middleware, erreurs)
+        }
+    }
+
+    Ok(sauvegarder_traiter_transaction_v2(middleware, m, gestionnaire, session).await?)
+}
+
+/// Classe de programme offerte a l'usager. Les arguments sont absents pour une classe sans schema.
+#[derive(Serialize)]
+struct ClasseProgrammeReponse {
+    class: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    args: Option<&'static [SchemaArgument]>,
+}
+
+#[derive(Serialize)]
+struct ReponseGetClassesProgrammes {
+    ok: bool,
+    classes: Vec<ClasseProgrammeReponse>,
+}
+
+#[derive(Deserialize)]
+struct RowClasseProgramme {
+    #[serde(rename="_id")]
+    class: Option<String>,
+}
+
+/// Classes configurees sur les appareils de l'usager (programmes reellement executes par les relais).
+async fn charger_classes_appareils<M>(middleware: &M, user_id: &str) -> Result<BTreeSet<String>, Error>
+    where M: MongoDao
+{
+    let pipeline = vec![
+        doc! { "$match": { CHAMP_USER_ID: user_id, "configuration.programmes": {"$exists": true} } },
+        doc! { "$project": { "programmes": {"$objectToArray": "$configuration.programmes"} } },
+        doc! { "$unwind": "$programmes" },
+        doc! { "$group": { "_id": "$programmes.v.class" } },
+    ];
+    let collection = middleware.get_collection(COLLECTIONS_APPAREILS)?;
+    let mut curseur = collection.aggregate(pipeline, None).await?;
+    let mut classes = BTreeSet::new();
+    while let Some(row) = curseur.next().await {
+        match convertir_bson_deserializable::<RowClasseProgramme>(row?) {
+            Ok(RowClasseProgramme { class: Some(class) }) => { classes.insert(class); },
+            Ok(_) => (),
+            Err(e) => warn!("charger_classes_appareils Erreur mapping classe : {:?}", e)
+        }
+    }
+    Ok(classes)
+}
+
+/// Classes avec schema et classes configurees sur les appareils de l'usager.
+pub async fn requete_get_classes_programmes<M>(middleware: &M, m: MessageValide, _gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_get_classes_programmes Consommer requete : {:?}", & m.type_message);
+    let mut classes: Vec<ClasseProgrammeReponse> = CLASSES_PROGRAMMES.iter()
+        .map(|c| ClasseProgrammeReponse { class: c.class.to_string(), description: Some(c.description), args: Some(c.args) })
+        .collect();
+    if let Some(user_id) = m.certificat.get_user_id()? {
+        for class in charger_classes_appareils(middleware, user_id.as_str()).await? {
+            if trouver_classe_programme(class.as_str()).is_none() {
+                classes.push(ClasseProgrammeReponse { class, description: None, args: None });
+            }
+        }
+    }
+
+    let reponse = ReponseGetClassesProgrammes { ok: true, classes };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RowVersionProgramme {
+    user_id: String,
+    uuid_appareil: String,
+    programme_id: String,
+    version: i64,
+    transaction_id: String,
+    #[serde(serialize_with = "epochseconds::serialize", deserialize_with = "chrono_datetime_as_bson_datetime::deserialize")]
+    date: DateTime<Utc>,
+    #[serde(default)]
+    supprime: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    programme: Option<ProgrammeAppareil>,
+}
+
+async fn charger_version_programme<M>(middleware: &M, user_id: &str, uuid_appareil: &str, programme_id: &str,
+                                      version: Option<i64>, session: Option<&mut ClientSession>)
+    -> Result<Option<RowVersionProgramme>, Error>
+    where M: MongoDao
+{
+    let mut filtre = doc! { CHAMP_USER_ID: user_id, CHAMP_UUID_APPAREIL: uuid_appareil, "programme_id": programme_id };
+    if let Some(version) = version {
+        filtre.insert("version", version);
+    }
+    let options = FindOneOptions::builder().sort(doc! {"version": -1}).build();
+    let collection = middleware.get_collection_typed::<RowVersionProgramme>(COLLECTIONS_PROGRAMMES_VERSIONS)?;
+    Ok(match session {
+        Some(session) => collection.find_one_with_session(filtre, options, session).await?,
+        None => collection.find_one(filtre, options).await?
+    })
+}
+
+/// Conserve une nouvelle version du programme (None si supprime). Aucune version n'est ajoutee si
+/// le programme n'a pas change depuis la derniere version.
+pub async fn enregistrer_version_programme<M>(
+    middleware: &M, transaction: &TransactionValide, user_id: &str, uuid_appareil: &str, programme_id: &str,
+    programme: Option<&ProgrammeAppareil>, session: &mut ClientSession
+)
+    -> Result<(), Error>
+    where M: MongoDao
+{
+    let derniere = charger_version_programme(middleware, user_id, uuid_appareil, programme_id, None, Some(&mut *session)).await?;
+    let version = match derniere {
+        Some(derniere) => {
+            let inchange = match (derniere.programme.as_ref(), programme) {
+                (Some(avant), Some(apres)) => serde_json::to_value(avant)? == serde_json::to_value(apres)?,
+                (None, None) => true,
+                _ => false
+            };
+            if inchange {
+                return Ok(())
+            }
+            derniere.version + 1
+        },
+        None => match programme {
+            Some(_) => 1,
+            None => return Ok(())  // Aucun historique a fermer
+        }
+    };
+
+    let row = doc! {
+        CHAMP_USER_ID: user_id,
+        CHAMP_UUID_APPAREIL: uuid_appareil,
+        "programme_id": programme_id,
+        "version": version,
+        "transaction_id": transaction.transaction.id.to_string(),
+        "date": transaction.transaction.estampille,
+        "supprime": programme.is_none(),
+        "programme": match programme { Some(inner) => Some(convertir_to_bson(inner)?), None => None },
+    };
+    let collection = middleware.get_collection(COLLECTIONS_PROGRAMMES_VERSIONS)?;
+    collection.insert_one_with_session(row, None, session).await?;
+    Ok(())
+}
+
+/// Conserve les versions des programmes d'une transaction majAppareil. La liste recue remplace les
+/// programmes de l'appareil, les programmes absents sont conserves comme supprimes.
+pub async fn enregistrer_versions_programmes<M>(
+    middleware: &M, transaction: &TransactionValide, user_id: &str, uuid_appareil: &str,
+    programmes: &HashMap<String, ProgrammeAppareil>, session: &mut ClientSession
+)
+    -> Result<(), Error>
+    where M: MongoDao
+{
+    for (programme_id, programme) in programmes {
+        enregistrer_version_programme(middleware, transaction, user_id, uuid_appareil, programme_id, Some(programme), session).await?;
+    }
+
+    let filtre = doc! { CHAMP_USER_ID: user_id, CHAMP_UUID_APPAREIL: uuid_appareil };
+    let collection = middleware.get_collection(COLLECTIONS_PROGRAMMES_VERSIONS)?;
+    let programme_ids = collection.distinct_with_session("programme_id", filtre, None, session).await?;
+    for programme_id in programme_ids.iter().filter_map(|p| p.as_str()) {
+        if !programmes.contains_key(programme_id) {
+            enregistrer_version_programme(middleware, transaction, user_id, uuid_appareil, programme_id, None, session).await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct RequeteVersionsProgramme {
+    uuid_appareil: String,
+    programme_id: String,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct ReponseVersionsProgramme {
+    ok: bool,
+    versions: Vec<RowVersionProgramme>,
+}
+
+pub async fn requete_get_versions_programme<M>(middleware: &M, m: MessageValide, _gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_get_versions_programme Consommer requete : {:?}", & m.type_message);
+    let requete: RequeteVersionsProgramme = deser_message_buffer!(m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    };
+
+    let filtre = doc! { CHAMP_USER_ID: &user_id, CHAMP_UUID_APPAREIL: &requete.uuid_appareil, "programme_id": &requete.programme_id };
+    let options = FindOptions::builder()
+        .sort(doc! {"version": -1})
+        .limit(requete.limit.unwrap_or(CONST_VERSIONS_PROGRAMME_LIMITE).clamp(1, CONST_VERSIONS_PROGRAMME_LIMITE))
+        .build();
+    let collection = middleware.get_collection_typed::<RowVersionProgramme>(COLLECTIONS_PROGRAMMES_VERSIONS)?;
+    let mut curseur = collection.find(filtre, options).await?;
+    let mut versions = Vec::new();
+    while let Some(row) = curseur.next().await {
+        match row {
+            Ok(inner) => versions.push(inner),
+            Err(e) => warn!("requete_get_versions_programme Erreur mapping version : {:?}", e)
+        }
+    }
+
+    let reponse = ReponseVersionsProgramme { ok: true, versions };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+#[derive(Deserialize)]
+struct RequeteDiffVersionsProgramme {
+    uuid_appareil: String,
+    programme_id: String,
+    version_avant: i64,
+    /// Derniere version si absent.
+    version_apres: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct DifferenceProgramme {
+    /// class, descriptif, actif ou args.<nom>
+    champ: String,
+    avant: Option<Value>,
+    apres: Option<Value>,
+}
+
+#[derive(Serialize)]
+struct ReponseDiffVersionsProgramme {
+    ok: bool,
+    version_avant: i64,
+    version_apres: i64,
+    differences: Vec<DifferenceProgramme>,
+}
+
+/// Champs d'un programme a comparer. Un programme supprime n'a aucun champ.
+fn champs_programme(programme: Option<&ProgrammeAppareil>) -> BTreeMap<String, Value> {
+    let mut champs = BTreeMap::new();
+    if let Some(programme) = programme {
+        champs.insert("class".to_string(), Value::from(programme.class.as_str()));
+        if let Some(inner) = programme.descriptif.as_ref() {
+            champs.insert("descriptif".to_string(), Value::from(inner.as_str()));
+        }
+        if let Some(inner) = programme.actif {
+            champs.insert("actif".to_string(), Value::from(inner));
+        }
+        for (nom, valeur) in &programme.args {
+            champs.insert(format!("args.{}", nom), valeur.clone());
+        }
+    }
+    champs
+}
+
+pub async fn requete_diff_versions_programme<M>(middleware: &M, m: MessageValide, _gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_diff_versions_programme Consommer requete : {:?}", & m.type_message);
+    let requete: RequeteDiffVersionsProgramme = deser_message_buffer!(m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    };
+
+    let uuid_appareil = requete.uuid_appareil.as_str();
+    let programme_id = requete.programme_id.as_str();
+    let avant = charger_version_programme(middleware, &user_id, uuid_appareil, programme_id, Some(requete.version_avant), None).await?;
+    let apres = charger_version_programme(middleware, &user_id, uuid_appareil, programme_id, requete.version_apres, None).await?;
+    let (avant, apres) = match (avant, apres) {
+        (Some(avant), Some(apres)) => (avant, apres),
+        _ => return Ok(Some(middleware.reponse_err(None, None, Some("Version de programme inconnue"))?))
+    };
+
+    let mut champs_avant = champs_programme(avant.programme.as_ref());
+    let champs_apres = champs_programme(apres.programme.as_ref());
+    let mut differences = Vec::new();
+    for (champ, valeur_apres) in champs_apres {
+        let valeur_avant = champs_avant.remove(&champ);
+        if valeur_avant.as_ref() != Some(&valeur_apres) {
+            differences.push(DifferenceProgramme { champ, avant: valeur_avant, apres: Some(valeur_apres) });
+        }
+    }
+    for (champ, valeur_avant) in champs_avant {
+        differences.push(DifferenceProgramme { champ, avant: Some(valeur_avant), apres: None });
+    }
+    differences.sort_by(|a, b| a.champ.cmp(&b.champ));
+
+    let reponse = ReponseDiffVersionsProgramme {
+        ok: true,
+        version_avant: avant.version,
+        version_apres: apres.version,
+        differences,
+    };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionRestaurerVersionProgramme {
+    pub uuid_appareil: String,
+    pub programme_id: String,
+    pub version: i64,
+}
+
+pub async fn commande_restaurer_version_programme<M>(middleware: &M, m: MessageValide, gestionnaire: &SenseursPassifsDomainManager, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    debug!("commande_restaurer_version_programme Consommer commande : {:?}", & m.type_message);
+    let commande: TransactionRestaurerVersionProgramme = deser_message_buffer!(m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    };
+
+    let version = charger_version_programme(
+        middleware, &user_id, &commande.uuid_appareil, &commande.programme_id, Some(commande.version), Some(&mut *session)).await?;
+    let programme = match version.and_then(|v| v.programme) {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("Version de programme inconnue ou supprimee"))?))
+    };
+
+    // La classe peut avoir change depuis la sauvegarde de cette version
+    let erreurs = valider_programmes(middleware, Some(user_id.as_str()), commande.uuid_appareil.as_str(), [&programme]).await?;
+    if !erreurs.is_empty() {
+        return reponse_erreurs_validation(middleware, erreurs)
+    }
+
+    Ok(sauvegarder_traiter_transaction_v2(middleware, m, gestionnaire, session).await?)
+}
+
+/// Sauvegarde de nouveau le programme d'une version precedente. Produit une nouvelle version et
+/// l'evenement evenementMajProgrammes comme sauvegarderProgramme.
+pub async fn transaction_restaurer_version_programme<M>(middleware: &M, transaction: TransactionValide, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("transaction_restaurer_version_programme Consommer transaction : {:?}", transaction.transaction.id);
+    let contenu_transaction: TransactionRestaurerVersionProgramme = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+
+    let user_id = match transaction.certificat.get_user_id()? {
+        Some(user) => user.to_owned(),
+        None => Err(Error::Str("senseurspassifs.transaction_restaurer_version_programme Erreur user_id absent du certificat"))?
+    };
+
+    let version = charger_version_programme(
+        middleware, &user_id, &contenu_transaction.uuid_appareil, &contenu_transaction.programme_id,
+        Some(contenu_transaction.version), Some(&mut *session)).await?;
+    let programme = match version.and_then(|v| v.programme) {
+        Some(inner) => inner,
+        None => Err(format!("senseurspassifs.transaction_restaurer_version_programme Version {} du programme {} inconnue",
+                            contenu_transaction.version, contenu_transaction.programme_id))?
+    };
+
+    let transaction_programme = TransactionSauvegarderProgramme {
+        uuid_appareil: contenu_transaction.uuid_appareil,
+        programme,
+        supprimer: None,
+    };
+    sauvegarder_programme_appareil(middleware, &transaction, user_id, transaction_programme, session).await
+}
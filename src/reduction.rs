@@ -0,0 +1,237 @@
+//! Reduction du nombre de points d'une serie statistique pour l'affichage de longues periodes.
+//!
+//! Deux algorithmes sont disponibles :
+//!
+//! * `lttb` (Largest-Triangle-Three-Buckets, Steinarsson 2013). Le premier et le dernier point sont
+//!   conserves. Les autres points sont repartis en `max_points - 2` groupes de taille egale. Pour chaque
+//!   groupe, on conserve le point qui forme le plus grand triangle avec le point retenu pour le groupe
+//!   precedent et la moyenne du groupe suivant. La forme generale de la courbe et les pics sont preserves.
+//! * `minmax` : les points sont repartis en `max_points / 2` groupes (un groupe par "pixel"). Pour chaque
+//!   groupe, on conserve la rangee avec la plus petite et la plus grande moyenne, dans l'ordre chronologique.
+//!   Les extremes sont toujours preserves, au prix d'un signal plus bruite.
+//!
+//! Les deux algorithmes utilisent la valeur `avg` de chaque rangee. Les rangees sans `avg` ne peuvent
+//! pas etre positionnees sur un graphique et sont retirees lorsque la serie est reduite.
+
+use crate::requetes::ResultatStatistiquesSenseurRow;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum AlgorithmeReduction {
+    Lttb,
+    MinMax,
+}
+
+impl AlgorithmeReduction {
+    /// Algorithme demande, lttb par defaut.
+    pub(crate) fn parse(valeur: Option<&String>) -> Result<Self, String> {
+        match valeur.map(|v| v.as_str()) {
+            None | Some("lttb") => Ok(Self::Lttb),
+            Some("minmax") => Ok(Self::MinMax),
+            Some(inner) => Err(format!("Algorithme de reduction {} non supporte", inner)),
+        }
+    }
+}
+
+/// Reduit la serie a au plus max_points rangees. Retourne la serie intacte si elle est deja assez courte.
+pub(crate) fn reduire_serie(rows: Vec<ResultatStatistiquesSenseurRow>, max_points: usize, algorithme: AlgorithmeReduction)
+    -> Vec<ResultatStatistiquesSenseurRow>
+{
+    if rows.len() <= max_points {
+        return rows
+    }
+
+    let rows: Vec<ResultatStatistiquesSenseurRow> = rows.into_iter().filter(|r| r.avg.is_some()).collect();
+    if rows.len() <= max_points {
+        return rows
+    }
+
+    match algorithme {
+        AlgorithmeReduction::Lttb => reduire_lttb(rows, max_points),
+        AlgorithmeReduction::MinMax => reduire_min_max(rows, max_points),
+    }
+}
+
+fn coordonnees(row: &ResultatStatistiquesSenseurRow) -> (f64, f64) {
+    (row.heure.timestamp() as f64, row.avg.unwrap_or(0.0))
+}
+
+/// Largest-Triangle-Three-Buckets. Toutes les rangees doivent avoir une valeur avg.
+fn reduire_lttb(rows: Vec<ResultatStatistiquesSenseurRow>, max_points: usize) -> Vec<ResultatStatistiquesSenseurRow> {
+    let nombre_rows = rows.len();
+    if max_points >= nombre_rows {
+        return rows
+    }
+    if max_points < 3 {
+        // Pas assez de points pour des groupes, conserver les extremites.
+        let mut reduites = Vec::with_capacity(2);
+        reduites.push(rows[0].clone());
+        if max_points == 2 {
+            reduites.push(rows[nombre_rows - 1].clone());
+        }
+        return reduites
+    }
+
+    let taille_groupe = (nombre_rows - 2) as f64 / (max_points - 2) as f64;
+    let mut indices = Vec::with_capacity(max_points);
+    indices.push(0);
+
+    let mut idx_a = 0;
+    for i in 0..(max_points - 2) {
+        // Moyenne du groupe suivant (point c du triangle)
+        let debut_suivant = ((i + 1) as f64 * taille_groupe) as usize + 1;
+        let fin_suivant = usize::min(((i + 2) as f64 * taille_groupe) as usize + 1, nombre_rows);
+        let (mut avg_x, mut avg_y) = (0.0, 0.0);
+        for row in &rows[debut_suivant..fin_suivant] {
+            let (x, y) = coordonnees(row);
+            avg_x += x;
+            avg_y += y;
+        }
+        let taille_suivant = (fin_suivant - debut_suivant) as f64;
+        avg_x /= taille_suivant;
+        avg_y /= taille_suivant;
+
+        // Groupe courant (point b du triangle)
+        let debut_courant = (i as f64 * taille_groupe) as usize + 1;
+        let fin_courant = ((i + 1) as f64 * taille_groupe) as usize + 1;
+
+        let (a_x, a_y) = coordonnees(&rows[idx_a]);
+        let mut aire_max = -1.0;
+        let mut idx_max = debut_courant;
+        for (j, row) in rows[debut_courant..fin_courant].iter().enumerate() {
+            let (b_x, b_y) = coordonnees(row);
+            let aire = ((a_x - avg_x) * (b_y - a_y) - (a_x - b_x) * (avg_y - a_y)).abs() * 0.5;
+            if aire > aire_max {
+                aire_max = aire;
+                idx_max = debut_courant + j;
+            }
+        }
+
+        indices.push(idx_max);
+        idx_a = idx_max;
+    }
+
+    indices.push(nombre_rows - 1);
+    indices.into_iter().map(|i| rows[i].clone()).collect()
+}
+
+/// Conserve les rangees min et max (avg) de chaque groupe. Toutes les rangees doivent avoir une valeur avg.
+fn reduire_min_max(rows: Vec<ResultatStatistiquesSenseurRow>, max_points: usize) -> Vec<ResultatStatistiquesSenseurRow> {
+    let nombre_rows = rows.len();
+    let nombre_groupes = usize::max(max_points / 2, 1);
+    let taille_groupe = nombre_rows as f64 / nombre_groupes as f64;
+
+    let mut reduites = Vec::with_capacity(max_points);
+    for i in 0..nombre_groupes {
+        let debut = (i as f64 * taille_groupe) as usize;
+        let fin = usize::min(((i + 1) as f64 * taille_groupe) as usize, nombre_rows);
+        if debut >= fin {
+            continue
+        }
+
+        let mut idx_min = debut;
+        let mut idx_max = debut;
+        for (j, row) in rows.iter().enumerate().take(fin).skip(debut) {
+            if row.avg < rows[idx_min].avg {
+                idx_min = j;
+            }
+            if row.avg > rows[idx_max].avg {
+                idx_max = j;
+            }
+        }
+
+        if idx_min == idx_max {
+            reduites.push(rows[idx_min].clone());
+        } else {
+            reduites.push(rows[usize::min(idx_min, idx_max)].clone());
+            if max_points > 1 {
+                reduites.push(rows[usize::max(idx_min, idx_max)].clone());
+            }
+        }
+    }
+
+    reduites
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_setup::setup;
+    use millegrilles_common_rust::chrono::{DateTime, Duration, Utc};
+
+    fn generer_serie(valeurs: &[f64]) -> Vec<ResultatStatistiquesSenseurRow> {
+        let debut = DateTime::<Utc>::from_timestamp(1_700_000_000, 0).expect("timestamp");
+        valeurs.iter().enumerate().map(|(i, v)| ResultatStatistiquesSenseurRow {
+            heure: debut + Duration::hours(i as i64),
+            min: Some(*v - 1.0),
+            max: Some(*v + 1.0),
+            avg: Some(*v),
+            provisoire: None,
+        }).collect()
+    }
+
+    #[test]
+    fn test_serie_courte_inchangee() {
+        setup("test_serie_courte_inchangee");
+        let rows = generer_serie(&[1.0, 2.0, 3.0]);
+        let reduites = reduire_serie(rows.clone(), 10, AlgorithmeReduction::Lttb);
+        assert_eq!(rows.len(), reduites.len());
+    }
+
+    #[test]
+    fn test_lttb_nombre_points_et_extremites() {
+        setup("test_lttb_nombre_points_et_extremites");
+        let valeurs: Vec<f64> = (0..8760).map(|i| (i as f64 / 24.0).sin() * 10.0).collect();
+        let rows = generer_serie(&valeurs);
+        let reduites = reduire_serie(rows.clone(), 500, AlgorithmeReduction::Lttb);
+        assert_eq!(500, reduites.len());
+        assert_eq!(rows[0].heure, reduites[0].heure);
+        assert_eq!(rows[rows.len() - 1].heure, reduites[reduites.len() - 1].heure);
+        for paire in reduites.windows(2) {
+            assert!(paire[0].heure < paire[1].heure);
+        }
+    }
+
+    #[test]
+    fn test_lttb_conserve_pic() {
+        setup("test_lttb_conserve_pic");
+        let mut valeurs = vec![20.0; 1000];
+        valeurs[637] = 45.0;
+        let rows = generer_serie(&valeurs);
+        let reduites = reduire_serie(rows, 50, AlgorithmeReduction::Lttb);
+        assert!(reduites.iter().any(|r| r.avg == Some(45.0)));
+    }
+
+    #[test]
+    fn test_minmax_conserve_extremes() {
+        setup("test_minmax_conserve_extremes");
+        let mut valeurs: Vec<f64> = (0..1000).map(|i| (i % 7) as f64).collect();
+        valeurs[123] = -30.0;
+        valeurs[876] = 90.0;
+        let rows = generer_serie(&valeurs);
+        let reduites = reduire_serie(rows, 100, AlgorithmeReduction::MinMax);
+        assert!(reduites.len() <= 100);
+        assert!(reduites.iter().any(|r| r.avg == Some(-30.0)));
+        assert!(reduites.iter().any(|r| r.avg == Some(90.0)));
+        for paire in reduites.windows(2) {
+            assert!(paire[0].heure < paire[1].heure);
+        }
+    }
+
+    #[test]
+    fn test_rangees_sans_valeur_retirees() {
+        setup("test_rangees_sans_valeur_retirees");
+        let mut rows = generer_serie(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        rows[2].avg = None;
+        let reduites = reduire_serie(rows, 4, AlgorithmeReduction::Lttb);
+        assert_eq!(4, reduites.len());
+        assert!(reduites.iter().all(|r| r.avg.is_some()));
+    }
+
+    #[test]
+    fn test_algorithme_inconnu() {
+        setup("test_algorithme_inconnu");
+        assert_eq!(Ok(AlgorithmeReduction::Lttb), AlgorithmeReduction::parse(None));
+        assert_eq!(Ok(AlgorithmeReduction::MinMax), AlgorithmeReduction::parse(Some(&"minmax".to_string())));
+        assert!(AlgorithmeReduction::parse(Some(&"cubic".to_string())).is_err());
+    }
+}
@@ -0,0 +1,314 @@
+use log::{debug, info, warn};
+
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::bson::serde_helpers::chrono_datetime_as_bson_datetime;
+use millegrilles_common_rust::chrono::{DateTime, Utc};
+use millegrilles_common_rust::constantes::*;
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, opt_chrono_datetime_as_bson_datetime, MongoDao};
+use millegrilles_common_rust::mongodb::error::{BulkWriteFailure, ErrorKind};
+use millegrilles_common_rust::mongodb::options::{InsertManyOptions, UpdateOptions};
+use millegrilles_common_rust::mongodb::ClientSession;
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+
+use crate::common::*;
+use crate::constants::COLLECTION_NAME_TRANSACTIONS;
+use crate::domain_manager::SenseursPassifsDomainManager;
+use crate::transactions::SenseurHoraireRow;
+
+/// Nombre de rangees horaires accumulees avant une insertion en lot.
+const CONST_REGENERATION_TAILLE_LOT: usize = 1000;
+
+/// Identificateur du point de reprise des rangees horaires dans la collection regeneration.
+const CONST_POINT_REPRISE_SENSEURS_HORAIRE: &str = "senseurs_horaire";
+
+const CONST_ETAT_EN_COURS: &str = "en_cours";
+const CONST_ETAT_TERMINE: &str = "termine";
+
+/// Code d'erreur MongoDB pour une cle dupliquee.
+const CONST_MONGO_DUPLICATE_KEY: i32 = 11000;
+
+/// Derniere transaction appliquee de facon durable (point de reprise).
+#[derive(Clone)]
+struct TransactionReprise {
+    id: String,
+    estampille: DateTime<Utc>,
+}
+
+/// Etat en memoire de la regeneration des rangees horaires. Conserve dans le gestionnaire.
+#[derive(Default)]
+pub struct EtatRegeneration {
+    initialise: bool,
+    /// Derniere transaction appliquee par une regeneration interrompue. None une fois depassee.
+    reprise: Option<TransactionReprise>,
+    /// Transaction courante, conservee comme point de reprise avec le prochain lot.
+    courante: Option<TransactionReprise>,
+    /// Nombre de transactions depuis le debut de la regeneration (progression seulement).
+    position: i64,
+    rangees: Vec<SenseurHoraireRow>,
+    rangees_inserees: i64,
+    rangees_dupliquees: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RowPointReprise {
+    nom: String,
+    etat: String,
+    position: i64,
+    #[serde(default)]
+    transaction_id: Option<String>,
+    #[serde(default, deserialize_with = "opt_chrono_datetime_as_bson_datetime::deserialize")]
+    estampille: Option<DateTime<Utc>>,
+    rangees_inserees: i64,
+    rangees_dupliquees: i64,
+    #[serde(with="chrono_datetime_as_bson_datetime")]
+    debut: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct EvenementRegenerationSenseursHoraire {
+    etat: String,
+    transactions: i64,
+    rangees_inserees: i64,
+    rangees_dupliquees: i64,
+}
+
+/// Les rangees horaires (et sommaires quotidiens) sont conservees par le point de reprise plutot que
+/// d'etre videes par la regeneration. Les transactions qui les produisent sont sautees a la reprise.
+fn action_reprise(action: &str) -> bool {
+    matches!(action, TRANSACTION_SENSEUR_HORAIRE | TRANSACTION_SENSEUR_HORAIRE_MENSUEL) || action_lit_rangees(action)
+}
+
+/// Transactions qui lisent ou suppriment des rangees horaires. Le lot doit etre insere au prealable.
+fn action_lit_rangees(action: &str) -> bool {
+    matches!(action, TRANSACTION_ELAGUER_SENSEURS_HORAIRE | TRANSACTION_SUPPRIMER_SENSEURS_HORAIRE)
+}
+
+/// Charge le point de reprise au debut de la regeneration. Reprend une regeneration interrompue
+/// ou vide les rangees horaires et sommaires quotidiens pour une nouvelle regeneration.
+async fn initialiser_regeneration<M>(middleware: &M, etat: &mut EtatRegeneration) -> Result<(), Error>
+    where M: MongoDao
+{
+    let collection = middleware.get_collection(COLLECTIONS_REGENERATION)?;
+    let filtre = doc! { "nom": CONST_POINT_REPRISE_SENSEURS_HORAIRE };
+    let point_reprise = match collection.find_one(filtre.clone(), None).await? {
+        Some(inner) => Some(convertir_bson_deserializable::<RowPointReprise>(inner)?),
+        None => None
+    };
+
+    *etat = EtatRegeneration { initialise: true, ..Default::default() };
+
+    // La reprise est ancree sur la derniere transaction appliquee. Si elle n'existe plus (e.g. archivee
+    // par la compaction), l'ordre des transactions a change et la regeneration doit recommencer.
+    let reprise = match point_reprise {
+        Some(point_reprise) if point_reprise.etat.as_str() == CONST_ETAT_EN_COURS => {
+            match (point_reprise.transaction_id.clone(), point_reprise.estampille) {
+                (Some(transaction_id), Some(estampille)) => {
+                    let collection_transactions = middleware.get_collection(COLLECTION_NAME_TRANSACTIONS)?;
+                    if collection_transactions.find_one(doc!{"id": &transaction_id}, None).await?.is_some() {
+                        Some((point_reprise, TransactionReprise { id: transaction_id, estampille }))
+                    } else {
+                        warn!("initialiser_regeneration Transaction de reprise {} introuvable, nouvelle regeneration", transaction_id);
+                        None
+                    }
+                },
+                _ => {
+                    info!("initialiser_regeneration Point de reprise sans transaction, nouvelle regeneration");
+                    None
+                }
+            }
+        },
+        _ => None
+    };
+
+    match reprise {
+        Some((point_reprise, transaction_reprise)) => {
+            info!("initialiser_regeneration Reprise de la regeneration debutee le {:?} apres la transaction {} ({} transactions)",
+                point_reprise.debut, transaction_reprise.id, point_reprise.position);
+            etat.reprise = Some(transaction_reprise);
+            etat.rangees_inserees = point_reprise.rangees_inserees;
+            etat.rangees_dupliquees = point_reprise.rangees_dupliquees;
+        },
+        None => {
+            info!("initialiser_regeneration Nouvelle regeneration, suppression des rangees horaires");
+            middleware.get_collection(COLLECTIONS_SENSEURS_HORAIRE)?.delete_many(doc!{}, None).await?;
+            middleware.get_collection(COLLECTIONS_SENSEURS_QUOTIDIEN)?.delete_many(doc!{}, None).await?;
+            let ops = doc! {
+                "$set": {
+                    "etat": CONST_ETAT_EN_COURS,
+                    "position": 0i64,
+                    "transaction_id": null,
+                    "estampille": null,
+                    "rangees_inserees": 0i64,
+                    "rangees_dupliquees": 0i64,
+                    "debut": Utc::now(),
+                },
+                "$currentDate": { CHAMP_MODIFICATION: true },
+            };
+            let options = UpdateOptions::builder().upsert(true).build();
+            collection.update_one(filtre, ops, options).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Transactions rejouees en ordre d'estampille : une transaction est deja appliquee si elle precede
+/// la transaction de reprise. Les transactions de la meme seconde sont rejouees (doublons ignores).
+fn deja_appliquee(reprise: &TransactionReprise, transaction_id: &str, estampille: &DateTime<Utc>) -> bool {
+    transaction_id == reprise.id || estampille < &reprise.estampille
+}
+
+/// Avance la position de regeneration pour une transaction. Retourne true si la transaction
+/// a deja ete appliquee par une regeneration interrompue et doit etre sautee.
+pub async fn avancer_regeneration<M>(
+    middleware: &M, gestionnaire: &SenseursPassifsDomainManager, action: &str, transaction_id: &str,
+    estampille: &DateTime<Utc>, session: &mut ClientSession
+)
+    -> Result<bool, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    let mut etat = gestionnaire.regeneration.lock().await;
+    if !etat.initialise {
+        initialiser_regeneration(middleware, &mut etat).await?;
+    }
+    etat.position += 1;
+    etat.courante = Some(TransactionReprise { id: transaction_id.to_string(), estampille: *estampille });
+
+    let reprise_depassee = match etat.reprise.as_ref() {
+        Some(reprise) if deja_appliquee(reprise, transaction_id, estampille) => {
+            if action_reprise(action) {
+                return Ok(true)
+            }
+            false
+        },
+        Some(_) => true,
+        None => false
+    };
+    if reprise_depassee {
+        debug!("avancer_regeneration Point de reprise depasse a la transaction {}", transaction_id);
+        etat.reprise = None;
+    }
+
+    if action_lit_rangees(action) {
+        // Les rangees horaires doivent etre inserees avant l'elagage ou la suppression.
+        // Le point de reprise inclut la transaction, il est conserve dans la meme session.
+        vider_rangees(middleware, &mut etat, session).await?;
+    }
+
+    Ok(false)
+}
+
+/// Ajoute des rangees horaires au lot de regeneration. Le lot est insere lorsqu'il est plein.
+pub async fn ajouter_rangees_regeneration<M>(
+    middleware: &M, gestionnaire: &SenseursPassifsDomainManager, rangees: Vec<SenseurHoraireRow>, session: &mut ClientSession
+)
+    -> Result<(), Error>
+    where M: GenerateurMessages + MongoDao
+{
+    let mut etat = gestionnaire.regeneration.lock().await;
+    etat.rangees.extend(rangees);
+    if etat.rangees.len() >= CONST_REGENERATION_TAILLE_LOT {
+        vider_rangees(middleware, &mut etat, session).await?;
+    }
+    Ok(())
+}
+
+/// Insere le lot de rangees horaires et conserve le point de reprise.
+///
+/// L'insertion est faite hors session : une cle dupliquee annulerait la transaction mongo. Les
+/// doublons (transactions dupliquees ou reprise) sont ignores, la premiere rangee est conservee.
+/// Le point de reprise est sauvegarde dans la session pour etre confirme avec les autres transactions du lot.
+async fn vider_rangees<M>(middleware: &M, etat: &mut EtatRegeneration, session: &mut ClientSession)
+    -> Result<(), Error>
+    where M: GenerateurMessages + MongoDao
+{
+    if !etat.rangees.is_empty() {
+        let rangees = std::mem::take(&mut etat.rangees);
+        let nombre_rangees = rangees.len() as i64;
+        let collection = middleware.get_collection_typed::<SenseurHoraireRow>(COLLECTIONS_SENSEURS_HORAIRE)?;
+        let options = InsertManyOptions::builder().ordered(false).build();
+        let dupliquees = match collection.insert_many(rangees, options).await {
+            Ok(_) => 0,
+            Err(e) => match e.kind.as_ref() {
+                ErrorKind::BulkWrite(BulkWriteFailure { write_errors: Some(erreurs), write_concern_error: None, .. })
+                    if erreurs.iter().all(|e| e.code == CONST_MONGO_DUPLICATE_KEY) => erreurs.len() as i64,
+                _ => Err(e)?
+            }
+        };
+        if dupliquees > 0 {
+            warn!("vider_rangees Ignoring {} duplicate hourly rows", dupliquees);
+        }
+        etat.rangees_inserees += nombre_rangees - dupliquees;
+        etat.rangees_dupliquees += dupliquees;
+    }
+
+    let filtre = doc! { "nom": CONST_POINT_REPRISE_SENSEURS_HORAIRE };
+    let mut set_ops = doc! {
+        "position": etat.position,
+        "rangees_inserees": etat.rangees_inserees,
+        "rangees_dupliquees": etat.rangees_dupliquees,
+    };
+    // Pendant la reprise, l'ancre precedente reste valide jusqu'a ce qu'elle soit depassee
+    if etat.reprise.is_none() {
+        if let Some(courante) = etat.courante.as_ref() {
+            set_ops.insert("transaction_id", courante.id.clone());
+            set_ops.insert("estampille", courante.estampille);
+        }
+    }
+    let ops = doc! {
+        "$set": set_ops,
+        "$currentDate": { CHAMP_MODIFICATION: true },
+    };
+    middleware.get_collection(COLLECTIONS_REGENERATION)?
+        .update_one_with_session(filtre, ops, None, session).await?;
+    debug!("vider_rangees Point de reprise a la transaction {:?}", etat.courante.as_ref().map(|t| t.id.as_str()));
+
+    emettre_evenement_regeneration(middleware, etat, CONST_ETAT_EN_COURS).await
+}
+
+async fn emettre_evenement_regeneration<M>(middleware: &M, etat: &EtatRegeneration, etat_regeneration: &str)
+    -> Result<(), Error>
+    where M: GenerateurMessages
+{
+    let evenement = EvenementRegenerationSenseursHoraire {
+        etat: etat_regeneration.to_string(),
+        transactions: etat.position,
+        rangees_inserees: etat.rangees_inserees,
+        rangees_dupliquees: etat.rangees_dupliquees,
+    };
+    let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_REGENERATION_SENSEURS_HORAIRE, vec![Securite::L3Protege])
+        .build();
+    middleware.emettre_evenement(routage, &evenement).await?;
+    Ok(())
+}
+
+/// Insere les dernieres rangees et marque la regeneration terminee. Doit etre appele avant
+/// la reconstruction de la liste des senseurs.
+pub async fn terminer_regeneration<M>(middleware: &M, gestionnaire: &SenseursPassifsDomainManager, session: &mut ClientSession)
+    -> Result<(), Error>
+    where M: GenerateurMessages + MongoDao
+{
+    let mut etat = gestionnaire.regeneration.lock().await;
+    if !etat.initialise {
+        // Aucune transaction, s'assurer que les rangees d'une regeneration precedente sont retirees
+        initialiser_regeneration(middleware, &mut etat).await?;
+    }
+    vider_rangees(middleware, &mut etat, session).await?;
+
+    let filtre = doc! { "nom": CONST_POINT_REPRISE_SENSEURS_HORAIRE };
+    let ops = doc! {
+        "$set": { "etat": CONST_ETAT_TERMINE },
+        "$currentDate": { CHAMP_MODIFICATION: true },
+    };
+    middleware.get_collection(COLLECTIONS_REGENERATION)?
+        .update_one_with_session(filtre, ops, None, session).await?;
+
+    info!("terminer_regeneration {} transactions, {} rangees horaires inserees, {} doublons ignores",
+        etat.position, etat.rangees_inserees, etat.rangees_dupliquees);
+    emettre_evenement_regeneration(middleware, &etat, CONST_ETAT_TERMINE).await?;
+
+    *etat = EtatRegeneration::default();
+    Ok(())
+}
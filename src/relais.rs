@@ -0,0 +1,268 @@
+use log::{debug, warn};
+
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
+use millegrilles_common_rust::chrono::{DateTime, Duration, Utc};
+use millegrilles_common_rust::constantes::*;
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::GenerateurMessages;
+use millegrilles_common_rust::millegrilles_cryptographie::deser_message_buffer;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::{optionepochseconds, MessageMilleGrillesBufferDefault};
+use millegrilles_common_rust::mongo_dao::{opt_chrono_datetime_as_bson_datetime, MongoDao};
+use millegrilles_common_rust::mongodb::ClientSession;
+use millegrilles_common_rust::mongodb::options::{FindOptions, UpdateOptions};
+use millegrilles_common_rust::recepteur_messages::MessageValide;
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+use millegrilles_common_rust::tokio_stream::StreamExt;
+
+use crate::common::*;
+use crate::domain_manager::SenseursPassifsDomainManager;
+
+/// Intervalle minimal entre deux ecritures de derniere_visite pour un meme relai et une meme instance.
+const CONST_VISITE_RELAI_INTERVALLE_SECS: i64 = 300;
+
+/// Relai autorise a transmettre les lectures d'un appareil. Un seul relai par appareil, la confirmation
+/// d'un nouveau relai remplace le precedent.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RowRelais {
+    pub user_id: String,
+    pub uuid_appareil: String,
+    pub fingerprint: String,
+    /// Instance du relai lors de la derniere visite.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance_id: Option<String>,
+    /// Date d'expiration de l'autorisation. Aucune expiration si absent.
+    #[serde(default,
+        serialize_with = "optionepochseconds::serialize",
+        deserialize_with = "opt_chrono_datetime_as_bson_datetime::deserialize")]
+    pub expiration: Option<DateTime<Utc>>,
+    #[serde(default,
+        serialize_with = "optionepochseconds::serialize",
+        deserialize_with = "opt_chrono_datetime_as_bson_datetime::deserialize")]
+    pub premiere_visite: Option<DateTime<Utc>>,
+    #[serde(default,
+        serialize_with = "optionepochseconds::serialize",
+        deserialize_with = "opt_chrono_datetime_as_bson_datetime::deserialize")]
+    pub derniere_visite: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub revoque: bool,
+    #[serde(default,
+        serialize_with = "optionepochseconds::serialize",
+        deserialize_with = "opt_chrono_datetime_as_bson_datetime::deserialize")]
+    pub date_revocation: Option<DateTime<Utc>>,
+}
+
+impl RowRelais {
+    fn est_expire(&self, maintenant: &DateTime<Utc>) -> bool {
+        match self.expiration.as_ref() {
+            Some(expiration) => expiration <= maintenant,
+            None => false
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CommandeConfirmerRelai {
+    fingerprint: String,
+    #[serde(default, with="optionepochseconds")]
+    expiration: Option<DateTime<Utc>>,
+    /// Instance du relai (optionnel).
+    instance_id: Option<String>,
+}
+
+pub async fn commande_confirmer_relai<M>(middleware: &M, m: MessageValide, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug ! ("commande_confirmer_relai Consommer requete : {:?}", m.type_message);
+    let commande: CommandeConfirmerRelai = deser_message_buffer!(m.message);
+
+    let certificat = m.certificat.as_ref();
+    let common_name = certificat.get_common_name()?;
+    let user_id = match certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => Err(Error::Str("commande_confirmer_relai Certificat sans user_id"))?
+    };
+
+    if let Some(expiration) = commande.expiration.as_ref() {
+        if expiration <= &Utc::now() {
+            return Ok(Some(middleware.reponse_err(None, None, Some("Expiration du relai dans le passe"))?))
+        }
+    }
+
+    let filtre = doc! { CHAMP_UUID_APPAREIL: &common_name, CHAMP_USER_ID: &user_id };
+    let collection = middleware.get_collection_typed::<RowRelais>(COLLECTIONS_RELAIS)?;
+    let relai_existant = collection.find_one_with_session(filtre.clone(), None, session).await?;
+
+    let meme_relai = match relai_existant.as_ref() {
+        Some(relai) => relai.fingerprint == commande.fingerprint,
+        None => false
+    };
+    if meme_relai && relai_existant.as_ref().map(|r| r.revoque).unwrap_or(false) {
+        // Une revocation par l'usager ne peut pas etre renversee par l'appareil
+        return Ok(Some(middleware.reponse_err(None, None, Some("Relai revoque"))?))
+    }
+
+    let mut set_ops = doc! {
+        "fingerprint": &commande.fingerprint,
+        "expiration": commande.expiration,
+    };
+    if let Some(instance_id) = commande.instance_id.as_ref() {
+        set_ops.insert(CHAMP_INSTANCE_ID, instance_id);
+    }
+    if ! meme_relai {
+        // Nouveau relai pour l'appareil, remplace le precedent
+        set_ops.insert("premiere_visite", Utc::now());
+        set_ops.insert("revoque", false);
+        set_ops.insert("date_revocation", None::<DateTime<Utc>>);
+    }
+
+    let ops = doc! {
+        "$set": set_ops,
+        "$setOnInsert": {
+            CHAMP_UUID_APPAREIL: common_name,
+            CHAMP_USER_ID: user_id,
+            CHAMP_CREATION: Utc::now()
+        },
+        "$currentDate": { CHAMP_MODIFICATION: true, "derniere_visite": true }
+    };
+    let collection = middleware.get_collection(COLLECTIONS_RELAIS)?;
+    let options = UpdateOptions::builder().upsert(true).build();
+    collection.update_one_with_session(filtre, ops, options, session).await?;
+
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
+
+/// Verifie que le relai est autorise (non expire, non revoque) pour l'appareil et conserve la visite.
+/// La visite est ecrite seulement si l'instance change ou si la derniere visite date de plus de
+/// CONST_VISITE_RELAI_INTERVALLE_SECS, pour eviter une ecriture par lecture relayee.
+pub async fn verifier_relai_autorise<M>(middleware: &M, user_id: &str, uuid_appareil: &str, fingerprint: &str, instance_id: &str)
+    -> Result<bool, Error>
+    where M: MongoDao
+{
+    let maintenant = Utc::now();
+    let filtre = doc! {
+        CHAMP_USER_ID: user_id,
+        CHAMP_UUID_APPAREIL: uuid_appareil,
+        "fingerprint": fingerprint,
+    };
+    let collection = middleware.get_collection_typed::<RowRelais>(COLLECTIONS_RELAIS)?;
+    let relai = match collection.find_one(filtre.clone(), None).await? {
+        Some(inner) => inner,
+        None => return Ok(false)
+    };
+    if relai.revoque || relai.est_expire(&maintenant) {
+        return Ok(false)
+    }
+
+    let meme_instance = relai.instance_id.as_ref().map(|i| i.as_str()) == Some(instance_id);
+    let visite_recente = match relai.derniere_visite.as_ref() {
+        Some(derniere_visite) => maintenant - *derniere_visite < Duration::seconds(CONST_VISITE_RELAI_INTERVALLE_SECS),
+        None => false
+    };
+    if !meme_instance || !visite_recente {
+        let ops = doc! {
+            "$set": { CHAMP_INSTANCE_ID: instance_id },
+            "$currentDate": { "derniere_visite": true }
+        };
+        middleware.get_collection(COLLECTIONS_RELAIS)?.update_one(filtre, ops, None).await?;
+    }
+
+    Ok(true)
+}
+
+#[derive(Deserialize)]
+struct RequeteGetRelaisAppareils {
+    /// Filtre optionnel sur une liste d'appareils.
+    uuid_appareils: Option<Vec<String>>,
+    /// Inclure les relais expires et revoques.
+    inclure_inactifs: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct RelaiReponse {
+    #[serde(flatten)]
+    relai: RowRelais,
+    expire: bool,
+}
+
+#[derive(Serialize)]
+struct ReponseGetRelaisAppareils {
+    ok: bool,
+    relais: Vec<RelaiReponse>,
+}
+
+pub async fn requete_get_relais_appareils<M>(middleware: &M, m: MessageValide, _gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_get_relais_appareils Consommer requete : {:?}", & m.type_message);
+    let requete: RequeteGetRelaisAppareils = deser_message_buffer!(m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    };
+
+    let mut filtre = doc! { CHAMP_USER_ID: &user_id };
+    if let Some(uuid_appareils) = requete.uuid_appareils.as_ref() {
+        filtre.insert(CHAMP_UUID_APPAREIL, doc! {"$in": uuid_appareils});
+    }
+    let inclure_inactifs = requete.inclure_inactifs.unwrap_or(false);
+
+    let maintenant = Utc::now();
+    let options = FindOptions::builder().sort(doc! {CHAMP_UUID_APPAREIL: 1}).build();
+    let collection = middleware.get_collection_typed::<RowRelais>(COLLECTIONS_RELAIS)?;
+    let mut curseur = collection.find(filtre, options).await?;
+    let mut relais = Vec::new();
+    while let Some(row) = curseur.next().await {
+        match row {
+            Ok(relai) => {
+                let expire = relai.est_expire(&maintenant);
+                if inclure_inactifs || (!expire && !relai.revoque) {
+                    relais.push(RelaiReponse { relai, expire });
+                }
+            },
+            Err(e) => warn!("requete_get_relais_appareils Erreur mapping relai : {:?}", e)
+        }
+    }
+
+    let reponse = ReponseGetRelaisAppareils { ok: true, relais };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+#[derive(Deserialize)]
+struct CommandeRevoquerRelai {
+    uuid_appareil: String,
+    fingerprint: String,
+}
+
+pub async fn commande_revoquer_relai<M>(middleware: &M, m: MessageValide, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    debug!("commande_revoquer_relai Consommer commande : {:?}", & m.type_message);
+    let commande: CommandeRevoquerRelai = deser_message_buffer!(m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    };
+
+    let filtre = doc! {
+        CHAMP_USER_ID: &user_id,
+        CHAMP_UUID_APPAREIL: &commande.uuid_appareil,
+        "fingerprint": &commande.fingerprint,
+    };
+    let ops = doc! {
+        "$set": { "revoque": true },
+        "$currentDate": { CHAMP_MODIFICATION: true, "date_revocation": true }
+    };
+    let collection = middleware.get_collection(COLLECTIONS_RELAIS)?;
+    let resultat = collection.update_one_with_session(filtre, ops, None, session).await?;
+    if resultat.matched_count == 0 {
+        return Ok(Some(middleware.reponse_err(None, None, Some("Relai inconnu"))?))
+    }
+
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
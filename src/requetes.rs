@@ -22,8 +22,23 @@ use millegrilles_common_rust::rabbitmq_dao::TypeMessageOut;
 use millegrilles_common_rust::bson::serde_helpers::chrono_datetime_as_bson_datetime;
 use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::{epochseconds, optionepochseconds};
 
+use crate::actionneurs::requete_get_commandes_appareil;
+use crate::anomalies::requete_get_anomalies_senseurs;
 use crate::common::*;
 use crate::domain_manager::SenseursPassifsDomainManager;
+use crate::lectures::{calculer_statistiques_lectures, charger_lectures_heures_ouvertes};
+use crate::programmes::{requete_diff_versions_programme, requete_get_classes_programmes, requete_get_versions_programme};
+use crate::relais::requete_get_relais_appareils;
+use crate::sante_relais::requete_get_sante_relais;
+use crate::simulateur::requete_simuler_programme;
+use crate::reduction::{reduire_serie, AlgorithmeReduction};
+use crate::retention::{fusionner_sommaires_quotidiens, requete_get_retention};
+use crate::scenes::requete_get_scenes;
+use crate::completude::{requete_completude_senseur, requete_rapports_completude};
+use crate::degres_jours::requete_get_degres_jours;
+use crate::gabarits::{requete_get_derive_gabarit, requete_get_gabarits};
+use crate::horaires::{requete_get_horaires, requete_previsualiser_horaire};
+use crate::statistiques::{requete_comparaison_periodes, requete_comparaison_senseurs, requete_export_statistiques, requete_histogramme_senseur, requete_matrice_horaire_senseur, SenseurRef};
 
 pub async fn consommer_requete<M>(middleware: &M, message: MessageValide, gestionnaire: &SenseursPassifsDomainManager)
                                   -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
@@ -51,6 +66,29 @@ pub async fn consommer_requete<M>(middleware: &M, message: MessageValide, gestio
                     REQUETE_GET_NOEUD => requete_get_noeud(middleware, message, gestionnaire).await,
                     REQUETE_GET_APPAREILS_EN_ATTENTE => requete_get_appareils_en_attente(middleware, message, gestionnaire).await,
                     REQUETE_GET_STATISTIQUES_SENSEUR => requete_get_statistiques_senseur(middleware, message, gestionnaire).await,
+                    REQUETE_EXPORT_STATISTIQUES => requete_export_statistiques(middleware, message, gestionnaire).await,
+                    REQUETE_COMPARAISON_SENSEURS => requete_comparaison_senseurs(middleware, message, gestionnaire).await,
+                    REQUETE_GET_LECTURES_SENSEUR => requete_get_lectures_senseur(middleware, message, gestionnaire).await,
+                    REQUETE_GET_COMPLETUDE_SENSEUR => requete_completude_senseur(middleware, message, gestionnaire).await,
+                    REQUETE_GET_RAPPORTS_COMPLETUDE => requete_rapports_completude(middleware, message, gestionnaire).await,
+                    REQUETE_GET_ANOMALIES_SENSEURS => requete_get_anomalies_senseurs(middleware, message, gestionnaire).await,
+                    REQUETE_COMPARAISON_PERIODES => requete_comparaison_periodes(middleware, message, gestionnaire).await,
+                    REQUETE_GET_DEGRES_JOURS => requete_get_degres_jours(middleware, message, gestionnaire).await,
+                    REQUETE_HISTOGRAMME_SENSEUR => requete_histogramme_senseur(middleware, message, gestionnaire).await,
+                    REQUETE_MATRICE_HORAIRE_SENSEUR => requete_matrice_horaire_senseur(middleware, message, gestionnaire).await,
+                    REQUETE_GET_RETENTION => requete_get_retention(middleware, message, gestionnaire).await,
+                    REQUETE_GET_RELAIS_APPAREILS => requete_get_relais_appareils(middleware, message, gestionnaire).await,
+                    REQUETE_GET_COMMANDES_APPAREIL => requete_get_commandes_appareil(middleware, message, gestionnaire).await,
+                    REQUETE_GET_SCENES => requete_get_scenes(middleware, message, gestionnaire).await,
+                    REQUETE_GET_CLASSES_PROGRAMMES => requete_get_classes_programmes(middleware, message, gestionnaire).await,
+                    REQUETE_GET_VERSIONS_PROGRAMME => requete_get_versions_programme(middleware, message, gestionnaire).await,
+                    REQUETE_DIFF_VERSIONS_PROGRAMME => requete_diff_versions_programme(middleware, message, gestionnaire).await,
+                    REQUETE_SIMULER_PROGRAMME => requete_simuler_programme(middleware, message, gestionnaire).await,
+                    REQUETE_GET_GABARITS => requete_get_gabarits(middleware, message, gestionnaire).await,
+                    REQUETE_GET_DERIVE_GABARIT => requete_get_derive_gabarit(middleware, message, gestionnaire).await,
+                    REQUETE_GET_HORAIRES => requete_get_horaires(middleware, message, gestionnaire).await,
+                    REQUETE_PREVISUALISER_HORAIRE => requete_previsualiser_horaire(middleware, message, gestionnaire).await,
+                    REQUETE_GET_SANTE_RELAIS => requete_get_sante_relais(middleware, message, gestionnaire).await,
                     REQUETE_GET_CONFIGURATION_USAGER => requete_get_configuration_usager(middleware, message, gestionnaire).await,
                     REQUETE_GET_TIMEZONE_APPAREIL => requete_get_timezone_appareil(middleware, message, gestionnaire).await,
                     _ => {
@@ -73,6 +111,28 @@ pub async fn consommer_requete<M>(middleware: &M, message: MessageValide, gestio
                     REQUETE_GET_APPAREIL_PROGRAMMES_CONFIGURATION => requete_appareil_programmes_configuration(middleware, message, gestionnaire).await,
                     REQUETE_GET_APPAREILS_EN_ATTENTE => requete_get_appareils_en_attente(middleware, message, gestionnaire).await,
                     REQUETE_GET_STATISTIQUES_SENSEUR => requete_get_statistiques_senseur(middleware, message, gestionnaire).await,
+                    REQUETE_EXPORT_STATISTIQUES => requete_export_statistiques(middleware, message, gestionnaire).await,
+                    REQUETE_COMPARAISON_SENSEURS => requete_comparaison_senseurs(middleware, message, gestionnaire).await,
+                    REQUETE_GET_LECTURES_SENSEUR => requete_get_lectures_senseur(middleware, message, gestionnaire).await,
+                    REQUETE_GET_COMPLETUDE_SENSEUR => requete_completude_senseur(middleware, message, gestionnaire).await,
+                    REQUETE_GET_RAPPORTS_COMPLETUDE => requete_rapports_completude(middleware, message, gestionnaire).await,
+                    REQUETE_GET_ANOMALIES_SENSEURS => requete_get_anomalies_senseurs(middleware, message, gestionnaire).await,
+                    REQUETE_COMPARAISON_PERIODES => requete_comparaison_periodes(middleware, message, gestionnaire).await,
+                    REQUETE_GET_DEGRES_JOURS => requete_get_degres_jours(middleware, message, gestionnaire).await,
+                    REQUETE_HISTOGRAMME_SENSEUR => requete_histogramme_senseur(middleware, message, gestionnaire).await,
+                    REQUETE_MATRICE_HORAIRE_SENSEUR => requete_matrice_horaire_senseur(middleware, message, gestionnaire).await,
+                    REQUETE_GET_RETENTION => requete_get_retention(middleware, message, gestionnaire).await,
+                    REQUETE_GET_RELAIS_APPAREILS => requete_get_relais_appareils(middleware, message, gestionnaire).await,
+                    REQUETE_GET_COMMANDES_APPAREIL => requete_get_commandes_appareil(middleware, message, gestionnaire).await,
+                    REQUETE_GET_SCENES => requete_get_scenes(middleware, message, gestionnaire).await,
+                    REQUETE_GET_CLASSES_PROGRAMMES => requete_get_classes_programmes(middleware, message, gestionnaire).await,
+                    REQUETE_GET_VERSIONS_PROGRAMME => requete_get_versions_programme(middleware, message, gestionnaire).await,
+                    REQUETE_DIFF_VERSIONS_PROGRAMME => requete_diff_versions_programme(middleware, message, gestionnaire).await,
+                    REQUETE_SIMULER_PROGRAMME => requete_simuler_programme(middleware, message, gestionnaire).await,
+                    REQUETE_GET_GABARITS => requete_get_gabarits(middleware, message, gestionnaire).await,
+                    REQUETE_GET_DERIVE_GABARIT => requete_get_derive_gabarit(middleware, message, gestionnaire).await,
+                    REQUETE_GET_HORAIRES => requete_get_horaires(middleware, message, gestionnaire).await,
+                    REQUETE_PREVISUALISER_HORAIRE => requete_previsualiser_horaire(middleware, message, gestionnaire).await,
                     REQUETE_GET_CONFIGURATION_USAGER => requete_get_configuration_usager(middleware, message, gestionnaire).await,
                     _ => {
                         error!("Message requete/action inconnue : '{}'. Message dropped.", action);
@@ -581,18 +641,27 @@ struct RequeteGetStatistiquesSenseur {
     custom_grouping: Option<String>,
     custom_intervalle_min: Option<usize>,
     custom_intervalle_max: Option<usize>,
+    /// Ajouter une rangee provisoire (heures non aggregees) aux resultats par heure.
+    inclure_courant: Option<bool>,
+    /// Nombre maximal de rangees par serie. Les series plus longues sont reduites.
+    max_points: Option<usize>,
+    /// Algorithme de reduction : lttb (defaut) ou minmax. Voir module reduction.
+    algorithme_reduction: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-struct ResultatStatistiquesSenseurRow {
+pub(crate) struct ResultatStatistiquesSenseurRow {
     #[serde(
     serialize_with = "epochseconds::serialize",
     deserialize_with = "chrono_datetime_as_bson_datetime::deserialize"
     )]
-    heure: ChronoDateTime<Utc>,
-    min: Option<f64>,
-    max: Option<f64>,
-    avg: Option<f64>,
+    pub(crate) heure: ChronoDateTime<Utc>,
+    pub(crate) min: Option<f64>,
+    pub(crate) max: Option<f64>,
+    pub(crate) avg: Option<f64>,
+    /// Rangee calculee a partir des lectures brutes d'une heure qui n'est pas encore fermee.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) provisoire: Option<bool>,
 }
 
 async fn requete_get_statistiques_senseur<M>(middleware: &M, m: MessageValide, _gestionnaire: &SenseursPassifsDomainManager)
@@ -612,19 +681,19 @@ async fn requete_get_statistiques_senseur<M>(middleware: &M, m: MessageValide, _
     };
 
     // Determiner timezone
-    const UTC_STR: &str = "UTC";
-    let tz: Tz = match requete.timezone.as_ref() {
-        Some(tz) => {
-            tz.parse().unwrap_or_else(|e| {
-                info!("requete_get_statistiques_senseur Mauvais timezone, defaulting a UTC : {:?}", e);
-                UTC_STR.parse().expect("utc")
-            })
-        },
-        None => UTC_STR.parse().expect("utc")
-    };
+    let tz = parser_timezone(requete.timezone.as_ref());
 
     debug!("requete_get_statistiques_senseur Timezone {:?} - grouping {:?}", tz, requete.custom_grouping);
 
+    let algorithme_reduction = match AlgorithmeReduction::parse(requete.algorithme_reduction.as_ref()) {
+        Ok(inner) => inner,
+        Err(e) => return Ok(Some(middleware.reponse_err(None, None, Some(e.as_str()))?))
+    };
+    let reduire = |rows: Vec<ResultatStatistiquesSenseurRow>| match requete.max_points {
+        Some(max_points) => reduire_serie(rows, max_points, algorithme_reduction),
+        None => rows
+    };
+
     // let collection = middleware.get_collection(COLLECTIONS_SENSEURS_HORAIRE)?;
 
     let reponse = match requete.custom_grouping.as_ref() {
@@ -643,30 +712,44 @@ async fn requete_get_statistiques_senseur<M>(middleware: &M, m: MessageValide, _
             };
 
             // Query
-            let resultat = query_aggregate(
-                middleware, user_id.as_str(), &requete, grouping.as_str(), &tz, min_date, max_date).await?;
+            let mut resultat = query_aggregate(
+                middleware, user_id.as_str(), &requete.uuid_appareil, &requete.senseur_id,
+                grouping.as_str(), &tz, min_date, max_date).await?;
+
+            if grouping.as_str() == "heures" && max_date.is_none() && requete.inclure_courant == Some(true) {
+                ajouter_rangees_provisoires(
+                    middleware, user_id.as_str(), &requete.uuid_appareil, &requete.senseur_id, &mut resultat).await?;
+            }
 
             json!({
                 "ok": true,
-                "custom": resultat,
+                "custom": reduire(resultat),
             })
         },
         None => {
             let periode72h = {
                 let min_date = Utc::now() - Duration::days(3);
-                query_aggregate(middleware, user_id.as_str(), &requete, "heures", &tz, min_date, None).await?
+                let mut resultat = query_aggregate(
+                    middleware, user_id.as_str(), &requete.uuid_appareil, &requete.senseur_id,
+                    "heures", &tz, min_date, None).await?;
+                if requete.inclure_courant == Some(true) {
+                    ajouter_rangees_provisoires(
+                        middleware, user_id.as_str(), &requete.uuid_appareil, &requete.senseur_id, &mut resultat).await?;
+                }
+                resultat
             };
 
             let periode31j = {
                 let min_date = Utc::now() - Duration::days(31);
                 let min_date = jour_juste(&min_date);
-                query_aggregate(middleware, user_id.as_str(), &requete, "jours", &tz, min_date, None).await?
+                query_aggregate(middleware, user_id.as_str(), &requete.uuid_appareil, &requete.senseur_id,
+                                "jours", &tz, min_date, None).await?
             };
 
             json!({
                 "ok": true,
-                "periode72h": periode72h,
-                "periode31j": periode31j,
+                "periode72h": reduire(periode72h),
+                "periode31j": reduire(periode31j),
             })
         },
     };
@@ -676,15 +759,103 @@ async fn requete_get_statistiques_senseur<M>(middleware: &M, m: MessageValide, _
     Ok(Some(middleware.build_reponse(&reponse)?.0))
 }
 
+/// Ajoute une rangee provisoire pour chaque heure de lectures brutes pas encore aggregee.
+async fn ajouter_rangees_provisoires<M>(
+    middleware: &M, user_id: &str, uuid_appareil: &str, senseur_id: &str, rows: &mut Vec<ResultatStatistiquesSenseurRow>)
+    -> Result<(), Error>
+    where M: MongoDao
+{
+    let derniere_heure = rows.last().map(|r| r.heure);
+    let heures_ouvertes = charger_lectures_heures_ouvertes(middleware, user_id, uuid_appareil, senseur_id).await?;
+    for heure_ouverte in heures_ouvertes {
+        if let Some(derniere_heure) = derniere_heure.as_ref() {
+            if &heure_ouverte.heure <= derniere_heure {
+                continue  // Heure deja aggregee (job en cours)
+            }
+        }
+        let (min, max, avg) = calculer_statistiques_lectures(&heure_ouverte.lectures);
+        rows.push(ResultatStatistiquesSenseurRow {
+            heure: heure_ouverte.heure, min, max, avg, provisoire: Some(true)
+        });
+    }
+    Ok(())
+}
+
+/// Periode maximale couverte par une requete de lectures brutes.
+const CONST_LECTURES_PERIODE_MAX_HEURES: i64 = 24;
+/// Nombre maximal de lectures brutes retournees.
+const CONST_LECTURES_MAX: usize = 5_000;
+
+#[derive(Deserialize)]
+struct RequeteGetLecturesSenseur {
+    uuid_appareil: String,
+    senseur_id: String,
+    /// Debut de la periode, defaut CONST_LECTURES_PERIODE_MAX_HEURES avant la fin.
+    #[serde(default, with="optionepochseconds")]
+    debut: Option<ChronoDateTime<Utc>>,
+    /// Fin de la periode (exclusive), defaut maintenant.
+    #[serde(default, with="optionepochseconds")]
+    fin: Option<ChronoDateTime<Utc>>,
+    /// Nombre maximal de lectures, plafonne a CONST_LECTURES_MAX. Les plus recentes sont conservees.
+    limite: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct ReponseGetLecturesSenseur {
+    ok: bool,
+    lectures: Vec<LectureSenseur>,
+    /// true si des lectures de la periode ont ete retirees par la limite.
+    tronque: bool,
+}
+
+async fn requete_get_lectures_senseur<M>(middleware: &M, m: MessageValide, _gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_get_lectures_senseur Consommer requete : {:?}", & m.message);
+    let requete: RequeteGetLecturesSenseur = deser_message_buffer!(m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    };
+
+    let fin = requete.fin.unwrap_or_else(Utc::now);
+    let debut = requete.debut.unwrap_or(fin - Duration::hours(CONST_LECTURES_PERIODE_MAX_HEURES));
+    if fin <= debut || (fin - debut).num_hours() > CONST_LECTURES_PERIODE_MAX_HEURES {
+        let message = format!("La periode doit etre positive et d'au plus {} heures", CONST_LECTURES_PERIODE_MAX_HEURES);
+        return Ok(Some(middleware.reponse_err(None, None, Some(message.as_str()))?))
+    }
+    let limite = requete.limite.unwrap_or(CONST_LECTURES_MAX).min(CONST_LECTURES_MAX);
+
+    let heures_ouvertes = charger_lectures_heures_ouvertes(
+        middleware, user_id.as_str(), &requete.uuid_appareil, &requete.senseur_id).await?;
+    let mut lectures: Vec<LectureSenseur> = heures_ouvertes.into_iter()
+        .filter(|h| h.heure < fin && h.heure + Duration::hours(1) > debut)
+        .flat_map(|h| h.lectures)
+        .filter(|l| l.timestamp >= debut && l.timestamp < fin)
+        .collect();
+
+    let tronque = lectures.len() > limite;
+    if tronque {
+        lectures.drain(..lectures.len() - limite);
+    }
+
+    let reponse = ReponseGetLecturesSenseur { ok: true, lectures, tronque };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
 #[derive(Deserialize)]
 struct RequeteGetConfigurationUsager {
     user_id: Option<String>
 }
 
 #[derive(Deserialize)]
-struct RowCollectionUsager {
-    user_id: String,
-    timezone: Option<String>,
+pub(crate) struct RowCollectionUsager {
+    pub(crate) user_id: String,
+    pub(crate) timezone: Option<String>,
+    pub(crate) anomalie_z_limite: Option<f64>,
+    pub(crate) senseur_exterieur: Option<SenseurRef>,
 }
 
 impl RowCollectionUsager {
@@ -694,16 +865,32 @@ impl RowCollectionUsager {
         Self {
             user_id: user_id.to_string(),
             timezone: None,
+            anomalie_z_limite: None,
+            senseur_exterieur: None,
         }
     }
 }
 
+/// Charge la configuration de l'usager, valeurs par defaut si absente.
+pub(crate) async fn charger_configuration_usager<M>(middleware: &M, user_id: &str) -> Result<RowCollectionUsager, Error>
+    where M: MongoDao
+{
+    let collection = middleware.get_collection_typed::<RowCollectionUsager>(COLLECTIONS_USAGER)?;
+    let filtre = doc! { CHAMP_USER_ID: user_id };
+    match collection.find_one(filtre, None).await? {
+        Some(inner) => Ok(inner),
+        None => Ok(RowCollectionUsager::default(user_id))
+    }
+}
+
 #[derive(Serialize)]
 struct ReponseGetConfigurationUsager {
     ok: bool,
     user_id: String,
     timezone: Option<String>,
     geoposition: Option<GeopositionAppareil>,
+    anomalie_z_limite: Option<f64>,
+    senseur_exterieur: Option<SenseurRef>,
 }
 
 impl From<RowCollectionUsager> for ReponseGetConfigurationUsager {
@@ -713,6 +900,8 @@ impl From<RowCollectionUsager> for ReponseGetConfigurationUsager {
             user_id: value.user_id,
             timezone: value.timezone,
             geoposition: None,
+            anomalie_z_limite: value.anomalie_z_limite,
+            senseur_exterieur: value.senseur_exterieur,
         }
     }
 }
@@ -743,12 +932,7 @@ async fn requete_get_configuration_usager<M>(middleware: &M, m: MessageValide, _
         }
     };
 
-    let collection = middleware.get_collection_typed::<RowCollectionUsager>(COLLECTIONS_USAGER)?;
-    let filtre = doc! { CHAMP_USER_ID: &user_id };
-    let configuration_usager = match collection.find_one(filtre, None).await? {
-        Some(inner) => inner,
-        None => RowCollectionUsager::default(&user_id)
-    };
+    let configuration_usager = charger_configuration_usager(middleware, user_id.as_str()).await?;
 
     let reponse = ReponseGetConfigurationUsager::from(configuration_usager);
 
@@ -815,8 +999,8 @@ async fn requete_get_timezone_appareil<M>(middleware: &M, m: MessageValide, _ges
     Ok(Some(middleware.build_reponse(reponse)?.0))
 }
 
-async fn query_aggregate<M>(
-    middleware: &M, user_id: &str, requete: &RequeteGetStatistiquesSenseur, grouping: &str,
+pub(crate) async fn query_aggregate<M>(
+    middleware: &M, user_id: &str, uuid_appareil: &str, senseur_id: &str, grouping: &str,
     tz: &Tz, min_date: ChronoDateTime<Utc>, max_date: Option<ChronoDateTime<Utc>>
 )
     -> Result<Vec<ResultatStatistiquesSenseurRow>, Error>
@@ -831,8 +1015,8 @@ async fn query_aggregate<M>(
 
     let filtre = doc! {
         "user_id": user_id,
-        "uuid_appareil": &requete.uuid_appareil,
-        "senseur_id": &requete.senseur_id,
+        "uuid_appareil": uuid_appareil,
+        "senseur_id": senseur_id,
         "heure": intervalle_heures,
     };
 
@@ -852,6 +1036,11 @@ async fn query_aggregate<M>(
         reponse.push(row);
     }
 
+    if grouping == "jours" {
+        // Jours elagues par la politique de retention
+        fusionner_sommaires_quotidiens(middleware, &mut reponse, user_id, uuid_appareil, senseur_id, tz, min_date, max_date).await?;
+    }
+
     Ok(reponse)
 }
 
@@ -879,7 +1068,21 @@ fn pipeline_jour(filtre: Document, tz: &Tz) -> Vec<Document> {
     ]
 }
 
-fn jour_juste(date: &ChronoDateTime<Utc>) -> ChronoDateTime<Utc> {
+/// Convertit le nom de timezone recu (e.g. America/Toronto). Utilise UTC si absent ou invalide.
+pub(crate) fn parser_timezone(timezone: Option<&String>) -> Tz {
+    const UTC_STR: &str = "UTC";
+    match timezone {
+        Some(tz) => {
+            tz.parse().unwrap_or_else(|e| {
+                info!("parser_timezone Mauvais timezone, defaulting a UTC : {:?}", e);
+                UTC_STR.parse().expect("utc")
+            })
+        },
+        None => UTC_STR.parse().expect("utc")
+    }
+}
+
+pub(crate) fn jour_juste(date: &ChronoDateTime<Utc>) -> ChronoDateTime<Utc> {
     date.with_hour(0).expect("with_minutes")
         .with_minute(0).expect("with_minutes")
         .with_second(0).expect("with_seconds")
@@ -0,0 +1,581 @@
+use std::collections::HashMap;
+use log::{debug, error, info, warn};
+use chrono_tz::Tz;
+
+use millegrilles_common_rust::bson::{doc, Bson, Document};
+use millegrilles_common_rust::bson::serde_helpers::chrono_datetime_as_bson_datetime;
+use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
+use millegrilles_common_rust::chrono::{DateTime, Duration, NaiveTime, TimeZone, Utc};
+use millegrilles_common_rust::db_structs::TransactionValide;
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::GenerateurMessages;
+use millegrilles_common_rust::middleware::{sauvegarder_traiter_transaction_serializable_v2, sauvegarder_traiter_transaction_v2};
+use millegrilles_common_rust::millegrilles_cryptographie::deser_message_buffer;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::{epochseconds, MessageMilleGrillesBufferDefault};
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::mongodb::ClientSession;
+use millegrilles_common_rust::mongodb::options::{FindOptions, UpdateOptions};
+use millegrilles_common_rust::recepteur_messages::MessageValide;
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+use millegrilles_common_rust::serde_json;
+use millegrilles_common_rust::tokio_stream::StreamExt;
+use millegrilles_common_rust::constantes::*;
+
+use crate::common::*;
+use crate::domain_manager::SenseursPassifsDomainManager;
+use crate::requetes::{charger_configuration_usager, parser_timezone, ResultatStatistiquesSenseurRow};
+use crate::statistiques::formatter_intervalle;
+
+/// Retention minimale des rangees horaires. Evite d'effacer des donnees recentes par erreur.
+const CONST_RETENTION_JOURS_MIN: u32 = 31;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionMajRetention {
+    /// Appareil vise. La configuration s'applique a tous les appareils de l'usager si absent.
+    pub uuid_appareil: Option<String>,
+    /// Nombre de jours de conservation des rangees horaires. None retire la configuration.
+    pub jours_horaire: Option<u32>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionElaguerSenseursHoraire {
+    pub user_id: String,
+    pub uuid_appareil: String,
+    /// Les rangees horaires anterieures sont remplacees par des sommaires quotidiens.
+    #[serde(with="epochseconds")]
+    pub avant: DateTime<Utc>,
+    /// Timezone utilisee pour regrouper les jours.
+    pub timezone: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RowRetention {
+    user_id: String,
+    uuid_appareil: Option<String>,
+    jours_horaire: u32,
+}
+
+#[derive(Deserialize)]
+struct RowSenseurQuotidien {
+    jour: String,
+    #[serde(with="chrono_datetime_as_bson_datetime")]
+    heure: DateTime<Utc>,
+    min: Option<f64>,
+    max: Option<f64>,
+    somme: f64,
+    nombre_heures: i64,
+    #[serde(rename="type")]
+    type_: Option<String>,
+    timezone: Option<String>,
+}
+
+/// Sommaire quotidien d'un senseur conserve dans une transaction senseurHoraireMensuel. Les rangees
+/// horaires du jour ont ete elaguees avant la compaction et ne peuvent plus etre reconstruites.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SommaireQuotidien {
+    /// Jour local (YYYY-MM-DD) dans la timezone de l'elagage.
+    pub jour: String,
+    /// Premiere heure du jour.
+    #[serde(with="epochseconds")]
+    pub heure: DateTime<Utc>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub somme: f64,
+    pub nombre_heures: i64,
+    #[serde(rename="type", skip_serializing_if = "Option::is_none")]
+    pub type_: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+}
+
+impl From<RowSenseurQuotidien> for SommaireQuotidien {
+    fn from(value: RowSenseurQuotidien) -> Self {
+        Self {
+            jour: value.jour,
+            heure: value.heure,
+            min: value.min,
+            max: value.max,
+            somme: value.somme,
+            nombre_heures: value.nombre_heures,
+            type_: value.type_,
+            timezone: value.timezone,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ElagageAggregateId {
+    senseur_id: String,
+    jour: String,
+}
+
+#[derive(Deserialize)]
+struct ElagageAggregateRow {
+    _id: ElagageAggregateId,
+    #[serde(with="chrono_datetime_as_bson_datetime")]
+    heure: DateTime<Utc>,
+    min: Option<f64>,
+    max: Option<f64>,
+    somme: f64,
+    nombre_heures: i64,
+    #[serde(rename="type")]
+    type_: Option<String>,
+}
+
+pub async fn commande_maj_retention<M>(middleware: &M, m: MessageValide, gestionnaire: &SenseursPassifsDomainManager, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    debug!("commande_maj_retention Consommer commande : {:?}", & m.type_message);
+    let commande: TransactionMajRetention = deser_message_buffer!(m.message);
+
+    if m.certificat.get_user_id()?.is_none() {
+        return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    }
+
+    if let Some(jours) = commande.jours_horaire {
+        if jours < CONST_RETENTION_JOURS_MIN {
+            let message = format!("jours_horaire doit etre d'au moins {} jours", CONST_RETENTION_JOURS_MIN);
+            return Ok(Some(middleware.reponse_err(None, None, Some(message.as_str()))?))
+        }
+    }
+
+    Ok(sauvegarder_traiter_transaction_v2(middleware, m, gestionnaire, session).await?)
+}
+
+pub async fn transaction_maj_retention<M>(middleware: &M, transaction: TransactionValide, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("transaction_maj_retention Consommer transaction : {:?}", transaction.transaction.id);
+    let contenu_transaction: TransactionMajRetention = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+
+    let user_id = match transaction.certificat.get_user_id()? {
+        Some(user) => user.to_owned(),
+        None => Err(Error::Str("senseurspassifs.transaction_maj_retention Erreur user_id absent du certificat"))?
+    };
+
+    let filtre = doc! { CHAMP_USER_ID: &user_id, CHAMP_UUID_APPAREIL: contenu_transaction.uuid_appareil.clone() };
+    let collection = middleware.get_collection(COLLECTIONS_RETENTION)?;
+    match contenu_transaction.jours_horaire {
+        Some(jours) => {
+            let ops = doc! {
+                "$set": { "jours_horaire": jours },
+                "$setOnInsert": {
+                    CHAMP_USER_ID: &user_id,
+                    CHAMP_UUID_APPAREIL: contenu_transaction.uuid_appareil.clone(),
+                    CHAMP_CREATION: Utc::now(),
+                },
+                "$currentDate": { CHAMP_MODIFICATION: true },
+            };
+            let options = UpdateOptions::builder().upsert(true).build();
+            collection.update_one_with_session(filtre, ops, options, session).await?;
+        },
+        None => {
+            collection.delete_one_with_session(filtre, None, session).await?;
+        }
+    }
+
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
+
+/// Debut du jour local de (maintenant - jours). Les jours elagues sont toujours complets.
+fn calculer_limite_retention(maintenant: &DateTime<Utc>, jours: u32, tz: &Tz) -> DateTime<Utc> {
+    let date_locale = (*maintenant - Duration::days(jours as i64)).with_timezone(tz).date_naive();
+    match tz.from_local_datetime(&date_locale.and_time(NaiveTime::MIN)).earliest() {
+        Some(inner) => inner.with_timezone(&Utc),
+        None => date_locale.and_time(NaiveTime::MIN).and_utc()
+    }
+}
+
+/// Applique les configurations de retention. Emet une transaction d'elagage par appareil avec
+/// des rangees horaires plus vieilles que la limite.
+pub async fn appliquer_retention<M>(middleware: &M, gestionnaire: &SenseursPassifsDomainManager) -> Result<(), Error>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    let collection_retention = middleware.get_collection_typed::<RowRetention>(COLLECTIONS_RETENTION)?;
+    let mut curseur = collection_retention.find(doc!{}, None).await?;
+
+    // Configuration usager (defaut) et configurations par appareil
+    let mut configurations: HashMap<String, (Option<u32>, HashMap<String, u32>)> = HashMap::new();
+    while let Some(row) = curseur.next().await {
+        let row = row?;
+        let configuration = configurations.entry(row.user_id).or_insert((None, HashMap::new()));
+        match row.uuid_appareil {
+            Some(uuid_appareil) => { configuration.1.insert(uuid_appareil, row.jours_horaire); },
+            None => configuration.0 = Some(row.jours_horaire),
+        }
+    }
+
+    let maintenant = Utc::now();
+    for (user_id, (defaut, appareils)) in configurations {
+        let tz = parser_timezone(charger_configuration_usager(middleware, user_id.as_str()).await?.timezone.as_ref());
+
+        let mut uuid_appareils: Vec<String> = appareils.keys().cloned().collect();
+        if defaut.is_some() {
+            let collection_appareils = middleware.get_collection(COLLECTIONS_APPAREILS)?;
+            for uuid_appareil in collection_appareils.distinct(CHAMP_UUID_APPAREIL, doc!{CHAMP_USER_ID: &user_id}, None).await? {
+                if let Bson::String(uuid_appareil) = uuid_appareil {
+                    if !appareils.contains_key(&uuid_appareil) {
+                        uuid_appareils.push(uuid_appareil);
+                    }
+                }
+            }
+        }
+
+        for uuid_appareil in uuid_appareils {
+            let jours = match appareils.get(&uuid_appareil).cloned().or(defaut) {
+                Some(inner) => inner,
+                None => continue
+            };
+            let avant = calculer_limite_retention(&maintenant, jours, &tz);
+            if let Err(e) = elaguer_appareil(middleware, gestionnaire, &user_id, uuid_appareil, avant, &tz).await {
+                error!("appliquer_retention Erreur elagage appareil user_id {} : {:?}", user_id, e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn elaguer_appareil<M>(
+    middleware: &M, gestionnaire: &SenseursPassifsDomainManager, user_id: &str, uuid_appareil: String,
+    avant: DateTime<Utc>, tz: &Tz
+)
+    -> Result<(), Error>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    let filtre = doc! { CHAMP_USER_ID: user_id, CHAMP_UUID_APPAREIL: &uuid_appareil, "heure": {"$lt": avant} };
+    let collection = middleware.get_collection(COLLECTIONS_SENSEURS_HORAIRE)?;
+    if collection.find_one(filtre, None).await?.is_none() {
+        return Ok(())  // Rien a elaguer
+    }
+
+    info!("elaguer_appareil Elagage rangees horaires user_id {} appareil {} avant {:?}", user_id, uuid_appareil, avant);
+    let transaction = TransactionElaguerSenseursHoraire {
+        user_id: user_id.to_string(),
+        uuid_appareil,
+        avant,
+        timezone: tz.to_string(),
+    };
+
+    let mut session = middleware.get_session().await?;
+    session.start_transaction(None).await?;
+    match sauvegarder_traiter_transaction_serializable_v2(
+        middleware, &transaction, gestionnaire, &mut session, DOMAINE_NOM, TRANSACTION_ELAGUER_SENSEURS_HORAIRE).await
+    {
+        Ok(_) => session.commit_transaction().await?,
+        Err(e) => {
+            session.abort_transaction().await?;
+            Err(e)?
+        }
+    }
+
+    Ok(())
+}
+
+/// Remplace les rangees horaires anterieures a la limite par des sommaires quotidiens.
+pub async fn transaction_elaguer_senseurs_horaire<M>(middleware: &M, transaction: TransactionValide, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("transaction_elaguer_senseurs_horaire Consommer transaction : {:?}", transaction.transaction.id);
+    let contenu_transaction: TransactionElaguerSenseursHoraire = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+
+    let filtre = doc! {
+        CHAMP_USER_ID: &contenu_transaction.user_id,
+        CHAMP_UUID_APPAREIL: &contenu_transaction.uuid_appareil,
+        "heure": {"$lt": contenu_transaction.avant},
+    };
+    let pipeline = vec![
+        doc! { "$match": filtre.clone() },
+        doc! { "$group": {
+            "_id": {
+                "senseur_id": "$senseur_id",
+                "jour": { "$dateToString": { "format": "%Y-%m-%d", "date": "$heure", "timezone": &contenu_transaction.timezone } },
+            },
+            "heure": {"$min": "$heure"},
+            "min": {"$min": "$min"},
+            "max": {"$max": "$max"},
+            "somme": {"$sum": "$avg"},
+            "nombre_heures": {"$sum": {"$cond": [{"$isNumber": "$avg"}, 1, 0]}},
+            "type": {"$last": "$type"},
+        } },
+    ];
+
+    let collection_horaire = middleware.get_collection(COLLECTIONS_SENSEURS_HORAIRE)?;
+    let collection_quotidien = middleware.get_collection(COLLECTIONS_SENSEURS_QUOTIDIEN)?;
+    let mut curseur = collection_horaire.aggregate_with_session(pipeline, None, session).await?;
+    let mut rows = Vec::new();
+    while let Some(d) = curseur.next(session).await {
+        rows.push(convertir_bson_deserializable::<ElagageAggregateRow>(d?)?);
+    }
+
+    for row in rows {
+        let filtre_quotidien = doc! {
+            CHAMP_USER_ID: &contenu_transaction.user_id,
+            CHAMP_UUID_APPAREIL: &contenu_transaction.uuid_appareil,
+            "senseur_id": &row._id.senseur_id,
+            "jour": &row._id.jour,
+        };
+
+        // Cumuler avec un sommaire existant (e.g. rangee horaire recue en retard)
+        let mut ops_min = doc! { "heure": row.heure };
+        if let Some(min) = row.min { ops_min.insert("min", min); }
+        let mut ops_max = Document::new();
+        if let Some(max) = row.max { ops_max.insert("max", max); }
+        let mut ops = doc! {
+            "$inc": { "somme": row.somme, "nombre_heures": row.nombre_heures },
+            "$min": ops_min,
+            "$set": { "type": row.type_ },
+            "$setOnInsert": { CHAMP_TIMEZONE: &contenu_transaction.timezone, CHAMP_CREATION: Utc::now() },
+            "$currentDate": { CHAMP_MODIFICATION: true },
+        };
+        if !ops_max.is_empty() {
+            ops.insert("$max", ops_max);
+        }
+        let options = UpdateOptions::builder().upsert(true).build();
+        collection_quotidien.update_one_with_session(filtre_quotidien, ops, options, session).await?;
+    }
+
+    let resultat = collection_horaire.delete_many_with_session(filtre, None, session).await?;
+    debug!("transaction_elaguer_senseurs_horaire {} rangees horaires supprimees pour appareil {}",
+        resultat.deleted_count, contenu_transaction.uuid_appareil);
+
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
+
+/// Charge les sommaires quotidiens conserves apres elagage. Les jours sont ceux de la timezone
+/// utilisee lors de l'elagage.
+pub(crate) async fn charger_sommaires_quotidiens<M>(
+    middleware: &M, user_id: &str, uuid_appareil: &str, senseur_id: &str,
+    min_date: DateTime<Utc>, max_date: Option<DateTime<Utc>>
+)
+    -> Result<Vec<ResultatStatistiquesSenseurRow>, Error>
+    where M: MongoDao
+{
+    let mut intervalle_heures = doc! {"$gte": min_date};
+    if let Some(inner) = max_date {
+        intervalle_heures.insert("$lt", inner);
+    }
+    let filtre = doc! {
+        CHAMP_USER_ID: user_id,
+        CHAMP_UUID_APPAREIL: uuid_appareil,
+        "senseur_id": senseur_id,
+        "heure": intervalle_heures,
+    };
+    let options = FindOptions::builder().sort(doc!{"heure": 1}).build();
+    let collection = middleware.get_collection(COLLECTIONS_SENSEURS_QUOTIDIEN)?;
+    let mut curseur = collection.find(filtre, options).await?;
+    let mut rows = Vec::new();
+    while let Some(d) = curseur.next().await {
+        let row: RowSenseurQuotidien = convertir_bson_deserializable(d?)?;
+        let avg = match row.nombre_heures {
+            0 => None,
+            n => Some(row.somme / n as f64)
+        };
+        rows.push(ResultatStatistiquesSenseurRow { heure: row.heure, min: row.min, max: row.max, avg, provisoire: None });
+    }
+    Ok(rows)
+}
+
+/// Ajoute les sommaires quotidiens pour les jours absents de rows (grouping jours).
+pub(crate) async fn fusionner_sommaires_quotidiens<M>(
+    middleware: &M, rows: &mut Vec<ResultatStatistiquesSenseurRow>, user_id: &str, uuid_appareil: &str,
+    senseur_id: &str, tz: &Tz, min_date: DateTime<Utc>, max_date: Option<DateTime<Utc>>
+)
+    -> Result<(), Error>
+    where M: MongoDao
+{
+    let sommaires = charger_sommaires_quotidiens(middleware, user_id, uuid_appareil, senseur_id, min_date, max_date).await?;
+    if sommaires.is_empty() {
+        return Ok(())
+    }
+
+    let jours_presents: Vec<String> = rows.iter().map(|r| formatter_intervalle(&r.heure, "jours", tz)).collect();
+    for sommaire in sommaires {
+        if !jours_presents.contains(&formatter_intervalle(&sommaire.heure, "jours", tz)) {
+            rows.push(sommaire);
+        }
+    }
+    rows.sort_by_key(|r| r.heure);
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct RetentionReponse {
+    uuid_appareil: Option<String>,
+    jours_horaire: u32,
+}
+
+#[derive(Serialize)]
+struct ReponseGetRetention {
+    ok: bool,
+    configurations: Vec<RetentionReponse>,
+}
+
+pub async fn requete_get_retention<M>(middleware: &M, m: MessageValide, _gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_get_retention Consommer requete : {:?}", & m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    };
+
+    let collection = middleware.get_collection_typed::<RowRetention>(COLLECTIONS_RETENTION)?;
+    let mut curseur = collection.find(doc!{CHAMP_USER_ID: &user_id}, None).await?;
+    let mut configurations = Vec::new();
+    while let Some(row) = curseur.next().await {
+        match row {
+            Ok(row) => configurations.push(RetentionReponse { uuid_appareil: row.uuid_appareil, jours_horaire: row.jours_horaire }),
+            Err(e) => warn!("requete_get_retention Erreur mapping retention : {:?}", e)
+        }
+    }
+
+    let reponse = ReponseGetRetention { ok: true, configurations };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+#[derive(Deserialize)]
+struct RowJourElague {
+    jour: String,
+    timezone: Option<String>,
+}
+
+/// Jours deja elagues pour un senseur. Chaque jour est accompagne de la timezone utilisee lors de l'elagage.
+pub(crate) struct JoursElagues(Vec<(String, Tz)>);
+
+impl JoursElagues {
+    pub(crate) fn contient(&self, heure: &DateTime<Utc>) -> bool {
+        self.0.iter().any(|(jour, tz)| &formatter_intervalle(heure, "jours", tz) == jour)
+    }
+}
+
+impl From<&Vec<SommaireQuotidien>> for JoursElagues {
+    fn from(value: &Vec<SommaireQuotidien>) -> Self {
+        Self(value.iter().map(|s| (s.jour.clone(), parser_timezone(s.timezone.as_ref()))).collect())
+    }
+}
+
+/// Filtre sur les sommaires quotidiens d'un senseur entre debut et fin. Le champ heure d'un sommaire
+/// est la premiere heure du jour, la recherche est elargie d'une journee.
+fn filtre_sommaires_senseur(user_id: &str, uuid_appareil: &str, senseur_id: &str, debut: &DateTime<Utc>, fin: &DateTime<Utc>)
+    -> Document
+{
+    doc! {
+        CHAMP_USER_ID: user_id,
+        CHAMP_UUID_APPAREIL: uuid_appareil,
+        "senseur_id": senseur_id,
+        "heure": {"$gte": *debut - Duration::days(1), "$lt": fin},
+    }
+}
+
+/// Charge les jours elagues entre debut et fin. Une rangee horaire recue pour un de ces jours
+/// (e.g. transaction compactee) ne doit pas etre reinseree.
+pub(crate) async fn charger_jours_elagues<M>(
+    middleware: &M, user_id: &str, uuid_appareil: &str, senseur_id: &str,
+    debut: &DateTime<Utc>, fin: &DateTime<Utc>, session: &mut ClientSession
+)
+    -> Result<JoursElagues, Error>
+    where M: MongoDao
+{
+    let filtre = filtre_sommaires_senseur(user_id, uuid_appareil, senseur_id, debut, fin);
+    let options = FindOptions::builder().projection(doc!{"jour": 1, CHAMP_TIMEZONE: 1}).build();
+    let collection = middleware.get_collection(COLLECTIONS_SENSEURS_QUOTIDIEN)?;
+    let mut curseur = collection.find_with_session(filtre, options, session).await?;
+    let mut jours = Vec::new();
+    while let Some(d) = curseur.next(session).await {
+        let row: RowJourElague = convertir_bson_deserializable(d?)?;
+        jours.push((row.jour, parser_timezone(row.timezone.as_ref())));
+    }
+    Ok(JoursElagues(jours))
+}
+
+/// Charge les sommaires quotidiens d'un senseur entre debut et fin pour les conserver dans une
+/// transaction compactee.
+pub(crate) async fn charger_sommaires_elagues<M>(
+    middleware: &M, user_id: &str, uuid_appareil: &str, senseur_id: &str,
+    debut: &DateTime<Utc>, fin: &DateTime<Utc>, session: &mut ClientSession
+)
+    -> Result<Vec<SommaireQuotidien>, Error>
+    where M: MongoDao
+{
+    let filtre = filtre_sommaires_senseur(user_id, uuid_appareil, senseur_id, debut, fin);
+    let options = FindOptions::builder().sort(doc!{"heure": 1}).build();
+    let collection = middleware.get_collection(COLLECTIONS_SENSEURS_QUOTIDIEN)?;
+    let mut curseur = collection.find_with_session(filtre, options, session).await?;
+    let mut sommaires = Vec::new();
+    while let Some(d) = curseur.next(session).await {
+        let row: RowSenseurQuotidien = convertir_bson_deserializable(d?)?;
+        sommaires.push(SommaireQuotidien::from(row));
+    }
+    Ok(sommaires)
+}
+
+/// Conserve les sommaires quotidiens d'une transaction compactee. Les valeurs remplacent celles d'un
+/// sommaire existant (meme sommaire deja present ou reconstruit lors d'une regeneration).
+pub(crate) async fn conserver_sommaires_quotidiens<M>(
+    middleware: &M, user_id: &str, uuid_appareil: &str, senseur_id: &str, sommaires: &Vec<SommaireQuotidien>,
+    session: &mut ClientSession
+)
+    -> Result<(), Error>
+    where M: MongoDao
+{
+    let collection = middleware.get_collection(COLLECTIONS_SENSEURS_QUOTIDIEN)?;
+    for sommaire in sommaires {
+        let filtre = doc! {
+            CHAMP_USER_ID: user_id,
+            CHAMP_UUID_APPAREIL: uuid_appareil,
+            "senseur_id": senseur_id,
+            "jour": &sommaire.jour,
+        };
+        let ops = doc! {
+            "$set": {
+                "heure": sommaire.heure,
+                "min": sommaire.min,
+                "max": sommaire.max,
+                "somme": sommaire.somme,
+                "nombre_heures": sommaire.nombre_heures,
+                "type": sommaire.type_.as_ref(),
+                CHAMP_TIMEZONE: sommaire.timezone.as_ref(),
+            },
+            "$setOnInsert": { CHAMP_CREATION: Utc::now() },
+            "$currentDate": { CHAMP_MODIFICATION: true },
+        };
+        let options = UpdateOptions::builder().upsert(true).build();
+        collection.update_one_with_session(filtre, ops, options, session).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_setup::setup;
+
+    fn date(timestamp: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(timestamp, 0).expect("timestamp")
+    }
+
+    #[test]
+    fn test_limite_retention_minuit_local() {
+        setup("test_limite_retention_minuit_local");
+        let tz: Tz = "America/Toronto".parse().expect("tz");
+        // 2024-03-15 08:00 EDT - 5 jours => 2024-03-10 00:00 EST
+        assert_eq!(date(1710046800), calculer_limite_retention(&date(1710504000), 5, &tz));
+        // 2024-11-04 12:00 EST - 1 jour => 2024-11-03 00:00 EDT (jour de 25 heures)
+        assert_eq!(date(1730606400), calculer_limite_retention(&date(1730739600), 1, &tz));
+    }
+
+    #[test]
+    fn test_limite_retention_utc() {
+        setup("test_limite_retention_utc");
+        let tz: Tz = "UTC".parse().expect("tz");
+        assert_eq!(date(1710460800), calculer_limite_retention(&date(1710504000), 0, &tz));
+        assert_eq!(date(1710460800) - Duration::days(31), calculer_limite_retention(&date(1710504000), 31, &tz));
+    }
+}
@@ -0,0 +1,233 @@
+use log::{debug, info, warn};
+
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
+use millegrilles_common_rust::chrono;
+use millegrilles_common_rust::chrono::{DateTime, Utc};
+use millegrilles_common_rust::constantes::*;
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
+use millegrilles_common_rust::millegrilles_cryptographie::deser_message_buffer;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::{optionepochseconds, MessageMilleGrillesBufferDefault};
+use millegrilles_common_rust::mongo_dao::{opt_chrono_datetime_as_bson_datetime, start_transaction_regular, MongoDao};
+use millegrilles_common_rust::mongodb::ClientSession;
+use millegrilles_common_rust::mongodb::options::{FindOptions, UpdateOptions};
+use millegrilles_common_rust::recepteur_messages::MessageValide;
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+use millegrilles_common_rust::tokio_stream::StreamExt;
+
+use crate::common::*;
+use crate::domain_manager::SenseursPassifsDomainManager;
+use crate::maintenance::deconnecter_appareils_instance;
+
+const CONST_ETAT_RELAI_ACTIF: &str = "actif";
+const CONST_ETAT_RELAI_PERDU: &str = "perdu";
+const CONST_ETAT_RELAI_DECONNECTE: &str = "deconnecte";
+
+/// Heartbeat emis periodiquement par un relai. L'instance est le commonName du certificat.
+#[derive(Debug, Deserialize)]
+struct EvenementHeartbeatRelai {
+    version: Option<String>,
+    appareils_connectes: Option<u32>,
+    messages_recus_minute: Option<f64>,
+    messages_emis_minute: Option<f64>,
+    derniere_erreur: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RowSanteRelai {
+    pub instance_id: String,
+    pub etat: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub appareils_connectes: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub messages_recus_minute: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub messages_emis_minute: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub derniere_erreur: Option<String>,
+    #[serde(default,
+        serialize_with = "optionepochseconds::serialize",
+        deserialize_with = "opt_chrono_datetime_as_bson_datetime::deserialize")]
+    pub date_derniere_erreur: Option<DateTime<Utc>>,
+    #[serde(default,
+        serialize_with = "optionepochseconds::serialize",
+        deserialize_with = "opt_chrono_datetime_as_bson_datetime::deserialize")]
+    pub premier_heartbeat: Option<DateTime<Utc>>,
+    #[serde(default,
+        serialize_with = "optionepochseconds::serialize",
+        deserialize_with = "opt_chrono_datetime_as_bson_datetime::deserialize")]
+    pub dernier_heartbeat: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct EvenementRelaiPerdu<'a> {
+    instance_id: &'a str,
+    #[serde(with="optionepochseconds")]
+    dernier_heartbeat: Option<DateTime<Utc>>,
+    appareils_deconnectes: u64,
+}
+
+pub async fn evenement_heartbeat_relai<M>(middleware: &M, m: &MessageValide) -> Result<(), Error>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    debug!("evenement_heartbeat_relai Recu evenement {:?}", &m.type_message);
+    let certificat = m.certificat.as_ref();
+    if ! certificat.verifier_exchanges(vec![Securite::L2Prive])? {
+        warn!("evenement_heartbeat_relai Evenement heartbeatRelai recu sans securite 2.prive, SKIP");
+        return Ok(())
+    }
+    if ! certificat.verifier_roles_string(vec![ROLE_RELAI_NOM.to_string()])? {
+        warn!("evenement_heartbeat_relai Evenement heartbeatRelai recu sans role {}, SKIP", ROLE_RELAI_NOM);
+        return Ok(())
+    }
+
+    let evenement: EvenementHeartbeatRelai = deser_message_buffer!(m.message);
+    let instance_id = certificat.get_common_name()?;
+
+    let mut set_ops = doc! {
+        "etat": CONST_ETAT_RELAI_ACTIF,
+        CHAMP_VERSION: evenement.version,
+        "appareils_connectes": evenement.appareils_connectes,
+        "messages_recus_minute": evenement.messages_recus_minute,
+        "messages_emis_minute": evenement.messages_emis_minute,
+    };
+    let mut current_date = doc! { CHAMP_MODIFICATION: true, "dernier_heartbeat": true };
+    if let Some(erreur) = evenement.derniere_erreur {
+        // La derniere erreur est conservee jusqu'a la prochaine erreur
+        set_ops.insert("derniere_erreur", erreur);
+        current_date.insert("date_derniere_erreur", true);
+    }
+
+    let filtre = doc! { CHAMP_INSTANCE_ID: &instance_id };
+    let ops = doc! {
+        "$set": set_ops,
+        "$setOnInsert": { CHAMP_INSTANCE_ID: &instance_id, CHAMP_CREATION: Utc::now(), "premier_heartbeat": Utc::now() },
+        "$currentDate": current_date,
+    };
+    let options = UpdateOptions::builder().upsert(true).build();
+    let collection = middleware.get_collection(COLLECTIONS_SANTE_RELAIS)?;
+    collection.update_one(filtre, ops, options).await?;
+
+    Ok(())
+}
+
+/// Marque un relai deconnecte volontairement (commande disconnectRelay). Evite l'evenement relaiPerdu.
+pub async fn marquer_relai_deconnecte<M>(middleware: &M, instance_id: &str, session: &mut ClientSession)
+    -> Result<(), Error>
+    where M: MongoDao
+{
+    let filtre = doc! { CHAMP_INSTANCE_ID: instance_id };
+    let ops = doc! {
+        "$set": { "etat": CONST_ETAT_RELAI_DECONNECTE },
+        "$currentDate": { CHAMP_MODIFICATION: true },
+    };
+    let collection = middleware.get_collection(COLLECTIONS_SANTE_RELAIS)?;
+    collection.update_one_with_session(filtre, ops, None, session).await?;
+    Ok(())
+}
+
+/// Detecte les relais qui n'emettent plus de heartbeat. Les appareils du relai sont marques
+/// deconnectes immediatement plutot qu'a l'expiration de leur derniere lecture.
+pub async fn detecter_relais_perdus<M>(middleware: &M) -> Result<(), Error>
+    where M: GenerateurMessages + MongoDao
+{
+    let expiration = Utc::now() - chrono::Duration::seconds(CONST_RELAI_HEARTBEAT_TIMEOUT_SECS);
+    let filtre = doc! { "etat": CONST_ETAT_RELAI_ACTIF, "dernier_heartbeat": {"$lte": expiration} };
+
+    let collection = middleware.get_collection_typed::<RowSanteRelai>(COLLECTIONS_SANTE_RELAIS)?;
+    let mut curseur = collection.find(filtre, None).await?;
+    let mut relais = Vec::new();
+    while let Some(row) = curseur.next().await {
+        relais.push(row?);
+    }
+
+    for relai in relais {
+        let mut session = middleware.get_session().await?;
+        start_transaction_regular(&mut session).await?;
+        match marquer_relai_perdu(middleware, &relai, expiration, &mut session).await {
+            Ok(Some(appareils_deconnectes)) => {
+                session.commit_transaction().await?;
+                info!("detecter_relais_perdus Relai {} perdu, {} appareils deconnectes", relai.instance_id, appareils_deconnectes);
+                let evenement = EvenementRelaiPerdu {
+                    instance_id: relai.instance_id.as_str(),
+                    dernier_heartbeat: relai.dernier_heartbeat,
+                    appareils_deconnectes,
+                };
+                let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_RELAI_PERDU, vec![Securite::L3Protege])
+                    .build();
+                middleware.emettre_evenement(routage, &evenement).await?;
+            },
+            Ok(None) => session.abort_transaction().await?,  // Heartbeat recu entre-temps
+            Err(e) => {
+                session.abort_transaction().await?;
+                Err(e)?
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn marquer_relai_perdu<M>(middleware: &M, relai: &RowSanteRelai, expiration: DateTime<Utc>, session: &mut ClientSession)
+    -> Result<Option<u64>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    let filtre = doc! {
+        CHAMP_INSTANCE_ID: &relai.instance_id,
+        "etat": CONST_ETAT_RELAI_ACTIF,
+        "dernier_heartbeat": {"$lte": expiration},
+    };
+    let ops = doc! {
+        "$set": { "etat": CONST_ETAT_RELAI_PERDU },
+        "$currentDate": { CHAMP_MODIFICATION: true },
+    };
+    let collection = middleware.get_collection(COLLECTIONS_SANTE_RELAIS)?;
+    let resultat = collection.update_one_with_session(filtre, ops, None, session).await?;
+    if resultat.modified_count == 0 {
+        return Ok(None)
+    }
+
+    Ok(Some(deconnecter_appareils_instance(middleware, relai.instance_id.as_str(), session).await?))
+}
+
+#[derive(Serialize)]
+struct ReponseGetSanteRelais {
+    ok: bool,
+    relais: Vec<RowSanteRelai>,
+}
+
+pub async fn requete_get_sante_relais<M>(middleware: &M, m: MessageValide, _gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_get_sante_relais Consommer requete : {:?}", & m.type_message);
+
+    // L'etat est recalcule : le relai peut etre perdu avant le passage de detecter_relais_perdus
+    let expiration = Utc::now() - chrono::Duration::seconds(CONST_RELAI_HEARTBEAT_TIMEOUT_SECS);
+
+    let options = FindOptions::builder().sort(doc! {CHAMP_INSTANCE_ID: 1}).build();
+    let collection = middleware.get_collection_typed::<RowSanteRelai>(COLLECTIONS_SANTE_RELAIS)?;
+    let mut curseur = collection.find(doc!{}, options).await?;
+    let mut relais = Vec::new();
+    while let Some(row) = curseur.next().await {
+        match row {
+            Ok(mut relai) => {
+                let heartbeat_expire = match relai.dernier_heartbeat.as_ref() {
+                    Some(dernier_heartbeat) => dernier_heartbeat <= &expiration,
+                    None => true
+                };
+                if relai.etat.as_str() == CONST_ETAT_RELAI_ACTIF && heartbeat_expire {
+                    relai.etat = CONST_ETAT_RELAI_PERDU.to_string();
+                }
+                relais.push(relai);
+            },
+            Err(e) => warn!("requete_get_sante_relais Erreur mapping relai : {:?}", e)
+        }
+    }
+
+    let reponse = ReponseGetSanteRelais { ok: true, relais };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
@@ -0,0 +1,227 @@
+use log::{debug, warn};
+
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
+use millegrilles_common_rust::chrono::Utc;
+use millegrilles_common_rust::db_structs::TransactionValide;
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::GenerateurMessages;
+use millegrilles_common_rust::middleware::sauvegarder_traiter_transaction_v2;
+use millegrilles_common_rust::millegrilles_cryptographie::deser_message_buffer;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::MessageMilleGrillesBufferDefault;
+use millegrilles_common_rust::mongo_dao::{convertir_to_bson_array, MongoDao};
+use millegrilles_common_rust::mongodb::ClientSession;
+use millegrilles_common_rust::mongodb::options::{FindOptions, UpdateOptions};
+use millegrilles_common_rust::recepteur_messages::MessageValide;
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+use millegrilles_common_rust::serde_json;
+use millegrilles_common_rust::tokio_stream::StreamExt;
+
+use crate::actionneurs::{actionner_appareil, ActionActionneur, CONST_COMMANDE_TIMEOUT_DEFAUT_SECS};
+use crate::common::*;
+use crate::domain_manager::SenseursPassifsDomainManager;
+
+/// Scene : ensemble nomme d'actions sur les actionneurs de plusieurs appareils.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionSauvegarderScene {
+    pub scene_id: String,
+    pub nom: Option<String>,
+    #[serde(default)]
+    pub actions: Vec<ActionActionneur>,
+    pub supprimer: Option<bool>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RowScene {
+    user_id: String,
+    scene_id: String,
+    nom: String,
+    actions: Vec<ActionActionneur>,
+}
+
+pub async fn commande_sauvegarder_scene<M>(middleware: &M, m: MessageValide, gestionnaire: &SenseursPassifsDomainManager, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    debug!("commande_sauvegarder_scene Consommer commande : {:?}", & m.type_message);
+    let commande: TransactionSauvegarderScene = deser_message_buffer!(m.message);
+
+    if m.certificat.get_user_id()?.is_none() {
+        return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    }
+
+    if commande.supprimer != Some(true) {
+        match commande.nom.as_ref() {
+            Some(nom) if !nom.trim().is_empty() => (),
+            _ => return Ok(Some(middleware.reponse_err(None, None, Some("nom de scene requis"))?))
+        }
+        if commande.actions.is_empty() {
+            return Ok(Some(middleware.reponse_err(None, None, Some("La scene doit contenir au moins une action"))?))
+        }
+    }
+
+    Ok(sauvegarder_traiter_transaction_v2(middleware, m, gestionnaire, session).await?)
+}
+
+pub async fn transaction_sauvegarder_scene<M>(middleware: &M, transaction: TransactionValide, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("transaction_sauvegarder_scene Consommer transaction : {:?}", transaction.transaction.id);
+    let contenu_transaction: TransactionSauvegarderScene = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+
+    let user_id = match transaction.certificat.get_user_id()? {
+        Some(user) => user.to_owned(),
+        None => Err(Error::Str("senseurspassifs.transaction_sauvegarder_scene Erreur user_id absent du certificat"))?
+    };
+
+    let filtre = doc! { CHAMP_USER_ID: &user_id, "scene_id": &contenu_transaction.scene_id };
+    let collection = middleware.get_collection(COLLECTIONS_SCENES)?;
+    if let Some(true) = contenu_transaction.supprimer {
+        collection.delete_one_with_session(filtre, None, session).await?;
+    } else {
+        let ops = doc! {
+            "$set": {
+                "nom": contenu_transaction.nom,
+                "actions": convertir_to_bson_array(contenu_transaction.actions)?,
+            },
+            "$setOnInsert": {
+                CHAMP_USER_ID: &user_id,
+                "scene_id": &contenu_transaction.scene_id,
+                CHAMP_CREATION: Utc::now(),
+            },
+            "$currentDate": { CHAMP_MODIFICATION: true },
+        };
+        let options = UpdateOptions::builder().upsert(true).build();
+        collection.update_one_with_session(filtre, ops, options, session).await?;
+    }
+
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
+
+#[derive(Serialize)]
+struct ReponseGetScenes {
+    ok: bool,
+    scenes: Vec<RowScene>,
+}
+
+pub async fn requete_get_scenes<M>(middleware: &M, m: MessageValide, _gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_get_scenes Consommer requete : {:?}", & m.type_message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    };
+
+    let options = FindOptions::builder().sort(doc! {"nom": 1}).build();
+    let collection = middleware.get_collection_typed::<RowScene>(COLLECTIONS_SCENES)?;
+    let mut curseur = collection.find(doc!{CHAMP_USER_ID: &user_id}, options).await?;
+    let mut scenes = Vec::new();
+    while let Some(row) = curseur.next().await {
+        match row {
+            Ok(row) => scenes.push(row),
+            Err(e) => warn!("requete_get_scenes Erreur mapping scene : {:?}", e)
+        }
+    }
+
+    let reponse = ReponseGetScenes { ok: true, scenes };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+#[derive(Deserialize)]
+struct CommandeExecuterScene {
+    scene_id: String,
+}
+
+#[derive(Serialize)]
+pub struct ResultatActionScene {
+    pub uuid_appareil: String,
+    pub senseur_id: String,
+    pub action: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commande_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub en_file: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub err: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ReponseExecuterScene {
+    /// true si toutes les actions ont ete transmises
+    ok: bool,
+    scene_id: String,
+    resultats: Vec<ResultatActionScene>,
+}
+
+/// Transmet chaque action de la scene au relai de son appareil. Une action en erreur n'interrompt
+/// pas les suivantes, la reponse contient le resultat de chaque action.
+pub async fn commande_executer_scene<M>(middleware: &M, m: MessageValide, _gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("commande_executer_scene Consommer commande : {:?}", & m.type_message);
+    let commande: CommandeExecuterScene = deser_message_buffer!(m.message);
+    let message_id = m.message.parse()?.id.to_string();
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    };
+
+    let resultats = match executer_scene(middleware, user_id.as_str(), commande.scene_id.as_str(), message_id.as_str()).await? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("Scene inconnue"))?))
+    };
+
+    let ok = resultats.iter().all(|r| r.ok);
+    let reponse = ReponseExecuterScene { ok, scene_id: commande.scene_id, resultats };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+/// Transmet les actions d'une scene. Les commandes recoivent l'identificateur prefixe_commande/index.
+/// Retourne None si la scene est inconnue.
+pub async fn executer_scene<M>(middleware: &M, user_id: &str, scene_id: &str, prefixe_commande: &str)
+    -> Result<Option<Vec<ResultatActionScene>>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    let filtre = doc! { CHAMP_USER_ID: user_id, "scene_id": scene_id };
+    let collection = middleware.get_collection_typed::<RowScene>(COLLECTIONS_SCENES)?;
+    let scene = match collection.find_one(filtre, None).await? {
+        Some(inner) => inner,
+        None => return Ok(None)
+    };
+
+    let mut resultats = Vec::with_capacity(scene.actions.len());
+    for (idx, action) in scene.actions.into_iter().enumerate() {
+        let mut resultat = ResultatActionScene {
+            uuid_appareil: action.uuid_appareil.clone(),
+            senseur_id: action.senseur_id.clone(),
+            action: action.action.clone(),
+            ok: false,
+            commande_id: None,
+            en_file: None,
+            err: None,
+        };
+        let commande_id = format!("{}/{}", prefixe_commande, idx);
+        match actionner_appareil(middleware, user_id, commande_id, action, CONST_COMMANDE_TIMEOUT_DEFAUT_SECS).await {
+            Ok(Some(transmise)) => {
+                resultat.ok = true;
+                resultat.commande_id = Some(transmise.commande_id);
+                resultat.en_file = Some(transmise.en_file);
+            },
+            Ok(None) => resultat.err = Some("Appareil inconnu".to_string()),
+            Err(e) => {
+                warn!("executer_scene Erreur action {} de la scene {} : {:?}", idx, scene_id, e);
+                resultat.err = Some(format!("{:?}", e));
+            }
+        }
+        resultats.push(resultat);
+    }
+
+    Ok(Some(resultats))
+}
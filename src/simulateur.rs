@@ -0,0 +1,378 @@
+use std::collections::BTreeMap;
+use log::{debug, warn};
+
+use millegrilles_common_rust::bson::{doc, Document};
+use millegrilles_common_rust::certificats::VerificateurPermissions;
+use millegrilles_common_rust::chrono::{DateTime, Duration, Utc};
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::GenerateurMessages;
+use millegrilles_common_rust::millegrilles_cryptographie::deser_message_buffer;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::{epochseconds, MessageMilleGrillesBufferDefault};
+use millegrilles_common_rust::mongo_dao::MongoDao;
+use millegrilles_common_rust::mongodb::options::{FindOneOptions, FindOptions};
+use millegrilles_common_rust::recepteur_messages::MessageValide;
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+use millegrilles_common_rust::serde_json::Value;
+use millegrilles_common_rust::tokio_stream::StreamExt;
+
+use crate::common::*;
+use crate::domain_manager::SenseursPassifsDomainManager;
+use crate::programmes::{reponse_erreurs_validation, valider_programmes};
+use crate::transactions::SenseurHoraireRow;
+
+/// Periode maximale simulee.
+const CONST_SIMULATION_JOURS_MAX: i64 = 366;
+/// Les rangees horaires donnent une decision par heure.
+const CONST_PAS_SIMULATION_SECS: i64 = 3600;
+
+/// Sens de la regulation d'un programme supporte par le simulateur.
+#[derive(Clone, Copy, PartialEq)]
+enum Regulation {
+    /// En marche sous la cible (chauffage, humidificateur)
+    Hausse,
+    /// En marche au-dessus de la cible (climatisation)
+    Baisse,
+}
+
+/// Parametres d'un programme de regulation (voir programmes::CLASSES_PROGRAMMES).
+struct ParametresRegulation {
+    regulation: Regulation,
+    senseurs: Vec<String>,
+    cible: f64,
+    precision: f64,
+    duree_on_min: i64,
+    duree_off_min: i64,
+}
+
+impl ParametresRegulation {
+    fn from_programme(programme: &ProgrammeAppareil) -> Option<Self> {
+        let (regulation, arg_senseurs, arg_cible) = match programme.class.as_str() {
+            "programmes.environnement.Humidificateur" => (Regulation::Hausse, "senseurs_humidite", "humidite"),
+            "programmes.environnement.Chauffage" => (Regulation::Hausse, "senseurs", "temperature"),
+            "programmes.environnement.Climatisation" => (Regulation::Baisse, "senseurs", "temperature"),
+            _ => return None
+        };
+        let senseurs = programme.args.get(arg_senseurs)?.as_array()?
+            .iter().filter_map(|s| s.as_str().map(|s| s.to_string())).collect();
+        Some(Self {
+            regulation,
+            senseurs,
+            cible: programme.args.get(arg_cible)?.as_f64()?,
+            precision: programme.args.get("precision").and_then(Value::as_f64).unwrap_or(0.0),
+            duree_on_min: programme.args.get("duree_on_min").and_then(Value::as_i64).unwrap_or(0),
+            duree_off_min: programme.args.get("duree_off_min").and_then(Value::as_i64).unwrap_or(0),
+        })
+    }
+
+    /// Etat desire pour une valeur, None dans la zone de precision (etat conserve).
+    fn decider(&self, valeur: f64) -> Option<bool> {
+        let (sous_cible, au_dessus_cible) = (valeur < self.cible - self.precision, valeur > self.cible + self.precision);
+        match self.regulation {
+            Regulation::Hausse if sous_cible => Some(true),
+            Regulation::Hausse if au_dessus_cible => Some(false),
+            Regulation::Baisse if au_dessus_cible => Some(true),
+            Regulation::Baisse if sous_cible => Some(false),
+            _ => None
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RequeteSimulerProgramme {
+    uuid_appareil: String,
+    programme: ProgrammeAppareil,
+    #[serde(with="epochseconds")]
+    debut: DateTime<Utc>,
+    #[serde(with="epochseconds")]
+    fin: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct DecisionSimulee {
+    #[serde(with="epochseconds")]
+    heure: DateTime<Utc>,
+    /// true : switches en marche
+    etat: bool,
+    /// Moyenne des senseurs ayant produit la decision
+    valeur: f64,
+}
+
+#[derive(Serialize)]
+struct ReponseSimulerProgramme {
+    ok: bool,
+    programme_id: String,
+    resolution: &'static str,
+    /// Changements d'etat des switches, en ordre chronologique
+    decisions: Vec<DecisionSimulee>,
+    heures_simulees: u32,
+    heures_sans_donnees: u32,
+    heures_en_marche: u32,
+    /// Fraction des heures simulees en marche (0.0 - 1.0)
+    cycle_utile: Option<f64>,
+    /// Approximation : une heure en marche compte en entier
+    duree_marche_estimee_secs: i64,
+}
+
+/// Rejoue un programme de regulation sur l'historique horaire de ses senseurs. Le programme n'est
+/// pas sauvegarde. Une periode qui touche des jours elagues par la retention est refusee.
+pub async fn requete_simuler_programme<M>(middleware: &M, m: MessageValide, _gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_simuler_programme Consommer requete : {:?}", & m.type_message);
+    let requete: RequeteSimulerProgramme = deser_message_buffer!(m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    };
+
+    if requete.fin <= requete.debut || requete.fin - requete.debut > Duration::days(CONST_SIMULATION_JOURS_MAX) {
+        let message = format!("La periode doit etre positive et d'au plus {} jours", CONST_SIMULATION_JOURS_MAX);
+        return Ok(Some(middleware.reponse_err(None, None, Some(message.as_str()))?))
+    }
+
+    let erreurs = valider_programmes(middleware, Some(user_id.as_str()), requete.uuid_appareil.as_str(), [&requete.programme]).await?;
+    if !erreurs.is_empty() {
+        return reponse_erreurs_validation(middleware, erreurs)
+    }
+
+    let parametres = match ParametresRegulation::from_programme(&requete.programme) {
+        Some(inner) => inner,
+        None => {
+            let message = format!("Classe {} non supportee par le simulateur", requete.programme.class);
+            return Ok(Some(middleware.reponse_err(None, None, Some(message.as_str()))?))
+        }
+    };
+
+    // Aligner sur les rangees horaires
+    let debut = requete.debut - Duration::seconds(requete.debut.timestamp().rem_euclid(CONST_PAS_SIMULATION_SECS));
+
+    // Les rangees horaires des jours elagues (retention) ne sont plus disponibles
+    if let Some(jour) = dernier_jour_elague(
+        middleware, user_id.as_str(), requete.uuid_appareil.as_str(), &parametres.senseurs, debut, requete.fin).await?
+    {
+        let message = format!("Historique horaire elague (retention) jusqu'au {}, debut doit suivre cette date", jour);
+        return Ok(Some(middleware.reponse_err(None, None, Some(message.as_str()))?))
+    }
+
+    let valeurs = charger_valeurs_horaires(
+        middleware, user_id.as_str(), requete.uuid_appareil.as_str(), &parametres.senseurs, debut, requete.fin).await?;
+
+    let reponse = simuler_regulation(requete.programme.programme_id.clone(), &parametres, &valeurs, debut, requete.fin);
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+/// Simule la regulation heure par heure. Un changement d'etat attend la duree minimale de l'etat courant
+/// (duree_on_min en marche, duree_off_min a l'arret). Les heures sans donnees conservent l'etat precedent.
+fn simuler_regulation(programme_id: String, parametres: &ParametresRegulation, valeurs: &BTreeMap<DateTime<Utc>, (f64, u32)>,
+                      debut: DateTime<Utc>, fin: DateTime<Utc>)
+    -> ReponseSimulerProgramme
+{
+    let mut reponse = ReponseSimulerProgramme {
+        ok: true,
+        programme_id,
+        resolution: "horaire",
+        decisions: Vec::new(),
+        heures_simulees: 0,
+        heures_sans_donnees: 0,
+        heures_en_marche: 0,
+        cycle_utile: None,
+        duree_marche_estimee_secs: 0,
+    };
+
+    let mut etat = false;
+    let mut dernier_changement: Option<DateTime<Utc>> = None;
+    let mut heure = debut;
+    while heure < fin {
+        reponse.heures_simulees += 1;
+        match valeurs.get(&heure) {
+            Some((somme, nombre)) => {
+                let valeur = somme / *nombre as f64;
+                let duree_min = if etat { parametres.duree_on_min } else { parametres.duree_off_min };
+                let duree_min_atteinte = match dernier_changement {
+                    Some(changement) => (heure - changement).num_seconds() >= duree_min,
+                    None => true
+                };
+                if let Some(etat_desire) = parametres.decider(valeur) {
+                    if etat_desire != etat && duree_min_atteinte {
+                        etat = etat_desire;
+                        dernier_changement = Some(heure);
+                        reponse.decisions.push(DecisionSimulee { heure, etat, valeur });
+                    }
+                }
+            },
+            None => reponse.heures_sans_donnees += 1
+        }
+        if etat {
+            reponse.heures_en_marche += 1;
+        }
+        heure += Duration::seconds(CONST_PAS_SIMULATION_SECS);
+    }
+
+    if reponse.heures_simulees > 0 {
+        reponse.cycle_utile = Some(reponse.heures_en_marche as f64 / reponse.heures_simulees as f64);
+    }
+    reponse.duree_marche_estimee_secs = reponse.heures_en_marche as i64 * CONST_PAS_SIMULATION_SECS;
+
+    reponse
+}
+
+/// Filtre des senseurs du programme. Un senseur d'un autre appareil est reference avec uuid_appareil:senseur_id.
+fn filtre_references_senseurs(uuid_appareil: &str, senseurs: &[String]) -> Vec<Document> {
+    senseurs.iter().map(|s| match s.split_once(':') {
+        Some((uuid, senseur_id)) => doc! { CHAMP_UUID_APPAREIL: uuid, "senseur_id": senseur_id },
+        None => doc! { CHAMP_UUID_APPAREIL: uuid_appareil, "senseur_id": s },
+    }).collect()
+}
+
+#[derive(Deserialize)]
+struct RowJourQuotidien {
+    jour: String,
+}
+
+/// Dernier jour de la periode dont les rangees horaires ont ete remplacees par un sommaire quotidien.
+async fn dernier_jour_elague<M>(middleware: &M, user_id: &str, uuid_appareil: &str, senseurs: &[String],
+                                debut: DateTime<Utc>, fin: DateTime<Utc>)
+    -> Result<Option<String>, Error>
+    where M: MongoDao
+{
+    // Le champ heure d'un sommaire est la premiere heure du jour, elargir d'une journee.
+    let filtre = doc! {
+        CHAMP_USER_ID: user_id,
+        "$or": filtre_references_senseurs(uuid_appareil, senseurs),
+        "heure": {"$gte": debut - Duration::days(1), "$lt": fin},
+    };
+    let options = FindOneOptions::builder()
+        .projection(doc! {"jour": 1})
+        .sort(doc! {"heure": -1})
+        .build();
+    let collection = middleware.get_collection_typed::<RowJourQuotidien>(COLLECTIONS_SENSEURS_QUOTIDIEN)?;
+    Ok(collection.find_one(filtre, options).await?.map(|r| r.jour))
+}
+
+/// Charge la moyenne horaire des senseurs, cumulee par heure (somme, nombre de senseurs).
+async fn charger_valeurs_horaires<M>(middleware: &M, user_id: &str, uuid_appareil: &str, senseurs: &[String],
+                                     debut: DateTime<Utc>, fin: DateTime<Utc>)
+    -> Result<BTreeMap<DateTime<Utc>, (f64, u32)>, Error>
+    where M: MongoDao
+{
+    let filtre = doc! {
+        CHAMP_USER_ID: user_id,
+        "$or": filtre_references_senseurs(uuid_appareil, senseurs),
+        "heure": {"$gte": debut, "$lt": fin},
+    };
+    let options = FindOptions::builder().sort(doc! {"heure": 1}).build();
+    let collection = middleware.get_collection_typed::<SenseurHoraireRow>(COLLECTIONS_SENSEURS_HORAIRE)?;
+    let mut curseur = collection.find(filtre, options).await?;
+    let mut valeurs: BTreeMap<DateTime<Utc>, (f64, u32)> = BTreeMap::new();
+    while let Some(row) = curseur.next().await {
+        match row {
+            Ok(row) => if let Some(avg) = row.avg {
+                let entree = valeurs.entry(row.heure).or_insert((0.0, 0));
+                entree.0 += avg;
+                entree.1 += 1;
+            },
+            Err(e) => warn!("charger_valeurs_horaires Erreur mapping rangee horaire : {:?}", e)
+        }
+    }
+    Ok(valeurs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_setup::setup;
+
+    fn parametres(regulation: Regulation, duree_on_min: i64, duree_off_min: i64) -> ParametresRegulation {
+        ParametresRegulation {
+            regulation,
+            senseurs: vec!["temp".to_string()],
+            cible: 20.0,
+            precision: 1.0,
+            duree_on_min,
+            duree_off_min,
+        }
+    }
+
+    fn serie(debut: DateTime<Utc>, valeurs: &[f64]) -> BTreeMap<DateTime<Utc>, (f64, u32)> {
+        valeurs.iter().enumerate()
+            .map(|(i, v)| (debut + Duration::hours(i as i64), (*v, 1)))
+            .collect()
+    }
+
+    fn debut() -> DateTime<Utc> {
+        DateTime::<Utc>::from_timestamp(1_700_000_000 - 1_700_000_000 % 3600, 0).expect("timestamp")
+    }
+
+    #[test]
+    fn test_decider_hysteresis_hausse() {
+        setup("test_decider_hysteresis_hausse");
+        let p = parametres(Regulation::Hausse, 0, 0);
+        assert_eq!(Some(true), p.decider(18.9));
+        assert_eq!(None, p.decider(19.0));
+        assert_eq!(None, p.decider(20.0));
+        assert_eq!(None, p.decider(21.0));
+        assert_eq!(Some(false), p.decider(21.1));
+    }
+
+    #[test]
+    fn test_decider_hysteresis_baisse() {
+        setup("test_decider_hysteresis_baisse");
+        let p = parametres(Regulation::Baisse, 0, 0);
+        assert_eq!(Some(true), p.decider(21.1));
+        assert_eq!(None, p.decider(20.5));
+        assert_eq!(Some(false), p.decider(18.9));
+    }
+
+    #[test]
+    fn test_simulation_zone_precision_conserve_etat() {
+        setup("test_simulation_zone_precision_conserve_etat");
+        let p = parametres(Regulation::Hausse, 0, 0);
+        let valeurs = serie(debut(), &[18.0, 19.5, 20.5, 21.5, 20.5]);
+        let reponse = simuler_regulation("p".to_string(), &p, &valeurs, debut(), debut() + Duration::hours(5));
+        let etats: Vec<bool> = reponse.decisions.iter().map(|d| d.etat).collect();
+        assert_eq!(vec![true, false], etats);
+        assert_eq!(debut() + Duration::hours(3), reponse.decisions[1].heure);
+        assert_eq!(3, reponse.heures_en_marche);
+        assert_eq!(5, reponse.heures_simulees);
+        assert_eq!(Some(0.6), reponse.cycle_utile);
+    }
+
+    #[test]
+    fn test_simulation_duree_on_min() {
+        setup("test_simulation_duree_on_min");
+        // En marche au moins 3 heures malgre la temperature atteinte
+        let p = parametres(Regulation::Hausse, 3 * 3600, 0);
+        let valeurs = serie(debut(), &[18.0, 22.0, 22.0, 22.0, 22.0]);
+        let reponse = simuler_regulation("p".to_string(), &p, &valeurs, debut(), debut() + Duration::hours(5));
+        assert_eq!(2, reponse.decisions.len());
+        assert_eq!(debut() + Duration::hours(3), reponse.decisions[1].heure);
+        assert_eq!(3, reponse.heures_en_marche);
+    }
+
+    #[test]
+    fn test_simulation_duree_off_min() {
+        setup("test_simulation_duree_off_min");
+        // A l'arret au moins 2 heures avant de repartir
+        let p = parametres(Regulation::Hausse, 0, 2 * 3600);
+        let valeurs = serie(debut(), &[18.0, 22.0, 18.0, 18.0, 18.0]);
+        let reponse = simuler_regulation("p".to_string(), &p, &valeurs, debut(), debut() + Duration::hours(5));
+        let heures: Vec<DateTime<Utc>> = reponse.decisions.iter().map(|d| d.heure).collect();
+        assert_eq!(vec![debut(), debut() + Duration::hours(1), debut() + Duration::hours(3)], heures);
+        assert_eq!(3, reponse.heures_en_marche);
+    }
+
+    #[test]
+    fn test_simulation_heures_sans_donnees() {
+        setup("test_simulation_heures_sans_donnees");
+        let p = parametres(Regulation::Hausse, 0, 0);
+        let mut valeurs = serie(debut(), &[18.0]);
+        valeurs.insert(debut() + Duration::hours(3), (22.0, 1));
+        let reponse = simuler_regulation("p".to_string(), &p, &valeurs, debut(), debut() + Duration::hours(4));
+        assert_eq!(2, reponse.heures_sans_donnees);
+        // Etat conserve pendant les heures sans donnees
+        assert_eq!(3, reponse.heures_en_marche);
+        assert_eq!(3 * 3600, reponse.duree_marche_estimee_secs);
+    }
+}
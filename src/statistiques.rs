@@ -0,0 +1,1304 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use log::{debug, info};
+use chrono_tz::Tz;
+
+use millegrilles_common_rust::bson::{doc, Bson, Document};
+use millegrilles_common_rust::certificats::VerificateurPermissions;
+use millegrilles_common_rust::chrono::{DateTime, Datelike, Days, Duration, Months, NaiveTime, TimeZone, Timelike, Utc};
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::GenerateurMessages;
+use millegrilles_common_rust::millegrilles_cryptographie::deser_message_buffer;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::MessageMilleGrillesBufferDefault;
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::mongodb::options::FindOptions;
+use millegrilles_common_rust::recepteur_messages::MessageValide;
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::{epochseconds, optionepochseconds};
+use millegrilles_common_rust::tokio_stream::StreamExt;
+
+use crate::common::*;
+use crate::domain_manager::SenseursPassifsDomainManager;
+use crate::lectures::heure_juste;
+use crate::reduction::{reduire_serie, AlgorithmeReduction};
+use crate::requetes::{charger_configuration_usager, parser_timezone, query_aggregate, ResultatStatistiquesSenseurRow};
+
+/// Nombre maximal de senseurs pour une meme requete d'export.
+const CONST_EXPORT_SENSEURS_MAX: usize = 20;
+
+/// Nombre maximal de rangees (tous senseurs confondus) d'un export. L'export est retourne au complet
+/// dans une seule reponse, il faut decouper les periodes plus longues en plusieurs requetes.
+/// La limite est verifiee avant les requetes a partir du nombre d'intervalles de la periode.
+const CONST_EXPORT_RANGEES_MAX: usize = 100_000;
+
+/// Nombre maximal de senseurs pour une requete de comparaison.
+const CONST_COMPARAISON_SENSEURS_MAX: usize = 20;
+
+/// Nombre maximal d'heures couvertes par une requete avec intervalles alignes (~ 2 ans).
+const CONST_INTERVALLES_HEURES_MAX: i64 = 2 * 366 * 24;
+
+/// Verifie que la periode demandee est positive et ne depasse pas CONST_INTERVALLES_HEURES_MAX.
+pub(crate) fn valider_periode(min_date: &DateTime<Utc>, max_date: &DateTime<Utc>) -> Result<(), String> {
+    if max_date <= min_date {
+        Err(String::from("La periode doit etre positive"))
+    } else if (*max_date - *min_date).num_hours() > CONST_INTERVALLES_HEURES_MAX {
+        Err(format!("La periode ne doit pas depasser {} heures", CONST_INTERVALLES_HEURES_MAX))
+    } else {
+        Ok(())
+    }
+}
+
+/// Estime le nombre maximal de rangees d'un senseur pour la periode et le grouping demandes.
+/// Les jours sont comptes avec une marge pour les changements d'heure et les jours partiels.
+fn estimer_rangees_senseur(min_date: &DateTime<Utc>, max_date: &DateTime<Utc>, grouping: &str, max_points: Option<usize>)
+    -> Result<usize, String>
+{
+    let heures = (*max_date - *min_date).num_hours().max(0) as usize + 1;
+    let rangees = match grouping {
+        "heures" => heures,
+        "jours" => heures / 24 + 2,
+        _ => Err(format!("Type grouping {} non supporte", grouping))?
+    };
+    Ok(match max_points {
+        Some(max_points) => rangees.min(max_points),
+        None => rangees
+    })
+}
+
+/// Reference a un senseur d'un appareil.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SenseurRef {
+    pub uuid_appareil: String,
+    pub senseur_id: String,
+}
+
+impl SenseurRef {
+    fn libelle_defaut(&self) -> String {
+        format!("{}/{}", self.uuid_appareil, self.senseur_id)
+    }
+}
+
+/// Charge le libelle de chaque senseur a partir de configuration.descriptif et descriptif_senseurs.
+/// Utilise uuid_appareil/senseur_id lorsqu'aucun descriptif n'est disponible.
+pub(crate) async fn charger_libelles_senseurs<M>(middleware: &M, user_id: &str, senseurs: &Vec<SenseurRef>)
+    -> Result<HashMap<SenseurRef, String>, Error>
+    where M: MongoDao
+{
+    let uuid_appareils: Vec<&String> = senseurs.iter().map(|s| &s.uuid_appareil).collect();
+    let filtre = doc! { CHAMP_USER_ID: user_id, CHAMP_UUID_APPAREIL: {"$in": uuid_appareils} };
+    let projection = doc! { CHAMP_UUID_APPAREIL: 1, CHAMP_USER_ID: 1, "configuration": 1 };
+    let options = FindOptions::builder().projection(projection).build();
+    let collection = middleware.get_collection_typed::<DocAppareil>(COLLECTIONS_APPAREILS)?;
+    let mut curseur = collection.find(filtre, options).await?;
+
+    let mut configurations = HashMap::new();
+    while let Some(appareil) = curseur.next().await {
+        let appareil = appareil?;
+        if let Some(configuration) = appareil.configuration {
+            configurations.insert(appareil.uuid_appareil, configuration);
+        }
+    }
+
+    let mut libelles = HashMap::new();
+    for senseur in senseurs {
+        let libelle = match configurations.get(&senseur.uuid_appareil) {
+            Some(configuration) => {
+                let descriptif_senseur = match configuration.descriptif_senseurs.as_ref() {
+                    Some(inner) => inner.get(&senseur.senseur_id).cloned(),
+                    None => None
+                };
+                match (configuration.descriptif.as_ref(), descriptif_senseur) {
+                    (Some(appareil), Some(descriptif)) => format!("{} {}", appareil, descriptif),
+                    (None, Some(descriptif)) => descriptif,
+                    (Some(appareil), None) => format!("{} {}", appareil, senseur.senseur_id),
+                    (None, None) => senseur.libelle_defaut(),
+                }
+            },
+            None => senseur.libelle_defaut()
+        };
+        libelles.insert(senseur.clone(), libelle);
+    }
+
+    Ok(libelles)
+}
+
+/// Format de date local utilise pour identifier un intervalle (heure ou jour).
+pub(crate) fn formatter_intervalle(heure: &DateTime<Utc>, grouping: &str, tz: &Tz) -> String {
+    let heure_locale = heure.with_timezone(tz);
+    match grouping {
+        "jours" => heure_locale.format("%Y-%m-%d").to_string(),
+        _ => heure_locale.format("%Y-%m-%d %H:00").to_string(),
+    }
+}
+
+/// Debut UTC de l'intervalle (heure ou jour local) qui contient heure.
+fn debut_intervalle(heure: &DateTime<Utc>, grouping: &str, tz: &Tz) -> DateTime<Utc> {
+    match grouping {
+        "jours" => {
+            let minuit = heure.with_timezone(tz).date_naive().and_time(NaiveTime::MIN);
+            match tz.from_local_datetime(&minuit).earliest() {
+                Some(inner) => inner.with_timezone(&Utc),
+                None => heure_juste(heure)  // Minuit local inexistant (changement d'heure)
+            }
+        },
+        _ => heure_juste(heure)
+    }
+}
+
+/// Format mongo ($dateToString) equivalent a formatter_intervalle.
+fn format_intervalle_mongo(grouping: &str) -> Result<&'static str, Error> {
+    match grouping {
+        "heures" => Ok("%Y-%m-%d %H:00"),
+        "jours" => Ok("%Y-%m-%d"),
+        _ => Err(format!("Type grouping {} non supporte", grouping))?
+    }
+}
+
+/// Intervalle aligne dans la timezone demandee.
+#[derive(Clone, Debug)]
+pub(crate) struct IntervalleAligne {
+    /// Cle locale de l'intervalle (e.g. 2024-01-31 ou 2024-01-31 13:00)
+    pub(crate) cle: String,
+    /// Debut de l'intervalle (UTC)
+    pub(crate) debut: DateTime<Utc>,
+}
+
+/// Genere les intervalles (heures ou jours locaux) entre min_date et max_date.
+/// Avance par increments d'une heure pour traiter correctement les changements d'heure.
+pub(crate) fn generer_intervalles(min_date: &DateTime<Utc>, max_date: &DateTime<Utc>, grouping: &str, tz: &Tz)
+    -> Result<Vec<IntervalleAligne>, Error>
+{
+    format_intervalle_mongo(grouping)?;  // Valider grouping
+
+    let mut courant = heure_juste(min_date);
+    if (*max_date - courant).num_hours() > CONST_INTERVALLES_HEURES_MAX {
+        Err(format!("generer_intervalles Intervalle trop grand ({:?} a {:?})", min_date, max_date))?
+    }
+
+    let mut intervalles: Vec<IntervalleAligne> = Vec::new();
+    while &courant < max_date {
+        let cle = formatter_intervalle(&courant, grouping, tz);
+        let nouvelle_cle = match intervalles.last() {
+            Some(derniere) => derniere.cle != cle,
+            None => true
+        };
+        if nouvelle_cle {
+            intervalles.push(IntervalleAligne { cle, debut: courant });
+        }
+        courant = courant + Duration::hours(1);
+    }
+
+    Ok(intervalles)
+}
+
+fn echapper_csv(valeur: &str) -> String {
+    if valeur.contains(',') || valeur.contains('"') || valeur.contains('\n') {
+        format!("\"{}\"", valeur.replace('"', "\"\""))
+    } else {
+        valeur.to_string()
+    }
+}
+
+fn formatter_valeur_csv(valeur: Option<f64>) -> String {
+    match valeur {
+        Some(inner) => inner.to_string(),
+        None => String::new()
+    }
+}
+
+#[derive(Deserialize)]
+struct RequeteExportStatistiques {
+    senseurs: Vec<SenseurRef>,
+    grouping: String,
+    timezone: Option<String>,
+    intervalle_min: usize,
+    intervalle_max: Option<usize>,
+    /// csv (defaut) ou json
+    format: Option<String>,
+    /// long (defaut, une ligne par senseur et intervalle) ou large (une colonne par senseur)
+    disposition: Option<String>,
+    /// Nombre maximal de rangees par senseur. Les series plus longues sont reduites.
+    max_points: Option<usize>,
+    /// Algorithme de reduction : lttb (defaut) ou minmax. Voir module reduction.
+    algorithme_reduction: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ExportSenseurJson {
+    uuid_appareil: String,
+    senseur_id: String,
+    descriptif: String,
+    rows: Vec<ResultatStatistiquesSenseurRow>,
+}
+
+#[derive(Serialize)]
+struct ReponseExportStatistiques {
+    ok: bool,
+    format: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    csv: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    senseurs: Option<Vec<ExportSenseurJson>>,
+}
+
+pub async fn requete_export_statistiques<M>(middleware: &M, m: MessageValide, _gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_export_statistiques Consommer requete : {:?}", & m.message);
+    let requete: RequeteExportStatistiques = deser_message_buffer!(m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    };
+
+    if requete.senseurs.is_empty() || requete.senseurs.len() > CONST_EXPORT_SENSEURS_MAX {
+        return Ok(Some(middleware.reponse_err(None, None, Some("Nombre de senseurs invalide"))?))
+    }
+
+    let tz = parser_timezone(requete.timezone.as_ref());
+    let min_date = match DateTime::from_timestamp(requete.intervalle_min as i64, 0) {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("intervalle_min invalide"))?))
+    };
+    let max_date = match requete.intervalle_max {
+        Some(inner) => match DateTime::from_timestamp(inner as i64, 0) {
+            Some(inner) => inner,
+            None => return Ok(Some(middleware.reponse_err(None, None, Some("intervalle_max invalide"))?))
+        },
+        None => Utc::now()
+    };
+    if let Err(e) = valider_periode(&min_date, &max_date) {
+        return Ok(Some(middleware.reponse_err(None, None, Some(e.as_str()))?))
+    }
+
+    let nombre_rangees = match estimer_rangees_senseur(&min_date, &max_date, requete.grouping.as_str(), requete.max_points) {
+        Ok(inner) => inner * requete.senseurs.len(),
+        Err(e) => return Ok(Some(middleware.reponse_err(None, None, Some(e.as_str()))?))
+    };
+    if nombre_rangees > CONST_EXPORT_RANGEES_MAX {
+        let message = format!("Export trop volumineux ({} rangees, maximum {}), reduire la periode", nombre_rangees, CONST_EXPORT_RANGEES_MAX);
+        return Ok(Some(middleware.reponse_err(None, None, Some(message.as_str()))?))
+    }
+
+    let algorithme_reduction = match AlgorithmeReduction::parse(requete.algorithme_reduction.as_ref()) {
+        Ok(inner) => inner,
+        Err(e) => return Ok(Some(middleware.reponse_err(None, None, Some(e.as_str()))?))
+    };
+
+    let libelles = charger_libelles_senseurs(middleware, user_id.as_str(), &requete.senseurs).await?;
+
+    let mut resultats = Vec::with_capacity(requete.senseurs.len());
+    for senseur in &requete.senseurs {
+        let rows = query_aggregate(
+            middleware, user_id.as_str(), &senseur.uuid_appareil, &senseur.senseur_id,
+            requete.grouping.as_str(), &tz, min_date, Some(max_date)).await?;
+        let rows = match requete.max_points {
+            Some(max_points) => reduire_serie(rows, max_points, algorithme_reduction),
+            None => rows
+        };
+        resultats.push((senseur, rows));
+    }
+
+    let format = requete.format.as_ref().map(|f| f.as_str()).unwrap_or("csv");
+    let reponse = match format {
+        "json" => {
+            let senseurs = resultats.into_iter().map(|(senseur, rows)| ExportSenseurJson {
+                uuid_appareil: senseur.uuid_appareil.clone(),
+                senseur_id: senseur.senseur_id.clone(),
+                descriptif: libelles.get(senseur).cloned().unwrap_or_else(|| senseur.libelle_defaut()),
+                rows,
+            }).collect();
+            ReponseExportStatistiques { ok: true, format: format.to_string(), csv: None, senseurs: Some(senseurs) }
+        },
+        "csv" => {
+            let csv = match requete.disposition.as_ref().map(|d| d.as_str()) {
+                Some("large") => generer_csv_large(&resultats, &libelles, requete.grouping.as_str(), &tz),
+                _ => generer_csv_long(&resultats, &libelles, requete.grouping.as_str(), &tz),
+            };
+            ReponseExportStatistiques { ok: true, format: format.to_string(), csv: Some(csv), senseurs: None }
+        },
+        _ => {
+            info!("requete_export_statistiques Format non supporte : {}", format);
+            return Ok(Some(middleware.reponse_err(None, None, Some("Format non supporte"))?))
+        }
+    };
+
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+/// Une ligne par senseur et par intervalle.
+fn generer_csv_long(resultats: &Vec<(&SenseurRef, Vec<ResultatStatistiquesSenseurRow>)>,
+                    libelles: &HashMap<SenseurRef, String>, grouping: &str, tz: &Tz) -> String
+{
+    let mut csv = String::from("date,uuid_appareil,senseur_id,senseur,avg,min,max\n");
+    for (senseur, rows) in resultats {
+        let libelle = libelles.get(*senseur).cloned().unwrap_or_else(|| senseur.libelle_defaut());
+        for row in rows {
+            csv.push_str(format!("{},{},{},{},{},{},{}\n",
+                formatter_intervalle(&row.heure, grouping, tz),
+                echapper_csv(senseur.uuid_appareil.as_str()),
+                echapper_csv(senseur.senseur_id.as_str()),
+                echapper_csv(libelle.as_str()),
+                formatter_valeur_csv(row.avg),
+                formatter_valeur_csv(row.min),
+                formatter_valeur_csv(row.max),
+            ).as_str());
+        }
+    }
+    csv
+}
+
+/// Une ligne par intervalle, colonnes avg/min/max pour chaque senseur.
+fn generer_csv_large(resultats: &Vec<(&SenseurRef, Vec<ResultatStatistiquesSenseurRow>)>,
+                     libelles: &HashMap<SenseurRef, String>, grouping: &str, tz: &Tz) -> String
+{
+    let nombre_senseurs = resultats.len();
+
+    // Aligner les senseurs sur le debut UTC de l'intervalle. La date locale n'est pas unique (l'heure
+    // repetee au retour a l'heure normale), elle est formattee seulement pour la sortie.
+    let mut lignes: BTreeMap<DateTime<Utc>, Vec<Option<&ResultatStatistiquesSenseurRow>>> = BTreeMap::new();
+    for (idx, (_, rows)) in resultats.iter().enumerate() {
+        for row in rows {
+            let ligne = lignes.entry(debut_intervalle(&row.heure, grouping, tz))
+                .or_insert_with(|| vec![None; nombre_senseurs]);
+            ligne[idx] = Some(row);
+        }
+    }
+
+    let mut csv = String::from("date");
+    for (senseur, _) in resultats {
+        let libelle = libelles.get(*senseur).cloned().unwrap_or_else(|| senseur.libelle_defaut());
+        for suffixe in ["avg", "min", "max"] {
+            csv.push(',');
+            csv.push_str(echapper_csv(format!("{} {}", libelle, suffixe).as_str()).as_str());
+        }
+    }
+    csv.push('\n');
+
+    for (debut, valeurs) in lignes {
+        csv.push_str(formatter_intervalle(&debut, grouping, tz).as_str());
+        for valeur in valeurs {
+            let (avg, min, max) = match valeur {
+                Some(row) => (row.avg, row.min, row.max),
+                None => (None, None, None)
+            };
+            csv.push_str(format!(",{},{},{}", formatter_valeur_csv(avg), formatter_valeur_csv(min), formatter_valeur_csv(max)).as_str());
+        }
+        csv.push('\n');
+    }
+
+    csv
+}
+
+#[derive(Deserialize)]
+struct RequeteComparaisonSenseurs {
+    senseurs: Vec<SenseurRef>,
+    grouping: String,
+    timezone: Option<String>,
+    intervalle_min: usize,
+    intervalle_max: Option<usize>,
+    /// Nombre maximal d'intervalles retournes. Voir reduire_intervalles_comparaison.
+    max_points: Option<usize>,
+    /// Algorithme de reduction : lttb (defaut) ou minmax. Voir module reduction.
+    algorithme_reduction: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ComparaisonAggregateId {
+    intervalle: String,
+    uuid_appareil: String,
+    senseur_id: String,
+}
+
+#[derive(Deserialize)]
+struct ComparaisonAggregateRow {
+    _id: ComparaisonAggregateId,
+    min: Option<f64>,
+    max: Option<f64>,
+    avg: Option<f64>,
+}
+
+#[derive(Clone, Serialize)]
+struct ValeurComparaison {
+    min: Option<f64>,
+    max: Option<f64>,
+    avg: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct IntervalleComparaison {
+    #[serde(with="epochseconds")]
+    heure: DateTime<Utc>,
+    cle: String,
+    /// Une valeur par senseur, dans l'ordre de la requete. null indique un intervalle manquant.
+    valeurs: Vec<Option<ValeurComparaison>>,
+    /// true si au moins un senseur n'a pas de donnees pour cet intervalle.
+    manquant: bool,
+}
+
+#[derive(Serialize)]
+struct SenseurComparaison {
+    uuid_appareil: String,
+    senseur_id: String,
+    descriptif: String,
+}
+
+#[derive(Serialize)]
+struct ReponseComparaisonSenseurs {
+    ok: bool,
+    grouping: String,
+    timezone: String,
+    senseurs: Vec<SenseurComparaison>,
+    intervalles: Vec<IntervalleComparaison>,
+}
+
+fn pipeline_comparaison(user_id: &str, senseurs: &Vec<SenseurRef>, grouping: &str, tz: &Tz,
+                        min_date: &DateTime<Utc>, max_date: &DateTime<Utc>)
+    -> Result<Vec<Document>, Error>
+{
+    let format_intervalle = format_intervalle_mongo(grouping)?;
+    let senseurs_filtre: Vec<Document> = senseurs.iter()
+        .map(|s| doc! { CHAMP_UUID_APPAREIL: &s.uuid_appareil, "senseur_id": &s.senseur_id })
+        .collect();
+
+    let filtre = doc! {
+        CHAMP_USER_ID: user_id,
+        "heure": {"$gte": min_date, "$lt": max_date},
+        "$or": senseurs_filtre,
+    };
+
+    Ok(vec![
+        doc! { "$match": filtre },
+        doc! { "$project": {CHAMP_UUID_APPAREIL: 1, "senseur_id": 1, "heure": 1, "avg": 1, "min": 1, "max": 1} },
+        doc! { "$group": {
+            "_id": {
+                "intervalle": { "$dateToString": { "format": format_intervalle, "date": "$heure", "timezone": tz.to_string() } },
+                CHAMP_UUID_APPAREIL: "$uuid_appareil",
+                "senseur_id": "$senseur_id",
+            },
+            "avg": {"$avg": "$avg"},
+            "min": {"$min": "$min"},
+            "max": {"$max": "$max"},
+        } },
+    ])
+}
+
+/// Reduit le nombre d'intervalles en conservant l'alignement entre senseurs. Les intervalles conserves
+/// sont choisis en reduisant la serie des moyennes de tous les senseurs (voir module reduction).
+fn reduire_intervalles_comparaison(intervalles: Vec<IntervalleComparaison>, max_points: usize, algorithme: AlgorithmeReduction)
+    -> Vec<IntervalleComparaison>
+{
+    if intervalles.len() <= max_points {
+        return intervalles
+    }
+
+    let serie = intervalles.iter().map(|intervalle| {
+        let (somme, nombre) = intervalle.valeurs.iter()
+            .filter_map(|v| v.as_ref().and_then(|v| v.avg))
+            .fold((0.0, 0), |(somme, nombre), avg| (somme + avg, nombre + 1));
+        let avg = if nombre > 0 { Some(somme / nombre as f64) } else { None };
+        ResultatStatistiquesSenseurRow { heure: intervalle.heure, min: None, max: None, avg, provisoire: None }
+    }).collect();
+    let conservees: HashSet<DateTime<Utc>> = reduire_serie(serie, max_points, algorithme)
+        .into_iter().map(|r| r.heure).collect();
+
+    intervalles.into_iter().filter(|i| conservees.contains(&i.heure)).collect()
+}
+
+pub async fn requete_comparaison_senseurs<M>(middleware: &M, m: MessageValide, _gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_comparaison_senseurs Consommer requete : {:?}", & m.message);
+    let requete: RequeteComparaisonSenseurs = deser_message_buffer!(m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    };
+
+    if requete.senseurs.is_empty() || requete.senseurs.len() > CONST_COMPARAISON_SENSEURS_MAX {
+        return Ok(Some(middleware.reponse_err(None, None, Some("Nombre de senseurs invalide"))?))
+    }
+
+    let tz = parser_timezone(requete.timezone.as_ref());
+    let min_date = match DateTime::from_timestamp(requete.intervalle_min as i64, 0) {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("intervalle_min invalide"))?))
+    };
+    let max_date = match requete.intervalle_max {
+        Some(inner) => match DateTime::from_timestamp(inner as i64, 0) {
+            Some(inner) => inner,
+            None => return Ok(Some(middleware.reponse_err(None, None, Some("intervalle_max invalide"))?))
+        },
+        None => Utc::now()
+    };
+
+    let algorithme_reduction = match AlgorithmeReduction::parse(requete.algorithme_reduction.as_ref()) {
+        Ok(inner) => inner,
+        Err(e) => return Ok(Some(middleware.reponse_err(None, None, Some(e.as_str()))?))
+    };
+
+    let intervalles = match generer_intervalles(&min_date, &max_date, requete.grouping.as_str(), &tz) {
+        Ok(inner) => inner,
+        Err(e) => {
+            info!("requete_comparaison_senseurs Erreur intervalles : {:?}", e);
+            return Ok(Some(middleware.reponse_err(None, None, Some("Intervalle ou grouping invalide"))?))
+        }
+    };
+
+    // Index des senseurs dans la reponse
+    let index_senseurs: HashMap<SenseurRef, usize> = requete.senseurs.iter().enumerate()
+        .map(|(idx, s)| (s.clone(), idx))
+        .collect();
+
+    // Une seule aggregation pour tous les senseurs, les cles d'intervalles sont calculees par mongo.
+    let pipeline = pipeline_comparaison(
+        user_id.as_str(), &requete.senseurs, requete.grouping.as_str(), &tz, &min_date, &max_date)?;
+    let collection = middleware.get_collection(COLLECTIONS_SENSEURS_HORAIRE)?;
+    let mut resultat = collection.aggregate(pipeline, None).await?;
+    let mut valeurs_par_intervalle: HashMap<String, Vec<Option<ValeurComparaison>>> = HashMap::new();
+    while let Some(d) = resultat.next().await {
+        let row: ComparaisonAggregateRow = convertir_bson_deserializable(d?)?;
+        let senseur = SenseurRef { uuid_appareil: row._id.uuid_appareil, senseur_id: row._id.senseur_id };
+        if let Some(idx) = index_senseurs.get(&senseur) {
+            let valeurs = valeurs_par_intervalle.entry(row._id.intervalle)
+                .or_insert_with(|| vec![None; requete.senseurs.len()]);
+            valeurs[*idx] = Some(ValeurComparaison { min: row.min, max: row.max, avg: row.avg });
+        }
+    }
+
+    let intervalles = intervalles.into_iter().map(|intervalle| {
+        let valeurs = valeurs_par_intervalle.remove(&intervalle.cle)
+            .unwrap_or_else(|| vec![None; requete.senseurs.len()]);
+        let manquant = valeurs.iter().any(|v| v.is_none());
+        IntervalleComparaison { heure: intervalle.debut, cle: intervalle.cle, valeurs, manquant }
+    }).collect();
+    let intervalles = match requete.max_points {
+        Some(max_points) => reduire_intervalles_comparaison(intervalles, max_points, algorithme_reduction),
+        None => intervalles
+    };
+
+    let libelles = charger_libelles_senseurs(middleware, user_id.as_str(), &requete.senseurs).await?;
+    let senseurs = requete.senseurs.iter().map(|s| SenseurComparaison {
+        uuid_appareil: s.uuid_appareil.clone(),
+        senseur_id: s.senseur_id.clone(),
+        descriptif: libelles.get(s).cloned().unwrap_or_else(|| s.libelle_defaut()),
+    }).collect();
+
+    let reponse = ReponseComparaisonSenseurs {
+        ok: true,
+        grouping: requete.grouping,
+        timezone: tz.to_string(),
+        senseurs,
+        intervalles,
+    };
+
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+/// Temperature de base par defaut pour le calcul des degres-jours (°C).
+pub(crate) const CONST_DEGRES_JOURS_BASE_DEFAUT: f64 = 18.0;
+
+/// Moyenne journaliere locale (jour dans la timezone tz) des valeurs avg des rangees.
+pub(crate) fn moyennes_journalieres(rows: &[ResultatStatistiquesSenseurRow], tz: &Tz) -> BTreeMap<String, f64> {
+    let mut sommes: BTreeMap<String, (f64, usize)> = BTreeMap::new();
+    for row in rows {
+        if let Some(avg) = row.avg {
+            let somme = sommes.entry(formatter_intervalle(&row.heure, "jours", tz)).or_insert((0.0, 0));
+            somme.0 += avg;
+            somme.1 += 1;
+        }
+    }
+    sommes.into_iter().map(|(jour, (somme, nombre))| (jour, somme / nombre as f64)).collect()
+}
+
+/// Degres-jours de chauffage et de climatisation pour une moyenne journaliere.
+pub(crate) fn degres_jour(moyenne: f64, base: f64) -> (f64, f64) {
+    (f64::max(base - moyenne, 0.0), f64::max(moyenne - base, 0.0))
+}
+
+#[derive(Deserialize)]
+struct RequeteComparaisonPeriodes {
+    uuid_appareil: String,
+    senseur_id: String,
+    grouping: String,
+    timezone: Option<String>,
+    intervalle_min: usize,
+    intervalle_max: usize,
+    /// Debut de la periode de reference. La periode a la meme duree que la periode courante.
+    reference_min: Option<usize>,
+    /// Decalage de la periode de reference : jour, semaine, mois ou annee. Ignore si reference_min est fourni.
+    decalage: Option<String>,
+    /// Temperature de base pour les degres-jours, 18°C par defaut.
+    base_degres_jours: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct SommairePeriode {
+    #[serde(with="epochseconds")]
+    debut: DateTime<Utc>,
+    #[serde(with="epochseconds")]
+    fin: DateTime<Utc>,
+    moyenne: Option<f64>,
+    min: Option<f64>,
+    max: Option<f64>,
+    degres_jours_chauffage: f64,
+    degres_jours_climatisation: f64,
+}
+
+impl SommairePeriode {
+    fn calculer(rows: &[ResultatStatistiquesSenseurRow], debut: DateTime<Utc>, fin: DateTime<Utc>, tz: &Tz, base: f64) -> Self {
+        let valeurs: Vec<f64> = rows.iter().filter_map(|r| r.avg).collect();
+        let moyenne = match valeurs.len() {
+            0 => None,
+            n => Some(valeurs.iter().sum::<f64>() / n as f64)
+        };
+        let min = rows.iter().filter_map(|r| r.min).reduce(f64::min);
+        let max = rows.iter().filter_map(|r| r.max).reduce(f64::max);
+
+        let (mut chauffage, mut climatisation) = (0.0, 0.0);
+        for moyenne_jour in moyennes_journalieres(rows, tz).values() {
+            let (hdd, cdd) = degres_jour(*moyenne_jour, base);
+            chauffage += hdd;
+            climatisation += cdd;
+        }
+
+        Self { debut, fin, moyenne, min, max, degres_jours_chauffage: chauffage, degres_jours_climatisation: climatisation }
+    }
+}
+
+#[derive(Serialize)]
+struct DeltasPeriodes {
+    moyenne: Option<f64>,
+    min: Option<f64>,
+    max: Option<f64>,
+    degres_jours_chauffage: f64,
+    degres_jours_climatisation: f64,
+}
+
+fn delta(courant: Option<f64>, reference: Option<f64>) -> Option<f64> {
+    match (courant, reference) {
+        (Some(c), Some(r)) => Some(c - r),
+        _ => None
+    }
+}
+
+#[derive(Serialize)]
+struct IntervallePeriodes {
+    /// Position de l'intervalle dans la periode
+    index: usize,
+    #[serde(with="epochseconds")]
+    heure: DateTime<Utc>,
+    cle: String,
+    courant: Option<ValeurComparaison>,
+    #[serde(serialize_with="optionepochseconds::serialize")]
+    heure_reference: Option<DateTime<Utc>>,
+    cle_reference: Option<String>,
+    reference: Option<ValeurComparaison>,
+}
+
+#[derive(Serialize)]
+struct ReponseComparaisonPeriodes {
+    ok: bool,
+    grouping: String,
+    timezone: String,
+    courant: SommairePeriode,
+    reference: SommairePeriode,
+    deltas: DeltasPeriodes,
+    intervalles: Vec<IntervallePeriodes>,
+}
+
+/// Calcule le debut de la periode de reference a partir d'un decalage calendaire local.
+fn decaler_date(date: &DateTime<Utc>, decalage: &str, tz: &Tz) -> Result<DateTime<Utc>, Error> {
+    let date_locale = date.with_timezone(tz);
+    let resultat = match decalage {
+        "jour" => date_locale.checked_sub_days(Days::new(1)),
+        "semaine" => date_locale.checked_sub_days(Days::new(7)),
+        "mois" => date_locale.checked_sub_months(Months::new(1)),
+        "annee" => date_locale.checked_sub_months(Months::new(12)),
+        _ => Err(format!("Decalage {} non supporte", decalage))?
+    };
+    match resultat {
+        Some(inner) => Ok(inner.with_timezone(&Utc)),
+        None => Err(format!("Decalage {} invalide pour {:?}", decalage, date))?
+    }
+}
+
+fn indexer_rows(rows: Vec<ResultatStatistiquesSenseurRow>, grouping: &str, tz: &Tz) -> HashMap<String, ValeurComparaison> {
+    rows.into_iter()
+        .map(|r| (formatter_intervalle(&r.heure, grouping, tz), ValeurComparaison { min: r.min, max: r.max, avg: r.avg }))
+        .collect()
+}
+
+pub async fn requete_comparaison_periodes<M>(middleware: &M, m: MessageValide, _gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_comparaison_periodes Consommer requete : {:?}", & m.message);
+    let requete: RequeteComparaisonPeriodes = deser_message_buffer!(m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    };
+
+    let tz = parser_timezone(requete.timezone.as_ref());
+    let grouping = requete.grouping.as_str();
+    let base = requete.base_degres_jours.unwrap_or(CONST_DEGRES_JOURS_BASE_DEFAUT);
+
+    let (min_date, max_date) = match (DateTime::from_timestamp(requete.intervalle_min as i64, 0), DateTime::from_timestamp(requete.intervalle_max as i64, 0)) {
+        (Some(min_date), Some(max_date)) if min_date < max_date => (min_date, max_date),
+        _ => return Ok(Some(middleware.reponse_err(None, None, Some("Intervalle invalide"))?))
+    };
+
+    let (reference_min, reference_max) = match (requete.reference_min, requete.decalage.as_ref()) {
+        (Some(reference_min), _) => match DateTime::from_timestamp(reference_min as i64, 0) {
+            Some(inner) => (inner, inner + (max_date - min_date)),
+            None => return Ok(Some(middleware.reponse_err(None, None, Some("reference_min invalide"))?))
+        },
+        (None, Some(decalage)) => {
+            match (decaler_date(&min_date, decalage, &tz), decaler_date(&max_date, decalage, &tz)) {
+                (Ok(reference_min), Ok(reference_max)) => (reference_min, reference_max),
+                _ => return Ok(Some(middleware.reponse_err(None, None, Some("Decalage invalide"))?))
+            }
+        },
+        (None, None) => return Ok(Some(middleware.reponse_err(None, None, Some("reference_min ou decalage requis"))?))
+    };
+
+    let (intervalles_courants, intervalles_reference) = match (
+        generer_intervalles(&min_date, &max_date, grouping, &tz),
+        generer_intervalles(&reference_min, &reference_max, grouping, &tz)
+    ) {
+        (Ok(courants), Ok(reference)) => (courants, reference),
+        _ => return Ok(Some(middleware.reponse_err(None, None, Some("Intervalle ou grouping invalide"))?))
+    };
+
+    let rows_courants = query_aggregate(middleware, user_id.as_str(), &requete.uuid_appareil, &requete.senseur_id,
+                                        grouping, &tz, min_date, Some(max_date)).await?;
+    let rows_reference = query_aggregate(middleware, user_id.as_str(), &requete.uuid_appareil, &requete.senseur_id,
+                                         grouping, &tz, reference_min, Some(reference_max)).await?;
+
+    let sommaire_courant = SommairePeriode::calculer(&rows_courants, min_date, max_date, &tz, base);
+    let sommaire_reference = SommairePeriode::calculer(&rows_reference, reference_min, reference_max, &tz, base);
+    let deltas = DeltasPeriodes {
+        moyenne: delta(sommaire_courant.moyenne, sommaire_reference.moyenne),
+        min: delta(sommaire_courant.min, sommaire_reference.min),
+        max: delta(sommaire_courant.max, sommaire_reference.max),
+        degres_jours_chauffage: sommaire_courant.degres_jours_chauffage - sommaire_reference.degres_jours_chauffage,
+        degres_jours_climatisation: sommaire_courant.degres_jours_climatisation - sommaire_reference.degres_jours_climatisation,
+    };
+
+    // Les deux series sont alignees par position (e.g. 3e jour de chaque periode).
+    let mut valeurs_courantes = indexer_rows(rows_courants, grouping, &tz);
+    let mut valeurs_reference = indexer_rows(rows_reference, grouping, &tz);
+    let mut intervalles_reference = intervalles_reference.into_iter();
+    let intervalles = intervalles_courants.into_iter().enumerate().map(|(index, intervalle)| {
+        let courant = valeurs_courantes.remove(&intervalle.cle);
+        let (heure_reference, cle_reference, reference) = match intervalles_reference.next() {
+            Some(inner) => {
+                let reference = valeurs_reference.remove(&inner.cle);
+                (Some(inner.debut), Some(inner.cle), reference)
+            },
+            None => (None, None, None)
+        };
+        IntervallePeriodes { index, heure: intervalle.debut, cle: intervalle.cle, courant, heure_reference, cle_reference, reference }
+    }).collect();
+
+    let reponse = ReponseComparaisonPeriodes {
+        ok: true,
+        grouping: requete.grouping,
+        timezone: tz.to_string(),
+        courant: sommaire_courant,
+        reference: sommaire_reference,
+        deltas,
+        intervalles,
+    };
+
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+/// Nombre maximal de classes pour un histogramme.
+const CONST_HISTOGRAMME_BORNES_MAX: usize = 200;
+
+#[derive(Deserialize)]
+struct RequeteHistogrammeSenseur {
+    uuid_appareil: String,
+    senseur_id: String,
+    intervalle_min: usize,
+    intervalle_max: Option<usize>,
+    /// Bornes des classes en ordre croissant. Chaque classe inclut sa borne inferieure.
+    bornes: Vec<f64>,
+    /// Seuils pour le temps passe au-dessus / au-dessous (e.g. 80% d'humidite).
+    seuils: Option<Vec<f64>>,
+    /// Valeur horaire utilisee : avg (defaut), min ou max.
+    champ: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct HistogrammeBucketRow {
+    _id: Bson,
+    heures: i64,
+    lectures: i64,
+}
+
+#[derive(Serialize)]
+struct ClasseHistogramme {
+    min: f64,
+    max: f64,
+    /// Nombre de rangees horaires dans la classe
+    heures: i64,
+    /// Nombre de lectures brutes (0 pour les anciennes rangees sans nombre_lectures)
+    lectures: i64,
+}
+
+#[derive(Serialize)]
+struct SeuilHistogramme {
+    seuil: f64,
+    heures_au_dessus: i64,
+    heures_au_dessous: i64,
+}
+
+#[derive(Serialize)]
+struct ReponseHistogrammeSenseur {
+    ok: bool,
+    champ: String,
+    total_heures: i64,
+    /// Heures dont la valeur est hors des bornes
+    hors_bornes: i64,
+    classes: Vec<ClasseHistogramme>,
+    seuils: Vec<SeuilHistogramme>,
+}
+
+const CLE_HORS_BORNES: &str = "hors_bornes";
+
+fn pipeline_histogramme(filtre: Document, champ: &str, bornes: &[f64], seuils: &[f64]) -> Vec<Document> {
+    let champ_ref = format!("${}", champ);
+
+    let mut groupe_seuils = doc! { "_id": null };
+    for (idx, seuil) in seuils.iter().enumerate() {
+        groupe_seuils.insert(format!("dessus_{}", idx), doc!{"$sum": {"$cond": [{"$gt": [champ_ref.as_str(), *seuil]}, 1, 0]}});
+        groupe_seuils.insert(format!("dessous_{}", idx), doc!{"$sum": {"$cond": [{"$lt": [champ_ref.as_str(), *seuil]}, 1, 0]}});
+    }
+    groupe_seuils.insert("total", doc!{"$sum": 1});
+
+    vec![
+        doc! { "$match": filtre },
+        doc! { "$project": {champ: 1, "nombre_lectures": 1} },
+        doc! { "$facet": {
+            "classes": [
+                { "$bucket": {
+                    "groupBy": champ_ref.as_str(),
+                    "boundaries": bornes.to_vec(),
+                    "default": CLE_HORS_BORNES,
+                    "output": {
+                        "heures": {"$sum": 1},
+                        "lectures": {"$sum": {"$ifNull": ["$nombre_lectures", 0]}},
+                    },
+                } }
+            ],
+            "seuils": [ { "$group": groupe_seuils } ],
+        } },
+    ]
+}
+
+pub async fn requete_histogramme_senseur<M>(middleware: &M, m: MessageValide, _gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_histogramme_senseur Consommer requete : {:?}", & m.message);
+    let requete: RequeteHistogrammeSenseur = deser_message_buffer!(m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    };
+
+    let champ = match requete.champ.as_deref() {
+        None | Some("avg") => "avg",
+        Some("min") => "min",
+        Some("max") => "max",
+        Some(_) => return Ok(Some(middleware.reponse_err(None, None, Some("champ invalide (avg, min ou max)"))?))
+    };
+
+    let bornes = requete.bornes;
+    let bornes_valides = bornes.len() >= 2 && bornes.len() <= CONST_HISTOGRAMME_BORNES_MAX &&
+        bornes.iter().all(|b| b.is_finite()) && bornes.windows(2).all(|p| p[0] < p[1]);
+    if !bornes_valides {
+        return Ok(Some(middleware.reponse_err(None, None, Some("bornes invalides (au moins 2, ordre croissant)"))?))
+    }
+    let seuils = requete.seuils.unwrap_or_default();
+
+    let min_date = match DateTime::from_timestamp(requete.intervalle_min as i64, 0) {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("intervalle_min invalide"))?))
+    };
+    let mut intervalle_heures = doc! {"$gte": min_date};
+    if let Some(inner) = requete.intervalle_max.and_then(|v| DateTime::from_timestamp(v as i64, 0)) {
+        intervalle_heures.insert("$lt", inner);
+    }
+    let filtre = doc! {
+        CHAMP_USER_ID: &user_id,
+        CHAMP_UUID_APPAREIL: &requete.uuid_appareil,
+        "senseur_id": &requete.senseur_id,
+        "heure": intervalle_heures,
+        champ: {"$type": "number"},
+    };
+
+    let pipeline = pipeline_histogramme(filtre, champ, &bornes, &seuils);
+    let collection = middleware.get_collection(COLLECTIONS_SENSEURS_HORAIRE)?;
+    let mut resultat = collection.aggregate(pipeline, None).await?;
+    let facettes = match resultat.next().await {
+        Some(inner) => inner?,
+        None => Document::new()
+    };
+
+    // Classes vides absentes du resultat de $bucket
+    let mut classes: Vec<ClasseHistogramme> = bornes.windows(2)
+        .map(|p| ClasseHistogramme { min: p[0], max: p[1], heures: 0, lectures: 0 })
+        .collect();
+    let mut hors_bornes = 0;
+    if let Ok(rows) = facettes.get_array("classes") {
+        for row in rows {
+            let row: HistogrammeBucketRow = match row.as_document() {
+                Some(d) => convertir_bson_deserializable(d.to_owned())?,
+                None => continue
+            };
+            match row._id.as_f64().or_else(|| row._id.as_i64().map(|v| v as f64)).or_else(|| row._id.as_i32().map(|v| v as f64)) {
+                Some(borne) => {
+                    if let Some(classe) = classes.iter_mut().find(|c| c.min == borne) {
+                        classe.heures = row.heures;
+                        classe.lectures = row.lectures;
+                    }
+                },
+                None => hors_bornes = row.heures  // CLE_HORS_BORNES
+            }
+        }
+    }
+
+    let groupe_seuils = facettes.get_array("seuils").ok()
+        .and_then(|s| s.first())
+        .and_then(|s| s.as_document())
+        .cloned()
+        .unwrap_or_default();
+    let valeur_groupe = |cle: &str| -> i64 {
+        groupe_seuils.get_i32(cle).map(|v| v as i64).or_else(|_| groupe_seuils.get_i64(cle)).unwrap_or(0)
+    };
+    let seuils = seuils.iter().enumerate().map(|(idx, seuil)| SeuilHistogramme {
+        seuil: *seuil,
+        heures_au_dessus: valeur_groupe(format!("dessus_{}", idx).as_str()),
+        heures_au_dessous: valeur_groupe(format!("dessous_{}", idx).as_str()),
+    }).collect();
+
+    let reponse = ReponseHistogrammeSenseur {
+        ok: true,
+        champ: champ.to_string(),
+        total_heures: valeur_groupe("total"),
+        hors_bornes,
+        classes,
+        seuils,
+    };
+
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+#[derive(Deserialize)]
+struct RequeteMatriceHoraireSenseur {
+    uuid_appareil: String,
+    senseur_id: String,
+    /// Timezone d'affichage. Utilise la timezone de l'usager si absente.
+    timezone: Option<String>,
+    intervalle_min: usize,
+    intervalle_max: Option<usize>,
+    /// Valeur horaire : avg (defaut), min ou max.
+    valeur: Option<String>,
+    /// jours : une rangee par jour (defaut). semaine : profil moyen par jour de la semaine.
+    mode: Option<String>,
+}
+
+#[derive(Serialize)]
+struct RangeeMatriceHoraire {
+    /// Jour local (YYYY-MM-DD) ou jour de la semaine (0 = lundi)
+    cle: String,
+    /// Une valeur par heure locale (0 a 23). null si aucune donnee.
+    valeurs: Vec<Option<f64>>,
+    /// Nombre de rangees horaires utilisees pour chaque heure.
+    echantillons: Vec<u32>,
+}
+
+#[derive(Serialize)]
+struct ReponseMatriceHoraireSenseur {
+    ok: bool,
+    mode: String,
+    valeur: String,
+    timezone: String,
+    rangees: Vec<RangeeMatriceHoraire>,
+}
+
+/// Cumule (somme, nombre) par rangee et par heure locale. Les heures doublees (changement d'heure) sont moyennees.
+fn cumuler_matrice<F>(rows: &[ResultatStatistiquesSenseurRow], tz: &Tz, valeur: &str, cle_rangee: F)
+    -> BTreeMap<String, [(f64, u32); 24]>
+    where F: Fn(&DateTime<Tz>) -> String
+{
+    let mut cumul: BTreeMap<String, [(f64, u32); 24]> = BTreeMap::new();
+    for row in rows {
+        let valeur_row = match valeur {
+            "min" => row.min,
+            "max" => row.max,
+            _ => row.avg,
+        };
+        if let Some(v) = valeur_row {
+            let heure_locale = row.heure.with_timezone(tz);
+            let cellule = &mut cumul.entry(cle_rangee(&heure_locale)).or_insert([(0.0, 0); 24])[heure_locale.hour() as usize];
+            cellule.0 += v;
+            cellule.1 += 1;
+        }
+    }
+    cumul
+}
+
+pub async fn requete_matrice_horaire_senseur<M>(middleware: &M, m: MessageValide, _gestionnaire: &SenseursPassifsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_matrice_horaire_senseur Consommer requete : {:?}", & m.message);
+    let requete: RequeteMatriceHoraireSenseur = deser_message_buffer!(m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("user_id manquant"))?))
+    };
+
+    let valeur = match requete.valeur.as_deref() {
+        None | Some("avg") => "avg",
+        Some("min") => "min",
+        Some("max") => "max",
+        Some(_) => return Ok(Some(middleware.reponse_err(None, None, Some("valeur invalide (avg, min ou max)"))?))
+    };
+    let mode = match requete.mode.as_deref() {
+        None | Some("jours") => "jours",
+        Some("semaine") => "semaine",
+        Some(_) => return Ok(Some(middleware.reponse_err(None, None, Some("mode invalide (jours ou semaine)"))?))
+    };
+
+    let tz = match requete.timezone.as_ref() {
+        Some(_) => parser_timezone(requete.timezone.as_ref()),
+        None => {
+            let configuration = charger_configuration_usager(middleware, user_id.as_str()).await?;
+            parser_timezone(configuration.timezone.as_ref())
+        }
+    };
+
+    let min_date = match DateTime::from_timestamp(requete.intervalle_min as i64, 0) {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("intervalle_min invalide"))?))
+    };
+    let max_date = match requete.intervalle_max {
+        Some(inner) => match DateTime::from_timestamp(inner as i64, 0) {
+            Some(inner) => inner,
+            None => return Ok(Some(middleware.reponse_err(None, None, Some("intervalle_max invalide"))?))
+        },
+        None => Utc::now()
+    };
+    if let Err(e) = valider_periode(&min_date, &max_date) {
+        return Ok(Some(middleware.reponse_err(None, None, Some(e.as_str()))?))
+    }
+
+    let rows = query_aggregate(middleware, user_id.as_str(), &requete.uuid_appareil, &requete.senseur_id,
+                               "heures", &tz, min_date, Some(max_date)).await?;
+
+    let cumul = match mode {
+        "semaine" => cumuler_matrice(&rows, &tz, valeur, |h| h.weekday().num_days_from_monday().to_string()),
+        _ => cumuler_matrice(&rows, &tz, valeur, |h| h.format("%Y-%m-%d").to_string()),
+    };
+
+    let rangees = cumul.into_iter().map(|(cle, cellules)| RangeeMatriceHoraire {
+        cle,
+        valeurs: cellules.iter().map(|(somme, nombre)| match nombre {
+            0 => None,
+            n => Some(somme / *n as f64)
+        }).collect(),
+        echantillons: cellules.iter().map(|(_, nombre)| *nombre).collect(),
+    }).collect();
+
+    let reponse = ReponseMatriceHoraireSenseur {
+        ok: true,
+        mode: mode.to_string(),
+        valeur: valeur.to_string(),
+        timezone: tz.to_string(),
+        rangees,
+    };
+
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_setup::setup;
+
+    const TZ_TORONTO: &str = "America/Toronto";
+
+    fn rangee(timestamp: i64, avg: f64) -> ResultatStatistiquesSenseurRow {
+        ResultatStatistiquesSenseurRow {
+            heure: DateTime::from_timestamp(timestamp, 0).expect("timestamp"),
+            min: Some(avg - 1.0),
+            max: Some(avg + 1.0),
+            avg: Some(avg),
+            provisoire: None,
+        }
+    }
+
+    fn senseur(uuid_appareil: &str, senseur_id: &str) -> SenseurRef {
+        SenseurRef { uuid_appareil: uuid_appareil.to_string(), senseur_id: senseur_id.to_string() }
+    }
+
+    #[test]
+    fn test_echapper_csv() {
+        setup("test_echapper_csv");
+        assert_eq!("salon", echapper_csv("salon"));
+        assert_eq!("\"salon, nord\"", echapper_csv("salon, nord"));
+        assert_eq!("\"ecran \"\"2\"\"\"", echapper_csv("ecran \"2\""));
+        assert_eq!("\"ligne\nsuivante\"", echapper_csv("ligne\nsuivante"));
+    }
+
+    #[test]
+    fn test_csv_long() {
+        setup("test_csv_long");
+        let tz: Tz = TZ_TORONTO.parse().expect("tz");
+        let senseur_a = senseur("appareil1", "temp");
+        let mut rangee_vide = rangee(1730613600, 0.0);
+        rangee_vide.min = None;
+        rangee_vide.max = None;
+        rangee_vide.avg = None;
+        let resultats = vec![(&senseur_a, vec![rangee(1730610000, 20.5), rangee_vide])];
+        let mut libelles = HashMap::new();
+        libelles.insert(senseur_a.clone(), "Salon, nord".to_string());
+
+        let csv = generer_csv_long(&resultats, &libelles, "heures", &tz);
+        let lignes: Vec<&str> = csv.lines().collect();
+        assert_eq!(vec![
+            "date,uuid_appareil,senseur_id,senseur,avg,min,max",
+            "2024-11-03 01:00,appareil1,temp,\"Salon, nord\",20.5,19.5,21.5",
+            "2024-11-03 01:00,appareil1,temp,\"Salon, nord\",,,",
+        ], lignes);
+    }
+
+    #[test]
+    fn test_csv_large_heure_repetee() {
+        setup("test_csv_large_heure_repetee");
+        let tz: Tz = TZ_TORONTO.parse().expect("tz");
+        let senseur_a = senseur("appareil1", "temp");
+        let senseur_b = senseur("appareil2", "temp");
+        // 05:00Z et 06:00Z sont toutes deux 01:00 locale le 2024-11-03
+        let resultats = vec![
+            (&senseur_a, vec![rangee(1730610000, 1.0), rangee(1730613600, 3.0)]),
+            (&senseur_b, vec![rangee(1730613600, 5.0)]),
+        ];
+
+        let csv = generer_csv_large(&resultats, &HashMap::new(), "heures", &tz);
+        let lignes: Vec<&str> = csv.lines().collect();
+        assert_eq!(vec![
+            "date,appareil1/temp avg,appareil1/temp min,appareil1/temp max,appareil2/temp avg,appareil2/temp min,appareil2/temp max",
+            "2024-11-03 01:00,1,0,2,,,",
+            "2024-11-03 01:00,3,2,4,5,4,6",
+        ], lignes);
+    }
+
+    #[test]
+    fn test_csv_large_jours_alignes() {
+        setup("test_csv_large_jours_alignes");
+        let tz: Tz = TZ_TORONTO.parse().expect("tz");
+        let senseur_a = senseur("appareil1", "temp");
+        let senseur_b = senseur("appareil2", "temp");
+        // Premiere heure du jour differente pour chaque senseur (04:00Z = minuit local)
+        let resultats = vec![
+            (&senseur_a, vec![rangee(1730606400, 1.0)]),
+            (&senseur_b, vec![rangee(1730610000, 5.0)]),
+        ];
+
+        let csv = generer_csv_large(&resultats, &HashMap::new(), "jours", &tz);
+        let lignes: Vec<&str> = csv.lines().collect();
+        assert_eq!(2, lignes.len());
+        assert_eq!("2024-11-03,1,0,2,5,4,6", lignes[1]);
+    }
+
+    fn date(timestamp: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(timestamp, 0).expect("timestamp")
+    }
+
+    #[test]
+    fn test_estimer_rangees_export() {
+        setup("test_estimer_rangees_export");
+        let debut = date(1730520000);
+        let fin = debut + Duration::days(365);
+        assert_eq!(8761, estimer_rangees_senseur(&debut, &fin, "heures", None).expect("heures"));
+        assert_eq!(367, estimer_rangees_senseur(&debut, &fin, "jours", None).expect("jours"));
+        assert_eq!(500, estimer_rangees_senseur(&debut, &fin, "heures", Some(500)).expect("max_points"));
+        assert!(estimer_rangees_senseur(&debut, &fin, "minutes", None).is_err());
+        // 20 senseurs horaires sur un an depassent la limite avant toute requete
+        assert!(20 * estimer_rangees_senseur(&debut, &fin, "heures", None).expect("heures") > CONST_EXPORT_RANGEES_MAX);
+    }
+
+    #[test]
+    fn test_decaler_date_jour_calendrier() {
+        setup("test_decaler_date_jour_calendrier");
+        let tz: Tz = TZ_TORONTO.parse().expect("tz");
+        // 2024-03-10 13:00 EDT => 2024-03-09 13:00 EST, 23 heures plus tot
+        let resultat = decaler_date(&date(1710090000), "jour", &tz).expect("decaler");
+        assert_eq!(date(1710007200), resultat);
+        let resultat = decaler_date(&date(1710090000), "semaine", &tz).expect("decaler");
+        assert_eq!("2024-03-03 13:00", formatter_intervalle(&resultat, "heures", &tz));
+    }
+
+    #[test]
+    fn test_decaler_date_mois_annee() {
+        setup("test_decaler_date_mois_annee");
+        let tz: Tz = TZ_TORONTO.parse().expect("tz");
+        // 2024-03-31 10:00 EDT => 2024-02-29 10:00 EST (dernier jour du mois)
+        let resultat = decaler_date(&date(1711893600), "mois", &tz).expect("decaler");
+        assert_eq!(date(1709218800), resultat);
+        // 2024-02-29 => 2023-02-28
+        let resultat = decaler_date(&date(1709218800), "annee", &tz).expect("decaler");
+        assert_eq!(date(1677596400), resultat);
+        assert!(decaler_date(&date(1709218800), "siecle", &tz).is_err());
+    }
+
+    #[test]
+    fn test_intervalles_heures_retour_heure_normale() {
+        setup("test_intervalles_heures_retour_heure_normale");
+        let tz: Tz = TZ_TORONTO.parse().expect("tz");
+        // 2024-11-03 04:00Z a 08:00Z, l'heure locale 01:00 est repetee
+        let intervalles = generer_intervalles(&date(1730606400), &date(1730620800), "heures", &tz).expect("intervalles");
+        let cles: Vec<&str> = intervalles.iter().map(|i| i.cle.as_str()).collect();
+        assert_eq!(vec!["2024-11-03 00:00", "2024-11-03 01:00", "2024-11-03 02:00"], cles);
+        assert_eq!(date(1730610000), intervalles[1].debut);
+    }
+
+    #[test]
+    fn test_intervalles_jours_changement_heure() {
+        setup("test_intervalles_jours_changement_heure");
+        let tz: Tz = TZ_TORONTO.parse().expect("tz");
+        // 2024-11-02 00:00 EDT a 2024-11-04 00:00 EST, le 3 novembre a 25 heures
+        let intervalles = generer_intervalles(&date(1730520000), &date(1730696400), "jours", &tz).expect("intervalles");
+        let cles: Vec<&str> = intervalles.iter().map(|i| i.cle.as_str()).collect();
+        assert_eq!(vec!["2024-11-02", "2024-11-03"], cles);
+        assert_eq!(date(1730606400), intervalles[1].debut);
+
+        // 2024-03-10 a 23 heures
+        let intervalles = generer_intervalles(&date(1710046800), &date(1710129600), "heures", &tz).expect("intervalles");
+        assert_eq!(23, intervalles.len());
+        let intervalles = generer_intervalles(&date(1710046800), &date(1710129600), "jours", &tz).expect("intervalles");
+        assert_eq!(1, intervalles.len());
+    }
+
+    #[test]
+    fn test_intervalles_invalides() {
+        setup("test_intervalles_invalides");
+        let tz: Tz = TZ_TORONTO.parse().expect("tz");
+        assert!(generer_intervalles(&date(1730520000), &date(1730696400), "minutes", &tz).is_err());
+        let max_date = date(1730520000) + Duration::hours(CONST_INTERVALLES_HEURES_MAX + 1);
+        assert!(generer_intervalles(&date(1730520000), &max_date, "jours", &tz).is_err());
+    }
+}
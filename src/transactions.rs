@@ -1,12 +1,23 @@
 use log::{debug, error, warn};
 use std::collections::HashMap;
 
+use crate::boite_envoi::emettre_configuration_appareil;
+use crate::coherence::transaction_supprimer_senseurs_horaire;
 use crate::common::*;
+use crate::compaction::transaction_senseur_horaire_mensuel;
 use crate::domain_manager::SenseursPassifsDomainManager;
+use crate::gabarits::{associer_appareil_gabarit, transaction_sauvegarder_gabarit};
+use crate::horaires::transaction_sauvegarder_horaire;
+use crate::migration::{transaction_legacy_migree, transaction_migration_senseur_legacy};
+use crate::programmes::{enregistrer_version_programme, enregistrer_versions_programmes, transaction_restaurer_version_programme};
+use crate::regeneration::{ajouter_rangees_regeneration, avancer_regeneration};
+use crate::retention::{charger_jours_elagues, transaction_elaguer_senseurs_horaire, transaction_maj_retention};
+use crate::scenes::transaction_sauvegarder_scene;
+use crate::statistiques::SenseurRef;
 use millegrilles_common_rust::bson::doc;
 use millegrilles_common_rust::bson::serde_helpers::chrono_datetime_as_bson_datetime;
 use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
-use millegrilles_common_rust::chrono::{DateTime, Utc};
+use millegrilles_common_rust::chrono::{DateTime, Duration, Utc};
 use millegrilles_common_rust::constantes::*;
 use millegrilles_common_rust::db_structs::TransactionValide;
 use millegrilles_common_rust::error::Error;
@@ -15,9 +26,9 @@ use millegrilles_common_rust::middleware::sauvegarder_traiter_transaction_serial
 use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::epochseconds;
 use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::MessageMilleGrillesBufferDefault;
 use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, convertir_to_bson, filtrer_doc_id, MongoDao};
-use millegrilles_common_rust::mongodb::options::{FindOneAndUpdateOptions, FindOneOptions, Hint, ReturnDocument, UpdateOptions};
+use millegrilles_common_rust::mongodb::options::{FindOneAndUpdateOptions, Hint, ReturnDocument, UpdateOptions};
 use millegrilles_common_rust::mongodb::ClientSession;
-use millegrilles_common_rust::serde::{Deserialize, Serialize};
+use millegrilles_common_rust::serde::{Deserialize, Deserializer, Serialize};
 use millegrilles_common_rust::serde_json;
 use millegrilles_common_rust::serde_json::json;
 
@@ -36,26 +47,59 @@ pub async fn aiguillage_transaction<M>(
 
     debug!("aiguillage_transaction {}", action);
 
+    if middleware.get_mode_regeneration() {
+        let transaction_id = transaction.transaction.id.to_string();
+        let estampille = transaction.transaction.estampille;
+        if avancer_regeneration(middleware, gestionnaire, action.as_str(), transaction_id.as_str(), &estampille, session).await? {
+            debug!("aiguillage_transaction Transaction {} deja appliquee (reprise regeneration)", transaction.transaction.id);
+            return Ok(None)
+        }
+    }
+
+    // Transactions legacy sans effet une fois le senseur migre (voir migrerTransactionsLegacy)
+    if transaction_legacy_migree(middleware, action.as_str(), &transaction, session).await? {
+        return transaction_legacy(middleware, transaction).await
+    }
+
     match action.as_str() {
-        TRANSACTION_MAJ_SENSEUR => transaction_maj_senseur(middleware, transaction, gestionnaire, session).await,
         TRANSACTION_MAJ_NOEUD => transaction_maj_noeud(middleware, transaction,  session).await,
-        TRANSACTION_SUPPRESSION_SENSEUR => transaction_suppression_senseur(middleware, transaction, session).await,
         TRANSACTION_MAJ_APPAREIL => transaction_maj_appareil(middleware, transaction, gestionnaire, session).await,
-        TRANSACTION_SENSEUR_HORAIRE => transaction_senseur_horaire(middleware, transaction, session).await,
+        TRANSACTION_SENSEUR_HORAIRE => transaction_senseur_horaire(middleware, transaction, gestionnaire, session).await,
+        TRANSACTION_SENSEUR_HORAIRE_MENSUEL => transaction_senseur_horaire_mensuel(middleware, transaction, gestionnaire, session).await,
         TRANSACTION_INIT_APPAREIL => transaction_initialiser_appareil(middleware, transaction, session).await,
         TRANSACTION_APPAREIL_SUPPRIMER => transaction_appareil_supprimer(middleware, transaction, session).await,
         TRANSACTION_APPAREIL_RESTAURER => transaction_appareil_restaurer(middleware, transaction, session).await,
         TRANSACTION_MAJ_CONFIGURATION_USAGER => transaction_maj_configuration_usager(middleware, transaction, session).await,
         TRANSACTION_SAUVEGARDER_PROGRAMME => transaction_sauvegarder_programme(middleware, transaction, gestionnaire, session).await,
         TRANSACTION_SHOW_HIDE_SENSOR => transaction_show_hide_sensor(middleware, transaction, session).await,
+        TRANSACTION_MAJ_RETENTION => transaction_maj_retention(middleware, transaction, session).await,
+        TRANSACTION_ELAGUER_SENSEURS_HORAIRE => transaction_elaguer_senseurs_horaire(middleware, transaction, session).await,
+        TRANSACTION_SUPPRIMER_SENSEURS_HORAIRE => transaction_supprimer_senseurs_horaire(middleware, transaction, session).await,
+        TRANSACTION_MIGRATION_SENSEUR_LEGACY => transaction_migration_senseur_legacy(middleware, transaction, session).await,
+        TRANSACTION_SAUVEGARDER_SCENE => transaction_sauvegarder_scene(middleware, transaction, session).await,
+        TRANSACTION_SAUVEGARDER_HORAIRE => transaction_sauvegarder_horaire(middleware, transaction, session).await,
+        TRANSACTION_RESTAURER_VERSION_PROGRAMME => transaction_restaurer_version_programme(middleware, transaction, session).await,
+        TRANSACTION_SAUVEGARDER_GABARIT => transaction_sauvegarder_gabarit(middleware, transaction, session).await,
 
         // Legacy
+        TRANSACTION_MAJ_SENSEUR => transaction_maj_senseur(middleware, transaction, gestionnaire, session).await,
+        TRANSACTION_SUPPRESSION_SENSEUR => transaction_suppression_senseur(middleware, transaction, session).await,
         TRANSACTION_LECTURE => transaction_lectures(middleware, transaction, session).await,
 
         _ => Err(Error::String(format!("senseurspassifs.aiguillage_transaction: Transaction {} est de type non gere : {}", transaction.transaction.id, action))),
     }
 }
 
+/// Transaction legacy (uuid_senseur/instance_id) d'un senseur deja migre. Les lectures sont conservees
+/// dans les rangees horaires produites par migrerTransactionsLegacy, la transaction est sans effet.
+async fn transaction_legacy<M>(middleware: &M, transaction: TransactionValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages
+{
+    debug!("transaction_legacy Transaction legacy {} ignoree (senseur migre)", transaction.transaction.id);
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
+
 async fn transaction_maj_senseur<M>(
     middleware: &M, transaction: TransactionValide, gestionnaire: &SenseursPassifsDomainManager, session: &mut ClientSession)
     -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
@@ -157,6 +201,12 @@ async fn transaction_maj_senseur<M>(
 pub struct TransactionMajAppareil {
     pub uuid_appareil: String,
     pub configuration: ConfigurationAppareil,
+    /// Usager de l'appareil pour une transaction emise par le domaine (voir appliquerGabarit)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    /// Gabarit applique, l'appareil est ajoute au groupe du gabarit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gabarit_id: Option<String>,
 }
 
 async fn transaction_maj_appareil<M>(middleware: &M, transaction: TransactionValide, _gestionnaire: &SenseursPassifsDomainManager, session: &mut ClientSession)
@@ -164,14 +214,23 @@ async fn transaction_maj_appareil<M>(middleware: &M, transaction: TransactionVal
     where M: GenerateurMessages + MongoDao
 {
     debug!("transaction_maj_senseur Consommer transaction : {:?}", transaction.transaction.id);
-    let user_id = match transaction.certificat.get_user_id()? {
-        Some(user) => user.to_owned(),
-        None => Err(Error::Str("senseurspassifs.transaction_maj_senseur Erreur user_id absent du certificat"))?
-    };
-
     let transaction_convertie: TransactionMajAppareil = serde_json::from_str(transaction.transaction.contenu.as_str())?;
     debug!("transaction_maj_senseur Transaction convertie: {:?}", transaction_convertie);
 
+    // Le user_id et le gabarit_id du contenu sont acceptes uniquement pour une transaction emise par
+    // le domaine (appliquerGabarit). Les commandes externes qui les contiennent sont refusees.
+    let emise_domaine = transaction.certificat.verifier_domaines(vec![DOMAINE_NOM.to_string()])?;
+    let user_id = match (transaction.certificat.get_user_id()?, transaction_convertie.user_id.as_ref()) {
+        (Some(user), _) => user.to_owned(),
+        (None, Some(user)) if emise_domaine => user.to_owned(),
+        _ => Err(Error::Str("senseurspassifs.transaction_maj_senseur Erreur user_id absent du certificat"))?
+    };
+    let gabarit_id = match emise_domaine {
+        true => transaction_convertie.gabarit_id.as_ref(),
+        false => None
+    };
+    let programmes_versions = transaction_convertie.configuration.programmes.clone();
+
     let document_transaction: DocAppareil = {
         let mut set_ops = doc! {};
         let unset_ops = doc! {};
@@ -256,6 +315,13 @@ async fn transaction_maj_appareil<M>(middleware: &M, transaction: TransactionVal
     };
     debug!("transaction_maj_appareil Resultat maj transaction : {:?}", document_transaction);
 
+    if let Some(programmes) = programmes_versions.as_ref() {
+        enregistrer_versions_programmes(middleware, &transaction, &user_id, &transaction_convertie.uuid_appareil, programmes, session).await?;
+    }
+    if let Some(gabarit_id) = gabarit_id {
+        associer_appareil_gabarit(middleware, &user_id, gabarit_id, &transaction_convertie.uuid_appareil, session).await?;
+    }
+
     // Evenement de mise a jour de l'appareil (web)
     {
         let routage_evenement = RoutageMessageAction::builder(DOMAINE_NOM, TRANSACTION_MAJ_APPAREIL, vec![Securite::L2Prive])
@@ -266,41 +332,32 @@ async fn transaction_maj_appareil<M>(middleware: &M, transaction: TransactionVal
 
     if let Some(configuration) = &document_transaction.configuration {
 
-        // Evenement de mise a jour des displays (relais)
+        // Evenements de configuration (relais), conserves dans la boite d'envoi si l'appareil est hors ligne
         {
-            let routage_evenement = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_MAJ_CONFIGURATION_APPAREIL, vec![Securite::L2Prive])
-                .partition(&user_id)
-                .build();
             let evenement = json!({
                 CHAMP_USER_ID: &user_id,
                 CHAMP_UUID_APPAREIL: &transaction_convertie.uuid_appareil,
                 CHAMP_TIMEZONE: configuration.timezone.as_ref(),
             });
-            middleware.emettre_evenement(routage_evenement, &evenement).await?;
+            emettre_configuration_appareil(middleware, &document_transaction, &user_id, EVENEMENT_MAJ_CONFIGURATION_APPAREIL, evenement, session).await?;
         }
 
         // Evenement de mise a jour des displays (relais)
         if let Some(displays) = &configuration.displays {
-            let routage_evenement = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_MAJ_DISPLAYS, vec![Securite::L2Prive])
-                .partition(&user_id)
-                .build();
             let evenement_displays = json!({
                 CHAMP_UUID_APPAREIL: &transaction_convertie.uuid_appareil,
                 "displays": displays
             });
-            middleware.emettre_evenement(routage_evenement, &evenement_displays).await?;
+            emettre_configuration_appareil(middleware, &document_transaction, &user_id, EVENEMENT_MAJ_DISPLAYS, evenement_displays, session).await?;
         }
 
         // Evenement de mise a jour des programmes (relais)
         if let Some(programmes) = &configuration.programmes {
-            let routage_evenement = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_MAJ_PROGRAMMES, vec![Securite::L2Prive])
-                .partition(&user_id)
-                .build();
             let evenement_programmes = json!({
                 CHAMP_UUID_APPAREIL: &transaction_convertie.uuid_appareil,
                 "programmes": programmes
             });
-            middleware.emettre_evenement(routage_evenement, &evenement_programmes).await?;
+            emettre_configuration_appareil(middleware, &document_transaction, &user_id, EVENEMENT_MAJ_PROGRAMMES, evenement_programmes, session).await?;
         }
     }
 
@@ -328,12 +385,28 @@ async fn transaction_sauvegarder_programme<M>(middleware: &M, transaction: Trans
     let transaction_convertie: TransactionSauvegarderProgramme = serde_json::from_str(transaction.transaction.contenu.as_str())?;
     debug!("transaction_sauvegarder_programmes Transaction lue {:?}", transaction_convertie);
 
+    sauvegarder_programme_appareil(middleware, &transaction, user_id, transaction_convertie, session).await
+}
+
+/// Sauvegarde (ou supprime) un programme de l'appareil et conserve la version. Utilise aussi pour
+/// restaurer une version precedente (voir programmes.rs).
+pub async fn sauvegarder_programme_appareil<M>(
+    middleware: &M, transaction: &TransactionValide, user_id: String, transaction_convertie: TransactionSauvegarderProgramme, session: &mut ClientSession
+)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    let supprimer = transaction_convertie.supprimer == Some(true);
+    enregistrer_version_programme(
+        middleware, transaction, &user_id, &transaction_convertie.uuid_appareil, &transaction_convertie.programme.programme_id,
+        if supprimer { None } else { Some(&transaction_convertie.programme) }, session).await?;
+
     let document_transaction: DocAppareil = {
         let mut set_ops = doc! {};
         let mut unset_ops = doc! {};
 
         let programme_id = transaction_convertie.programme.programme_id.clone();
-        if let Some(true) = transaction_convertie.supprimer {
+        if supprimer {
             unset_ops.insert(format!("configuration.programmes.{}", programme_id), true);
         } else {
             let bson_map = match convertir_to_bson(transaction_convertie.programme) {
@@ -388,16 +461,13 @@ async fn transaction_sauvegarder_programme<M>(middleware: &M, transaction: Trans
 
     if let Some(configuration) = &document_transaction.configuration {
 
-        // Evenement de mise a jour des programmes (relais)
+        // Evenement de mise a jour des programmes (relais), conserve si l'appareil est hors ligne
         if let Some(programmes) = &configuration.programmes {
-            let routage_evenement = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_MAJ_PROGRAMMES, vec![Securite::L2Prive])
-                .partition(&user_id)
-                .build();
             let evenement_programmes = json!({
                 CHAMP_UUID_APPAREIL: &transaction_convertie.uuid_appareil,
                 "programmes": programmes
             });
-            middleware.emettre_evenement(routage_evenement, &evenement_programmes).await?;
+            emettre_configuration_appareil(middleware, &document_transaction, &user_id, EVENEMENT_MAJ_PROGRAMMES, evenement_programmes, session).await?;
         }
     }
 
@@ -768,15 +838,17 @@ pub struct SenseurHoraireRow {
     pub min: Option<f64>,
     pub max: Option<f64>,
     pub avg: Option<f64>,
+    /// Nombre de lectures recues pendant l'heure (absent pour les anciennes rangees)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nombre_lectures: Option<u32>,
 }
 
 impl From<&TransactionLectureHoraire> for SenseurHoraireRow {
     fn from(value: &TransactionLectureHoraire) -> Self {
 
-        let type_ = match value.lectures.get(value.lectures.len()-1) {
-            Some(lecture) => Some(lecture.type_.clone()),
-            None => None
-        };
+        // Meme lecture que le type_donnees de l'appareil (transaction_senseur_horaire), pour que
+        // rebuild_sensor_list retrouve le meme type a partir des rangees.
+        let type_ = value.lectures.first().map(|lecture| lecture.type_.clone());
 
         Self {
             creation: Utc::now(),
@@ -788,11 +860,13 @@ impl From<&TransactionLectureHoraire> for SenseurHoraireRow {
             min: value.min,
             max: value.max,
             avg: value.avg,
+            nombre_lectures: Some(value.lectures.len() as u32),
         }
     }
 }
 
-async fn transaction_senseur_horaire<M>(middleware: &M, transaction: TransactionValide, session: &mut ClientSession)
+async fn transaction_senseur_horaire<M>(
+    middleware: &M, transaction: TransactionValide, gestionnaire: &SenseursPassifsDomainManager, session: &mut ClientSession)
     -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
     where M: GenerateurMessages + MongoDao
 {
@@ -800,24 +874,30 @@ async fn transaction_senseur_horaire<M>(middleware: &M, transaction: Transaction
     let transaction_convertie: TransactionLectureHoraire = serde_json::from_str(transaction.transaction.contenu.as_str())?;
     let senseur_horaire_row = SenseurHoraireRow::from(&transaction_convertie);
 
+    // Ne pas reintroduire une heure deja remplacee par un sommaire quotidien (retention)
+    let fin = senseur_horaire_row.heure + Duration::hours(1);
+    let jours_elagues = charger_jours_elagues(
+        middleware, &senseur_horaire_row.user_id, &senseur_horaire_row.uuid_appareil, &senseur_horaire_row.senseur_id,
+        &senseur_horaire_row.heure, &fin, session).await?;
+    if jours_elagues.contient(&senseur_horaire_row.heure) {
+        debug!("transaction_senseur_horaire Heure {:?} deja elaguee, rangee ignoree", senseur_horaire_row.heure);
+        return Ok(Some(middleware.reponse_ok(None, None)?))
+    }
+
+    if middleware.get_mode_regeneration() {
+        // Insertion en lot. L'appareil est mis a jour par rebuild_sensor_list a la fin de la regeneration.
+        ajouter_rangees_regeneration(middleware, gestionnaire, vec![senseur_horaire_row], session).await?;
+        return Ok(Some(middleware.reponse_ok(None, None)?))
+    }
+
     // Inserer dans la table de lectures senseurs horaires
-    let collection = middleware.get_collection_typed::<SenseurHoraireRow>(COLLECTIONS_SENSEURS_HORAIRE)?;
-    if middleware.get_mode_regeneration() == true {
+    if ! inserer_senseur_horaire(middleware, &senseur_horaire_row, session).await? {
         // HACK - duplicate transactions have been produced. Remove once all transactions are fixed/migrated
-        let filtre = doc!{
-            CHAMP_USER_ID: &transaction_convertie.user_id,
-            CHAMP_UUID_APPAREIL: &transaction_convertie.uuid_appareil,
-            "senseur_id": &transaction_convertie.senseur_id,
-            "heure": &transaction_convertie.heure
-        };
-        let options = FindOneOptions::builder().hint(Hint::Name("lectures_horaire".to_string())).build();
-        if collection.find_one_with_session(filtre, options, session).await?.is_some() {
-            warn!("transaction_senseur_horaire Ignoring duplicate transaction: {} on rebuild", transaction.transaction.id);
-            return Ok(None);
-        }
+        warn!("transaction_senseur_horaire Ignoring duplicate transaction: {}", transaction.transaction.id);
+        return Ok(None);
     }
 
-    collection.insert_one_with_session(&senseur_horaire_row, None, session).await?;
+    // La detection d'anomalies est faite apres le commit (voir anomalies::traiter_anomalies_rangees)
 
     // Other approach - pre-commit (slow)
     // if middleware.get_mode_regeneration() == true {
@@ -836,53 +916,98 @@ async fn transaction_senseur_horaire<M>(middleware: &M, transaction: Transaction
     //     }
     // }
 
-    // S'assurer que l'appareil existe (e.g. pour regeneration)
-    if middleware.get_mode_regeneration() == false {
-        let collection = middleware.get_collection(COLLECTIONS_APPAREILS)?;
-        let filtre = doc! {
-            CHAMP_USER_ID: &transaction_convertie.user_id,
-            CHAMP_UUID_APPAREIL: &transaction_convertie.uuid_appareil,
-        };
-        let mut ops = doc! {
-            "$setOnInsert": {
-                CHAMP_USER_ID: &transaction_convertie.user_id,
-                CHAMP_UUID_APPAREIL: &transaction_convertie.uuid_appareil,
-                CHAMP_CREATION: Utc::now(),
-                "present": false,
-            },
-            "$currentDate": {
-                CHAMP_MODIFICATION: true,
-            },
-            "$addToSet": {
-                CHAMP_LECTURES_DISPONIBLES: &transaction_convertie.senseur_id
-            }
-        };
+    // S'assurer que l'appareil existe
+    // Detecter type de lectures (aucun si vide)
+    let type_donnees = transaction_convertie.lectures.first().map(|l| &l.type_);
+    maj_appareil_senseur_horaire(
+        middleware, &transaction_convertie.user_id, &transaction_convertie.uuid_appareil,
+        &transaction_convertie.senseur_id, type_donnees, session).await?;
 
-        // Detecter type de lectures (aucun si vide)
-        let mut type_donnees = None;
-        for l in &transaction_convertie.lectures {
-            type_donnees = Some(l.type_.clone());
-            break
-        }
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
 
-        if let Some(type_donnees) = type_donnees {
-            ops.insert("$set", doc!{
-                format!("types_donnees.{}", transaction_convertie.senseur_id): type_donnees
-            });
-        }
+/// Insere une rangee horaire si elle n'existe pas deja (user_id, uuid_appareil, senseur_id, heure).
+/// Retourne false si la rangee existait. Utilise par les transactions senseurHoraire et senseurHoraireMensuel.
+pub(crate) async fn inserer_senseur_horaire<M>(middleware: &M, row: &SenseurHoraireRow, session: &mut ClientSession)
+    -> Result<bool, Error>
+    where M: MongoDao
+{
+    let filtre = doc!{
+        CHAMP_USER_ID: &row.user_id,
+        CHAMP_UUID_APPAREIL: &row.uuid_appareil,
+        "senseur_id": &row.senseur_id,
+        "heure": &row.heure
+    };
+    let ops = doc! { "$setOnInsert": convertir_to_bson(row)? };
+    let options = UpdateOptions::builder()
+        .upsert(true)
+        .hint(Hint::Name(INDEX_LECTURES_HORAIRE.to_string()))
+        .build();
+    let collection = middleware.get_collection(COLLECTIONS_SENSEURS_HORAIRE)?;
+    let resultat = collection.update_one_with_session(filtre, ops, options, session).await?;
+    Ok(resultat.upserted_id.is_some())
+}
 
-        let options = UpdateOptions::builder().upsert(true).build();
-        if let Err(e) = collection.update_one_with_session(filtre, ops, options, session).await {
-            Err(format!("transactions.transaction_initialiser_appareil Erreur chargement collection : {:?}", e))?
+/// S'assure que l'appareil existe et indique que le senseur a des lectures disponibles.
+pub(crate) async fn maj_appareil_senseur_horaire<M>(
+    middleware: &M, user_id: &str, uuid_appareil: &str, senseur_id: &str, type_donnees: Option<&String>,
+    session: &mut ClientSession
+)
+    -> Result<(), Error>
+    where M: MongoDao
+{
+    let collection = middleware.get_collection(COLLECTIONS_APPAREILS)?;
+    let filtre = doc! {
+        CHAMP_USER_ID: user_id,
+        CHAMP_UUID_APPAREIL: uuid_appareil,
+    };
+    let mut ops = doc! {
+        "$setOnInsert": {
+            CHAMP_USER_ID: user_id,
+            CHAMP_UUID_APPAREIL: uuid_appareil,
+            CHAMP_CREATION: Utc::now(),
+            "present": false,
+        },
+        "$currentDate": {
+            CHAMP_MODIFICATION: true,
+        },
+        "$addToSet": {
+            CHAMP_LECTURES_DISPONIBLES: senseur_id
         }
+    };
+
+    if let Some(type_donnees) = type_donnees {
+        ops.insert("$set", doc!{
+            format!("types_donnees.{}", senseur_id): type_donnees
+        });
     }
 
-    Ok(Some(middleware.reponse_ok(None, None)?))
+    let options = UpdateOptions::builder().upsert(true).build();
+    if let Err(e) = collection.update_one_with_session(filtre, ops, options, session).await {
+        Err(format!("transactions.maj_appareil_senseur_horaire Erreur chargement collection : {:?}", e))?
+    }
+
+    Ok(())
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct TransactionMajConfigurationUsager {
-    timezone: Option<String>,
+    /// Timezone de l'usager. null retire la valeur.
+    #[serde(default, skip_serializing_if = "Option::is_none", deserialize_with = "deserialiser_champ_nullable")]
+    timezone: Option<Option<String>>,
+    /// Limite de z-score pour la detection d'anomalies sur les lectures horaires. null retire la valeur.
+    #[serde(default, skip_serializing_if = "Option::is_none", deserialize_with = "deserialiser_champ_nullable")]
+    anomalie_z_limite: Option<Option<f64>>,
+    /// Senseur de temperature exterieure utilise pour les degres-jours. null retire la valeur.
+    #[serde(default, skip_serializing_if = "Option::is_none", deserialize_with = "deserialiser_champ_nullable")]
+    senseur_exterieur: Option<Option<SenseurRef>>,
+}
+
+/// Distingue un champ absent (None, valeur conservee) d'un champ null (Some(None), valeur retiree).
+fn deserialiser_champ_nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+    where D: Deserializer<'de>, T: Deserialize<'de>
+{
+    Option::<T>::deserialize(deserializer).map(Some)
 }
 
 async fn transaction_maj_configuration_usager<M>(middleware: &M, transaction: TransactionValide, session: &mut ClientSession)
@@ -900,19 +1025,40 @@ async fn transaction_maj_configuration_usager<M>(middleware: &M, transaction: Tr
     let filtre = doc!{CHAMP_USER_ID: &user_id};
     let collection = middleware.get_collection(COLLECTIONS_USAGER)?;
 
-    let transaction_bson = match convertir_to_bson(contenu_transaction) {
-        Ok(inner) => inner,
-        Err(e) => Err(format!("senseurspassifs.transaction_maj_configuration_usager Erreur conversion : {:?}", e))?
-    };
+    let mut set_ops = doc! {};
+    let mut unset_ops = doc! {};
+    match contenu_transaction.timezone {
+        Some(Some(inner)) => { set_ops.insert("timezone", inner); },
+        Some(None) => { unset_ops.insert("timezone", true); },
+        None => ()
+    }
+    match contenu_transaction.anomalie_z_limite {
+        Some(Some(inner)) => { set_ops.insert("anomalie_z_limite", inner); },
+        Some(None) => { unset_ops.insert("anomalie_z_limite", true); },
+        None => ()
+    }
+    match contenu_transaction.senseur_exterieur {
+        Some(Some(inner)) => match convertir_to_bson(inner) {
+            Ok(inner) => { set_ops.insert("senseur_exterieur", inner); },
+            Err(e) => Err(format!("senseurspassifs.transaction_maj_configuration_usager Erreur conversion : {:?}", e))?
+        },
+        Some(None) => { unset_ops.insert("senseur_exterieur", true); },
+        None => ()
+    }
 
-    let ops = doc!{
-        "$set": transaction_bson,
+    let mut ops = doc!{
         "$setOnInsert": {
             CHAMP_USER_ID: &user_id,
             CHAMP_CREATION: Utc::now(),
         },
         "$currentDate": {CHAMP_MODIFICATION: true}
     };
+    if !set_ops.is_empty() {
+        ops.insert("$set", set_ops);
+    }
+    if !unset_ops.is_empty() {
+        ops.insert("$unset", unset_ops);
+    }
     let options = UpdateOptions::builder().upsert(true).build();
     if let Err(e) = collection.update_one_with_session(filtre, ops, options, session).await {
         Err(format!("senseurspassifs.transaction_maj_configuration_usager Erreur maj configuration : {:?}", e))?